@@ -0,0 +1,138 @@
+//! alpaca-fit: fits the distributions of a corpus of crawled pages, and
+//! writes the ".dist" files to give to the alpaca_dist_* directives.
+//!
+//!     alpaca-fit [--bins N] [--out DIR] CORPUS
+//!
+//! CORPUS is a .csv file (columns html_size, obj_sizes and optionally
+//! weight), a .jsonl file (a {"html_size": .., "obj_sizes": [..]} object per
+//! line), a .profile file, or a directory with a saved site per subdirectory.
+//! With --bins the files are histograms of (at most) N bins instead of the
+//! observed values. The best parametric fits are reported, and written in a
+//! comment of each file.
+extern crate alpaca;
+
+use alpaca::fit::{ self, Fit, Page };
+use alpaca::AlpacaError;
+use std::fs;
+use std::path::Path;
+use std::process;
+
+const USAGE: &str = "usage: alpaca-fit [--bins N] [--out DIR] CORPUS";
+
+struct Args {
+    corpus : String       ,
+    out    : String       ,
+    bins   : Option<usize>,
+}
+
+fn parse_args() -> Result<Args, String> {
+
+    let mut args   = std::env::args().skip(1);
+    let mut corpus = None;
+    let mut out    = String::from(".");
+    let mut bins   = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--bins" => bins = Some( args.next().and_then( |n| n.parse().ok() ).filter( |&n| n > 0 ).ok_or("--bins needs a positive number")? ),
+            "--out"  => out  = args.next().ok_or("--out needs a directory")?,
+            "-h" | "--help" => return Err( String::from(USAGE) ),
+            _ if corpus.is_none() && !arg.starts_with('-') => corpus = Some(arg),
+            _ => return Err( format!("unexpected argument {}\n{}", arg, USAGE) ),
+        }
+    }
+
+    Ok( Args { corpus: corpus.ok_or(USAGE)?, out, bins } )
+}
+
+fn read_corpus(path: &str) -> Result<Vec<Page>, AlpacaError> {
+
+    let read = || fs::read_to_string(path).map_err( |e| AlpacaError::Parse( format!("cannot read {}: {}", path, e) ) );
+
+    if Path::new(path).is_dir() {
+        fit::from_dir(path)
+    } else if path.ends_with(".csv") {
+        fit::from_csv( &read()? )
+    } else if path.ends_with(".jsonl") {
+        fit::from_jsonl( &read()? )
+    } else if path.ends_with(".profile") {
+        fit::from_profiles(path)
+    } else {
+        Err( AlpacaError::Parse( format!("unknown corpus format {} (expected .csv, .jsonl, .profile or a directory)", path) ) )
+    }
+}
+
+fn report(name: &str, fits: &[Fit]) {
+
+    println!("{}:", name);
+
+    for fit in fits {
+        println!("    {:<40} KS {:.4}   AIC {:.1}", fit.spec, fit.ks, fit.aic);
+    }
+}
+
+fn run(args: &Args) -> Result<(), AlpacaError> {
+
+    let pages = read_corpus(&args.corpus)?;
+
+    if pages.is_empty() {
+        return Err( AlpacaError::Parse( format!("no pages in {}", args.corpus) ) );
+    }
+
+    println!("{} pages\n", pages.len());
+
+    fs::create_dir_all(&args.out).map_err( |e| AlpacaError::Parse( format!("cannot create {}: {}", args.out, e) ) )?;
+
+    let marginals = vec![
+        ( "html_size"     , "HTML sizes"                 , fit::samples( &pages, &|p| p.html_size ) ),
+        ( "obj_num"       , "object counts"              , fit::samples( &pages, &|p| p.obj_sizes.len() ) ),
+        ( "obj_size"      , "object sizes"               , fit::object_samples(&pages) ),
+        ( "total_obj_size", "total object sizes per page", fit::samples( &pages, &|p| p.total_obj_size() ) ),
+        ( "total_size"    , "total page sizes"           , fit::samples( &pages, &|p| p.html_size + p.total_obj_size() ) ),
+    ];
+
+    let write = |name: &str, data: String| {
+        let path = Path::new(&args.out).join( format!("{}.dist", name) );
+
+        fs::write(&path, data).map_err( |e| AlpacaError::Parse( format!("cannot write {}: {}", path.display(), e) ) )?;
+        println!("    written to {}\n", path.display());
+
+        Ok(())
+    };
+
+    for (name, description, samples) in &marginals {
+
+        if samples.is_empty() {
+            continue;
+        }
+
+        let fits = fit::fit_parametric(samples);
+
+        report(description, &fits);
+
+        write( name, match args.bins {
+            Some(bins) => fit::histogram(samples, bins, name, description, &fits),
+            None       => fit::empirical(samples, name, description, &fits),
+        })?;
+    }
+
+    println!("joint html and total object sizes:");
+
+    write( "joint", fit::joint( &pages, args.bins, "joint html and total object sizes" ) )
+}
+
+fn main() {
+
+    let args = match parse_args() {
+        Ok (args) => args,
+        Err(msg)  => {
+            eprintln!("{}", msg);
+            process::exit(2);
+        }
+    };
+
+    if let Err(e) = run(&args) {
+        eprintln!("alpaca-fit: {}", e);
+        process::exit(1);
+    }
+}
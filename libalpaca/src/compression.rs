@@ -0,0 +1,260 @@
+//! Compresses the morphed html with the content-encoding negotiated with the
+//! client, so that the sampled html size is the size sent on the wire.
+use brotli;
+use pad;
+
+use error::{ AlpacaError, ALPACA_PANIC };
+use flate2::{ Compression, GzBuilder };
+use morphing::MorphInfo;
+use rand::RngCore;
+use std::io::Write;
+use utils::{ c_string_to_str, content_to_c, ffi_guard };
+
+// The brotli settings, those of nginx's brotli module by default.
+const BROTLI_QUALITY : u32   = 6;
+const BROTLI_LGWIN   : u32   = 22;
+const BROTLI_BUFFER  : usize = 4096;
+
+// The number of paddings tried for a brotli html, whose size can skip
+// the target when the padding grows by one character.
+const MAX_PADDING_ATTEMPTS: usize = 8;
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Encoding {
+    Identity,
+    Gzip    ,
+    Brotli  ,
+}
+
+impl Encoding {
+
+    // The encoding of the given code, as passed by the nginx module.
+    pub fn from_code(code: usize) -> Result<Encoding, AlpacaError> {
+        match code {
+            0 => Ok(Encoding::Identity),
+            1 => Ok(Encoding::Gzip    ),
+            2 => Ok(Encoding::Brotli  ),
+            n => Err( AlpacaError::Config( format!("invalid content encoding {}", n) ) ),
+        }
+    }
+
+    pub fn code(&self) -> u8 {
+        match *self {
+            Encoding::Identity => 0,
+            Encoding::Gzip     => 1,
+            Encoding::Brotli   => 2,
+        }
+    }
+
+    // The value of the Content-Encoding header.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Encoding::Identity => "identity",
+            Encoding::Gzip     => "gzip"    ,
+            Encoding::Brotli   => "br"      ,
+        }
+    }
+
+    // The encoding to use for an Accept-Encoding header: the one with the
+    // highest quality, brotli on ties. Encodings with q=0 are refused.
+    pub fn negotiate(accept_encoding: &str) -> Encoding {
+
+        let qualities: Vec<(String, f64)> = accept_encoding.split(',').map( |item| {
+
+            let mut parts = item.split(';');
+            let name      = parts.next().unwrap_or("").trim().to_ascii_lowercase();
+
+            let quality = parts.filter_map( |param| param.trim().strip_prefix("q=") )
+                               .map( |q| q.trim().parse::<f64>().unwrap_or(0.0) )
+                               .next()
+                               .unwrap_or(1.0);
+
+            (name, quality)
+        }).collect();
+
+        // The quality of an encoding, or of the wildcard if it is not listed
+        let quality = |names: &[&str]| {
+            qualities.iter().find( |(name, _)| names.contains( &name.as_str() ) )
+                     .or_else( || qualities.iter().find( |(name, _)| name == "*" ) )
+                     .map_or( 0.0, |&(_, q)| q )
+        };
+
+        let brotli = quality( &["br"] );
+        let gzip   = quality( &["gzip", "x-gzip"] );
+
+        if brotli > 0.0 && brotli >= gzip {
+            Encoding::Brotli
+        } else if gzip > 0.0 {
+            Encoding::Gzip
+        } else {
+            Encoding::Identity
+        }
+    }
+}
+
+// Compresses the content. A gzip comment adds its length plus one byte to
+// the header, outside the compressed stream.
+fn compress_with_comment(content: &[u8], encoding: Encoding, comment: Option<&[u8]>) -> Result<Vec<u8>, AlpacaError> {
+
+    let to_error = |e: std::io::Error| AlpacaError::Padding( format!( "cannot compress with {}: {}", encoding.name(), e ) );
+
+    match encoding {
+
+        Encoding::Identity => Ok( content.to_vec() ),
+
+        Encoding::Gzip => {
+
+            let mut builder = GzBuilder::new();
+
+            if let Some(comment) = comment {
+                builder = builder.comment(comment);
+            }
+
+            let mut encoder = builder.write( Vec::new(), Compression::default() );
+
+            encoder.write_all(content).map_err(to_error)?;
+            encoder.finish().map_err(to_error)
+        },
+
+        Encoding::Brotli => {
+
+            let mut encoder = brotli::CompressorWriter::new( Vec::new(), BROTLI_BUFFER, BROTLI_QUALITY, BROTLI_LGWIN );
+
+            encoder.write_all(content).map_err(to_error)?;
+            encoder.flush().map_err(to_error)?;
+
+            Ok( encoder.into_inner() )
+        },
+    }
+}
+
+pub fn compress(content: &[u8], encoding: Encoding) -> Result<Vec<u8>, AlpacaError> {
+    compress_with_comment(content, encoding, None)
+}
+
+// The html followed by a padding comment with the given characters.
+fn with_padding(content: &[u8], chars: &[u8]) -> Vec<u8> {
+
+    let mut padded = content.to_vec();
+    pad::append_html_comment(&mut padded, chars);
+
+    padded
+}
+
+// The smallest size of the html once padded and compressed, that is with
+// an empty padding comment.
+pub fn min_html_size(content: &[u8], encoding: Encoding) -> Result<usize, AlpacaError> {
+
+    match encoding {
+        Encoding::Identity => Ok( content.len() + pad::HTML_COMMENT_SIZE ),
+        _                  => Ok( compress( &with_padding(content, &[]), encoding )?.len() ),
+    }
+}
+
+// Pads the html so that it is exactly target_size bytes once compressed,
+// and returns the compressed html. The longest padding of random characters
+// that fits is found first; gzip fills the rest with a comment in its
+// header, while brotli tries other paddings and returns None if none fits
+// exactly.
+pub fn pad_html(content: Vec<u8>, target_size: usize, encoding: Encoding, rng: &mut dyn RngCore) -> Result<Option<Vec<u8>>, AlpacaError> {
+
+    if encoding == Encoding::Identity {
+
+        let mut content = content;
+        pad::get_html_padding(&mut content, target_size, rng)?;
+
+        return Ok( Some(content) );
+    }
+
+    let min_size = min_html_size(&content, encoding)?;
+
+    if target_size < min_size {
+        return Err( AlpacaError::Padding( format!( "cannot pad an html compressed to {} bytes to {} bytes", min_size, target_size ) ) );
+    }
+
+    for _ in 0..MAX_PADDING_ATTEMPTS {
+
+        // Random alphanumerics compress to at least 0.74 bytes each, so
+        // twice the missing bytes are more than enough.
+        let mut chars = Vec::new();
+        pad::add_random_chars( &mut chars, 2 * (target_size - min_size) + 64, rng );
+
+        let padded = |n: usize| compress( &with_padding(&content, &chars[..n]), encoding );
+
+        let mut fit = padded(0)?;
+        let (mut lo, mut hi) = (0, chars.len());
+
+        while hi - lo > 1 {
+
+            let mid        = lo + (hi - lo) / 2;
+            let compressed = padded(mid)?;
+
+            if compressed.len() <= target_size {
+                lo  = mid;
+                fit = compressed;
+            } else {
+                hi = mid;
+            }
+        }
+
+        let slack = target_size - fit.len();
+
+        if slack == 0 {
+            return Ok( Some(fit) );
+        }
+
+        if encoding == Encoding::Gzip {
+
+            let mut comment = Vec::new();
+            pad::add_random_chars( &mut comment, slack - 1, rng );
+
+            return compress_with_comment( &with_padding(&content, &chars[..lo]), encoding, Some(&comment) ).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+// Returns the encoding to use for the Accept-Encoding header given, as the
+// code expected by MorphInfo.
+#[no_mangle]
+pub extern "C" fn negotiate_encoding(accept_encoding: *const u8) -> u8 {
+
+    ffi_guard( "negotiate_encoding", Encoding::Identity.code(), || {
+
+        match c_string_to_str(accept_encoding) {
+            Ok (accept) => Encoding::negotiate(accept).code(),
+            Err(_)      => Encoding::Identity.code(),
+        }
+    })
+}
+
+// Compresses the original html with the negotiated encoding, for the pages
+// that cannot be morphed once the Content-Encoding header has been sent.
+#[no_mangle]
+pub extern "C" fn compress_content(pinfo: *mut MorphInfo) -> u8 {
+
+    ffi_guard( "compress_content", ALPACA_PANIC, || {
+
+        let res = MorphInfo::from_ptr(pinfo).and_then( |info| {
+
+            let encoding = info.encoding()?;
+
+            if info.content.is_null() {
+                return Err( AlpacaError::Parse( format!("no content to compress for {}", info.uri()) ) );
+            }
+
+            let content  = unsafe { std::slice::from_raw_parts(info.content, info.size) };
+
+            Ok( (compress(content, encoding)?, info) )
+        });
+
+        match res {
+            Ok ((content, info)) => content_to_c(content, info),
+            Err(e)               => {
+                eprintln!("libalpaca: compress_content: {}", e);
+                e.code()
+            }
+        }
+    })
+}
@@ -1,17 +1,19 @@
 //! Provides functions to sample objects' count and size
-//!	using the ALPaCA's deterministic way.
+//! using the ALPaCA's deterministic way.
+use rand::RngCore;
 use rand_distr::Distribution;
 
-// Returns the next multiple of "num" which is greater
-// or equal than "min".
-pub fn get_multiple(num: usize, min: usize) -> usize {
+use error::AlpacaError;
 
-    let mut count = num;
+// Returns the next multiple of "num" which is greater
+// or equal than "min" (and positive).
+pub fn get_multiple(num: usize, min: usize) -> Result<usize, AlpacaError> {
 
-    while count < min {
-        count += num;
+    if num == 0 {
+        return Err( AlpacaError::Config( String::from("cannot get a multiple of 0") ) );
     }
-    count
+
+    Ok( num * std::cmp::max(1, min.div_ceil(num)) )
 }
 
 // Returns a vector of target sizes for the fake objects. Sizes have
@@ -19,10 +21,11 @@ pub fn get_multiple(num: usize, min: usize) -> usize {
 // They are sampled uniformly
 pub fn get_multiples_in_range( obj_size     : usize,
                                max_obj_size : usize,
-                               n            : usize, ) -> Result<Vec<usize>, String> {
+                               n            : usize,
+                               rng          : &mut dyn RngCore ) -> Result<Vec<usize>, AlpacaError> {
 
-    if (obj_size > max_obj_size) || (max_obj_size % obj_size != 0) {
-        return Err( format!( "max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, obj_size ) );
+    if (obj_size == 0) || (obj_size > max_obj_size) || (!max_obj_size.is_multiple_of(obj_size)) {
+        return Err( AlpacaError::Config( format!( "max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, obj_size ) ) );
     }
 
     let mut sizes: Vec<usize> = Vec::with_capacity(n); // Vector of target sizes.
@@ -32,9 +35,58 @@ pub fn get_multiples_in_range( obj_size     : usize,
 
     for _ in 0..n {
 
-        let num: usize = between.sample( &mut rand::thread_rng() );
+        let num: usize = between.sample(rng);
         sizes.push(num * obj_size);
     }
 
     Ok(sizes)
-}
\ No newline at end of file
+}
+
+// The low bits of n which Padmé rounds away: for a size with an exponent
+// of e bits, only the floor(log2 e) + 1 bits after the leading one are kept.
+fn padme_mask(n: usize) -> usize {
+
+    if n < 2 {
+        return 0;
+    }
+
+    let e = (usize::BITS - 1 - n.leading_zeros()) as usize; // floor(log2 n)
+    let s = (usize::BITS - e.leading_zeros()) as usize;     // floor(log2 e) + 1
+
+    (1 << e.saturating_sub(s)) - 1
+}
+
+// Returns the smallest Padmé value which is greater or equal than "min".
+// Padmé values add at most about 12% to a size, and a size of n can take
+// only O(log log n) bits of information.
+pub fn padme(min: usize) -> usize {
+
+    let mask = padme_mask(min);
+
+    match min.checked_add(mask) {
+        Some(n) => n & !mask,
+        None    => min      , // too close to usize::MAX to round up
+    }
+}
+
+// Returns the largest Padmé value which is smaller or equal than "max".
+pub fn padme_floor(max: usize) -> usize {
+    max & !padme_mask(max)
+}
+
+// Returns a vector of target sizes for the fake objects using Padmé.
+// A size is sampled uniformly up to "max_obj_size" and rounded up to a
+// Padmé value, or down if that exceeds "max_obj_size".
+pub fn get_padme_in_range( max_obj_size : usize,
+                           n            : usize,
+                           rng          : &mut dyn RngCore ) -> Result<Vec<usize>, AlpacaError> {
+
+    if max_obj_size == 0 {
+        return Err( AlpacaError::Config( String::from("max_obj_size must be positive") ) );
+    }
+
+    let largest = padme_floor(max_obj_size);
+    let between = rand_distr::Uniform::from(1..=max_obj_size);
+
+    Ok( (0..n).map( |_| std::cmp::min( padme( between.sample(rng) ), largest ) ).collect() )
+}
@@ -0,0 +1,190 @@
+//! Parses ".dist" files. Each row gives a probability and a value (or a
+//! value per dimension for joint distributions):
+//!     prob value [value ...]
+//! Rows can be preceded by a header of "@" directives:
+//!     @version     1
+//!     @columns     prob html_size obj_size
+//!     @units       bytes | KB | KiB | MB | MiB
+//!     @description free text
+//!     @normalize
+//!     @bins
+//! With @bins, each value is a bin "low high" (high excluded) instead.
+//! Blank lines and "#" comments are ignored, and numbers can be given in
+//! scientific notation. Files without a header are read as version 1.
+use error::AlpacaError;
+
+// The latest version of the format.
+pub const VERSION: u32 = 1;
+
+// How far from 1 the probabilities may sum up, unless normalized.
+const PROB_TOLERANCE: f64 = 1e-3;
+
+// The rows of a ".dist" file.
+pub struct Rows {
+    pub probs  : Vec<f64>         ,
+    pub values : Vec< Vec<usize> >,
+    pub bins   : bool             , // whether the values are bins "low high"
+}
+
+// Returns the probabilities and the values of the rows.
+pub fn parse(path: &str, data: &str) -> Result<Rows, AlpacaError> {
+
+    let mut columns   : Option<usize> = None; // values per row, if declared
+    let mut unit                      = 1.0;
+    let mut normalize                 = false;
+    let mut bins                      = false;
+
+    let mut probs  : Vec<f64>          = Vec::new();
+    let mut values : Vec< Vec<usize> > = Vec::new();
+
+    for (i, line) in data.lines().enumerate() {
+
+        let error  = |col: usize, msg: String| AlpacaError::Distribution( format!("dist file {}, line {}, column {}: {}", path, i+1, col, msg) );
+        let column = |token: &str| token.as_ptr() as usize - line.as_ptr() as usize + 1;
+
+        let content = match line.find('#') {
+            Some(pos) => &line[..pos],
+            None      => line        ,
+        };
+
+        let tokens: Vec<&str> = content.split_whitespace().collect();
+
+        if tokens.is_empty() {
+            continue;
+        }
+
+        if tokens[0].starts_with('@') {
+
+            if !values.is_empty() {
+                return Err( error( column(tokens[0]), String::from("header directives must come before the rows") ) );
+            }
+
+            let arg = || match tokens.len() {
+                2 => Ok(tokens[1]),
+                _ => Err( error( column(tokens[0]), format!("{} takes one argument", tokens[0]) ) ),
+            };
+
+            match tokens[0] {
+
+                "@version" => {
+                    let version = arg()?;
+
+                    match version.parse::<u32>() {
+                        Ok (v) if v >= 1 && v <= VERSION => (),
+                        _                                => return Err( error( column(version), format!("unsupported version {} (latest: {})", version, VERSION) ) ),
+                    }
+                },
+
+                "@columns" => {
+                    if tokens.len() < 3 || tokens[1] != "prob" {
+                        return Err( error( column(tokens[0]), String::from("expected @columns prob name [name ...]") ) );
+                    }
+
+                    columns = Some( tokens.len() - 2 );
+                },
+
+                "@units" => {
+                    let units = arg()?;
+
+                    unit = match units {
+                        "B" | "bytes" => 1.0         ,
+                        "KB" | "kB"   => 1e3         ,
+                        "KiB"         => 1024.0      ,
+                        "MB"          => 1e6         ,
+                        "MiB"         => 1048576.0   ,
+                        _             => return Err( error( column(units), format!("unknown units {}", units) ) ),
+                    };
+                },
+
+                "@description" => (),
+
+                "@normalize" => normalize = true,
+
+                "@bins" => bins = true,
+
+                _ => return Err( error( column(tokens[0]), format!("unknown directive {}", tokens[0]) ) ),
+            }
+
+            continue;
+        }
+
+        // The first row sets the number of values, unless declared in the header
+        let width = match columns {
+            Some(width) => width,
+            None        => values.first().map( |v| v.len() ).unwrap_or( tokens.len() - 1 ),
+        };
+
+        if tokens.len() < 2 || tokens.len() != width + 1 {
+
+            let col = match tokens.get(width + 1) {
+                Some(extra) => column(extra),
+                None        => content.trim_end().len() + 1,
+            };
+
+            return Err( error( col, format!("expected a probability and {} values, found {} values", std::cmp::max(width, 1), tokens.len() - 1) ) );
+        }
+
+        let prob = match tokens[0].parse::<f64>() {
+            Ok (p) if p.is_finite() && p >= 0.0 => p,
+            _                                   => return Err( error( column(tokens[0]), format!("invalid probability {}", tokens[0]) ) ),
+        };
+
+        let mut row = Vec::with_capacity(width);
+
+        for token in &tokens[1..] {
+            row.push( parse_value(token, unit).ok_or_else( || error( column(token), format!("invalid value {}", token) ) )? );
+        }
+
+        if bins {
+
+            if row.len() % 2 != 0 {
+                return Err( error( column(tokens[1]), format!("expected a low and a high value per bin, found {} values", row.len()) ) );
+            }
+
+            if let Some(k) = (0..row.len()).step_by(2).find( |&k| row[k] >= row[k + 1] ) {
+                return Err( error( column(tokens[k + 2]), format!("empty bin [{}, {})", row[k], row[k + 1]) ) );
+            }
+        }
+
+        probs .push(prob);
+        values.push(row);
+    }
+
+    if values.is_empty() {
+        return Err( AlpacaError::Distribution( format!("empty dist file {}", path) ) );
+    }
+
+    let sum: f64 = probs.iter().sum();
+
+    if normalize {
+
+        if sum <= 0.0 {
+            return Err( AlpacaError::Distribution( format!("dist file {}: probabilities sum up to 0", path) ) );
+        }
+
+        probs.iter_mut().for_each( |p| *p /= sum );
+
+    } else if (sum - 1.0).abs() > PROB_TOLERANCE {
+        return Err( AlpacaError::Distribution( format!("dist file {}: probabilities sum up to {}, not 1 (add @normalize to normalize them)", path, sum) ) );
+    }
+
+    Ok( Rows { probs, values, bins } )
+}
+
+// A value in bytes, rounded to the nearest one.
+fn parse_value(token: &str, unit: f64) -> Option<usize> {
+
+    if unit == 1.0 {
+        if let Ok(v) = token.parse::<usize>() {
+            return Some(v);
+        }
+    }
+
+    let v = token.parse::<f64>().ok()? * unit;
+
+    if v.is_finite() && v >= 0.0 && v < usize::MAX as f64 {
+        Some( v.round() as usize )
+    } else {
+        None
+    }
+}
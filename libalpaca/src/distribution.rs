@@ -1,144 +1,346 @@
 //! Provides functions to sample objects' count and size from a
 //! probability distribution.
-use rand::Rng;
-use rand_distr::Distribution;
+use rand::{ Rng, RngCore };
 use rand_distr;
 use std::{ str, fs };
-use utils;
 
-// Number of tries per sample. If no sampled number satisfies a specified
-// threshold after `SAMPLE_LIMIT` tries the sampling function returns Err.
-const SAMPLE_LIMIT: usize = 30;
+use dist_file;
+use error::AlpacaError;
+use table::{ SortedPairs, SortedRows };
+use truncated;
 
-// Probability distribution
+// Probability distribution. The default (empty) one means "use the real value".
+#[derive(Default)]
 pub struct Dist {
     pub name  : String                     ,
     pub params: Vec<f64>                   , // For predefined distributions these are the params (eg mean, lambda, etc). For custom, these are the probabilities
     pub values: Option< Vec< Vec<usize> > >, // Only for custom, the values
+    pub bins  : bool                       , // Only for custom, whether the values are bins "low high"
+    pub components: Vec<Dist>              , // Only for Mix, the mixed distributions (params are their weights)
+
+    // Precomputed when loading. For custom: the running sum of the
+    // probabilities, and the rows sorted for sampling single values or bins
+    // (None if they are overlapping bins) and pairs of values. For Zipf:
+    // the tail sums of the weights.
+    pub cumulative   : Vec<f64>           ,
+    pub sorted       : Option<SortedRows> ,
+    pub sorted_pairs : Option<SortedPairs>,
 }
 
 // Parses a given distribution from the config file
 impl Dist {
 
     // Construct a Distributions object
-    pub fn from(dist: &str) -> Result<Dist,String> {
+    pub fn from(dist: &str) -> Result<Dist, AlpacaError> {
 
-        if dist.ends_with(".dist") {
+        if let Some(inner) = dist.strip_prefix("Mix(").and_then( |d| d.strip_suffix(')') ) {
 
-            // A distribution file has been given
-            let res = utils::stringify_error(fs::read_to_string(dist.clone()));
+            // A mixture "Mix(w1*spec1; w2*spec2; ...)" has been given
+            let mut weights    = Vec::new();
+            let mut components = Vec::new();
 
-            if res.is_err() {
-                eprint!("libalpaca: cannot open {}: \n", dist);
-            }
+            for part in split_components(inner) {
 
-            let data = res?;
+                let invalid = |msg: &str| AlpacaError::Distribution( format!("invalid component {} in distribution {}: {}", part.trim(), dist, msg) );
 
-            // Construct the 2 vectors containing the values and probabilities
-            let mut values: Vec< Vec<usize> > = Vec::new();
-            let mut probs : Vec<f64>          = Vec::new();
+                let (weight, spec) = match part.find('*') {
+                    Some(pos) => ( part[..pos].trim(), part[pos+1..].trim() ),
+                    None      => return Err( invalid("expected weight*distribution") ),
+                };
 
-            for line in data.lines() {
+                let weight = match weight.parse::<f64>() {
+                    Ok (w) if w > 0.0 && w.is_finite() => w,
+                    _                                  => return Err( invalid("invalid weight") ),
+                };
 
-                let l            = String::from(line);
-                let v: Vec<&str> = l.split_whitespace().collect();
+                let component = Dist::from(spec)?;
 
-                if values.len() > 0 && v.len() != values[0].len()+1 {
-                    return Err( format!("invalid dist file {}, line {}", dist, line) );
+                if component.name.is_empty() || component.name == "Joint" {
+                    return Err( invalid("empty distribution") );
                 }
 
-                probs .push( v[0].parse().unwrap() );
-                values.push( v[1..].iter().map(|e| e.parse().unwrap()).collect() );
+                weights   .push(weight);
+                components.push(component);
             }
 
-            return Ok(Dist {
-                name  : String::from("custom"),
-                params: probs                 ,
-                values: Some(values)          ,
-            });
+            if components.is_empty() {
+                return Err( AlpacaError::Distribution( format!("empty mixture {}", dist) ) );
+            }
+
+            Ok( Dist {
+                name   : String::from("Mix"),
+                params : weights            ,
+                components                  ,
+                ..Dist::default()
+            })
+
+        } else if dist.ends_with(".dist") {
+
+            // A distribution file has been given
+            let data = fs::read_to_string(dist).map_err( |e| AlpacaError::Distribution( format!("cannot open {}: {}", dist, e) ) )?;
+
+            let dist_file::Rows { probs, values, bins } = dist_file::parse(dist, &data)?;
+
+            let cumulative = probs.iter()
+                                  .scan( 0.0, |sum, prob| { *sum += prob; Some(*sum) } )
+                                  .collect();
 
-        } else if dist == "" || dist == "Joint" {
+            // Single values or bins, and pairs of values
+            let dims         = if bins { values[0].len() / 2 } else { values[0].len() };
+            let sorted       = if dims == 1          { SortedRows::new( &values, &probs, bins ) } else { None };
+            let sorted_pairs = if dims == 2 && !bins { Some( SortedPairs::new( &values, &probs ) ) } else { None };
 
-            return Ok( Dist {
+            Ok(Dist {
+                name         : String::from("custom"),
+                params       : probs                 ,
+                values       : Some(values)          ,
+                bins                                 ,
+                cumulative                           ,
+                sorted                               ,
+                sorted_pairs                         ,
+                ..Dist::default()
+            })
+
+        } else if dist.is_empty() || dist == "Joint" {
+
+            Ok( Dist {
                 name  : String::from(dist),
-                params: Vec::new()        ,
-                values: None              ,
-            });
+                ..Dist::default()
+            })
 
         } else {
 
-            let tokens: Vec<&str> = dist.split("/").collect();
+            let tokens: Vec<&str> = dist.split('/').collect();
 
             if tokens.len() != 2 {
-                return Err( format!("invalid distribution {}", dist) );
+                return Err( AlpacaError::Distribution( format!("invalid distribution {}", dist) ) );
             }
 
-            let name             = tokens[0];
-            let params: Vec<f64> = tokens[1].split(",").map( |s| s.parse().unwrap() ).collect(); // Distributions parameters
+            let name = tokens[0];
+
+            // Distributions parameters
+            let params: Vec<f64> = tokens[1].split(',')
+                                            .map( |s| s.trim().parse().map_err( |_| AlpacaError::Distribution( format!("invalid parameter {} in distribution {}", s, dist) ) ) )
+                                            .collect::<Result<_,_>>()?;
 
             let params_needed = match name {
-                "Normal"    => 2,
-                "LogNormal" => 2,
-                "Exp"       => 1,
-                "Poisson"   => 1,
-                "Binomial"  => 2,
-                "Gamma"     => 2,
-                _           => return Err( format!("invalid distribution {}", dist) ),
+                "Normal"      => 2,
+                "LogNormal"   => 2,
+                "Exp"         => 1,
+                "Poisson"     => 1,
+                "Binomial"    => 2,
+                "Gamma"       => 2,
+                "Uniform"     => 2,
+                "Pareto"      => 2,
+                "Weibull"     => 2,
+                "Zipf"        => 2,
+                "Geometric"   => 1,
+                "NegBinomial" => 2,
+                _             => return Err( AlpacaError::Distribution( format!("invalid distribution {}", dist) ) ),
             };
 
             // A predefined distribution and its parameters have been given
             if params.len() != params_needed {
-                return Err( format!( "{} distribution requires {} params, {} given", name, params_needed, params.len() ) );
+                return Err( AlpacaError::Distribution( format!( "{} distribution requires {} params, {} given", name, params_needed, params.len() ) ) );
             }
 
-            return Ok(Dist {
+            // Make sure the parameters are accepted, so that sampling cannot fail later
+            truncated::check_params(name, &params)?;
+
+            let cumulative = if name == "Zipf" { truncated::zipf_tails( params[0] as usize, params[1] ) } else { Vec::new() };
+
+            let dist = Dist {
                 name  : String::from(name),
-                params: params            ,
-                values: None              ,
-            });
+                params                    ,
+                cumulative                ,
+                ..Dist::default()
+            };
+
+            truncated::check_support(&dist)?;
+
+            Ok(dist)
         }
     }
 }
 
-pub fn sample_ge_many(dist:&Dist, lower_bound:usize, samples:usize) -> Result< Vec<usize>, String > {
+pub fn sample_ge_many(dist:&Dist, lower_bound:usize, samples:usize, rng:&mut dyn RngCore) -> Result< Vec<usize>, AlpacaError > {
 
     let mut vec: Vec<usize> = Vec::new();
 
     for _ in 0..samples {
-        vec.push( sample_ge(dist, lower_bound)? );
+        vec.push( sample_ge(dist, lower_bound, rng)? );
     }
 
     Ok(vec)
 }
 
 // Samples a value greater or equal than the given one
-pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize, String> {
+pub fn sample_ge(dist:&Dist, lower_bound:usize, rng:&mut dyn RngCore) -> Result<usize, AlpacaError> {
+    sample_between(dist, lower_bound, usize::MAX, rng)
+}
+
+// Samples a value in [lower_bound, upper_bound]
+pub fn sample_between(dist:&Dist, lower_bound:usize, upper_bound:usize, rng:&mut dyn RngCore) -> Result<usize, AlpacaError> {
 
     if dist.name == "custom" {
 
-        let values = dist.values.as_ref().unwrap();
+        Ok( sample_custom( dist, &[(lower_bound, upper_bound)], rng )?[0] )
+
+    } else if dist.name == "Mix" {
+
+        let component = choose_component( dist, &|c| ln_mass(c, lower_bound, upper_bound), rng )?
+                            .ok_or_else( || no_mass( dist, &[(lower_bound, upper_bound)] ) )?;
+
+        sample_between(component, lower_bound, upper_bound, rng)
+
+    } else if dist.name.is_empty() {
+        // Empty dist means use the real value
+        Ok(lower_bound)
+
+    } else {
+        // Sample exactly from the distribution truncated to the range
+        truncated::sample_truncated(dist, lower_bound, upper_bound, rng)
+    }
+}
+
+// Returns a pair (a,b) from a joint distribution, satisfying
+//    a >= lb_a   and   b >= lb_b      where (a,b) = lower_bound
+pub fn sample_pair_ge( dist: &Dist, lower_bound: (usize, usize), rng: &mut dyn RngCore ) -> Result<(usize,usize), AlpacaError> {
+
+    let pair = sample_joint_ge( dist, &[lower_bound.0, lower_bound.1], rng )?;
 
-        if values[0].len() != 1 {
-            return Err( format!( "alpaca: custom distribution contains {} values per row, expected 1", values[0].len() ) );
+    Ok( (pair[0], pair[1]) )
+}
+
+// Returns a value per dimension from a joint distribution, each greater or
+// equal than its lower bound.
+pub fn sample_joint_ge( dist: &Dist, lower_bounds: &[usize], rng: &mut dyn RngCore ) -> Result<Vec<usize>, AlpacaError> {
+
+    let ranges: Vec<(usize, usize)> = lower_bounds.iter().map( |&lb| (lb, usize::MAX) ).collect();
+
+    if dist.name == "Mix" {
+
+        let component = choose_component( dist, &|c| ln_joint_mass(c, &ranges), rng )?
+                            .ok_or_else( || no_mass(dist, &ranges) )?;
+
+        return sample_joint_ge(component, lower_bounds, rng);
+    }
+
+    if dist.name != "custom" {
+        return Err( AlpacaError::Distribution( format!( "joint distributions need to be given in a file (got: {})", dist.name) ) );
+    }
+
+    sample_custom(dist, &ranges, rng)
+}
+
+// The number of dimensions of a custom distribution (or of the first
+// component of a mixture), or 1 for the other distributions.
+pub fn dimensions(dist: &Dist) -> usize {
+
+    match dist.name.as_str() {
+        "custom" => dist.values.as_ref()
+                               .and_then( |values| values.first() )
+                               .map_or( 1, |row| if dist.bins { row.len() / 2 } else { row.len() } ),
+        "Mix"    => dist.components.first().map_or( 1, dimensions ),
+        _        => 1,
+    }
+}
+
+// Returns the rows of a custom distribution sampled in "dims" dimensions,
+// and whether they are bins: each row has either a value per dimension, or
+// a bin "low high" (high excluded) per dimension.
+fn custom_rows(dist: &Dist, dims: usize) -> Result<(&Vec< Vec<usize> >, bool), AlpacaError> {
+
+    match dist.values {
+        Some(ref values) if !values.is_empty() && dimensions(dist) == dims => Ok( (values, dist.bins) ),
+        Some(ref values) if !values.is_empty() => {
+            Err( AlpacaError::Distribution( format!( "custom distribution has {} dimensions, expected {}", dimensions(dist), dims ) ) )
+        },
+        _ => Err( AlpacaError::Distribution( String::from("empty custom distribution") ) ),
+    }
+}
+
+// The values of a row in dimension d which are in the range, and the
+// fraction of the row's mass they carry. None if there are no such values.
+fn row_overlap(row: &[usize], bins: bool, d: usize, range: (usize, usize)) -> Result<Option<(usize, usize, f64)>, AlpacaError> {
+
+    let (low, high) = if bins {
+
+        if row[2*d] >= row[2*d+1] {
+            return Err( AlpacaError::Distribution( format!( "empty bin [{}, {}) in custom distribution", row[2*d], row[2*d+1] ) ) );
         }
 
-        // Sample from custom distribution in a single try, by considering only values >= lower_bound
-        let total_mass: f64 = ( 0..values.len() ).filter( |i| values[*i][0] >= lower_bound ).map( |i| dist.params[i] ).sum();
+        ( row[2*d], row[2*d+1] - 1 )
+
+    } else {
+        ( row[d], row[d] )
+    };
+
+    let lo = std::cmp::max(low , range.0);
+    let hi = std::cmp::min(high, range.1);
+
+    if lo > hi {
+        return Ok(None);
+    }
+
+    Ok( Some( (lo, hi, (hi - lo + 1) as f64 / (high - low + 1) as f64) ) )
+}
 
-        if total_mass < 1e-5 {
-            return Err( format!("values >= {} have prob 0 in custom distribution", lower_bound) );
+// The fraction of a row's mass in the ranges.
+fn row_fraction(row: &[usize], bins: bool, ranges: &[(usize, usize)]) -> Result<f64, AlpacaError> {
+
+    let mut fraction = 1.0;
+
+    for (d, &range) in ranges.iter().enumerate() {
+        match row_overlap(row, bins, d, range)? {
+            Some( (_, _, f) ) => fraction *= f,
+            None              => return Ok(0.0),
+        }
+    }
+
+    Ok(fraction)
+}
+
+// Samples a value per dimension from a custom distribution, each within
+// its range. Bins are sampled uniformly.
+fn sample_custom(dist: &Dist, ranges: &[(usize, usize)], rng: &mut dyn RngCore) -> Result<Vec<usize>, AlpacaError> {
+
+    let (rows, bins) = custom_rows(dist, ranges.len())?;
+
+    let indexed = match *ranges {
+        [ range ]                 => dist.sorted.as_ref().map( |sorted| sorted.sample(range, rng) ),
+        [ (lb_a, _), (lb_b, _) ]  => dist.sorted_pairs.as_ref().map( |sorted| sorted.sample(rows, (lb_a, lb_b), rng) ),
+        _                         => None,
+    };
+
+    let pos = if let Some(pos) = indexed {
+
+        // A binary search in the sorted rows
+        pos.ok_or_else( || no_mass(dist, ranges) )?
+
+    } else {
+
+        // Sample from custom distribution in a single try, by considering only values in range
+        let masses: Vec<f64> = rows.iter()
+                                   .zip(&dist.params)
+                                   .map( |(row, prob)| Ok( prob * row_fraction(row, bins, ranges)? ) )
+                                   .collect::<Result<_, AlpacaError>>()?;
+
+        let total_mass: f64 = masses.iter().sum();
+
+        if total_mass.is_nan() || total_mass <= 0.0 {
+            return Err( no_mass(dist, ranges) );
         }
 
-        let probability: f64 = rand::thread_rng().sample( rand_distr::OpenClosed01 );
+        let probability: f64 = rng.sample( rand_distr::OpenClosed01 );
         let mut sum          = 0.0;
-        let mut sampled_num  = 0;
+        let mut pos          = 0;
 
-        // Sample a value from the given distribution
-        for i in 0..values.len() {
-            if values[i][0] >= lower_bound {
+        for (i, mass) in masses.iter().enumerate() {
+            if *mass > 0.0 {
 
-                sampled_num  = values[i][0];            // make sure we keep one
-                sum         += dist.params[i] / total_mass;
+                pos  = i;                // make sure we keep one
+                sum += mass / total_mass;
 
                 if sum >= probability {
                     break;
@@ -146,107 +348,173 @@ pub fn sample_ge(dist:&Dist, lower_bound:usize) -> Result<usize, String> {
             }
         }
 
-        Ok(sampled_num)
+        pos
+    };
 
-    } else if dist.name == "" {
-        // Empty dist means use the real value
-        Ok(lower_bound)
+    // A value in range, uniformly within the row's bin
+    let mut sample = Vec::with_capacity( ranges.len() );
 
-    } else {
+    for (d, &range) in ranges.iter().enumerate() {
+
+        let (lo, hi, _) = row_overlap( &rows[pos], bins, d, range )?
+                              .ok_or_else( || no_mass(dist, ranges) )?;
 
-        for _ in 0..SAMPLE_LIMIT {
+        sample.push( if lo == hi { lo } else { rng.gen_range(lo, hi + 1) } );
+    }
 
-            let sampled_num = sample_predefined(dist);
+    Ok(sample)
+}
 
-            if sampled_num >= lower_bound {
-                return Ok(sampled_num);
-            }
-        }
+// ln of the probability that a sample is in [lower_bound, upper_bound].
+fn ln_mass(dist: &Dist, lower_bound: usize, upper_bound: usize) -> Result<f64, AlpacaError> {
 
-        Err( format!("SAMPLE_LIMIT={} reached for distribution {}", SAMPLE_LIMIT, dist.name) )
+    match dist.name.as_str() {
+        "custom" => custom_ln_mass( dist, &[(lower_bound, upper_bound)] ),
+        "Mix"    => mix_ln_mass( dist, &|c| ln_mass(c, lower_bound, upper_bound) ),
+        _        => Ok( truncated::ln_mass(dist, lower_bound, upper_bound) ),
     }
 }
 
-// Returns a pair (a,b) from a joint distribution, satisfying
-//    a >= lb_a   and   b >= lb_b      where (a,b) = lower_bound
-pub fn sample_pair_ge( dist: &Dist, lower_bound: (usize, usize) ) -> Result<(usize,usize), String> {
+// ln of the probability that a sample of a joint distribution is in the ranges.
+fn ln_joint_mass(dist: &Dist, ranges: &[(usize, usize)]) -> Result<f64, AlpacaError> {
 
-    if dist.name != "custom" {
-        return Err( format!( "alpaca: joint distributions need to be given in a file (got: {})", dist.name) );
+    match dist.name.as_str() {
+        "custom" => custom_ln_mass(dist, ranges),
+        "Mix"    => mix_ln_mass( dist, &|c| ln_joint_mass(c, ranges) ),
+        _        => Err( AlpacaError::Distribution( format!( "joint distributions need to be given in a file (got: {})", dist.name) ) ),
     }
+}
 
-    let values = dist.values.as_ref().unwrap();
+fn custom_ln_mass(dist: &Dist, ranges: &[(usize, usize)]) -> Result<f64, AlpacaError> {
 
-    if values[0].len() != 2 {
-        return Err( format!( "alpaca: custom distribution contains {} values per row, expected 2", values[0].len() ) );
-    }
+    let (rows, bins) = custom_rows(dist, ranges.len())?;
+
+    let indexed = match *ranges {
+        [ range ]                => dist.sorted.as_ref().map( |sorted| sorted.mass(range) ),
+        [ (lb_a, _), (lb_b, _) ] => dist.sorted_pairs.as_ref().map( |sorted| sorted.mass(rows, (lb_a, lb_b)) ),
+        _                        => None,
+    };
 
-    // Sample from custom distribution in a single try, by considering only values >= lower_bound
-    let (lb_a, lb_b) = lower_bound;
+    let mass = match indexed {
+        Some(mass) => mass,
+        None       => {
+            let mut mass = 0.0;
 
-    let total_mass: f64 = ( 0..values.len() ).filter( |i| values[*i][0] >= lb_a && values[*i][1] >= lb_b ).map( |i| dist.params[i] ).sum();
+            for (row, prob) in rows.iter().zip(&dist.params) {
+                mass += prob * row_fraction(row, bins, ranges)?;
+            }
+
+            mass
+        },
+    };
+
+    let total_mass = dist.cumulative.last().cloned().unwrap_or(0.0);
+
+    Ok( if mass > 0.0 { (mass / total_mass).ln() } else { f64::NEG_INFINITY } )
+}
+
+// ln of the sum of the weighted component masses, normalized by the weights.
+fn mix_ln_mass(dist: &Dist, ln_mass: &dyn Fn(&Dist) -> Result<f64, AlpacaError>) -> Result<f64, AlpacaError> {
+
+    let masses = weighted_ln_masses(dist, ln_mass)?;
+    let max    = masses.iter().cloned().fold( f64::NEG_INFINITY, f64::max );
 
-    if total_mass < 1e-5 {
-        return Err( format!("values >= ({},{}) have prob 0 in custom distribution", lb_a, lb_b) );
+    if max == f64::NEG_INFINITY {
+        return Ok(max);
     }
 
-    let probability: f64 = rand::thread_rng().sample( rand_distr::OpenClosed01 );
+    let total: f64 = dist.params.iter().sum();
 
-    let mut sum       = 0.0;
-    let mut sampled_a = 0;
-    let mut sampled_b = 0;
+    Ok( max + masses.iter().map( |m| (m - max).exp() ).sum::<f64>().ln() - total.ln() )
+}
 
-    // Sample a value from the given distribution
-    for i in 0..values.len() {
+// ln(weight * mass) of each component of a mixture.
+fn weighted_ln_masses(dist: &Dist, ln_mass: &dyn Fn(&Dist) -> Result<f64, AlpacaError>) -> Result<Vec<f64>, AlpacaError> {
 
-        if values[i][0] >= lb_a && values[i][1] >= lb_b {
+    dist.components.iter()
+                   .zip(&dist.params)
+                   .map( |(c, w)| Ok( w.ln() + ln_mass(c)? ) )
+                   .collect()
+}
 
-            sampled_a  = values[i][0];            // make sure
-            sampled_b  = values[i][1];            // we keep one
-            sum       += dist.params[i] / total_mass;
+// Chooses a component of a mixture, with probability proportional to its
+// weight times its mass in range. None if no component has mass in range.
+fn choose_component<'a>( dist    : &'a Dist                                         ,
+                         ln_mass : &dyn Fn(&Dist) -> Result<f64, AlpacaError>       ,
+                         rng     : &mut dyn RngCore                                  ) -> Result<Option<&'a Dist>, AlpacaError>
+{
+    let masses = weighted_ln_masses(dist, ln_mass)?;
+    let max    = masses.iter().cloned().fold( f64::NEG_INFINITY, f64::max );
+
+    if max == f64::NEG_INFINITY {
+        return Ok(None);
+    }
 
-            if sum >= probability {
-                break;
-            }
+    let weights: Vec<f64> = masses.iter().map( |m| (m - max).exp() ).collect();
+    let total: f64        = weights.iter().sum();
+
+    let probability: f64 = rng.sample( rand_distr::OpenClosed01 );
+    let mut sum          = 0.0;
+
+    for (component, weight) in dist.components.iter().zip(&weights) {
+
+        sum += weight / total;
+
+        if sum >= probability {
+            return Ok( Some(component) );
         }
     }
 
-    Ok( (sampled_a, sampled_b) )
+    Ok( dist.components.iter().zip(&weights).rev().find( |(_, &w)| w > 0.0 ).map( |(c, _)| c ) )
 }
 
-fn sample_predefined(dist: &Dist) -> usize {
+fn no_mass(dist: &Dist, ranges: &[(usize, usize)]) -> AlpacaError {
 
-    match dist.name.as_str() {
+    let name  = if dist.name == "custom" { "custom distribution".to_string() } else { format!("distribution {}", dist.name) };
+    let range = match ranges {
+        [ (lb, ub) ] if *ub == usize::MAX => format!(">= {}", lb),
+        [ (lb, ub) ]                      => format!("in [{}, {}]", lb, ub),
+        _                                 => format!(">= ({})", ranges.iter().map( |r| r.0.to_string() ).collect::<Vec<_>>().join(",")),
+    };
 
-        "Normal" => {
-            let d = rand_distr::Normal::new( dist.params[0], dist.params[1] ).unwrap();
-            d.sample( &mut rand::thread_rng() ) as usize
-        },
+    AlpacaError::Distribution( format!("values {} have prob 0 in {}", range, name) )
+}
 
-        "LogNormal" => {
-            let d = rand_distr::LogNormal::new( dist.params[0], dist.params[1] ).unwrap();
-            d.sample( &mut rand::thread_rng() ) as usize
-        },
+// The files that a distribution is read from: its .dist file, or those of
+// the components of a mixture.
+pub fn spec_files(dist: &str) -> Vec<&str> {
 
-        "Exp" => {
-            let d = rand_distr::Exp::new( dist.params[0] ).unwrap();
-            d.sample( &mut rand::thread_rng() ) as usize
-        },
+    if let Some(inner) = dist.strip_prefix("Mix(").and_then( |d| d.strip_suffix(')') ) {
 
-        // "Poisson" => {
-        //     let d = Poisson::new(dist.params[0]).unwrap();
-        //     return Ok(d.sample(&mut rand::thread_rng()) as usize);
-        // },
-        "Binomial" => {
-            let d = rand_distr::Binomial::new( dist.params[0] as u64, dist.params[1] ).unwrap();
-            d.sample( &mut rand::thread_rng() ) as usize
-        },
+        split_components(inner).into_iter()
+                               .filter_map( |part| part.find('*').map( |pos| part[pos+1..].trim() ) )
+                               .flat_map(spec_files)
+                               .collect()
 
-        "Gamma" => {
-            let d = rand_distr::Gamma::new( dist.params[0], dist.params[1] ).unwrap();
-            d.sample( &mut rand::thread_rng() ) as usize
-        },
+    } else if dist.ends_with(".dist") {
+        vec![dist]
+    } else {
+        Vec::new()
+    }
+}
+
+// Splits the components of a mixture at the top-level ';'.
+fn split_components(inner: &str) -> Vec<&str> {
+
+    let mut parts = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
 
-        _ => panic!("not possible"),
+    for (i, c) in inner.char_indices() {
+        match c {
+            '('                => depth += 1,
+            ')'                => depth -= 1,
+            ';' if depth == 0  => { parts.push( &inner[start..i] ); start = i + 1; },
+            _                  => (),
+        }
     }
-}
\ No newline at end of file
+
+    parts.push( &inner[start..] );
+
+    parts
+}
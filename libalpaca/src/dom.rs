@@ -1,31 +1,68 @@
 //! Contains parsing routines
 use html5ever::{ interface::QualName, LocalName, ns, namespace_url, serialize, serialize::{SerializeOpts} };
-use kuchiki::NodeRef;
+use kuchiki::{ NodeDataRef, NodeRef };
+use std::cell::RefCell;
 use std::{ str, ptr };
 use std::ffi::CString;
 
 
 // Defines our basic object types, each of which has a corresponding
 // unique (distribution, padding type) tuple.
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone, Copy, Debug)]
 pub enum ObjectKind {
-    FakeIMG,  // Fake alpaca image
-    HTML   ,
-    CSS    ,
-    IMG    ,  // IMG: PNG, JPEG, etc.
-	JS     ,
-	CssImg ,
-    Unknown,
+    FakeIMG ,  // Fake alpaca image
+    FakeCSS ,  // Fake alpaca stylesheet
+    FakeJS  ,  // Fake alpaca script
+    FakeFont,  // Fake alpaca font
+    HTML    ,
+    CSS     ,
+    IMG     ,  // IMG: PNG, JPEG, etc.
+    JS      ,
+    CssImg  ,  // Image referenced with url() in a <style>
+    Font    ,  // WOFF, TTF, etc.
+    Media   ,  // Video, audio and their text tracks
+    Document,  // Framed or embedded document (<iframe>, <embed>, <object>)
+    Manifest,  // Web app manifest
+    Unknown ,
+}
+
+impl ObjectKind {
+
+    // The kind of the fake objects which stand for objects of this kind.
+    pub fn fake(self) -> ObjectKind {
+        match self {
+            ObjectKind::CSS  | ObjectKind::FakeCSS  => ObjectKind::FakeCSS ,
+            ObjectKind::JS   | ObjectKind::FakeJS   => ObjectKind::FakeJS  ,
+            ObjectKind::Font | ObjectKind::FakeFont => ObjectKind::FakeFont,
+            _                                       => ObjectKind::FakeIMG ,
+        }
+    }
+
+    // Whether this is the kind of a fake alpaca object.
+    pub fn is_fake(self) -> bool {
+        self.fake() == self
+    }
+}
+
+// Where the uri of an object is found in the element referencing it.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum RefSite {
+    Attr(&'static str)  , // the whole value of the attribute
+    Srcset(&'static str), // a candidate of a srcset attribute
+    Style               , // a url() in the style attribute
+    StyleText           , // a url() in the css of a <style>
 }
 
 // An object to be used in the morphing process.
 pub struct Object {
     // Type of the Object
     pub kind: ObjectKind,
-    // Content (Vector of bytes) of the Object
-    pub content: Vec<u8>,
+    // Size of the Object's content, before padding
+    pub size: usize,
     // Node in the html
     pub node: Option<NodeRef>,
+    // Where the uri is in the node
+    pub site: RefSite,
     // Size to pad the Object to
     pub target_size: Option<usize>,
     // The uri of the object, as mentioned in the html source
@@ -55,59 +92,83 @@ pub struct RequestData {
 pub type Map = *mut map;
 
 #[link(name = "map", kind = "static")]
-
 extern "C" {
     // fn map_create() -> Map;
     // fn map_set(m: Map, key: *const libc::c_char, value: *mut libc::c_void);
+    fn map_contains(m: Map, key: *const libc::c_char) -> bool;
     fn map_get(m: Map, key: *const libc::c_char) -> *mut libc::c_void;
 }
 
 impl Object {
 
     // Construct a real object from the html page
-    pub fn existing(content: &[u8], kind: ObjectKind, uri: String, node: &NodeRef) -> Object {
+    pub fn existing(size: usize, kind: ObjectKind, uri: String, node: &NodeRef, site: RefSite) -> Object {
         Object {
-            kind        : kind                ,
-            content     : content.to_vec()    ,
+            kind                              ,
+            size                              ,
             node        : Some( node.clone() ),
+            site                              ,
             target_size : None                ,
-            uri         : uri                 ,
+            uri                               ,
         }
     }
 
     // Create padding object
     pub fn fake_image(target_size: usize) -> Object {
+        Object::fake(ObjectKind::FakeIMG, target_size)
+    }
+
+    // Create padding object standing for an object of the given kind
+    pub fn fake(kind: ObjectKind, target_size: usize) -> Object {
         Object {
-            kind        : ObjectKind::FakeIMG       ,
-            content     : Vec::new()                ,
+            kind        : kind.fake()               ,
+            size        : 0                         ,
             node        : None                      ,
+            site        : RefSite::Attr("src")      ,
             target_size : Some(target_size)         ,
             uri         : String::from("pad_object"),
         }
     }
 }
 
-// Calls the map_get C function and converts the output to a
-// u8 vector in order for it to be used accordingly in Rust
-pub fn get_map_element(req_mapper : Map , uri : String) -> Vec<u8> {
+// Calls the map_get C function, which crashes on missing keys, only
+// if the map contains the uri.
+fn get_request_data<'a>(req_mapper : Map , uri : &str) -> Option<&'a RequestData> {
+
+    let c_uri = CString::new(uri).ok()?;
+
+    unsafe {
+        if req_mapper.is_null() || !map_contains(req_mapper, c_uri.as_ptr()) {
+            return None;
+        }
+
+        let data = map_get(req_mapper, c_uri.as_ptr()) as *const RequestData;
+
+        data.as_ref()
+    }
+}
 
-	let c_uri    = CString::new(uri).expect("CString::new Failed");
+pub fn get_map_element_size(req_mapper : Map , uri : &str) -> Option<usize> {
+    get_request_data(req_mapper, uri).map( |data| data.length as usize )
+}
 
-    let temp_old = unsafe { map_get(req_mapper, c_uri.as_ptr()) } as *mut RequestData;
-    let temp_old = unsafe { &mut *temp_old };
+// Copies the content stored in the map for the given uri to a
+// u8 vector in order for it to be used accordingly in Rust
+pub fn get_map_element(req_mapper : Map , uri : &str) -> Option<Vec<u8>> {
 
-	let mut element_data: Vec<u8> = Vec::new();
-    element_data.reserve(temp_old.length as usize);
+    let temp_old = get_request_data(req_mapper, uri)?;
+
+    let mut element_data: Vec<u8> = Vec::with_capacity(temp_old.length as usize);
 
     unsafe {
 
-        let dst_ptr = element_data.as_mut_ptr().offset(0) as *mut i8;
+        let dst_ptr = element_data.as_mut_ptr() as *mut libc::c_char;
 
         ptr::copy_nonoverlapping( temp_old.content, dst_ptr, temp_old.length as usize );
 
         element_data.set_len( temp_old.length as usize );
     }
-	element_data
+    Some(element_data)
 }
 
 pub fn create_element(name: &str) -> NodeRef {
@@ -117,29 +178,36 @@ pub fn create_element(name: &str) -> NodeRef {
 
 pub fn create_css_node(css_text: &str) -> NodeRef {
 
-	let elem_node = create_element("style");
-	let css_text  = NodeRef::new_text(css_text);
+    let elem_node = create_element("style");
+    let css_text  = NodeRef::new_text(css_text);
 
     elem_node.append(css_text);
-	elem_node
+    elem_node
 }
 
 pub fn node_get_attribute(node: &NodeRef, name: &str) -> Option<String> {
 
     match node.as_element() {
-        Some(element) => {
-            match element.attributes.borrow().get(name) {
-                Some(val) => Some( String::from(val) ),
-                None      => None,
-            }
-        },
-        None => None,
+        Some(element) => element.attributes.borrow().get(name).map(String::from),
+        None          => None,
     }
 }
 
 pub fn node_set_attribute(node: &NodeRef, name: &str, value: String) {
-    let elem = node.as_element().unwrap();
-    elem.attributes.borrow_mut().insert(name, value);
+
+    if let Some(elem) = node.as_element() {
+        elem.attributes.borrow_mut().insert(name, value);
+    }
+}
+
+// The lowercase tag name of an element, or None for other nodes.
+pub fn node_tag(node: &NodeRef) -> Option<String> {
+    node.as_element().map( |elem| elem.name.local.to_lowercase() )
+}
+
+// The text of a <style> element, or None if it is empty.
+pub fn style_text(node: &NodeRef) -> Option< NodeDataRef< RefCell<String> > > {
+    node.last_child()?.into_text_ref()
 }
 
 pub fn serialize_html(dom: &NodeRef) -> Vec<u8> {
@@ -157,15 +225,15 @@ pub fn insert_empty_favicon(document: &NodeRef) {
     // Append the <link> either to the <head> tag, if exists, otherwise
     // to the whole document
     let node_data;  // to outlive the match
-    let node = match document.select("head").unwrap().next() {
+    let node = match document.select("head").ok().and_then( |mut nodes| nodes.next() ) {
         Some(nd) => { node_data = nd; node_data.as_node() },
         None     => document                               ,
     };
 
-	let elem = create_element("link");
+    let elem = create_element("link");
 
     node_set_attribute( &elem, "href", String::from("data:,")       );
-	node_set_attribute( &elem, "rel", String::from("shortcut icon") );
+    node_set_attribute( &elem, "rel", String::from("shortcut icon") );
 
     node.append(elem);
 }
\ No newline at end of file
@@ -0,0 +1,54 @@
+//! Contains the error type returned by the morphing routines.
+use limits::Limit;
+use std::{ error, fmt };
+
+// Status returned by the extern "C" functions when the content was morphed.
+// Any other status is an error code, in which case the content is left
+// untouched and the nginx module serves the original one.
+pub const ALPACA_OK    : u8 = 1;
+pub const ALPACA_PANIC : u8 = 255;
+
+// An error that prevented a page or an object from being morphed.
+#[derive(Debug)]
+pub enum AlpacaError {
+    Config(String)      , // invalid or inconsistent parameters
+    Distribution(String), // a distribution cannot be loaded or sampled
+    Parse(String)       , // the html, a query or a C string cannot be read
+    Resolver(String)    , // the content of an object is not available
+    Inlining(String)    , // an object cannot be inlined in the html
+    Padding(String)     , // an object or the html cannot be padded
+    Limit(Limit)        , // a page or an object exceeds a limit
+}
+
+impl AlpacaError {
+
+    // The error code returned to C.
+    pub fn code(&self) -> u8 {
+        match *self {
+            AlpacaError::Config(_)       => 2,
+            AlpacaError::Distribution(_) => 3,
+            AlpacaError::Parse(_)        => 4,
+            AlpacaError::Resolver(_)     => 5,
+            AlpacaError::Inlining(_)     => 6,
+            AlpacaError::Padding(_)      => 7,
+            AlpacaError::Limit(_)        => 8,
+        }
+    }
+}
+
+impl fmt::Display for AlpacaError {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            AlpacaError::Config(ref msg)       => write!(f, "invalid configuration: {}", msg),
+            AlpacaError::Distribution(ref msg) => write!(f, "distribution error: {}"   , msg),
+            AlpacaError::Parse(ref msg)        => write!(f, "parse error: {}"          , msg),
+            AlpacaError::Resolver(ref msg)     => write!(f, "resolver error: {}"       , msg),
+            AlpacaError::Inlining(ref msg)     => write!(f, "inlining error: {}"       , msg),
+            AlpacaError::Padding(ref msg)      => write!(f, "padding error: {}"        , msg),
+            AlpacaError::Limit(limit)          => write!(f, "exceeds the {}"           , limit),
+        }
+    }
+}
+
+impl error::Error for AlpacaError {}
@@ -0,0 +1,88 @@
+//! Generates the content of the fake objects, so that they are valid objects
+//! of their kind with exactly their target size.
+use image;
+use image_webp;
+use pad;
+
+use dom::ObjectKind;
+use error::AlpacaError;
+use image_pad::{ pad_image, ImageFormat };
+use rand::{ Rng, RngCore };
+
+// The smallest fake image, which fits a 1x1 PNG with a padding chunk.
+pub const MIN_FAKE_IMAGE_SIZE : usize = 128;
+
+// The smallest fake JPEG, which fits a 1x1 JPEG (about 640 bytes, most of
+// them its tables) with a padding segment. Smaller fake images are PNGs.
+pub const MIN_FAKE_JPEG_SIZE  : usize = 768;
+
+const JPEG_QUALITY : u8 = 80;
+
+// Returns a fake object of the given kind and target size: an image (a PNG)
+// that decodes, a stylesheet or script made of a comment, or the random
+// bytes of a font.
+pub fn generate_fake_object(kind: ObjectKind, target_size: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    match kind.fake() {
+        ObjectKind::FakeIMG  => generate_fake_image(ImageFormat::PNG, target_size, rng),
+        ObjectKind::FakeCSS  => pad::get_object_padding(ObjectKind::CSS , 0, target_size, rng),
+        ObjectKind::FakeJS   => pad::get_object_padding(ObjectKind::JS  , 0, target_size, rng),
+        _                    => pad::get_object_padding(ObjectKind::Font, 0, target_size, rng),
+    }
+}
+
+// Returns an image of the given format and target size, of a single random
+// color. Its dimensions are drawn as those of a common picture, and halved
+// until it fits in the target size with a padding chunk.
+pub fn generate_fake_image(format: ImageFormat, target_size: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    let mut width  = rng.gen_range(16, 513);
+    let mut height = std::cmp::max( width * rng.gen_range(50, 201) / 100, 1 );
+    let color      = [ rng.gen::<u8>(), rng.gen::<u8>(), rng.gen::<u8>() ];
+
+    loop {
+
+        let image   = encode_image(format, width, height, color)?;
+        let pad_len = target_size.saturating_sub( image.len() );
+
+        if image.len() <= target_size && (pad_len == 0 || pad_len >= format.min_padding()) {
+            return pad_image(&image, pad_len, rng)?.ok_or_else( || AlpacaError::Padding( format!( "cannot pad a fake {:?} image", format ) ) );
+        }
+
+        if width == 1 && height == 1 {
+            return Err( AlpacaError::Padding( format!( "a fake {:?} image cannot take {} bytes", format, target_size ) ) );
+        }
+
+        width  = std::cmp::max( width  / 2, 1 );
+        height = std::cmp::max( height / 2, 1 );
+    }
+}
+
+// Encodes an image of a single color.
+fn encode_image(format: ImageFormat, width: u32, height: u32, color: [u8; 3]) -> Result<Vec<u8>, AlpacaError> {
+
+    let to_error = |e: String| AlpacaError::Padding( format!( "cannot encode a fake {:?} image: {}", format, e ) );
+
+    let mut content = Vec::new();
+
+    if format == ImageFormat::WebP {
+
+        let pixels = color.repeat( (width * height) as usize );
+
+        image_webp::WebPEncoder::new(&mut content).encode( &pixels, width, height, image_webp::ColorType::Rgb8 )
+                                                  .map_err( |e| to_error( e.to_string() ) )?;
+        return Ok(content);
+    }
+
+    let output = match format {
+        ImageFormat::PNG  => image::ImageOutputFormat::Png               ,
+        ImageFormat::JPEG => image::ImageOutputFormat::Jpeg(JPEG_QUALITY),
+        _                 => image::ImageOutputFormat::Gif               ,
+    };
+
+    image::DynamicImage::ImageRgb8( image::RgbImage::from_pixel( width, height, image::Rgb(color) ) )
+        .write_to(&mut content, output)
+        .map_err( |e| to_error( e.to_string() ) )?;
+
+    Ok(content)
+}
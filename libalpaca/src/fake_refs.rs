@@ -0,0 +1,295 @@
+//! Disguises the references to the fake objects as those of the page's own
+//! objects: their uris follow the directories and extensions of its objects,
+//! they are referenced by elements of several kinds, and those are scattered
+//! through the document.
+use dom;
+
+use dom::{ Object, ObjectKind, RefSite };
+use error::AlpacaError;
+use fake::MIN_FAKE_JPEG_SIZE;
+use kuchiki::NodeRef;
+use rand::{ Rng, RngCore, SeedableRng };
+use rand_chacha::ChaCha20Rng;
+
+// The extensions that fake objects of each kind may take, which the nginx
+// module serves. The first one is used when the page has no such object.
+const IMAGE_EXTENSIONS  : &[&str] = &["png", "jpg", "jpeg", "gif", "webp"];
+const STYLE_EXTENSIONS  : &[&str] = &["css"];
+const SCRIPT_EXTENSIONS : &[&str] = &["js"];
+const FONT_EXTENSIONS   : &[&str] = &["woff2", "woff", "ttf", "otf"];
+
+const NAME_CHARS   : &[u8] = b"abcdefghijklmnopqrstuvwxyz0123456789";
+const MIN_NAME_LEN : usize = 6;
+const MAX_NAME_LEN : usize = 24;
+
+// The elements that the fake objects are placed in, besides <head>. An
+// element with a carrier is inserted before one of their children, or at
+// their end.
+const CONTAINERS : &str = "body, body div, body section, body article, body main, body header, body footer, \
+                           body nav, body aside, body li, body figure, body blockquote, body td, body dd";
+
+// The elements that reference a fake object. Stylesheets, scripts and fonts
+// can also be referenced as in the page's own source (a stylesheet link, an
+// async script or a font preload), while images need one of these.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Carrier {
+    Image     , // <img> of zero size
+    Prefetch  , // <link rel=prefetch>
+    Preload   , // <link rel=preload>
+    Background, // CSS background image of an empty <div>
+}
+
+impl Carrier {
+
+    pub const ALL: [Carrier; 4] = [Carrier::Image, Carrier::Prefetch, Carrier::Preload, Carrier::Background];
+
+    // The bit of the carrier, in the masks passed by the nginx module. As in
+    // nginx's bitmasks, the lowest bit only tells that the mask is set.
+    pub fn mask(&self) -> usize {
+        match *self {
+            Carrier::Image      => 2 ,
+            Carrier::Prefetch   => 4 ,
+            Carrier::Preload    => 8 ,
+            Carrier::Background => 16,
+        }
+    }
+
+    // The carriers of a mask, or all of them if it has none.
+    pub fn from_mask(mask: usize) -> Result<Vec<Carrier>, AlpacaError> {
+
+        if mask >= 32 {
+            return Err( AlpacaError::Config( format!("invalid fake object carriers {}", mask) ) );
+        }
+
+        if mask & !1 == 0 {
+            return Ok( Carrier::ALL.to_vec() );
+        }
+
+        Ok( Carrier::ALL.iter().cloned().filter( |carrier| mask & carrier.mask() != 0 ).collect() )
+    }
+}
+
+// A directory and an extension of the page's objects, with the length of
+// the object's name.
+struct UriPattern {
+    kind     : ObjectKind, // the fake kind of the object
+    dir      : String    ,
+    ext      : String    ,
+    name_len : usize     ,
+}
+
+// The uris and the elements of the fake objects of a page.
+pub struct FakeRefs {
+    patterns : Vec<UriPattern>,
+    carriers : Vec<Carrier>   ,
+    seed     : [u8; 32]       ,
+}
+
+fn extensions(kind: ObjectKind) -> &'static [&'static str] {
+    match kind.fake() {
+        ObjectKind::FakeCSS  => STYLE_EXTENSIONS ,
+        ObjectKind::FakeJS   => SCRIPT_EXTENSIONS,
+        ObjectKind::FakeFont => FONT_EXTENSIONS  ,
+        _                    => IMAGE_EXTENSIONS ,
+    }
+}
+
+// The pattern of an object's uri, if the extension of its name is one that
+// fake objects of its kind may take.
+fn uri_pattern(obj: &Object) -> Option<UriPattern> {
+
+    let path = obj.uri.split(['?', '#']).next().unwrap_or_default();
+
+    let (dir, name) = match path.rfind('/') {
+        Some(pos) => path.split_at(pos + 1),
+        None      => ("", path)            ,
+    };
+
+    let (stem, ext) = name.rsplit_once('.')?;
+    let ext         = ext.to_ascii_lowercase();
+
+    if !extensions(obj.kind).contains( &ext.as_str() ) {
+        return None;
+    }
+
+    Some( UriPattern {
+        kind     : obj.kind.fake(),
+        dir      : String::from(dir),
+        ext,
+        name_len : stem.len().clamp(MIN_NAME_LEN, MAX_NAME_LEN),
+    })
+}
+
+impl FakeRefs {
+
+    // Collects the patterns of the page's objects. The fake objects' uris and
+    // carriers are derived from a seed drawn from rng.
+    pub fn new(objects: &[Object], carriers: &[Carrier], rng: &mut dyn RngCore) -> FakeRefs {
+
+        let mut seed = [0u8; 32];
+        rng.fill_bytes(&mut seed);
+
+        FakeRefs {
+            patterns : objects.iter().filter_map(uri_pattern).collect(),
+            carriers : carriers.to_vec(),
+            seed,
+        }
+    }
+
+    // The i-th fake object of the page, of the given kind and target size,
+    // with its uri and the element referencing it. The same i always gives
+    // the same uri and carrier, so that the size of the element can be
+    // known before the object is added.
+    pub fn object(&self, kind: ObjectKind, target_size: usize, i: usize) -> Object {
+
+        let mut rng = ChaCha20Rng::from_seed(self.seed);
+        rng.set_stream(i as u64);
+
+        let mut object = Object::fake(kind, target_size);
+
+        object.uri  = self.uri(object.kind, target_size, &mut rng);
+        let (node, site) = self.element(object.kind, &object.uri, &mut rng);
+
+        object.node = Some(node);
+        object.site = site;
+
+        object
+    }
+
+    // A uri in the directory of an object of the same kind, with its
+    // extension, or else in the directory of any object. An image too small
+    // to be a JPEG takes the extension of a PNG, so that it is served as one.
+    fn uri(&self, kind: ObjectKind, target_size: usize, rng: &mut ChaCha20Rng) -> String {
+
+        let same_kind: Vec<&UriPattern> = self.patterns.iter().filter( |pattern| pattern.kind == kind ).collect();
+
+        let (dir, ext, name_len) = if !same_kind.is_empty() {
+            let pattern = same_kind[ rng.gen_range( 0, same_kind.len() ) ];
+            (pattern.dir.as_str(), pattern.ext.as_str(), pattern.name_len)
+        } else if !self.patterns.is_empty() {
+            let pattern = &self.patterns[ rng.gen_range( 0, self.patterns.len() ) ];
+            (pattern.dir.as_str(), extensions(kind)[0], pattern.name_len)
+        } else {
+            ("/", extensions(kind)[0], rng.gen_range(MIN_NAME_LEN, MAX_NAME_LEN + 1))
+        };
+
+        let ext = match ext {
+            "jpg" | "jpeg" if target_size < MIN_FAKE_JPEG_SIZE => "png",
+            _                                                  => ext  ,
+        };
+
+        let name: String = (0..name_len).map( |_| NAME_CHARS[ rng.gen_range( 0, NAME_CHARS.len() ) ] as char ).collect();
+
+        format!("{}{}.{}", dir, name, ext)
+    }
+
+    // The element referencing the fake object at uri, with one of the
+    // carriers allowed for its kind (None for the kind's own element), and
+    // where the uri is in it.
+    fn element(&self, kind: ObjectKind, uri: &str, rng: &mut ChaCha20Rng) -> (NodeRef, RefSite) {
+
+        let mut carriers: Vec<Option<Carrier>> = self.carriers.iter()
+                                                              .filter( |&&carrier| kind == ObjectKind::FakeIMG || carrier == Carrier::Prefetch || carrier == Carrier::Preload )
+                                                              .map( |&carrier| Some(carrier) )
+                                                              .collect();
+
+        if kind != ObjectKind::FakeIMG || carriers.is_empty() {
+            carriers.push(None);
+        }
+
+        let carrier = carriers[ rng.gen_range( 0, carriers.len() ) ];
+        let ext     = uri.rsplit('.').next().unwrap_or_default();
+
+        let (tag, attrs): (&str, Vec<(&str, String)>) = match (carrier, kind) {
+
+            (Some(Carrier::Image)     , _) => ( "img" , vec![ ("src", uri.to_string()), ("width", String::from("0")), ("height", String::from("0")),
+                                                             ("alt", String::new()), ("loading", String::from("eager")) ] ),
+            (Some(Carrier::Prefetch)  , _) => ( "link", vec![ ("rel", String::from("prefetch")), ("href", uri.to_string()) ] ),
+            (Some(Carrier::Background), _) => ( "div" , vec![ ("style", format!("background-image:url({})", uri)) ] ),
+
+            (Some(Carrier::Preload), _) => {
+
+                let destination = match kind {
+                    ObjectKind::FakeCSS  => "style" ,
+                    ObjectKind::FakeJS   => "script",
+                    ObjectKind::FakeFont => "font"  ,
+                    _                    => "image" ,
+                };
+
+                ( "link", vec![ ("rel", String::from("preload")), ("as", String::from(destination)), ("href", uri.to_string()) ] )
+            },
+
+            (None, ObjectKind::FakeCSS) => ( "link"  , vec![ ("rel", String::from("stylesheet")), ("href", uri.to_string()) ] ),
+            (None, ObjectKind::FakeJS ) => ( "script", vec![ ("src", uri.to_string()), ("async", String::new()) ] ),
+
+            // Preloaded, as no @font-face refers to it
+            (None, ObjectKind::FakeFont) => ( "link", vec![ ("rel", String::from("preload")), ("as", String::from("font")), ("href", uri.to_string()),
+                                                            ("type", format!("font/{}", ext)), ("crossorigin", String::new()) ] ),
+
+            (None, _) => ( "img", vec![ ("src", uri.to_string()), ("width", String::from("0")), ("height", String::from("0")), ("alt", String::new()) ] ),
+        };
+
+        let elem = dom::create_element(tag);
+
+        for (name, value) in attrs {
+            dom::node_set_attribute(&elem, name, value);
+        }
+
+        let site = match tag {
+            "link" => RefSite::Attr("href"),
+            "div"  => RefSite::Style       ,
+            _      => RefSite::Attr("src") ,
+        };
+
+        (elem, site)
+    }
+}
+
+// Whether an element is hidden, so that the background images of its
+// descendants might not be loaded.
+fn is_hidden(node: &NodeRef) -> bool {
+
+    let style = dom::node_get_attribute(node, "style").unwrap_or_default().replace(' ', "");
+
+    dom::node_get_attribute(node, "hidden").is_some() || style.contains("display:none")
+}
+
+// Inserts the elements of the fake objects at random positions of the
+// document: links and scripts anywhere in <head> or the containers of
+// <body>, and images and backgrounds in the latter.
+pub fn scatter_fake_elements(document: &NodeRef, elements: &[NodeRef], rng: &mut dyn RngCore) {
+
+    let body_containers: Vec<NodeRef> = document.select(CONTAINERS)
+                                                .into_iter()
+                                                .flatten()
+                                                .map( |data| data.as_node().clone() )
+                                                .filter( |node| !node.inclusive_ancestors().any( |ancestor| is_hidden(&ancestor) ) )
+                                                .collect();
+
+    let head_containers: Vec<NodeRef> = document.select("head")
+                                                .into_iter()
+                                                .flatten()
+                                                .map( |data| data.as_node().clone() )
+                                                .collect();
+
+    for elem in elements {
+
+        let in_head = matches!( dom::node_tag(elem).as_deref(), Some("link") | Some("script") );
+
+        let mut containers: Vec<&NodeRef> = body_containers.iter().collect();
+
+        if in_head || containers.is_empty() {
+            containers.extend( head_containers.iter() );
+        }
+
+        let container = if containers.is_empty() { document } else { containers[ rng.gen_range( 0, containers.len() ) ] };
+        let children  = container.children().collect::< Vec<_> >();
+        let pos       = rng.gen_range( 0, children.len() + 1 );
+
+        if pos == children.len() {
+            container.append( elem.clone() );
+        } else {
+            children[pos].insert_before( elem.clone() );
+        }
+    }
+}
@@ -0,0 +1,547 @@
+//! Fits distributions to a corpus of crawled pages, producing the ".dist"
+//! files and the parametric distributions given to the alpaca_dist_*
+//! directives. Used by the alpaca-fit tool.
+use serde_json;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+use dist_file;
+use distribution::Dist;
+use error::AlpacaError;
+use profile::PageProfiles;
+use truncated;
+
+// A page of the corpus, with its weight (1 unless the corpus gives one).
+pub struct Page {
+    pub html_size : usize     ,
+    pub obj_sizes : Vec<usize>,
+    pub weight    : f64       ,
+}
+
+impl Page {
+
+    pub fn total_obj_size(&self) -> usize {
+        self.obj_sizes.iter().sum()
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+// Reading a corpus
+
+// A CSV file with a header and a page per row. The columns are html_size,
+// obj_sizes (separated by ';' or spaces) and optionally weight.
+pub fn from_csv(data: &str) -> Result<Vec<Page>, AlpacaError> {
+
+    let mut lines = data.lines().enumerate().filter( |(_, line)| !line.trim().is_empty() );
+
+    let header: Vec<String> = match lines.next() {
+        Some( (_, line) ) => line.split(',').map( |c| c.trim().trim_matches('"').to_string() ).collect(),
+        None              => return Err( AlpacaError::Parse( String::from("empty CSV corpus") ) ),
+    };
+
+    let column = |name: &str| header.iter().position( |c| c == name );
+
+    let html_col = column("html_size").ok_or_else( || AlpacaError::Parse( String::from("CSV corpus has no html_size column") ) )?;
+    let objs_col = column("obj_sizes");
+    let weight_col = column("weight");
+
+    let mut pages = Vec::new();
+
+    for (i, line) in lines {
+
+        let invalid = |what: &str| AlpacaError::Parse( format!("CSV corpus, line {}: invalid {}", i+1, what) );
+
+        let fields: Vec<&str> = line.split(',').map( |f| f.trim().trim_matches('"') ).collect();
+
+        if fields.len() != header.len() {
+            return Err( invalid("number of columns") );
+        }
+
+        let obj_sizes = match objs_col {
+            Some(col) => fields[col].split( |c: char| c == ';' || c.is_whitespace() )
+                                    .filter( |s| !s.is_empty() )
+                                    .map( |s| s.parse().map_err( |_| invalid("object size") ) )
+                                    .collect::<Result<_,_>>()?,
+            None      => Vec::new(),
+        };
+
+        let weight = match weight_col {
+            Some(col) => parse_weight( fields[col] ).ok_or_else( || invalid("weight") )?,
+            None      => 1.0,
+        };
+
+        pages.push( Page {
+            html_size : fields[html_col].parse().map_err( |_| invalid("html size") )?,
+            obj_sizes                                                               ,
+            weight                                                                  ,
+        });
+    }
+
+    Ok(pages)
+}
+
+// A JSON object per line, as {"html_size": 1000, "obj_sizes": [..], "weight": 1}
+// where obj_sizes and weight are optional.
+pub fn from_jsonl(data: &str) -> Result<Vec<Page>, AlpacaError> {
+
+    let mut pages = Vec::new();
+
+    for (i, line) in data.lines().enumerate() {
+
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let invalid = |what: &str| AlpacaError::Parse( format!("JSONL corpus, line {}: invalid {}", i+1, what) );
+
+        let page: serde_json::Value = serde_json::from_str(line).map_err( |e| invalid( &format!("JSON ({})", e) ) )?;
+
+        let size = |v: &serde_json::Value| v.as_u64().map( |s| s as usize );
+
+        let obj_sizes = match page.get("obj_sizes") {
+            Some(sizes) => sizes.as_array()
+                                .ok_or_else( || invalid("obj_sizes") )?
+                                .iter()
+                                .map( |s| size(s).ok_or_else( || invalid("object size") ) )
+                                .collect::<Result<_,_>>()?,
+            None        => Vec::new(),
+        };
+
+        let weight = match page.get("weight") {
+            Some(w) => w.as_f64().filter( |w| w.is_finite() && *w >= 0.0 ).ok_or_else( || invalid("weight") )?,
+            None    => 1.0,
+        };
+
+        pages.push( Page {
+            html_size : page.get("html_size").and_then(size).ok_or_else( || invalid("html_size") )?,
+            obj_sizes                                                                             ,
+            weight                                                                                ,
+        });
+    }
+
+    Ok(pages)
+}
+
+// The pages of a ".profile" file, weighted by their probabilities.
+pub fn from_profiles(path: &str) -> Result<Vec<Page>, AlpacaError> {
+
+    let profiles = PageProfiles::from(path)?;
+
+    Ok( profiles.profiles.iter().zip(&profiles.probs).map( |(profile, &weight)| Page {
+        html_size : profile.html_size                                   ,
+        obj_sizes : profile.objects.iter().map( |o| o.0 ).collect()     ,
+        weight                                                          ,
+    }).collect() )
+}
+
+// A directory with a saved site per subdirectory. The html of a site is its
+// index.html (or its first html file), and its objects are all other files.
+pub fn from_dir(path: &str) -> Result<Vec<Page>, AlpacaError> {
+
+    let mut pages = Vec::new();
+
+    for site in sorted_entries( Path::new(path) )? {
+
+        if !site.is_dir() {
+            continue;
+        }
+
+        let mut files = Vec::new();
+        list_files(&site, &mut files)?;
+
+        let is_html = |f: &Path| f.extension().map( |e| e == "html" || e == "htm" ).unwrap_or(false);
+
+        let html = files.iter()
+                        .find( |f| f.file_name().map( |n| n == "index.html" ).unwrap_or(false) && f.parent() == Some(&site) )
+                        .or_else( || files.iter().find( |f| is_html(f) ) );
+
+        let html = match html {
+            Some(html) => html.clone(),
+            None       => continue,
+        };
+
+        let size = |f: &Path| fs::metadata(f).map( |m| m.len() as usize )
+                                             .map_err( |e| AlpacaError::Parse( format!("cannot read {}: {}", f.display(), e) ) );
+
+        let mut obj_sizes = Vec::new();
+
+        for file in files.iter().filter( |f| !is_html(f) ) {
+            obj_sizes.push( size(file)? );
+        }
+
+        pages.push( Page { html_size: size(&html)?, obj_sizes, weight: 1.0 } );
+    }
+
+    Ok(pages)
+}
+
+fn sorted_entries(dir: &Path) -> Result<Vec<std::path::PathBuf>, AlpacaError> {
+
+    let mut entries = fs::read_dir(dir).map_err( |e| AlpacaError::Parse( format!("cannot read {}: {}", dir.display(), e) ) )?
+                                       .filter_map( |e| e.ok().map( |e| e.path() ) )
+                                       .collect::<Vec<_>>();
+    entries.sort();
+
+    Ok(entries)
+}
+
+fn list_files(dir: &Path, files: &mut Vec<std::path::PathBuf>) -> Result<(), AlpacaError> {
+
+    for entry in sorted_entries(dir)? {
+        if entry.is_dir() {
+            list_files(&entry, files)?;
+        } else {
+            files.push(entry);
+        }
+    }
+
+    Ok(())
+}
+
+fn parse_weight(s: &str) -> Option<f64> {
+    s.parse::<f64>().ok().filter( |w| w.is_finite() && *w >= 0.0 )
+}
+
+// -------------------------------------------------------------------------------------------
+// Writing ".dist" files
+
+// Weighted values, eg html_size of every page.
+pub fn samples(pages: &[Page], value: &dyn Fn(&Page) -> usize) -> Vec<(usize, f64)> {
+    pages.iter().map( |p| ( value(p), p.weight ) ).collect()
+}
+
+// The weighted sizes of all objects of all pages.
+pub fn object_samples(pages: &[Page]) -> Vec<(usize, f64)> {
+    pages.iter().flat_map( |p| p.obj_sizes.iter().map( move |&s| (s, p.weight) ) ).collect()
+}
+
+fn header(name: &str, description: &str, fits: &[Fit]) -> String {
+
+    let mut out = format!("# {} generated by alpaca-fit\n", description);
+
+    if let Some(best) = fits.first() {
+        out += &format!("# best parametric fit: {} (KS {:.4}, AIC {:.1})\n", best.spec, best.ks, best.aic);
+    }
+
+    out + &format!("@version {}\n@columns prob {}\n@description {}\n@normalize\n", dist_file::VERSION, name, description)
+}
+
+// A ".dist" file with the values and their frequencies.
+pub fn empirical(samples: &[(usize, f64)], name: &str, description: &str, fits: &[Fit]) -> String {
+
+    let mut counts: BTreeMap<usize, f64> = BTreeMap::new();
+
+    for &(v, w) in samples {
+        *counts.entry(v).or_insert(0.0) += w;
+    }
+
+    let mut out = header(name, description, fits);
+
+    for (v, w) in counts.iter().filter( |(_, &w)| w > 0.0 ) {
+        out += &format!("{} {}\n", w, v);
+    }
+
+    out
+}
+
+// Logarithmically spaced bin edges covering the values.
+fn bin_edges(samples: &[(usize, f64)], bins: usize) -> Vec<usize> {
+
+    let min = samples.iter().map( |s| s.0 ).min().unwrap_or(0);
+    let max = samples.iter().map( |s| s.0 ).max().unwrap_or(0) + 1;
+
+    let lo = (std::cmp::max(min, 1) as f64).ln();
+    let hi = (max as f64).ln();
+
+    let mut edges = vec![min];
+
+    for k in 1..=bins {
+
+        let edge = if k == bins { max } else { ( lo + (hi - lo) * k as f64 / bins as f64 ).exp().round() as usize };
+
+        if edge > edges[edges.len() - 1] {
+            edges.push(edge);
+        }
+    }
+
+    if edges.len() == 1 {
+        edges.push(max);
+    }
+
+    edges
+}
+
+fn bin_of(edges: &[usize], v: usize) -> usize {
+    std::cmp::min( edges.partition_point( |&e| e <= v ), edges.len() - 1 ) - 1
+}
+
+// A ".dist" file with a histogram of the values in (at most) the given
+// number of bins, logarithmically spaced since sizes are heavy-tailed.
+pub fn histogram(samples: &[(usize, f64)], bins: usize, name: &str, description: &str, fits: &[Fit]) -> String {
+
+    let edges      = bin_edges(samples, bins);
+    let mut masses = vec![0.0; edges.len() - 1];
+
+    for &(v, w) in samples {
+        masses[ bin_of(&edges, v) ] += w;
+    }
+
+    let mut out = header( &format!("{}_low {}_high", name, name), description, fits ) + "@bins\n";
+
+    for (k, mass) in masses.iter().enumerate().filter( |(_, &m)| m > 0.0 ) {
+        out += &format!("{} {} {}\n", mass, edges[k], edges[k + 1]);
+    }
+
+    out
+}
+
+// A ".dist" file with the joint distribution of the html size and the total
+// size of the objects, for "alpaca_dist_obj_size Joint". With bins, it is a
+// two-dimensional histogram.
+pub fn joint(pages: &[Page], bins: Option<usize>, description: &str) -> String {
+
+    let pairs: Vec<(usize, usize, f64)> = pages.iter().map( |p| (p.html_size, p.total_obj_size(), p.weight) ).collect();
+
+    let mut out = format!("# {} generated by alpaca-fit\n@version {}\n", description, dist_file::VERSION);
+
+    match bins {
+
+        None => {
+            let mut counts: BTreeMap<(usize, usize), f64> = BTreeMap::new();
+
+            for &(a, b, w) in &pairs {
+                *counts.entry( (a, b) ).or_insert(0.0) += w;
+            }
+
+            out += &format!("@columns prob html_size obj_size\n@description {}\n@normalize\n", description);
+
+            for ((a, b), w) in counts.iter().filter( |(_, &w)| w > 0.0 ) {
+                out += &format!("{} {} {}\n", w, a, b);
+            }
+        },
+
+        Some(bins) => {
+            let edges_a = bin_edges( &pairs.iter().map( |p| (p.0, p.2) ).collect::<Vec<_>>(), bins );
+            let edges_b = bin_edges( &pairs.iter().map( |p| (p.1, p.2) ).collect::<Vec<_>>(), bins );
+
+            let mut counts: BTreeMap<(usize, usize), f64> = BTreeMap::new();
+
+            for &(a, b, w) in &pairs {
+                *counts.entry( ( bin_of(&edges_a, a), bin_of(&edges_b, b) ) ).or_insert(0.0) += w;
+            }
+
+            out += &format!("@columns prob html_low html_high obj_low obj_high\n@description {}\n@normalize\n@bins\n", description);
+
+            for ((i, j), w) in counts.iter().filter( |(_, &w)| w > 0.0 ) {
+                out += &format!("{} {} {} {} {}\n", w, edges_a[*i], edges_a[i + 1], edges_b[*j], edges_b[j + 1]);
+            }
+        },
+    }
+
+    out
+}
+
+// -------------------------------------------------------------------------------------------
+// Parametric fits
+
+// A fitted distribution, as given to the alpaca_dist_* directives, with the
+// Kolmogorov-Smirnov distance to the data and the Akaike information criterion.
+pub struct Fit {
+    pub spec : String,
+    pub ks   : f64   ,
+    pub aic  : f64   ,
+}
+
+// A number for a distribution spec, with 6 significant digits.
+fn num(x: f64) -> String {
+
+    if x == 0.0 || !x.is_finite() {
+        return format!("{}", x);
+    }
+
+    let digits = 5 - x.abs().log10().floor() as i32;
+
+    if (0..=12).contains(&digits) {
+        let s = format!("{:.*}", digits as usize, x);
+
+        if s.contains('.') { s.trim_end_matches('0').trim_end_matches('.').to_string() } else { s }
+    } else {
+        format!("{:.5e}", x)
+    }
+}
+
+struct Moments {
+    weight   : f64, // total weight
+    mean     : f64,
+    var      : f64,
+    mean_ln  : f64, // of value + 0.5
+    var_ln   : f64,
+    min      : usize,
+    max      : usize,
+}
+
+fn moments(samples: &[(usize, f64)]) -> Moments {
+
+    let weight: f64 = samples.iter().map( |s| s.1 ).sum();
+
+    let avg = |f: &dyn Fn(f64) -> f64| samples.iter().map( |&(v, w)| w * f(v as f64) ).sum::<f64>() / weight;
+
+    let mean    = avg( &|x| x );
+    let mean_ln = avg( &|x| (x + 0.5).ln() );
+
+    Moments {
+        var      : avg( &|x| (x - mean).powi(2) )             ,
+        var_ln   : avg( &|x| ((x + 0.5).ln() - mean_ln).powi(2) ),
+        min      : samples.iter().map( |s| s.0 ).min().unwrap_or(0),
+        max      : samples.iter().map( |s| s.0 ).max().unwrap_or(0),
+        weight                                                 ,
+        mean                                                   ,
+        mean_ln                                                ,
+    }
+}
+
+// The Weibull (scale, shape) by maximum likelihood, solving for the shape
+// by bisection. Values are taken at the middle of their byte.
+fn weibull_mle(samples: &[(usize, f64)], m: &Moments) -> Option<(f64, f64)> {
+
+    let y_max = m.max as f64 + 0.5;
+
+    // sum(w z^k ln y) / sum(w z^k) - 1/k - mean(ln y), with z = y / y_max
+    let score = |k: f64| {
+        let (mut num, mut den) = (0.0, 0.0);
+
+        for &(v, w) in samples {
+            let y  = v as f64 + 0.5;
+            let zk = (y / y_max).powf(k);
+
+            num += w * zk * y.ln();
+            den += w * zk;
+        }
+
+        num / den - 1.0 / k - m.mean_ln
+    };
+
+    let (mut lo, mut hi) = (0.01_f64, 100.0_f64);
+
+    if score(lo) > 0.0 || score(hi) < 0.0 {
+        return None;
+    }
+
+    for _ in 0..100 {
+        let mid = (lo * hi).sqrt();
+        if score(mid) < 0.0 { lo = mid; } else { hi = mid; }
+    }
+
+    let k      = (lo * hi).sqrt();
+    let mean_z = samples.iter().map( |&(v, w)| w * ((v as f64 + 0.5) / y_max).powf(k) ).sum::<f64>() / m.weight;
+
+    Some( (y_max * mean_z.powf(1.0 / k), k) )
+}
+
+// The candidate distributions, by maximum likelihood or moments.
+fn candidates(samples: &[(usize, f64)]) -> Vec<String> {
+
+    let m = moments(samples);
+    let mut specs = Vec::new();
+
+    let sd = m.var.sqrt();
+    let y  = m.mean + 0.5; // values are taken at the middle of their byte
+
+    specs.push( format!("Normal/{},{}", num(y), num(sd)) );
+    specs.push( format!("LogNormal/{},{}", num(m.mean_ln), num(m.var_ln.sqrt())) );
+    specs.push( format!("Exp/{}", num(1.0 / y)) );
+    specs.push( format!("Uniform/{},{}", m.min, m.max + 1) );
+
+    // Gamma, with Minka's approximation of the shape
+    let s = y.ln() - m.mean_ln;
+
+    if s > 0.0 {
+        let k = (3.0 - s + ((s - 3.0).powi(2) + 24.0 * s).sqrt()) / (12.0 * s);
+        specs.push( format!("Gamma/{},{}", num(k), num(y / k)) );
+    }
+
+    if let Some( (scale, shape) ) = weibull_mle(samples, &m) {
+        specs.push( format!("Weibull/{},{}", num(scale), num(shape)) );
+    }
+
+    // Pareto, starting at the smallest value
+    let x_m  = m.min as f64 + 0.5;
+    let mean_log_ratio = m.mean_ln - x_m.ln();
+
+    if mean_log_ratio > 0.0 {
+        specs.push( format!("Pareto/{},{}", num(x_m), num(1.0 / mean_log_ratio)) );
+    }
+
+    // Counts
+    if m.mean > 0.0 {
+        specs.push( format!("Poisson/{}", num(m.mean)) );
+        specs.push( format!("Geometric/{}", num(1.0 / (1.0 + m.mean))) );
+    }
+
+    if m.var > m.mean && m.mean > 0.0 {
+        specs.push( format!("NegBinomial/{},{}", num(m.mean * m.mean / (m.var - m.mean)), num(m.mean / m.var)) );
+    }
+
+    specs
+}
+
+// The KS distance and the AIC of a distribution for the values.
+fn goodness_of_fit(dist: &Dist, samples: &[(usize, f64)], params: usize) -> (f64, f64) {
+
+    let mut counts: BTreeMap<usize, f64> = BTreeMap::new();
+
+    for &(v, w) in samples {
+        *counts.entry(v).or_insert(0.0) += w;
+    }
+
+    let total: f64 = counts.values().sum();
+    let n          = samples.len() as f64;
+
+    let cdf = |k: usize| -( truncated::ln_survival(dist, k + 1) ).exp_m1();
+
+    let mut ks        = 0.0_f64;
+    let mut ll        = 0.0;
+    let mut empirical = 0.0;
+
+    for (&v, &w) in &counts {
+
+        let below = if v == 0 { 0.0 } else { cdf(v - 1) };
+
+        ks = ks.max( (empirical - below).abs() );
+
+        empirical += w / total;
+
+        ks = ks.max( (empirical - cdf(v)).abs() );
+
+        // ln P(X = v) = ln S(v) + ln(1 - S(v+1) / S(v))
+        let ln_sv  = truncated::ln_survival(dist, v);
+        let ln_pmf = ln_sv + ( -( truncated::ln_survival(dist, v + 1) - ln_sv ).exp() ).ln_1p();
+
+        ll += n * w / total * ln_pmf;
+    }
+
+    (ks, 2.0 * params as f64 - 2.0 * ll)
+}
+
+// Fits the parametric distributions to the values, best (by KS distance)
+// first.
+pub fn fit_parametric(samples: &[(usize, f64)]) -> Vec<Fit> {
+
+    if samples.iter().map( |s| s.1 ).sum::<f64>() <= 0.0 {
+        return Vec::new();
+    }
+
+    let mut fits: Vec<Fit> = candidates(samples).into_iter().filter_map( |spec| {
+
+        let dist     = Dist::from(&spec).ok()?;
+        let (ks, aic) = goodness_of_fit( &dist, samples, dist.params.len() );
+
+        if ks.is_nan() { None } else { Some( Fit { spec, ks, aic: if aic.is_nan() { f64::INFINITY } else { aic } } ) }
+
+    }).collect();
+
+    fits.sort_by( |a, b| a.ks.partial_cmp(&b.ks).unwrap_or(std::cmp::Ordering::Equal) );
+
+    fits
+}
@@ -0,0 +1,264 @@
+//! Pads images inside their own format, with chunks, segments or blocks that
+//! decoders skip, instead of bytes after their end.
+use error::AlpacaError;
+use flate2::Crc;
+use rand::{ Rng, RngCore };
+
+// The smallest padding that fits in every format: a PNG chunk with no data.
+pub const MIN_IMAGE_PADDING : usize = PNG_CHUNK_SIZE;
+
+const PNG_SIGNATURE   : &[u8] = b"\x89PNG\r\n\x1a\n";
+const PNG_CHUNK_SIZE  : usize = 12;      // length, type and CRC
+const PNG_CHUNK_TYPE  : &[u8] = b"alPa"; // ancillary, private, safe to copy
+
+const JPEG_SOI        : &[u8] = b"\xff\xd8";
+const JPEG_COM        : &[u8] = b"\xff\xfe";
+const JPEG_SEGMENT    : usize = 4;       // marker and length
+const JPEG_MAX_DATA   : usize = 65533;
+
+const GIF_COMMENT     : &[u8] = b"\x21\xfe";
+const GIF_TRAILER     : u8    = 0x3b;
+const GIF_MAX_BLOCK   : usize = 255;
+const GIF_MIN_COMMENT : usize = 5;       // a comment with one byte
+
+const WEBP_CHUNK_SIZE : usize = 8;       // FourCC and size
+const WEBP_CHUNK_TYPE : &[u8] = b"ALPA";
+
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum ImageFormat {
+    PNG ,
+    JPEG,
+    GIF ,
+    WebP,
+}
+
+impl ImageFormat {
+
+    // The format of an image, from its signature.
+    pub fn detect(content: &[u8]) -> Option<ImageFormat> {
+
+        if content.starts_with(PNG_SIGNATURE) {
+            Some(ImageFormat::PNG)
+        } else if content.starts_with(b"\xff\xd8\xff") {
+            Some(ImageFormat::JPEG)
+        } else if content.starts_with(b"GIF87a") || content.starts_with(b"GIF89a") {
+            Some(ImageFormat::GIF)
+        } else if content.len() >= 12 && &content[0..4] == b"RIFF" && &content[8..12] == b"WEBP" {
+            Some(ImageFormat::WebP)
+        } else {
+            None
+        }
+    }
+
+    // The format of an image of the given content type.
+    pub fn from_mime(mime: &str) -> Option<ImageFormat> {
+        match mime {
+            "image/png"                => Some(ImageFormat::PNG ),
+            "image/jpeg" | "image/jpg" => Some(ImageFormat::JPEG),
+            "image/gif"                => Some(ImageFormat::GIF ),
+            "image/webp"               => Some(ImageFormat::WebP),
+            _                          => None,
+        }
+    }
+
+    // The smallest padding that fits in an image of this format. A GIF can
+    // also take 3 bytes, but not 4.
+    pub fn min_padding(&self) -> usize {
+        match *self {
+            ImageFormat::PNG  => PNG_CHUNK_SIZE  ,
+            ImageFormat::JPEG => JPEG_SEGMENT    ,
+            ImageFormat::GIF  => GIF_MIN_COMMENT ,
+            ImageFormat::WebP => WEBP_CHUNK_SIZE ,
+        }
+    }
+}
+
+fn random_bytes(len: usize, rng: &mut dyn RngCore) -> Vec<u8> {
+    (0..len).map( |_| rng.gen::<u8>() ).collect()
+}
+
+fn malformed(format: ImageFormat, reason: &str) -> AlpacaError {
+    AlpacaError::Padding( format!( "malformed {:?} image: {}", format, reason ) )
+}
+
+// Pads an image of a known format with pad_len bytes, or returns None if
+// its format is not known.
+pub fn pad_image(content: &[u8], pad_len: usize, rng: &mut dyn RngCore) -> Result<Option<Vec<u8>>, AlpacaError> {
+
+    let format = match ImageFormat::detect(content) {
+        Some(format) => format      ,
+        None         => return Ok(None),
+    };
+
+    if pad_len == 0 {
+        return Ok( Some( content.to_vec() ) );
+    }
+
+    let padded = match format {
+        ImageFormat::PNG  => pad_png (content, pad_len, rng)?,
+        ImageFormat::JPEG => pad_jpeg(content, pad_len, rng)?,
+        ImageFormat::GIF  => pad_gif (content, pad_len, rng)?,
+        ImageFormat::WebP => pad_webp(content, pad_len, rng)?,
+    };
+
+    Ok( Some(padded) )
+}
+
+// Inserts a private ancillary chunk before IEND.
+fn pad_png(content: &[u8], pad_len: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    if pad_len < PNG_CHUNK_SIZE {
+        return Err( AlpacaError::Padding( format!( "a PNG chunk needs {} bytes, not {}", PNG_CHUNK_SIZE, pad_len ) ) );
+    }
+
+    // Find IEND, walking the chunks
+    let mut pos = PNG_SIGNATURE.len();
+
+    loop {
+
+        if pos + PNG_CHUNK_SIZE > content.len() {
+            return Err( malformed(ImageFormat::PNG, "no IEND chunk") );
+        }
+
+        if &content[pos + 4..pos + 8] == b"IEND" {
+            break;
+        }
+
+        let len = u32::from_be_bytes( [ content[pos], content[pos + 1], content[pos + 2], content[pos + 3] ] ) as usize;
+        pos += PNG_CHUNK_SIZE + len;
+    }
+
+    let data = random_bytes(pad_len - PNG_CHUNK_SIZE, rng);
+
+    let mut crc = Crc::new();
+    crc.update(PNG_CHUNK_TYPE);
+    crc.update(&data);
+
+    let mut padded = Vec::with_capacity( content.len() + pad_len );
+
+    padded.extend_from_slice( &content[..pos] );
+    padded.extend_from_slice( &(data.len() as u32).to_be_bytes() );
+    padded.extend_from_slice( PNG_CHUNK_TYPE );
+    padded.extend_from_slice( &data );
+    padded.extend_from_slice( &crc.sum().to_be_bytes() );
+    padded.extend_from_slice( &content[pos..] );
+
+    Ok(padded)
+}
+
+// Inserts COM segments after the APPn ones that follow SOI.
+fn pad_jpeg(content: &[u8], pad_len: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    if pad_len < JPEG_SEGMENT {
+        return Err( AlpacaError::Padding( format!( "a JPEG segment needs {} bytes, not {}", JPEG_SEGMENT, pad_len ) ) );
+    }
+
+    let mut pos = JPEG_SOI.len();
+
+    while pos + JPEG_SEGMENT <= content.len() && content[pos] == 0xff && (0xe0..=0xef).contains( &content[pos + 1] ) {
+        pos += 2 + u16::from_be_bytes( [ content[pos + 2], content[pos + 3] ] ) as usize;
+    }
+
+    if pos > content.len() {
+        return Err( malformed(ImageFormat::JPEG, "truncated APPn segment") );
+    }
+
+    let mut padded = Vec::with_capacity( content.len() + pad_len );
+    padded.extend_from_slice( &content[..pos] );
+
+    // Segments hold at most JPEG_MAX_DATA bytes, and the last one needs
+    // at least its marker and length
+    let mut left = pad_len;
+
+    while left > 0 {
+
+        let mut data_len = std::cmp::min( left - JPEG_SEGMENT, JPEG_MAX_DATA );
+
+        if left - JPEG_SEGMENT - data_len < JPEG_SEGMENT && left - JPEG_SEGMENT - data_len > 0 {
+            data_len -= JPEG_SEGMENT;
+        }
+
+        padded.extend_from_slice( JPEG_COM );
+        padded.extend_from_slice( &( (data_len + 2) as u16 ).to_be_bytes() );
+        padded.extend( random_bytes(data_len, rng) );
+
+        left -= JPEG_SEGMENT + data_len;
+    }
+
+    padded.extend_from_slice( &content[pos..] );
+
+    Ok(padded)
+}
+
+// Inserts a comment extension before the trailer. Its data sub-blocks take
+// one byte more than their data, so a padding of 4 bytes cannot be made.
+fn pad_gif(content: &[u8], pad_len: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    if pad_len < 3 || pad_len == 4 {
+        return Err( AlpacaError::Padding( format!( "a GIF comment cannot take {} bytes", pad_len ) ) );
+    }
+
+    if content.last() != Some(&GIF_TRAILER) {
+        return Err( malformed(ImageFormat::GIF, "no trailer") );
+    }
+
+    let pos = content.len() - 1;
+
+    let mut padded = Vec::with_capacity( content.len() + pad_len );
+
+    padded.extend_from_slice( &content[..pos] );
+    padded.extend_from_slice( GIF_COMMENT );
+
+    // Sub-blocks of 1 to 255 bytes, each after its length
+    let mut left = pad_len - 3;
+
+    while left > 0 {
+
+        let mut block_len = std::cmp::min( left - 1, GIF_MAX_BLOCK );
+
+        if left - 1 - block_len == 1 {
+            block_len -= 1;
+        }
+
+        padded.push( block_len as u8 );
+        padded.extend( random_bytes(block_len, rng) );
+
+        left -= 1 + block_len;
+    }
+
+    padded.push(0);
+    padded.push(GIF_TRAILER);
+
+    Ok(padded)
+}
+
+// Appends an unknown chunk and updates the RIFF size. Chunks take an even
+// number of bytes, so the missing pad byte of an odd-sized file is added
+// first. A RIFF file is then of an even size, so an odd byte left over is
+// appended after the RIFF data, which decoders do not read.
+fn pad_webp(content: &[u8], pad_len: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    let pad_byte = content.len() % 2;
+
+    if pad_len < WEBP_CHUNK_SIZE + pad_byte {
+        return Err( AlpacaError::Padding( format!( "a WebP chunk needs {} bytes, not {}", WEBP_CHUNK_SIZE + pad_byte, pad_len ) ) );
+    }
+
+    let left     = pad_len - pad_byte - WEBP_CHUNK_SIZE;
+    let data_len = left - left % 2;
+
+    let mut padded = Vec::with_capacity( content.len() + pad_len );
+
+    padded.extend_from_slice( content );
+    padded.extend( std::iter::repeat_n( 0, pad_byte ) );
+    padded.extend_from_slice( WEBP_CHUNK_TYPE );
+    padded.extend_from_slice( &(data_len as u32).to_le_bytes() );
+    padded.extend( random_bytes(data_len, rng) );
+
+    let riff_size = (padded.len() - 8) as u32;
+    padded[4..8].copy_from_slice( &riff_size.to_le_bytes() );
+
+    padded.extend( random_bytes(left % 2, rng) );
+
+    Ok(padded)
+}
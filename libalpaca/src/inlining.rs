@@ -2,37 +2,47 @@ use dom;
 use parse;
 
 use dom::Map;
+use error::{ AlpacaError, ALPACA_PANIC };
 use morphing::MorphInfo;
-use utils::{ get_img_data_uri, content_to_c, c_string_to_str };
+use resolver::ResourceResolver;
+use utils::{ get_img_data_uri, content_to_c, c_string_to_str, ffi_guard };
 
 #[no_mangle]
-pub extern "C" fn inline_all_css(pinfo: *mut MorphInfo, req_mapper: dom::Map) -> u8 {
+pub extern "C" fn inline_all_css(pinfo: *mut MorphInfo, req_mapper: Map) -> u8 {
 
     std::env::set_var("RUST_BACKTRACE", "full");
 
-    let info = unsafe { &mut *pinfo };
-    let uri  = c_string_to_str(info.uri).unwrap();
+    ffi_guard( "inline_all_css", ALPACA_PANIC, || {
 
-    let html = match c_string_to_str(info.content) {
-        Ok (s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot read html content of {}: {}\n", uri, e);
-            return 0; // return NULL pointer if html cannot be converted to a string
-        }
-    };
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return e.code();
+            }
+        };
+
+        let html = match c_string_to_str(info.content) {
+            Ok (s) => s,
+            Err(e) => {
+                eprintln!("libalpaca: cannot read html content of {}: {}", info.uri(), e);
+                return e.code(); // the css is left as is
+            }
+        };
 
-    let document = parse::parse_html(html);
+        let document = parse::parse_html(html);
 
-    // Vector of objects found in the html
-    parse::parse_css_and_inline(&document, req_mapper);
+        // Vector of objects found in the html
+        parse::parse_css_and_inline( &document, &info.resolver(req_mapper) );
 
-    let content = dom::serialize_html(&document);
+        let content = dom::serialize_html(&document);
 
-    return content_to_c(content, info);
+        content_to_c(content, info)
+    })
 }
 
-// Inserts the ALPaCA GET parameters to the html objects, and adds the fake objects to the html.
-pub fn make_objects_inlined(objects: &mut Vec<dom::Object>, req_mapper: Map , n: usize, css_as_object: usize) -> Result<(), String> {
+// Inlines the first n objects which can be inlined, and removes them from the objects.
+pub fn make_objects_inlined(objects: &mut Vec<dom::Object>, resolver: &dyn ResourceResolver, n: usize, css_as_object: bool) -> Result<(), AlpacaError> {
 
     // Slice which contains initial objects
     let mut objects_inlined = Vec::new();
@@ -46,42 +56,37 @@ pub fn make_objects_inlined(objects: &mut Vec<dom::Object>, req_mapper: Map , n:
             break;
         }
 
-        // Ignore objects without target size
-        println!("OBJECT ITER {}", i);
-
-        if object.target_size.is_none() {
-            println!("OBJECT NO TARGET SIZE {}", object.uri);
-        }
-
-        let node = object.node.as_ref().unwrap();
+        // Fake objects are not in the html
+        let node = match object.node {
+            Some(ref node) => node    ,
+            None           => continue,
+        };
 
-        let node_tag = node.as_element()
-                           .unwrap()
-                           .name
-                           .local
-                           .to_lowercase();
+        let node_tag = dom::node_tag(node).unwrap_or_default();
 
         let attr = match node_tag.as_ref() {
             "img" | "script" => "src",
             "link"           => "href",
             "style"          => "style",
-            _                => panic!("shouldn't happen"),
+            _                => return Err( AlpacaError::Inlining( format!("cannot inline {} (tag {})", object.uri, node_tag) ) ),
         };
 
         if node_tag == "link" {
 
-            if css_as_object == 0 {
+            if !css_as_object {
                 continue;
             }
 
-            objects_inlined.push(i);
-
             let path = match dom::node_get_attribute(node, attr) {
-                Some(p) if p != "" && !p.starts_with("data:") => p       ,
-                _                                             => continue,
+                Some(p) if !p.is_empty() && !p.starts_with("data:") => p       ,
+                _                                                    => continue,
             };
 
-            let res  = dom::get_map_element(req_mapper, format!("/{}", path) );
+            // The object was found when parsing, so its content should be there
+            let res = resolver.content_of( &format!("/{}", path) )
+                              .ok_or_else( || AlpacaError::Resolver( format!("content of /{} is not available", path) ) )?;
+
+            objects_inlined.push(i);
 
             let temp = res.iter().map(|&c| c as char).collect::<String>();
 
@@ -92,36 +97,36 @@ pub fn make_objects_inlined(objects: &mut Vec<dom::Object>, req_mapper: Map , n:
 
         } else if node_tag == "img" {
 
-            objects_inlined.push(i);
-
             let requested_uri = format!("/{}", object.uri);
-            let temp = get_img_data_uri(req_mapper, &requested_uri);
+            let temp          = get_img_data_uri(resolver, &requested_uri)?;
+
+            objects_inlined.push(i);
 
             dom::node_set_attribute(node, attr, temp);
 
         } else if node_tag == "style" {
 
-            objects_inlined.push(i);
-
             let requested_uri = format!("/{}", object.uri);
-            let temp = get_img_data_uri(req_mapper, &requested_uri);
+            let temp          = get_img_data_uri(resolver, &requested_uri)?;
+
+            objects_inlined.push(i);
 
             // Replaces the <img src="q1.gif"> element for example with <img src="data:image/gif;charset=utf-8;base64 , ...">
-            let last_child   = node.last_child().unwrap();
-            let refc         = last_child.into_text_ref().unwrap();
+            if let Some(refc) = dom::style_text(node) {
 
-            let mut refc_val = refc.borrow().clone();
+                let mut refc_val = refc.borrow().clone();
 
-            refc_val = refc_val.replace(&object.uri, &temp);
+                refc_val = refc_val.replace(&object.uri, &temp);
 
-            *refc.borrow_mut() = refc_val;
+                *refc.borrow_mut() = refc_val;
+            }
         }
         obj_cnt += 1;
     }
 
-    for _ in objects_inlined.clone() {
-        objects.remove( objects_inlined.pop().unwrap() );
+    while let Some(i) = objects_inlined.pop() {
+        objects.remove(i);
     }
 
     Ok(())
-}
\ No newline at end of file
+}
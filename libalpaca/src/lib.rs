@@ -2,19 +2,55 @@
 //!
 //! A library to implement the ALPaCA defense to Website Fingerprinting
 //! attacks.
+
+// The extern "C" functions dereference the pointers handed to them by the
+// nginx module, which is responsible for their validity.
+#![allow(clippy::not_unsafe_ptr_arg_deref)]
+
 extern crate base64;
+extern crate brotli;
+extern crate flate2;
 extern crate html5ever;
 extern crate image;
+extern crate image_webp;
 extern crate kuchiki;
 extern crate rand;
+extern crate rand_chacha;
 extern crate rand_distr;
 extern crate libc;
+extern crate hmac;
+extern crate sha2;
+extern crate serde_json;
 
+pub mod compression;
 pub mod deterministic;
+pub mod dist_file;
 pub mod distribution;
 pub mod dom;
+pub mod error;
+pub mod fake;
+pub mod fake_refs;
+pub mod fit;
+pub mod image_pad;
 pub mod inlining;
+pub mod limits;
+pub mod morpher;
 pub mod morphing;
 pub mod pad;
 pub mod parse;
+pub mod profile;
+pub mod registry;
+pub mod resolver;
+pub mod rng;
+pub mod size_model;
+pub mod table;
+pub mod token;
+pub mod truncated;
 pub mod utils;
+
+pub use error::AlpacaError;
+pub use compression::Encoding;
+pub use fake_refs::Carrier;
+pub use limits::{ Fallback, FallbackReport, Limit, Limits };
+pub use morpher::{ DeterministicScheme, MorphConfig, MorphedPage, Morpher, Overhead, OverheadCap, TotalSizeSplit };
+pub use resolver::{ FsResolver, ResourceResolver };
@@ -0,0 +1,173 @@
+//! Bounds the work and the memory spent on morphing a page or an object. A
+//! page beyond a limit falls back to the deterministic version, or is
+//! served as it is, and the caller is told which fallback was taken.
+use dom::Object;
+use error::AlpacaError;
+use morpher::{ DeterministicConfig, Overhead };
+use std::fmt;
+use std::time::{ Duration, Instant };
+
+// The limit that a page or an object exceeded.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Limit {
+    HtmlSize     , // the html is too large to parse
+    ObjectCount  , // the html references too many objects
+    ObjectPadding, // an object would be padded by too many bytes
+    PagePadding  , // the page would be padded by too many bytes
+    TimeBudget   , // parsing and sampling took too long
+}
+
+impl Limit {
+
+    // The code of the limit, reported to the nginx module.
+    pub fn code(&self) -> usize {
+        match *self {
+            Limit::HtmlSize      => 1,
+            Limit::ObjectCount   => 2,
+            Limit::ObjectPadding => 3,
+            Limit::PagePadding   => 4,
+            Limit::TimeBudget    => 5,
+        }
+    }
+
+    // Whether a page beyond the limit may still be morphed deterministically.
+    // Parsing too large an html or reading too many objects is what the
+    // first two limits guard against, so those pages are passed through.
+    fn allows_deterministic(&self) -> bool {
+        !matches!( *self, Limit::HtmlSize | Limit::ObjectCount )
+    }
+}
+
+impl fmt::Display for Limit {
+
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            Limit::HtmlSize      => write!(f, "maximum html size"       ),
+            Limit::ObjectCount   => write!(f, "maximum number of objects"),
+            Limit::ObjectPadding => write!(f, "maximum object padding"  ),
+            Limit::PagePadding   => write!(f, "maximum page padding"    ),
+            Limit::TimeBudget    => write!(f, "time budget"             ),
+        }
+    }
+}
+
+// What a page beyond a limit is served as.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum Fallback {
+    Passthrough  , // the original html, without references to padded objects
+    Deterministic, // the html morphed with the deterministic fallback parameters
+}
+
+impl Fallback {
+
+    // The code of the fallback, reported to the nginx module.
+    pub fn code(&self) -> usize {
+        match *self {
+            Fallback::Passthrough   => 1,
+            Fallback::Deterministic => 2,
+        }
+    }
+}
+
+// The fallback that a page was served with, and the limit that led to it.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct FallbackReport {
+    pub limit    : Limit   ,
+    pub fallback : Fallback,
+}
+
+// The limits on morphing, where None means no limit.
+#[derive(Default)]
+pub struct Limits {
+    pub max_html_size    : Option<usize>   , // bytes of the html to morph
+    pub max_objects      : Option<usize>   , // objects referenced by the html
+    pub max_obj_padding  : Option<usize>   , // bytes added to an object (the size of a fake one)
+    pub max_page_padding : Option<usize>   , // bytes added to the html and its objects
+    pub time_budget      : Option<Duration>, // for parsing and sampling a page
+
+    // The parameters that a page beyond the padding limits or the time
+    // budget is morphed with, instead of being passed through
+    pub deterministic_fallback : Option< Box<DeterministicConfig> >,
+}
+
+impl Limits {
+
+    pub fn check(&self) -> Result<(), AlpacaError> {
+
+        let zero = [self.max_html_size, self.max_objects, self.max_obj_padding, self.max_page_padding].contains( &Some(0) )
+                   || self.time_budget == Some( Duration::from_secs(0) );
+
+        if zero {
+            return Err( AlpacaError::Config( String::from("the limits must be positive") ) );
+        }
+
+        match self.deterministic_fallback {
+            Some(ref params) => params.check(),
+            None             => Ok(())       ,
+        }
+    }
+
+    // The fallback of a page beyond the given limit.
+    pub fn fallback(&self, limit: Limit) -> Fallback {
+
+        if self.deterministic_fallback.is_some() && limit.allows_deterministic() {
+            Fallback::Deterministic
+        } else {
+            Fallback::Passthrough
+        }
+    }
+
+    // The time by which a page that starts being morphed now has to be.
+    pub fn deadline(&self) -> Deadline {
+        Deadline( self.time_budget.map( |budget| Instant::now() + budget ) )
+    }
+
+    pub fn check_html_size(&self, size: usize) -> Result<(), AlpacaError> {
+        check(self.max_html_size, size, Limit::HtmlSize)
+    }
+
+    pub fn check_objects(&self, count: usize) -> Result<(), AlpacaError> {
+        check(self.max_objects, count, Limit::ObjectCount)
+    }
+
+    pub fn check_obj_padding(&self, padding: usize) -> Result<(), AlpacaError> {
+        check(self.max_obj_padding, padding, Limit::ObjectPadding)
+    }
+
+    // Checks the padding of every object of a morphed page, and of the
+    // whole page.
+    pub fn check_page_padding(&self, objects: &[Object], overhead: &Overhead) -> Result<(), AlpacaError> {
+
+        for obj in objects {
+            self.check_obj_padding( obj.target_size.unwrap_or(obj.size).saturating_sub(obj.size) )?;
+        }
+
+        check(self.max_page_padding, overhead.bytes(), Limit::PagePadding)
+    }
+}
+
+fn check(limit: Option<usize>, value: usize, exceeded: Limit) -> Result<(), AlpacaError> {
+
+    match limit {
+        Some(max) if value > max => Err( AlpacaError::Limit(exceeded) ),
+        _                        => Ok(())                             ,
+    }
+}
+
+// The end of the time budget of a page, if any.
+pub struct Deadline(Option<Instant>);
+
+impl Deadline {
+
+    pub fn none() -> Deadline {
+        Deadline(None)
+    }
+
+    pub fn check(&self) -> Result<(), AlpacaError> {
+
+        match self.0 {
+            Some(end) if Instant::now() > end => Err( AlpacaError::Limit(Limit::TimeBudget) ),
+            _                                 => Ok(())                                      ,
+        }
+    }
+}
@@ -0,0 +1,855 @@
+//! A safe interface to the ALPaCA defense, for callers that do not go
+//! through the nginx module (eg web services written in Rust).
+use compression;
+use dom;
+use fake;
+use pad;
+use parse;
+use token;
+
+use registry;
+
+use compression::Encoding;
+use distribution::Dist;
+use dom::{ Object, ObjectKind };
+use error::AlpacaError;
+use fake_refs::{ Carrier, FakeRefs };
+use image_pad::ImageFormat;
+use limits::{ Deadline, Fallback, FallbackReport, Limit, Limits };
+use profile::PageProfiles;
+use rand::RngCore;
+use std::sync::Arc;
+use morphing::{ morph_deterministic, morph_probabilistic, resample_html_size };
+use pad::get_object_padding;
+use resolver::ResourceResolver;
+use rng::{ morph_rng, KeyedConfig };
+use token::{ TokenIssuer, TokenKey };
+use utils::{ keep_local_objects, insert_objects_refs };
+
+
+// How a sampled total page size is split between the html and its objects.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum TotalSizeSplit {
+    Proportional, // the extra size is split in proportion to the minimum sizes
+    Conditional , // the sizes are sampled from dist_html_size and dist_obj_size, conditioned on the total
+}
+
+// How the deterministic version rounds up sizes and object counts.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum DeterministicScheme {
+    Multiple, // to multiples of obj_num and obj_size
+    Padme   , // to Padmé values, which keep the exponent and its log2 top bits of the mantissa
+}
+
+// A ceiling on the bytes added by morphing, as a ratio of the original size
+// and/or an absolute number of bytes. None means no limit.
+#[derive(PartialEq, Clone, Copy, Debug, Default)]
+pub struct OverheadCap {
+    pub max_ratio : Option<f64>  ,
+    pub max_bytes : Option<usize>,
+}
+
+impl OverheadCap {
+
+    // The largest size that something of the given original size can be
+    // morphed to.
+    pub fn limit(&self, size: usize) -> usize {
+
+        let by_ratio = self.max_ratio.map_or( usize::MAX, |ratio| size.saturating_add( (size as f64 * ratio) as usize ) );
+        let by_bytes = self.max_bytes.map_or( usize::MAX, |bytes| size.saturating_add(bytes) );
+
+        std::cmp::min(by_ratio, by_bytes)
+    }
+
+    // The largest size of a fake object. As there was nothing originally,
+    // only max_bytes applies.
+    pub fn fake_limit(&self) -> usize {
+        self.max_bytes.unwrap_or(usize::MAX)
+    }
+
+    fn check(&self) -> Result<(), AlpacaError> {
+
+        match self.max_ratio {
+            Some(ratio) if !ratio.is_finite() || ratio < 0.0 => {
+                Err( AlpacaError::Config( format!("invalid overhead ratio {}", ratio) ) )
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+// A setting for each kind of object, where None means to use the one for
+// all objects. Images include the fake ones and those referenced from css.
+#[derive(Clone)]
+pub struct PerKind<T> {
+    pub css  : Option<T>,
+    pub js   : Option<T>,
+    pub img  : Option<T>,
+    pub font : Option<T>,
+}
+
+impl<T> PerKind<T> {
+
+    pub fn get(&self, kind: ObjectKind) -> Option<&T> {
+        match kind {
+            ObjectKind::CSS  | ObjectKind::FakeCSS                       => self.css .as_ref(),
+            ObjectKind::JS   | ObjectKind::FakeJS                        => self.js  .as_ref(),
+            ObjectKind::IMG  | ObjectKind::FakeIMG | ObjectKind::CssImg  => self.img .as_ref(),
+            ObjectKind::Font | ObjectKind::FakeFont                      => self.font.as_ref(),
+            _                                                            => None              ,
+        }
+    }
+
+    // The slot of the given kind, or None for kinds without settings.
+    fn slot(&mut self, kind: ObjectKind) -> Option<&mut Option<T>> {
+        match kind {
+            ObjectKind::CSS  | ObjectKind::FakeCSS                       => Some(&mut self.css ),
+            ObjectKind::JS   | ObjectKind::FakeJS                        => Some(&mut self.js  ),
+            ObjectKind::IMG  | ObjectKind::FakeIMG | ObjectKind::CssImg  => Some(&mut self.img ),
+            ObjectKind::Font | ObjectKind::FakeFont                      => Some(&mut self.font),
+            _                                                            => None                ,
+        }
+    }
+}
+
+impl<T> Default for PerKind<T> {
+
+    fn default() -> PerKind<T> {
+        PerKind { css: None, js: None, img: None, font: None }
+    }
+}
+
+// Parameters of the probabilistic version.
+pub struct ProbabilisticConfig {
+    pub dist_html_size     : Arc<Dist>,
+    pub dist_obj_num       : Arc<Dist>,
+    pub dist_obj_size      : Arc<Dist>,
+    pub use_total_obj_size : bool     , // sample the total size of all objects, instead of each one
+
+    // The distribution of each kind of object's size, instead of dist_obj_size
+    pub dist_obj_size_kind : PerKind< Arc<Dist> >,
+
+    // The distribution of the number of objects of each kind; the other
+    // kinds' objects are counted together from dist_obj_num. If dist_obj_num
+    // has a value per kind (css, js, img, font), all counts are sampled from it.
+    pub dist_obj_num_kind  : PerKind< Arc<Dist> >,
+
+    // The total size of the page (html + objects), used instead of the
+    // html and object sizes unless it is empty
+    pub dist_total_size    : Arc<Dist>     ,
+    pub total_size_split   : TotalSizeSplit,
+
+    // Whole pages to morph to, used instead of all the distributions above
+    pub page_profiles      : Option< Arc<PageProfiles> >,
+}
+
+// Parameters of the deterministic version.
+#[derive(Clone)]
+pub struct DeterministicConfig {
+    pub scheme       : DeterministicScheme,
+    pub obj_num      : usize              , // λ: the object count is a multiple of it
+    pub obj_size     : usize              , // σ: object sizes are multiples of it
+    pub max_obj_size : usize              , // max size of a fake object
+
+    // σ and max_s of each kind of object, instead of obj_size and max_obj_size
+    pub obj_size_kind     : PerKind<usize>,
+    pub max_obj_size_kind : PerKind<usize>,
+
+    // λ of each kind of object, whose count is rounded up on its own; the
+    // other kinds' objects are counted together
+    pub obj_num_kind      : PerKind<usize>,
+}
+
+impl ProbabilisticConfig {
+
+    // The distribution of the size of objects of the given kind.
+    pub fn dist_obj_size(&self, kind: ObjectKind) -> &Arc<Dist> {
+        self.dist_obj_size_kind.get(kind).unwrap_or(&self.dist_obj_size)
+    }
+}
+
+impl DeterministicConfig {
+
+    // σ for objects of the given kind.
+    pub fn obj_size(&self, kind: ObjectKind) -> usize {
+        *self.obj_size_kind.get(kind).unwrap_or(&self.obj_size)
+    }
+
+    // max_s for objects of the given kind.
+    pub fn max_obj_size(&self, kind: ObjectKind) -> usize {
+        *self.max_obj_size_kind.get(kind).unwrap_or(&self.max_obj_size)
+    }
+
+    // Checks σ and max_s of every kind, as the nginx module does.
+    pub fn check(&self) -> Result<(), AlpacaError> {
+
+        let kinds = [ObjectKind::CSS, ObjectKind::JS, ObjectKind::IMG, ObjectKind::Font];
+
+        for &kind in &kinds {
+
+            let obj_size     = self.obj_size(kind);
+            let max_obj_size = self.max_obj_size(kind);
+
+            if max_obj_size == 0 {
+                return Err( AlpacaError::Config( format!("max_obj_size of {:?} objects must be positive", kind) ) );
+            }
+
+            if self.scheme == DeterministicScheme::Multiple && (obj_size == 0 || max_obj_size < obj_size || !max_obj_size.is_multiple_of(obj_size)) {
+                return Err( AlpacaError::Config( format!( "max_obj_size ({}) of {:?} objects must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, kind, obj_size ) ) );
+            }
+        }
+
+        Ok(())
+    }
+}
+
+pub enum MorphMode {
+    Probabilistic(Box<ProbabilisticConfig>),
+    Deterministic(Box<DeterministicConfig>),
+}
+
+// Configuration of a Morpher.
+pub struct MorphConfig {
+    pub mode                 : MorphMode,
+
+    // for object inlining
+    pub obj_inlining_enabled : bool,
+    pub force_css_inlining   : bool,
+    pub css_as_inline_object : bool,
+
+    // Derive all random choices from a secret key and the uri, instead of
+    // sampling afresh for every request
+    pub keyed                : Option<KeyedConfig>,
+
+    // The key of the padding tokens: derived from the secret key with keyed
+    // morphing, or else the key of this process
+    pub token_key            : Arc<TokenKey>,
+
+    // The most that morphing may add to the whole page (html and objects)
+    // and to each object. Samples beyond them are drawn again.
+    pub page_overhead        : OverheadCap,
+    pub obj_overhead         : OverheadCap,
+
+    // The elements that may reference the fake images, and the stylesheets,
+    // scripts and fonts besides their own elements
+    pub fake_carriers        : Vec<Carrier>,
+
+    // The seconds that the tokens of a morphed page are valid for. Its
+    // objects are served unpadded once they expire.
+    pub token_ttl            : u64,
+
+    // Bounds on the work and memory spent on a page, beyond which it falls
+    // back to deterministic morphing or is served as it is
+    pub limits               : Limits,
+}
+
+impl MorphConfig {
+
+    // Probabilistic morphing, with the distributions given as in the
+    // alpaca_dist_* directives (eg "Normal/20.0,1.0" or "/path/to/file.dist").
+    pub fn probabilistic( dist_html_size : &str,
+                          dist_obj_num   : &str,
+                          dist_obj_size  : &str ) -> Result<MorphConfig, AlpacaError>
+    {
+        let params = ProbabilisticConfig {
+            dist_html_size     : registry::distribution(dist_html_size)?,
+            dist_obj_num       : registry::distribution(dist_obj_num)?  ,
+            dist_obj_size      : registry::distribution(dist_obj_size)? ,
+            use_total_obj_size : false                                  ,
+            dist_obj_size_kind : PerKind::default()                     ,
+            dist_obj_num_kind  : PerKind::default()                     ,
+            dist_total_size    : Arc::default()                         ,
+            total_size_split   : TotalSizeSplit::Proportional           ,
+            page_profiles      : None                                   ,
+        };
+
+        Ok( MorphConfig::with_mode( MorphMode::Probabilistic( Box::new(params) ) ) )
+    }
+
+    // Deterministic morphing, checked as the nginx module does for the
+    // alpaca_obj_num, alpaca_obj_size and alpaca_max_obj_size directives.
+    pub fn deterministic( obj_num      : usize,
+                          obj_size     : usize,
+                          max_obj_size : usize ) -> Result<MorphConfig, AlpacaError>
+    {
+        if obj_size == 0 || max_obj_size == 0 {
+            return Err( AlpacaError::Config( String::from("obj_size and max_obj_size must be positive") ) );
+        }
+
+        if max_obj_size < obj_size || !max_obj_size.is_multiple_of(obj_size) {
+            return Err( AlpacaError::Config( format!( "max_obj_size ({}) must be greater-or-equal and a multiple of obj_size ({})", max_obj_size, obj_size ) ) );
+        }
+
+        let params = DeterministicConfig {
+            scheme            : DeterministicScheme::Multiple,
+            obj_num                                          ,
+            obj_size                                         ,
+            max_obj_size                                     ,
+            obj_size_kind     : PerKind::default()           ,
+            max_obj_size_kind : PerKind::default()           ,
+            obj_num_kind      : PerKind::default()           ,
+        };
+
+        Ok( MorphConfig::with_mode( MorphMode::Deterministic( Box::new(params) ) ) )
+    }
+
+    // Deterministic morphing which rounds the html size, the object sizes
+    // and the object count up to Padmé values, adding at most about 12% to
+    // each. obj_num is only used when object inlining is enabled, as the
+    // fixed object count.
+    pub fn padme( obj_num      : usize,
+                  max_obj_size : usize ) -> Result<MorphConfig, AlpacaError>
+    {
+        if max_obj_size == 0 {
+            return Err( AlpacaError::Config( String::from("max_obj_size must be positive") ) );
+        }
+
+        let params = DeterministicConfig {
+            scheme            : DeterministicScheme::Padme,
+            obj_num                                       ,
+            obj_size          : 0                         ,
+            max_obj_size                                  ,
+            obj_size_kind     : PerKind::default()        ,
+            max_obj_size_kind : PerKind::default()        ,
+            obj_num_kind      : PerKind::default()        ,
+        };
+
+        Ok( MorphConfig::with_mode( MorphMode::Deterministic( Box::new(params) ) ) )
+    }
+
+    // Samples the total size of the page from the given distribution, and
+    // splits it between the html and its objects. Only for probabilistic morphing.
+    pub fn with_total_size(mut self, dist_total_size: &str, split: TotalSizeSplit) -> Result<MorphConfig, AlpacaError> {
+
+        match self.mode {
+            MorphMode::Probabilistic(ref mut params) => {
+                params.dist_total_size  = registry::distribution(dist_total_size)?;
+                params.total_size_split = split;
+            },
+            MorphMode::Deterministic(_) => {
+                return Err( AlpacaError::Config( String::from("the total size distribution needs probabilistic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Morphs pages to one of the profiles of the given ".profile" file,
+    // instead of sampling each size. Only for probabilistic morphing.
+    pub fn with_page_profiles(mut self, path: &str) -> Result<MorphConfig, AlpacaError> {
+
+        match self.mode {
+            MorphMode::Probabilistic(ref mut params) => {
+                params.page_profiles = Some( registry::page_profiles(path)? );
+            },
+            MorphMode::Deterministic(_) => {
+                return Err( AlpacaError::Config( String::from("page profiles need probabilistic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Samples the sizes of objects of the given kind from their own
+    // distribution. Only for probabilistic morphing.
+    pub fn with_kind_dist_obj_size(mut self, kind: ObjectKind, dist_obj_size: &str) -> Result<MorphConfig, AlpacaError> {
+
+        let dist = registry::distribution(dist_obj_size)?;
+
+        match self.mode {
+            MorphMode::Probabilistic(ref mut params) => {
+                *params.dist_obj_size_kind.slot(kind).ok_or_else( || no_kind_settings(kind) )? = Some(dist);
+            },
+            MorphMode::Deterministic(_) => {
+                return Err( AlpacaError::Config( String::from("object size distributions need probabilistic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Samples the number of objects of the given kind from their own
+    // distribution, adding fake ones of that kind. Only for probabilistic morphing.
+    pub fn with_kind_dist_obj_num(mut self, kind: ObjectKind, dist_obj_num: &str) -> Result<MorphConfig, AlpacaError> {
+
+        let dist = registry::distribution(dist_obj_num)?;
+
+        match self.mode {
+            MorphMode::Probabilistic(ref mut params) => {
+                *params.dist_obj_num_kind.slot(kind).ok_or_else( || no_kind_settings(kind) )? = Some(dist);
+            },
+            MorphMode::Deterministic(_) => {
+                return Err( AlpacaError::Config( String::from("object number distributions need probabilistic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Rounds the number of objects of the given kind up on its own (to a
+    // multiple of obj_num, unless with Padmé), adding fake ones of that kind.
+    // Only for deterministic morphing.
+    pub fn with_kind_obj_num(mut self, kind: ObjectKind, obj_num: usize) -> Result<MorphConfig, AlpacaError> {
+
+        if obj_num == 0 {
+            return Err( AlpacaError::Config( String::from("obj_num must be positive") ) );
+        }
+
+        match self.mode {
+            MorphMode::Deterministic(ref mut params) => {
+                *params.obj_num_kind.slot(kind).ok_or_else( || no_kind_settings(kind) )? = Some(obj_num);
+            },
+            MorphMode::Probabilistic(_) => {
+                return Err( AlpacaError::Config( String::from("object numbers per kind need deterministic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Pads objects of the given kind to multiples of their own obj_size (σ),
+    // and fake ones up to their own max_obj_size. Only for deterministic
+    // morphing; obj_size is not used with Padmé.
+    pub fn with_kind_obj_size(mut self, kind: ObjectKind, obj_size: usize, max_obj_size: usize) -> Result<MorphConfig, AlpacaError> {
+
+        match self.mode {
+            MorphMode::Deterministic(ref mut params) => {
+                *params.obj_size_kind    .slot(kind).ok_or_else( || no_kind_settings(kind) )? = Some(obj_size);
+                *params.max_obj_size_kind.slot(kind).ok_or_else( || no_kind_settings(kind) )? = Some(max_obj_size);
+                params.check()?;
+            },
+            MorphMode::Probabilistic(_) => {
+                return Err( AlpacaError::Config( String::from("object sizes per kind need deterministic morphing") ) );
+            },
+        }
+
+        Ok(self)
+    }
+
+    // Derives the morphs of each uri from the secret key, so that they only
+    // change every epoch_length seconds (never, if it is 0).
+    pub fn with_secret(mut self, secret: Vec<u8>, epoch_length: u64) -> Result<MorphConfig, AlpacaError> {
+
+        self.token_key = Arc::new( TokenKey::from_secret(&secret) );
+        self.keyed     = Some( KeyedConfig::new(secret, epoch_length)? );
+
+        Ok(self)
+    }
+
+    // Bounds the bytes added to the whole page and to each object. Sizes
+    // are sampled within the allowed region of their distributions, and
+    // pages beyond the page cap are morphed again.
+    pub fn with_overhead_cap(mut self, page: OverheadCap, obj: OverheadCap) -> Result<MorphConfig, AlpacaError> {
+
+        page.check()?;
+        obj .check()?;
+
+        self.page_overhead = page;
+        self.obj_overhead  = obj;
+
+        Ok(self)
+    }
+
+    // Restricts the elements that reference the fake objects, which are
+    // otherwise drawn among all carriers.
+    pub fn with_fake_carriers(mut self, carriers: &[Carrier]) -> Result<MorphConfig, AlpacaError> {
+
+        if carriers.is_empty() {
+            return Err( AlpacaError::Config( String::from("no carrier is given for the fake objects") ) );
+        }
+
+        self.fake_carriers = carriers.to_vec();
+
+        Ok(self)
+    }
+
+    // Bounds the html size, the objects, the padding and the time spent on a
+    // page. Objects beyond max_obj_padding are not padded.
+    pub fn with_limits(mut self, limits: Limits) -> Result<MorphConfig, AlpacaError> {
+
+        limits.check()?;
+
+        self.limits = limits;
+
+        Ok(self)
+    }
+
+    // Sets the seconds that the padding tokens of a morphed page are valid for.
+    pub fn with_token_ttl(mut self, token_ttl: u64) -> Result<MorphConfig, AlpacaError> {
+
+        if token_ttl == 0 {
+            return Err( AlpacaError::Config( String::from("the token ttl must be positive") ) );
+        }
+
+        self.token_ttl = token_ttl;
+
+        Ok(self)
+    }
+
+    pub fn with_mode(mode: MorphMode) -> MorphConfig {
+        MorphConfig {
+            mode                        ,
+            obj_inlining_enabled : false,
+            force_css_inlining   : false,
+            css_as_inline_object : false,
+            keyed                : None ,
+            token_key            : TokenKey::process(),
+            page_overhead        : OverheadCap::default(),
+            obj_overhead         : OverheadCap::default(),
+            fake_carriers        : Carrier::ALL.to_vec(),
+            token_ttl            : token::DEFAULT_TOKEN_TTL,
+            limits               : Limits::default(),
+        }
+    }
+}
+
+fn no_kind_settings(kind: ObjectKind) -> AlpacaError {
+    AlpacaError::Config( format!("{:?} objects have no settings of their own", kind) )
+}
+
+// The default configuration samples every size from an empty distribution,
+// that is it keeps the real values.
+impl Default for MorphConfig {
+
+    fn default() -> MorphConfig {
+        MorphConfig::with_mode( MorphMode::Probabilistic( Box::new( ProbabilisticConfig {
+            dist_html_size     : Arc::default()              ,
+            dist_obj_num       : Arc::default()              ,
+            dist_obj_size      : Arc::default()              ,
+            use_total_obj_size : false                       ,
+            dist_obj_size_kind : PerKind::default()          ,
+            dist_obj_num_kind  : PerKind::default()          ,
+            dist_total_size    : Arc::default()              ,
+            total_size_split   : TotalSizeSplit::Proportional,
+            page_profiles      : None                        ,
+        })))
+    }
+}
+
+// The size an object of the morphed page has to be padded to.
+pub struct ObjectTarget {
+    pub uri         : String        ,
+    pub kind        : ObjectKind    ,
+    pub target_size : Option<usize> , // None if no padding was found
+}
+
+// The bytes of a page, html and objects, before and after morphing.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub struct Overhead {
+    pub original : usize,
+    pub morphed  : usize, // including the fake objects
+}
+
+impl Overhead {
+
+    // The bytes added by morphing.
+    pub fn bytes(&self) -> usize {
+        self.morphed.saturating_sub(self.original)
+    }
+
+    // The bytes added by morphing, relative to the original size.
+    pub fn ratio(&self) -> f64 {
+        if self.original == 0 { 0.0 } else { self.bytes() as f64 / self.original as f64 }
+    }
+}
+
+// The result of morphing an html page.
+pub struct MorphedPage {
+    pub content     : Vec<u8>          , // The padded html, compressed with encoding
+    pub target_size : usize            ,
+    pub objects     : Vec<ObjectTarget>, // Including the fake ones
+    pub overhead    : Overhead         ,
+    pub encoding    : Encoding         , // The content-encoding of the content
+
+    // The fallback the page was served with, if it exceeded a limit
+    pub fallback    : Option<FallbackReport>,
+}
+
+// The number of times a page is morphed when the samples exceed the
+// overhead caps or do not fit the html, before giving up.
+const MAX_MORPH_ATTEMPTS: usize = 100;
+
+pub struct Morpher {
+    config: MorphConfig,
+}
+
+impl Morpher {
+
+    pub fn new(config: MorphConfig) -> Morpher {
+        Morpher { config }
+    }
+
+    pub fn config(&self) -> &MorphConfig {
+        &self.config
+    }
+
+    // Samples a new page using probabilistic/deterministic morphing, changes
+    // the references to its objects accordingly, and pads it. Morphs beyond
+    // the overhead caps are sampled again.
+    pub fn morph_html(&self, uri: &str, html: &str, resolver: &dyn ResourceResolver) -> Result<MorphedPage, AlpacaError> {
+        self.morph_html_encoded(uri, html, Encoding::Identity, resolver)
+    }
+
+    // Morphs the page as morph_html, for an html sent with the given
+    // content-encoding: the sizes sampled for it are those of the
+    // compressed html, which is returned as the content.
+    //
+    // A page beyond the limits is morphed with the deterministic fallback
+    // parameters, or else served as it is; page.fallback tells which.
+    pub fn morph_html_encoded(&self, uri: &str, html: &str, encoding: Encoding, resolver: &dyn ResourceResolver) -> Result<MorphedPage, AlpacaError> {
+
+        let limits = &self.config.limits;
+
+        let limit = match self.morph_html_mode( uri, html, encoding, &self.config.mode, limits.deadline(), resolver ) {
+            Err( AlpacaError::Limit(limit) ) => limit,
+            res                              => return res,
+        };
+
+        let fallback = match (limits.fallback(limit), &limits.deterministic_fallback) {
+            (Fallback::Deterministic, Some(params)) => MorphMode::Deterministic( params.clone() ),
+            _                                       => return passthrough(html, encoding, limit),
+        };
+
+        // The deterministic version samples nothing but the fake objects, so
+        // it is not bound by the time budget
+        match self.morph_html_mode( uri, html, encoding, &fallback, Deadline::none(), resolver ) {
+
+            Ok (mut page)                    => {
+                page.fallback = Some( FallbackReport { limit, fallback: Fallback::Deterministic } );
+                Ok(page)
+            },
+            Err( AlpacaError::Limit(limit) ) => passthrough(html, encoding, limit),
+            Err(e)                           => Err(e),
+        }
+    }
+
+    // Morphs the page with the given mode, within the limits.
+    fn morph_html_mode( &self                        ,
+                        uri      : &str              ,
+                        html     : &str              ,
+                        encoding : Encoding          ,
+                        mode     : &MorphMode        ,
+                        deadline : Deadline          ,
+                        resolver : &dyn ResourceResolver ) -> Result<MorphedPage, AlpacaError>
+    {
+        self.config.limits.check_html_size( html.len() )?;
+
+        let mut rng = morph_rng( self.config.keyed.as_ref(), uri )?;
+
+        // The objects are referenced with tokens resolved against the page's uri
+        let tokens = TokenIssuer::new( self.config.token_key.clone(), uri, token::now() + self.config.token_ttl );
+
+        for _ in 0..MAX_MORPH_ATTEMPTS {
+
+            deadline.check()?;
+
+            if let Some(page) = self.morph_page(html, encoding, mode, &deadline, &tokens, resolver, &mut *rng)? {
+                return Ok(page);
+            }
+        }
+
+        Err( AlpacaError::Padding( format!( "no morph of {} fits its sizes and the overhead caps in {} attempts", uri, MAX_MORPH_ATTEMPTS ) ) )
+    }
+
+    // Morphs the page once, or returns None if it exceeds the overhead caps,
+    // its html does not fit in a size sampled together with the objects', or
+    // no brotli padding reaches its size exactly.
+    #[allow(clippy::too_many_arguments)]
+    fn morph_page( &self                            ,
+                   html     : &str                  ,
+                   encoding : Encoding              ,
+                   mode     : &MorphMode            ,
+                   deadline : &Deadline             ,
+                   tokens   : &TokenIssuer          ,
+                   resolver : &dyn ResourceResolver ,
+                   rng      : &mut dyn RngCore        ) -> Result<Option<MorphedPage>, AlpacaError>
+    {
+        let document = parse::parse_html(html);
+
+        // The objects are counted before any of them is read
+        self.config.limits.check_objects( parse::parse_object_names(&document).len() )?;
+        deadline.check()?;
+
+        // Vector of objects found in the html
+        let mut objects = parse::parse_objects(&document, resolver);
+
+        keep_local_objects(&mut objects);
+
+        // The fake objects follow the uris of the objects found
+        let fake_refs = FakeRefs::new(&objects, &self.config.fake_carriers, rng);
+
+        // A compressed html is compared to the original one compressed alike
+        let html_size = match encoding {
+            Encoding::Identity => html.len(),
+            _                  => compression::compress( html.as_bytes(), encoding )?.len(),
+        };
+
+        let original = html_size + objects.iter().map( |obj| obj.size ).sum::<usize>();
+
+        // Number of original objects
+        let mut orig_n = objects.len();
+
+        let mut target_size = match *mode {
+            MorphMode::Probabilistic(ref params) => {
+                morph_probabilistic( &document, &mut objects, params, &self.config, encoding, &fake_refs, &mut orig_n, resolver, rng )?
+            },
+            MorphMode::Deterministic(ref params) => {
+                morph_deterministic( &document, &mut objects, params, &self.config, encoding, &fake_refs, &mut orig_n, resolver, rng )?
+            },
+        };
+
+        // Insert refs, and measure the html they lead to
+        insert_objects_refs(&document, &objects, orig_n, tokens, rng)?;
+
+        let content     = dom::serialize_html(&document);
+        let needed_size = compression::min_html_size(&content, encoding)?;
+
+        if target_size < needed_size {
+
+            target_size = match resample_html_size(mode, needed_size, rng)? {
+                Some(size) => size          ,
+                None       => return Ok(None),
+            };
+        }
+
+        let overhead = Overhead {
+            original,
+            morphed  : target_size + objects.iter().map( |obj| obj.target_size.unwrap_or(obj.size) ).sum::<usize>(),
+        };
+
+        if !self.within_caps(mode, &objects, target_size, &overhead)? {
+            return Ok(None);
+        }
+
+        self.config.limits.check_page_padding(&objects, &overhead)?;
+        deadline.check()?;
+
+        // Pad the html to exactly the target size, once compressed.
+        let content = match compression::pad_html(content, target_size, encoding, rng)? {
+            Some(content) => content        ,
+            None          => return Ok(None),
+        };
+
+        let objects = objects.into_iter()
+                             .map( |obj| ObjectTarget { uri: obj.uri, kind: obj.kind, target_size: obj.target_size } )
+                             .collect();
+
+        Ok( Some( MorphedPage { content, target_size, objects, overhead, encoding, fallback: None } ) )
+    }
+
+    // Whether the morphed page is within the overhead caps. With deterministic
+    // morphing only the fake objects are random, so an error is returned if
+    // the rounded html and objects alone exceed them.
+    fn within_caps(&self, mode: &MorphMode, objects: &[Object], html_size: usize, overhead: &Overhead) -> Result<bool, AlpacaError> {
+
+        let page_limit = self.config.page_overhead.limit(overhead.original);
+        let obj_limit  = |obj: &Object| if obj.kind.is_fake() { self.config.obj_overhead.fake_limit() } else { self.config.obj_overhead.limit(obj.size) };
+
+        let within_obj_caps = objects.iter().all( |obj| obj.target_size.unwrap_or(obj.size) <= obj_limit(obj) );
+        let within_caps     = within_obj_caps && overhead.morphed <= page_limit;
+
+        if let MorphMode::Deterministic(_) = *mode {
+
+            let rounded = html_size + objects.iter()
+                                             .filter( |obj| !obj.kind.is_fake() )
+                                             .map( |obj| obj.target_size.unwrap_or(obj.size) )
+                                             .sum::<usize>();
+
+            if !within_obj_caps || rounded > page_limit {
+                return Err( AlpacaError::Config( String::from("the rounded sizes exceed the overhead caps") ) );
+            }
+        }
+
+        Ok(within_caps)
+    }
+
+    // Returns the padding to append to an object of the given content type
+    // and size, requested at uri (its decoded path, as nginx's $uri) with the
+    // given query (the part after '?'). Only the size of a valid token of
+    // that path is padded to; anything else is an error, and the object is
+    // served unpadded.
+    pub fn morph_object(&self, uri: &str, content_type: &str, size: usize, query: &str) -> Result<Vec<u8>, AlpacaError> {
+
+        let kind        = parse::parse_object_kind(content_type);
+        let target_size = self.object_target_size(uri, size, query)?;
+
+        let mut rng = morph_rng( self.config.keyed.as_ref(), uri )?;
+
+        get_object_padding(kind, size, target_size, &mut *rng)
+    }
+
+    // Returns the object of the given content type padded to the size in its
+    // query, as morph_object. Images of a known format are padded inside it,
+    // so that they still decode.
+    pub fn pad_object(&self, uri: &str, content_type: &str, content: &[u8], query: &str) -> Result<Vec<u8>, AlpacaError> {
+
+        let kind        = parse::parse_object_kind(content_type);
+        let target_size = self.object_target_size(uri, content.len(), query)?;
+
+        let mut rng = morph_rng( self.config.keyed.as_ref(), uri )?;
+
+        pad::pad_object(kind, content, target_size, &mut *rng)
+    }
+
+    // Returns the fake object of the given content type requested at uri,
+    // generated with exactly the size in its query: an image that decodes,
+    // a stylesheet or script made of a comment, or the bytes of a font.
+    pub fn fake_object(&self, uri: &str, content_type: &str, query: &str) -> Result<Vec<u8>, AlpacaError> {
+
+        let target_size = self.object_target_size(uri, 0, query)?;
+
+        let mut rng = morph_rng( self.config.keyed.as_ref(), uri )?;
+
+        match ImageFormat::from_mime(content_type) {
+            Some(format) => fake::generate_fake_image(format, target_size, &mut *rng),
+            None         => fake::generate_fake_object( parse::parse_object_kind(content_type), target_size, &mut *rng ),
+        }
+    }
+
+    // The query that has the object at uri (a decoded path) padded to
+    // target_size, as the references of a morphed page do.
+    pub fn padding_query(&self, uri: &str, target_size: usize) -> Result<String, AlpacaError> {
+
+        let expiry = token::now() + self.config.token_ttl;
+        let token  = self.config.token_key.issue( uri, target_size, expiry, &mut rand::thread_rng() )?;
+
+        Ok( format!("{}={}", token::TOKEN_PARAM, token) )
+    }
+
+    // The size an object of the given size is padded to, from the token in
+    // its query.
+    fn object_target_size(&self, uri: &str, size: usize, query: &str) -> Result<usize, AlpacaError> {
+
+        let target_size = self.config.token_key
+                                     .target_size( uri, query, token::now() )
+                                     .ok_or_else( || AlpacaError::Parse( format!( "no valid padding token for {} in query \"{}\"", uri, query ) ) )?;
+
+        if target_size <= size {
+            // Target size has to be greater than current size.
+            return Err( AlpacaError::Padding( format!( "target_size ({}) cannot match current size ({})", target_size, size ) ) );
+        }
+
+        self.config.limits.check_obj_padding(target_size - size)?;
+
+        Ok(target_size)
+    }
+}
+
+// The page served as it is, when it exceeds a limit. Its overhead only
+// counts the html, as its objects are not read.
+fn passthrough(html: &str, encoding: Encoding, limit: Limit) -> Result<MorphedPage, AlpacaError> {
+
+    let content = match encoding {
+        Encoding::Identity => html.as_bytes().to_vec(),
+        _                  => compression::compress( html.as_bytes(), encoding )?,
+    };
+
+    Ok( MorphedPage {
+        target_size : content.len(),
+        objects     : Vec::new(),
+        overhead    : Overhead { original: content.len(), morphed: content.len() },
+        encoding,
+        fallback    : Some( FallbackReport { limit, fallback: Fallback::Passthrough } ),
+        content,
+    })
+}
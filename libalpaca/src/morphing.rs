@@ -1,24 +1,48 @@
 //! Contains main morphing routines.
-use dom;
 use pad;
-use parse;
+use profile;
+use registry;
+use size_model;
+use token;
 
+use compression::Encoding;
 use deterministic::*;
 use dom::{ Map, Object, ObjectKind };
+use error::{ AlpacaError, ALPACA_OK, ALPACA_PANIC };
+use fake_refs::{ Carrier, FakeRefs };
+use limits::Limits;
 use inlining::make_objects_inlined;
 use kuchiki::NodeRef;
-use pad::{ get_html_padding, get_object_padding };
-
-use distribution::{ sample_ge     ,
-                    sample_ge_many,
-                    sample_pair_ge,
-                    Dist            };
-
-use utils::{ keep_local_objects ,
-             document_to_c      ,
-             content_to_c       ,
-             c_string_to_str    ,
-             insert_objects_refs };
+use rand::RngCore;
+use profile::PageProfiles;
+use resolver::{ MapResolver, ResourceResolver };
+use rng::KeyedConfig;
+use token::TokenKey;
+use std::sync::Arc;
+use std::time::Duration;
+
+use distribution::{ dimensions     ,
+                    sample_between ,
+                    sample_ge      ,
+                    sample_ge_many ,
+                    sample_joint_ge,
+                    sample_pair_ge ,
+                    Dist             };
+
+use morpher::{ DeterministicConfig ,
+               DeterministicScheme ,
+               MorphConfig         ,
+               MorphMode           ,
+               MorphedPage         ,
+               Morpher             ,
+               OverheadCap         ,
+               PerKind             ,
+               ProbabilisticConfig ,
+               TotalSizeSplit        };
+
+use utils::{ content_to_c   ,
+             c_string_to_str,
+             ffi_guard        };
 
 
 #[repr(C)]
@@ -39,20 +63,243 @@ pub struct MorphInfo {
     dist_html_size       : *const u8,
     dist_obj_num         : *const u8,
     dist_obj_size        : *const u8,
+    dist_total_size      : *const u8,
+    dist_page_profile    : *const u8,
     probabilistic        : usize    , // boolean
     use_total_obj_size   : usize    ,
+    total_size_split     : usize    , // 0: proportional, 1: conditional
+    dist_obj_size_kind   : [*const u8; 4], // css, js, img, font; empty to use dist_obj_size
+    dist_obj_num_kind    : [*const u8; 4], // css, js, img, font; empty to count them with the rest
 
     // for deterministic
     max_obj_size         : usize    ,
     obj_num              : usize    ,
     obj_size             : usize    ,
+    deter_scheme         : usize    , // 0: multiple, 1: padme
+    obj_size_kind        : [usize; 4], // css, js, img, font; 0 to use obj_size
+    max_obj_size_kind    : [usize; 4], // css, js, img, font; 0 to use max_obj_size
+    obj_num_kind         : [usize; 4], // css, js, img, font; 0 to count them with the rest
 
     // for object inlining
     obj_inlining_enabled : usize    ,
     force_css_inlining   : usize    ,
     css_as_inline_object : usize    ,
+
+    // for keyed morphing
+    secret_file          : *const u8,
+    epoch_length         : usize    ,
+
+    // for the overhead caps, 0 for no cap
+    max_page_overhead_pct   : usize ,
+    max_page_overhead_bytes : usize ,
+    max_obj_overhead_pct    : usize ,
+    max_obj_overhead_bytes  : usize ,
+
+    // the elements referencing the fake objects, a mask of Carrier bits; none for all
+    fake_carriers           : usize ,
+
+    // the seconds the tokens of a morphed html are valid for
+    token_ttl               : usize ,
+
+    // the limits on a page, 0 for no limit, and the fallback of the pages
+    // beyond them, 0: passthrough, 1: deterministic
+    max_html_size           : usize ,
+    max_objects             : usize ,
+    max_obj_padding         : usize ,
+    max_page_padding        : usize ,
+    time_budget_ms          : usize ,
+    limit_fallback          : usize ,
+
+    // reported after morphing an html
+    orig_page_size          : usize ,
+    morphed_page_size       : usize ,
+    fallback                : usize , // 0 if morphed, else the Fallback code
+    fallback_limit          : usize , // the code of the Limit exceeded
+
+    // the encoding negotiated for the html, 0: identity, 1: gzip, 2: br
+    content_encoding        : usize ,
+}
+
+impl MorphInfo {
+
+    // Borrows the struct passed by the nginx module.
+    pub fn from_ptr<'a>(pinfo: *mut MorphInfo) -> Result<&'a mut MorphInfo, AlpacaError> {
+        unsafe { pinfo.as_mut() }.ok_or_else( || AlpacaError::Parse( String::from("null MorphInfo") ) )
+    }
+
+    // The uri of the request, for logging.
+    pub fn uri(&self) -> &str {
+        c_string_to_str(self.uri).unwrap_or("<unknown uri>")
+    }
+
+    // The encoding the html is sent with.
+    pub fn encoding(&self) -> Result<Encoding, AlpacaError> {
+        Encoding::from_code(self.content_encoding)
+    }
+
+    // Builds the morphing configuration from the directives copied by the
+    // nginx module. Only valid for html requests, where all fields are set.
+    fn config(&self) -> Result<MorphConfig, AlpacaError> {
+
+        let mode = if self.probabilistic != 0 {
+            MorphMode::Probabilistic( Box::new( ProbabilisticConfig {
+                dist_html_size     : registry::distribution( c_string_to_str(self.dist_html_size )? )?,
+                dist_obj_num       : registry::distribution( c_string_to_str(self.dist_obj_num   )? )?,
+                dist_obj_size      : registry::distribution( c_string_to_str(self.dist_obj_size  )? )?,
+                use_total_obj_size : self.use_total_obj_size != 0                                      ,
+                dist_obj_size_kind : per_kind( &self.dist_obj_size_kind, kind_distribution )?,
+                dist_obj_num_kind  : per_kind( &self.dist_obj_num_kind , kind_distribution )?,
+                dist_total_size    : registry::distribution( c_string_to_str(self.dist_total_size)? )?,
+                total_size_split   : match self.total_size_split {
+                    0 => TotalSizeSplit::Proportional,
+                    1 => TotalSizeSplit::Conditional ,
+                    n => return Err( AlpacaError::Config( format!("invalid total size split {}", n) ) ),
+                },
+                page_profiles      : match c_string_to_str(self.dist_page_profile)? {
+                    ""   => None                           ,
+                    path => Some( registry::page_profiles(path)? ),
+                },
+            }))
+        } else {
+            MorphMode::Deterministic( Box::new( self.deterministic()? ) )
+        };
+
+        let mut config = MorphConfig::with_mode(mode);
+
+        config.obj_inlining_enabled = self.obj_inlining_enabled != 0;
+        config.force_css_inlining   = self.force_css_inlining   != 0;
+        config.css_as_inline_object = self.css_as_inline_object != 0;
+        config.keyed                = self.keyed()?;
+        config.token_key            = self.token_key()?;
+        config.fake_carriers        = Carrier::from_mask(self.fake_carriers)?;
+
+        config.with_overhead_cap( overhead_cap( self.max_page_overhead_pct, self.max_page_overhead_bytes ),
+                                  overhead_cap( self.max_obj_overhead_pct , self.max_obj_overhead_bytes  ) )?
+              .with_token_ttl( self.token_ttl as u64 )?
+              .with_limits( self.limits()? )
+    }
+
+    // The parameters of the deterministic version, which are also those of
+    // the fallback of a probabilistic location.
+    fn deterministic(&self) -> Result<DeterministicConfig, AlpacaError> {
+
+        Ok( DeterministicConfig {
+            scheme       : match self.deter_scheme {
+                0 => DeterministicScheme::Multiple,
+                1 => DeterministicScheme::Padme   ,
+                n => return Err( AlpacaError::Config( format!("invalid deterministic scheme {}", n) ) ),
+            },
+            obj_num      : self.obj_num     ,
+            obj_size     : self.obj_size    ,
+            max_obj_size : self.max_obj_size,
+
+            obj_size_kind     : per_kind( &self.obj_size_kind    , |&n| Ok( Some(n).filter( |&n| n > 0 ) ) )?,
+            max_obj_size_kind : per_kind( &self.max_obj_size_kind, |&n| Ok( Some(n).filter( |&n| n > 0 ) ) )?,
+            obj_num_kind      : per_kind( &self.obj_num_kind     , |&n| Ok( Some(n).filter( |&n| n > 0 ) ) )?,
+        })
+    }
+
+    // The limits copied by the nginx module. Objects only have their
+    // padding limited, the other fields being unset for them.
+    pub fn limits(&self) -> Result<Limits, AlpacaError> {
+
+        let limit = |n: usize| Some(n).filter( |&n| n > 0 );
+
+        Ok( Limits {
+            max_html_size    : limit(self.max_html_size)   ,
+            max_objects      : limit(self.max_objects)     ,
+            max_obj_padding  : limit(self.max_obj_padding) ,
+            max_page_padding : limit(self.max_page_padding),
+            time_budget      : limit(self.time_budget_ms).map( |ms| Duration::from_millis(ms as u64) ),
+
+            deterministic_fallback : match self.limit_fallback {
+                0 => None,
+                1 => Some( Box::new( self.deterministic()? ) ),
+                n => return Err( AlpacaError::Config( format!("invalid limit fallback {}", n) ) ),
+            },
+        })
+    }
+
+    // The configuration of object requests: objects are padded to the size
+    // in their query, so only the key of the location and the limit on
+    // their padding matter.
+    fn object_config(&self) -> Result<MorphConfig, AlpacaError> {
+
+        let limits = Limits { max_obj_padding: Some(self.max_obj_padding).filter( |&n| n > 0 ), ..Limits::default() };
+
+        Ok( MorphConfig { keyed: self.keyed()?, token_key: self.token_key()?, limits, ..MorphConfig::default() } )
+    }
+
+    // The secret key for keyed morphing, if a secret file is given.
+    fn keyed(&self) -> Result<Option<KeyedConfig>, AlpacaError> {
+
+        if self.secret_file.is_null() {
+            return Ok(None);
+        }
+
+        // Read once, when the configuration is loaded
+        match c_string_to_str(self.secret_file)? {
+            ""   => Ok(None),
+            path => Ok( Some( KeyedConfig::new( registry::secret(path)?.to_vec(), self.epoch_length as u64 )? ) ),
+        }
+    }
+
+    // The key of the padding tokens, derived once from the secret file if
+    // given, or else the key of the process.
+    fn token_key(&self) -> Result<Arc<TokenKey>, AlpacaError> {
+
+        if self.secret_file.is_null() {
+            return Ok( TokenKey::process() );
+        }
+
+        match c_string_to_str(self.secret_file)? {
+            ""   => Ok( TokenKey::process() ),
+            path => registry::token_key(path),
+        }
+    }
+
+    // Resolves objects from the subrequests' map, falling back to the
+    // files under the document root for the ones it does not contain.
+    pub fn resolver(&self, req_mapper: Map) -> MapResolver {
+
+        match c_string_to_str(self.root) {
+            Ok (root) if !self.root.is_null() && !root.is_empty() => MapResolver::with_root(req_mapper, root),
+            _                                                    => MapResolver::new(req_mapper)           ,
+        }
+    }
+}
+
+
+// Builds per-kind settings from the arrays of MorphInfo, which are in the
+// order css, js, img, font.
+fn per_kind<V, T, F>(values: &[V; 4], setting: F) -> Result<PerKind<T>, AlpacaError>
+    where F: Fn(&V) -> Result<Option<T>, AlpacaError>
+{
+    Ok( PerKind {
+        css  : setting(&values[0])?,
+        js   : setting(&values[1])?,
+        img  : setting(&values[2])?,
+        font : setting(&values[3])?,
+    })
 }
 
+// The overhead cap given in percent of the original size and in bytes,
+// where 0 means no limit.
+fn overhead_cap(pct: usize, bytes: usize) -> OverheadCap {
+    OverheadCap {
+        max_ratio : Some(pct).filter( |&pct| pct > 0 ).map( |pct| pct as f64 / 100.0 ),
+        max_bytes : Some(bytes).filter( |&bytes| bytes > 0 ),
+    }
+}
+
+// The distribution given for a kind, if any.
+fn kind_distribution(spec: &*const u8) -> Result<Option< Arc<Dist> >, AlpacaError> {
+
+    match c_string_to_str(*spec)? {
+        ""   => Ok(None),
+        spec => Ok( Some( registry::distribution(spec)? ) ),
+    }
+}
 
 #[no_mangle]
 // It samples a new page using probabilistic/deterministic morphing,
@@ -61,192 +308,483 @@ pub extern "C" fn morph_html(pinfo: *mut MorphInfo, req_mapper: Map) -> u8 {
 
     std::env::set_var("RUST_BACKTRACE", "full");
 
-    let info = unsafe { &mut *pinfo };
-    let uri  = c_string_to_str(info.uri).unwrap();
+    ffi_guard( "morph_html", ALPACA_PANIC, || {
 
-    let html = match c_string_to_str(info.content) {
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return e.code();
+            }
+        };
+
+        match morph_html_info(info, req_mapper) {
 
-        Ok (s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot read html content of {}: {}\n", uri, e);
-            return 0; // return NULL pointer if html cannot be converted to a string
+            Ok (page) => {
+                info.orig_page_size    = page.overhead.original;
+                info.morphed_page_size = page.overhead.morphed;
+                info.fallback          = page.fallback.map_or( 0, |report| report.fallback.code() );
+                info.fallback_limit    = page.fallback.map_or( 0, |report| report.limit.code() );
+
+                content_to_c(page.content, info)
+            },
+            Err(e)    => {
+                eprintln!("libalpaca: cannot morph {}: {}", info.uri(), e);
+                e.code() // the module serves the original content
+            }
         }
-    };
+    })
+}
 
-    let document = parse::parse_html(html);
+fn morph_html_info(info: &MorphInfo, req_mapper: Map) -> Result<MorphedPage, AlpacaError> {
 
-    // Vector of objects found in the html
-    let mut objects = parse::parse_objects(&document, req_mapper);
+    let uri      = c_string_to_str(info.uri)?;
+    let html     = c_string_to_str(info.content)?;
+    let config   = info.config()?;
+    let resolver = info.resolver(req_mapper);
 
-    // let objects: Vec<_> = objects.into_iter().unique().collect();
+    Morpher::new(config).morph_html_encoded(uri, html, info.encoding()?, &resolver)
+}
+
+// Returns the padded object, whose content (if any) is given in info.
+#[no_mangle]
+pub extern "C" fn morph_object(pinfo: *mut MorphInfo) -> u8 {
 
-    keep_local_objects(&mut objects);
+    ffi_guard( "morph_object", ALPACA_PANIC, || {
 
-    // Number of original objects
-    let mut orig_n = objects.len();
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return e.code();
+            }
+        };
 
-    let target_size = match if info.probabilistic != 0 {
-        morph_probabilistic( &document, &mut objects, &info, &mut orig_n, req_mapper )
+        match morph_object_info(info) {
 
-    } else {
-        morph_deterministic( &document, &mut objects, &info, &mut orig_n, req_mapper)
-    } {
-        Ok (s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot morph: {}\n", e);
-            return document_to_c(&document, info);
+            Ok (object) => content_to_c(object, info),
+            Err(e)      => {
+                eprintln!("libalpaca: morph_object: {}", e);
+                e.code() // the module serves the object unpadded
+            }
         }
+    })
+}
+
+fn morph_object_info(info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+
+    let content_type = c_string_to_str(info.content_type)?;
+    let query        = c_string_to_str(info.query)?;
+
+    let config = info.object_config()?;
+
+    let content = if info.content.is_null() || info.size == 0 {
+        &[]
+    } else {
+        unsafe { std::slice::from_raw_parts(info.content, info.size) }
     };
 
-    // Insert refs and add padding
-    match insert_objects_refs(&document, &objects, orig_n) {
+    Morpher::new(config).pad_object(info.uri(), content_type, content, query)
+}
+
+// Returns only the padding of an object of info.size bytes, for the objects
+// that the module streams and sends their padding after: all but images,
+// which are padded inside their format.
+#[no_mangle]
+pub extern "C" fn object_padding(pinfo: *mut MorphInfo) -> u8 {
+
+    ffi_guard( "object_padding", ALPACA_PANIC, || {
+
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return e.code();
+            }
+        };
+
+        match object_padding_info(info) {
 
-        Ok (_) => {}
-        Err(e) => {
-            eprint!("libalpaca: insert_objects_refs failed: {}\n", e);
-            return document_to_c(&document, info);
+            Ok (padding) => content_to_c(padding, info),
+            Err(e)       => {
+                eprintln!("libalpaca: object_padding: {}", e);
+                e.code() // the module serves the object unpadded
+            }
         }
-    }
+    })
+}
 
-    let mut content = dom::serialize_html(&document);
+fn object_padding_info(info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
 
-    // Pad the html to the target size.
-    get_html_padding(&mut content, target_size);
+    let content_type = c_string_to_str(info.content_type)?;
+    let query        = c_string_to_str(info.query)?;
 
-    return content_to_c(content, info);
+    Morpher::new( info.object_config()? ).morph_object(info.uri(), content_type, info.size, query)
 }
 
-// Returns the object's padding.
+// Returns a fake object generated with the size in its query.
 #[no_mangle]
-pub extern "C" fn morph_object(pinfo: *mut MorphInfo) -> u8 {
+pub extern "C" fn generate_fake_object(pinfo: *mut MorphInfo) -> u8 {
+
+    ffi_guard( "generate_fake_object", ALPACA_PANIC, || {
+
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return e.code();
+            }
+        };
+
+        match fake_object_info(info) {
+
+            Ok (object) => content_to_c(object, info),
+            Err(e)      => {
+                eprintln!("libalpaca: generate_fake_object: {}", e);
+                e.code()
+            }
+        }
+    })
+}
+
+fn fake_object_info(info: &MorphInfo) -> Result<Vec<u8>, AlpacaError> {
+
+    let content_type = c_string_to_str(info.content_type)?;
+    let query        = c_string_to_str(info.query)?;
+
+    let config = info.object_config()?;
+
+    Morpher::new(config).fake_object(info.uri(), content_type, query)
+}
+
+// Whether the query of the request has a valid padding token of its uri,
+// which tells the nginx module that a missing object is a fake one.
+#[no_mangle]
+pub extern "C" fn check_padding_token(pinfo: *mut MorphInfo) -> u8 {
+
+    ffi_guard( "check_padding_token", ALPACA_PANIC, || {
+
+        match check_padding_token_info(pinfo) {
+            Ok (()) => ALPACA_OK,
+            Err(e)  => e.code() ,
+        }
+    })
+}
+
+fn check_padding_token_info(pinfo: *mut MorphInfo) -> Result<(), AlpacaError> {
+
+    let info  = MorphInfo::from_ptr(pinfo)?;
+    let uri   = c_string_to_str(info.uri)?;
+    let query = c_string_to_str(info.query)?;
+
+    match info.token_key()?.target_size( uri, query, token::now() ) {
+        Some(_) => Ok(()),
+        None    => Err( AlpacaError::Parse( format!("no valid padding token for {}", uri) ) ),
+    }
+}
+
+// Counts the CSS objects that should not be inlined, since they are
+// not considered objects for inlining purposes.
+fn count_css_objects(objects: &[Object], config: &MorphConfig) -> usize {
+
+    if config.obj_inlining_enabled && !config.css_as_inline_object && !config.force_css_inlining {
+        objects.iter().filter( |obj| obj.kind == ObjectKind::CSS ).count()
+    } else {
+        0
+    }
+}
+
+// The kinds of objects which can be counted on their own, in the order of
+// the values of a joint dist_obj_num.
+const COUNTED_KINDS: [ObjectKind; 4] = [ ObjectKind::CSS, ObjectKind::JS, ObjectKind::IMG, ObjectKind::Font ];
+
+// The number of objects counted in the bucket of the given kind.
+fn count_kind(objects: &[Object], kind: ObjectKind) -> usize {
+    objects.iter().filter( |obj| obj.kind.fake() == kind.fake() ).count()
+}
+
+// Samples the number of objects of the kinds counted on their own, either
+// jointly from dist_obj_num or from each kind's distribution. Returns the
+// kinds of the fake objects to add, and the (fake) kinds counted.
+fn sample_kind_counts( objects : &[Object]           ,
+                       params  : &ProbabilisticConfig,
+                       rng     : &mut dyn RngCore      ) -> (Vec<ObjectKind>, Vec<ObjectKind>)
+{
+    let mut fakes   = Vec::new();
+    let mut counted = Vec::new();
 
-    let info = unsafe { &mut *pinfo };
+    if dimensions(&params.dist_obj_num) == COUNTED_KINDS.len() {
 
-    let content_type = c_string_to_str(info.content_type).unwrap();
-    let query        = c_string_to_str(info.query)       .unwrap();
+        let counts: Vec<usize> = COUNTED_KINDS.iter().map( |&kind| count_kind(objects, kind) ).collect();
 
-    let kind        = parse::parse_object_kind(content_type);
-    let target_size = parse::parse_target_size(query);
+        let targets = match sample_joint_ge(&params.dist_obj_num, &counts, rng) {
 
-    if (target_size == 0) || (target_size <= info.size) {
-        // Target size has to be greater than current size.
-        print!( "alpaca: morph_object: target_size ({}) cannot match current size ({})\n", target_size, info.size );
-        return content_to_c(Vec::new(), info);
+            Ok (targets) => targets,
+            Err(e)       => {
+                eprintln!( "libalpaca: could not sample object numbers ({}), leaving unchanged ({:?})", e, counts );
+                counts.clone()
+            }
+        };
+
+        for ((&kind, count), target) in COUNTED_KINDS.iter().zip(counts).zip(targets) {
+            fakes.extend( std::iter::repeat_n( kind.fake(), target - count ) );
+            counted.push( kind.fake() );
+        }
+
+        return (fakes, counted);
     }
 
-    let padding = get_object_padding(kind, info.size, target_size); // Get the padding for the object.
+    for &kind in COUNTED_KINDS.iter() {
+
+        let dist = match params.dist_obj_num_kind.get(kind) {
+            Some(dist) => dist    ,
+            None       => continue,
+        };
+
+        let count  = count_kind(objects, kind);
+        let target = match sample_ge(dist, count, rng) {
+
+            Ok (target) => target,
+            Err(e)      => {
+                eprintln!( "libalpaca: could not sample the number of {:?} objects ({}), leaving unchanged ({})", kind, e, count );
+                count
+            }
+        };
+
+        fakes.extend( std::iter::repeat_n( kind.fake(), target - count ) );
+        counted.push( kind.fake() );
+    }
 
-    return content_to_c(padding, info);
+    (fakes, counted)
 }
 
-fn morph_probabilistic( document   : &NodeRef        ,
-                        objects    : &mut Vec<Object>,
-                        info       : &MorphInfo      ,
-                        new_orig_n : &mut usize      ,
-                        req_mapper : Map              ) -> Result<usize, String>
+#[allow(clippy::too_many_arguments)]
+pub fn morph_probabilistic( document   : &NodeRef                 ,
+                            objects    : &mut Vec<Object>         ,
+                            params     : &ProbabilisticConfig     ,
+                            config     : &MorphConfig             ,
+                            encoding   : Encoding                 ,
+                            fake_refs  : &FakeRefs                ,
+                            new_orig_n : &mut usize               ,
+                            resolver   : &dyn ResourceResolver    ,
+                            rng        : &mut dyn RngCore           ) -> Result<usize, AlpacaError>
 {
-    let dist_html_size = Dist::from( c_string_to_str( info.dist_html_size )? )?;
-    let dist_obj_num   = Dist::from( c_string_to_str( info.dist_obj_num   )? )?;
-    let dist_obj_size  = Dist::from( c_string_to_str( info.dist_obj_size  )? )?;
+    let dist_html_size = &params.dist_html_size;
+    let dist_obj_num   = &params.dist_obj_num;
+    let dist_obj_size  = &params.dist_obj_size;
+
+    // Morph to a whole page of the corpus
+    if let Some(ref profiles) = params.page_profiles {
+        return morph_page_profile(document, objects, profiles, encoding, fake_refs, rng);
+    }
 
     // We'll have at least as many objects as the original ones
     let initial_obj_num = objects.len();
-    // let s = info.obj_inlining_enabled == true ? 1 : 0;
 
-    let lower_bound_obj_num = match info.obj_inlining_enabled {
-        1 => 0,
-        0 => initial_obj_num,
-        _ => panic!("Object Inlining Directive can be only on/off.")
-    };
+    // Sample the number of objects of the kinds counted on their own. Objects
+    // are not inlined then, since that would change the other kinds' counts.
+    let (mut fake_kinds, counted) = sample_kind_counts(objects, params, rng);
 
-    // Sample target number of objects (count)
-    let mut target_obj_num = match sample_ge(&dist_obj_num, lower_bound_obj_num) {
+    let rest_obj_num = objects.iter().filter( |obj| !counted.contains( &obj.kind.fake() ) ).count();
+    let inlining     = config.obj_inlining_enabled && counted.is_empty();
 
-        Ok (c) => c,
-        Err(e) => {
-            eprint!(
-                "libalpaca: could not sample object number ({}), leaving unchanged ({})\n",
-                e, initial_obj_num
-            );
-            initial_obj_num
+    let lower_bound_obj_num = if inlining { 0 } else { rest_obj_num };
+
+    // Sample target number of the rest of the objects (count)
+    let mut target_obj_num = if counted.len() == COUNTED_KINDS.len() {
+        rest_obj_num
+    } else {
+        match sample_ge(dist_obj_num, lower_bound_obj_num, rng) {
+
+            Ok (c) => c,
+            Err(e) => {
+                eprintln!(
+                    "libalpaca: could not sample object number ({}), leaving unchanged ({})",
+                    e, rest_obj_num
+                );
+                rest_obj_num
+            }
         }
     };
 
-    let content = dom::serialize_html(&document);
-
-    let final_obj_num: usize;
-    let min_html_size: usize;
-
     // This if defines whether inlining is activated and whether the parameter
     // for objects returned is greater or smaller than the actual object number
-    if target_obj_num < initial_obj_num && info.obj_inlining_enabled == 0 {
-        target_obj_num = initial_obj_num;
+    if target_obj_num < rest_obj_num && !inlining {
+        target_obj_num = rest_obj_num;
     }
 
-    if target_obj_num < initial_obj_num && info.obj_inlining_enabled != 0 {
-
-        final_obj_num = target_obj_num;
-        min_html_size = content.len()
-                        + 7                     // for the comment characters
-                        + 23 * initial_obj_num; // for ?alpaca-padding=...
-    } else {
+    let inline_objects = target_obj_num < initial_obj_num && inlining;
 
-        final_obj_num = target_obj_num - initial_obj_num;
-        min_html_size = content.len()
-                        + 7                     // for the comment characters
-                        + 23 * initial_obj_num  // for ?alpaca-padding=...
-                        + 94 * (final_obj_num); // for the fake images
+    // The rest of the fake objects are images
+    if !inline_objects {
+        fake_kinds.extend( std::iter::repeat_n( ObjectKind::FakeIMG, target_obj_num - rest_obj_num ) );
     }
 
     let target_html_size;
 
-    let mut count_css_objects: usize = 0;
+    let count_css_objects = count_css_objects(objects, config);
+    let inlined_obj_num   = initial_obj_num.saturating_sub(target_obj_num).saturating_sub(count_css_objects);
 
-    if info.obj_inlining_enabled != 0 && info.css_as_inline_object == 0 && info.force_css_inlining == 0 {
+    // Sample the total size of the page, and split it between the html and the objects
+    if !params.dist_total_size.name.is_empty() {
 
-        for object in objects.iter() {
-            if object.kind == dom::ObjectKind::CSS {
-                count_css_objects += 1;
+        if inline_objects {
+
+            make_objects_inlined( objects, resolver, inlined_obj_num, config.css_as_inline_object )?;
+
+            *new_orig_n = objects.len();
+
+        } else {
+
+            for (i, &kind) in fake_kinds.iter().enumerate() {
+                objects.push( fake_refs.object(kind, 0, i) );
             }
         }
+
+        return split_total_size(document, objects, params, *new_orig_n, encoding, rng);
     }
 
     // Find object sizes
-    if info.use_total_obj_size == 0 {
+    if !params.use_total_obj_size {
+
+        // Sample each object size from dist_obj_size (or its kind's distribution).
+        let fake_sizes = sample_object_sizes( objects, params, if inline_objects { &[] } else { &fake_kinds }, &config.obj_overhead, rng )?;
+
+        if inline_objects {
 
-        // Sample each object size from dist_obj_size.
-        target_html_size = sample_ge( &dist_html_size, min_html_size )?;
+            // Insert refs and add padding
+            make_objects_inlined( objects, resolver, inlined_obj_num, config.css_as_inline_object )?;
+
+            *new_orig_n = objects.len();
+
+        } else {
+
+            // Create padding objects
+            for (i, (&kind, size)) in fake_kinds.iter().zip(fake_sizes).enumerate() {
+                objects.push( fake_refs.object(kind, size, i) );
+            }
+        }
+
+        // The html has to fit the references to the objects' target sizes
+        let html_size = size_model::html_size(document, objects, *new_orig_n);
+
+        target_html_size = sample_ge( dist_html_size, size_model::encoded_html_size(document, html_size, encoding)?, rng )?;
+
+    } else {
+        // Sample the __total__ object size from dist_obj_size.
+
+        if inline_objects {
+
+            // Insert refs and add padding
+            make_objects_inlined( objects, resolver, inlined_obj_num, config.css_as_inline_object )?;
+
+            *new_orig_n = objects.len();
+
+        } else {
+
+            // Create padding objects, using the smallest of the sizes
+            for (i, &kind) in fake_kinds.iter().enumerate() {
+                objects.push( fake_refs.object(kind, 0, i) );
+            }
+        }
+
+        // min size of the html and of all objects
+        let min_html_size = size_model::min_html_size(document, objects, *new_orig_n);
+        let min_html_size = size_model::encoded_html_size(document, min_html_size, encoding)?;
+        let min_obj_size  = objects.iter()
+                                   .map( |obj| obj.size + pad::min_obj_padding(obj) )
+                                   .sum();
+        let target_obj_size;
+
+        // Sample html/obj sizes, either together or separately
+        if dist_obj_size.name == "Joint" {
+
+            let (a, b) = sample_pair_ge( dist_html_size, (min_html_size, min_obj_size), rng )?;
+
+            target_html_size = a;
+            target_obj_size  = b;
+
+        } else {
+            target_html_size = sample_ge( dist_html_size, min_html_size, rng )?;
+            target_obj_size  = sample_ge( dist_obj_size , min_obj_size , rng )?;
+        }
+
+        // Split all extra size equally among all objects (the remaining
+        // ones, if some could not be inlined)
+        let mut to_split = target_obj_size.saturating_sub(min_obj_size);
+        let count        = objects.len();
+
+        for (pos, obj) in objects.iter_mut().enumerate() {
+
+            let pad = to_split / (count - pos);
 
-        // To more closely match the actual obj_size distribution, we'll sample values for all objects,
-        // And then we'll use the largest to pad existing objects and the smallest for padding objects.
-        let mut target_obj_sizes: Vec<usize>;
+            obj.target_size = Some( obj.size + pad::min_obj_padding(obj) + pad );
+            to_split -= pad;
+        }
+    }
+
+    Ok(target_html_size)
+}
+
+// Sets the target size of each object, and returns the sizes of the fake
+// objects of the given kinds. To more closely match the actual obj_size
+// distribution, we sample values for all objects sharing a distribution (the
+// objects of a kind, if it has its own), and then use the largest to pad
+// existing objects and the smallest for padding objects. Sizes beyond the
+// overhead cap of an object are drawn again within it.
+fn sample_object_sizes( objects : &mut [Object]       ,
+                        params  : &ProbabilisticConfig,
+                        fakes   : &[ObjectKind]       ,
+                        cap     : &OverheadCap        ,
+                        rng     : &mut dyn RngCore      ) -> Result<Vec<usize>, AlpacaError>
+{
+    let mut fake_sizes = vec![0; fakes.len()];
+    let mut done       = vec![false; objects.len()];
+    let mut fake_done  = vec![false; fakes.len()];
 
-        let samples_num = match target_obj_num < initial_obj_num && info.obj_inlining_enabled != 0 {
-            true  => initial_obj_num,
-            false => target_obj_num
+    for i in 0..objects.len() + fakes.len() {
+
+        // Fake objects are sampled together with the first object sharing
+        // their distribution, or on their own at the end
+        let kind = if i < objects.len() {
+            if done[i] { continue; }
+            objects[i].kind
+        } else {
+            if fake_done[i - objects.len()] { continue; }
+            fakes[i - objects.len()]
         };
 
-        target_obj_sizes = sample_ge_many( &dist_obj_size, 1, samples_num )?;
+        let dist                     = params.dist_obj_size(kind);
+        let members: Vec<usize>      = (0..objects.len()).filter( |&j| !done[j] && Arc::ptr_eq( params.dist_obj_size(objects[j].kind), dist ) ).collect();
+        let fake_members: Vec<usize> = (0..fakes.len()).filter( |&j| !fake_done[j] && Arc::ptr_eq( params.dist_obj_size(fakes[j]), dist ) ).collect();
+
+        let mut target_obj_sizes: Vec<usize> = sample_ge_many( dist, 1, members.len() + fake_members.len(), rng )?;
 
         target_obj_sizes.sort_unstable(); // ascending
 
         // Pad existing objects
-        for obj in &mut *objects {
+        for j in members {
 
-            let needed_size = obj.content.len() + pad::min_obj_padding(&obj);
+            let obj         = &mut objects[j];
+            let needed_size = obj.size + pad::min_obj_padding(obj);
+            let limit       = cap.limit(obj.size);
 
-            // Take the largest size, if not enough draw a new one with this specific needed_size
-            obj.target_size = if target_obj_sizes[target_obj_sizes.len() - 1] >= needed_size {
-                Some(target_obj_sizes.pop().unwrap())
+            done[j] = true;
 
-            } else {
+            // Take the largest size within the cap, if not enough draw a new
+            // one between this specific needed_size and the cap
+            let largest = target_obj_sizes.iter().rposition( |&size| size <= limit );
+
+            obj.target_size = match largest {
 
-                match sample_ge(&dist_obj_size, needed_size) {
+                Some(pos) if target_obj_sizes[pos] >= needed_size => Some( target_obj_sizes.remove(pos) ),
+
+                _ => match sample_between(dist, needed_size, limit, rng) {
 
                     Ok (size) => Some(size),
                     Err(e) => {
-                        eprint!(
-                            "libalpaca: warning: no padding was found for {} ({})\n",
+                        eprintln!(
+                            "libalpaca: warning: no padding was found for {} ({})",
                             obj.uri, e
                         );
                         None
@@ -255,149 +793,315 @@ fn morph_probabilistic( document   : &NodeRef        ,
             };
         }
 
+        // The smallest of the sizes left are for the fake objects, which
+        // need room for their comments if they are stylesheets or scripts
+        for (j, size) in fake_members.into_iter().zip(target_obj_sizes) {
 
-        if target_obj_num < initial_obj_num && info.obj_inlining_enabled != 0 {
+            let min_size = std::cmp::max( pad::min_padding(fakes[j]), 1 );
 
-            // Insert refs and add padding
-            make_objects_inlined( objects, req_mapper, initial_obj_num - target_obj_num - count_css_objects, info.css_as_inline_object ).unwrap();
+            fake_done[j]  = true;
+            fake_sizes[j] = if size <= cap.fake_limit() {
+                std::cmp::max(size, min_size)
+            } else {
+                sample_between( dist, min_size, cap.fake_limit(), rng )?
+            };
+        }
+    }
 
-            *new_orig_n = objects.len();
+    Ok(fake_sizes)
+}
 
-        } else {
+// Pads the page and its objects to a profile which dominates it, and adds fake
+// objects for the profile's objects left. Objects are never inlined, since the
+// profile already has at least as many objects as the page.
+fn morph_page_profile( document  : &NodeRef         ,
+                       objects   : &mut Vec<Object> ,
+                       profiles  : &PageProfiles    ,
+                       encoding  : Encoding         ,
+                       fake_refs : &FakeRefs        ,
+                       rng       : &mut dyn RngCore   ) -> Result<usize, AlpacaError>
+{
+    // The html without references, and its lower bound once compressed,
+    // which does not depend on the references
+    let plain_size   = size_model::min_html_size(document, &[], 0);
+    let encoded_size = size_model::encoded_html_size(document, plain_size, encoding)?;
+
+    let min_html_size = |refs_size: usize| match encoding {
+        Encoding::Identity => plain_size + refs_size,
+        _                  => encoded_size          ,
+    };
 
-            // Create padding objects, using the smallest of the sizes
-            for i in 0..final_obj_num {
-                objects.push(Object::fake_image(target_obj_sizes[i]));
-            }
-        }
+    let (profile, slots) = profiles.sample_dominating(&min_html_size, objects, fake_refs, rng)?;
 
-    } else {
-        // Sample the __total__ object size from dist_obj_size.
+    let mut used = vec![false; profile.objects.len()];
 
-        // min size of all objects
-        let min_obj_size = objects.into_iter()
-                                  .map( |obj| obj.content.len() + pad::min_obj_padding(obj) )
-                                  .sum();
-        let target_obj_size;
+    for (obj, slot) in objects.iter_mut().zip(slots) {
+        obj.target_size = Some( profile.objects[slot].0 );
+        used[slot]      = true;
+    }
 
-        // Sample html/obj sizes, either together or separately
-        if dist_obj_size.name == "Joint" {
+    let unused = profile.objects.iter().zip(used).filter( |&(_, used)| !used );
+
+    for (i, (&(size, kind), _)) in unused.enumerate() {
+        objects.push( fake_refs.object( profile::fake_kind(kind, size), size, i ) );
+    }
+
+    Ok(profile.html_size)
+}
+
+// Samples the total size of the page, at least the minimum size of the html
+// and its objects, and splits the extra size among them. The objects' target
+// sizes are set, and the html's one is returned.
+fn split_total_size( document : &NodeRef            ,
+                     objects  : &mut [Object]       ,
+                     params   : &ProbabilisticConfig,
+                     orig_n   : usize               ,
+                     encoding : Encoding            ,
+                     rng      : &mut dyn RngCore      ) -> Result<usize, AlpacaError>
+{
+    // The target sizes of the objects are not known yet, so that the page
+    // is morphed again if their references do not fit in the html
+    let min_html_size = size_model::min_html_size(document, objects, orig_n);
+    let min_html_size = size_model::encoded_html_size(document, min_html_size, encoding)?;
+
+    // Fake objects need at least one byte
+    let min_obj_sizes: Vec<usize> = objects.iter()
+                                           .map( |obj| std::cmp::max( obj.size + pad::min_obj_padding(obj), 1 ) )
+                                           .collect();
+    let min_obj_size: usize       = min_obj_sizes.iter().sum();
+
+    let target_total_size = sample_ge( &params.dist_total_size, min_html_size + min_obj_size, rng )?;
+    let extra             = target_total_size - min_html_size - min_obj_size;
+
+    let proportional = |extra: usize| split_proportionally( extra, &[min_html_size, min_obj_size] )[0];
+
+    let (html_extra, obj_weights) = match params.total_size_split {
 
-            match sample_pair_ge( &dist_html_size, (min_html_size, min_obj_size) )? {
-                (a, b) => {
-                    target_html_size = a;
-                    target_obj_size = b;
+        TotalSizeSplit::Proportional => ( proportional(extra), min_obj_sizes.clone() ),
+
+        TotalSizeSplit::Conditional => {
+
+            // The html size, given that the objects fit in the rest
+            let html_extra = match sample_between( &params.dist_html_size, min_html_size, min_html_size + extra, rng ) {
+
+                Ok (size) => size - min_html_size,
+                Err(e)    => {
+                    eprintln!( "libalpaca: could not sample html size up to {} ({}), splitting proportionally", min_html_size + extra, e );
+                    proportional(extra)
                 }
+            };
+
+            // The objects share the rest according to the sizes sampled for them
+            let mut weights = Vec::with_capacity( objects.len() );
+
+            for (obj, &min_size) in objects.iter().zip(&min_obj_sizes) {
+
+                let size = match sample_ge(params.dist_obj_size(obj.kind), min_size, rng) {
+                    Ok (size) => size,
+                    Err(e)    => {
+                        eprintln!( "libalpaca: warning: no size was sampled for {} ({})", obj.uri, e );
+                        min_size
+                    }
+                };
+
+                weights.push(size - min_size);
             }
 
-        } else {
-            target_html_size = sample_ge( &dist_html_size, min_html_size )?;
-            target_obj_size  = sample_ge( &dist_obj_size, min_obj_size   )?;
-        }
+            if weights.iter().all( |&w| w == 0 ) {
+                weights = min_obj_sizes.clone();
+            }
 
-        if target_obj_num < initial_obj_num && info.obj_inlining_enabled != 0 {
+            (html_extra, weights)
+        },
+    };
 
-            // Insert refs and add padding
-            make_objects_inlined( objects, req_mapper, initial_obj_num - target_obj_num - count_css_objects, info.css_as_inline_object ).unwrap();
+    let obj_extras = split_proportionally( extra - html_extra, &obj_weights );
 
-            *new_orig_n = objects.len();
+    for ((obj, min_size), obj_extra) in objects.iter_mut().zip(min_obj_sizes).zip(obj_extras) {
+        obj.target_size = Some(min_size + obj_extra);
+    }
 
-        } else {
+    Ok(min_html_size + html_extra)
+}
 
-            // Create padding objects, using the smallest of the sizes
-            for _ in 0..final_obj_num {
-                objects.push( Object::fake_image(0) );
-            }
-        }
+// Splits "total" into parts proportional to the given weights. The parts
+// sum up to "total", unless all weights are 0.
+fn split_proportionally(total: usize, weights: &[usize]) -> Vec<usize> {
 
-        // Split all extra size equally among all objects
-        let mut to_split = target_obj_size - min_obj_size;
+    let weight_sum: u128 = weights.iter().map( |&w| w as u128 ).sum();
 
-        for (pos, obj) in objects.iter_mut().enumerate() {
+    if weight_sum == 0 {
+        return vec![0; weights.len()];
+    }
 
-            let pad = to_split / (target_obj_num - pos);
+    let mut parts: Vec<usize> = weights.iter()
+                                       .map( |&w| (total as u128 * w as u128 / weight_sum) as usize )
+                                       .collect();
 
-            obj.target_size = Some( obj.content.len() + pad::min_obj_padding(obj) + pad );
-            to_split -= pad;
-        }
+    // Give the rounding remainder to the heaviest part
+    let remainder = total - parts.iter().sum::<usize>();
+
+    if let Some(heaviest) = (0..weights.len()).max_by_key( |&i| weights[i] ) {
+        parts[heaviest] += remainder;
     }
 
-    Ok(target_html_size)
+    parts
+}
+
+// The max_obj_size of the fake objects of a kind, lowered to fit in the
+// overhead cap of an object (to a multiple of obj_size, unless with Padmé).
+fn capped_max_obj_size(params: &DeterministicConfig, kind: ObjectKind, cap: &OverheadCap) -> Result<usize, AlpacaError> {
+
+    let max_obj_size = std::cmp::min( params.max_obj_size(kind), cap.fake_limit() );
+    let obj_size     = params.obj_size(kind);
+
+    let capped = match params.scheme {
+        DeterministicScheme::Multiple => max_obj_size - max_obj_size % obj_size,
+        DeterministicScheme::Padme    => max_obj_size                          ,
+    };
+
+    if capped == 0 {
+        return Err( AlpacaError::Config( format!( "the object overhead cap leaves no room for {:?} objects", kind ) ) );
+    }
+
+    Ok(capped)
 }
 
-fn morph_deterministic( document   : &NodeRef        ,
-                        objects    : &mut Vec<Object>,
-                        info       : &MorphInfo      ,
-                        new_orig_n : &mut usize      ,
-                        req_mapper : Map              ) -> Result<usize, String>
+#[allow(clippy::too_many_arguments)]
+pub fn morph_deterministic( document   : &NodeRef                 ,
+                            objects    : &mut Vec<Object>         ,
+                            params     : &DeterministicConfig     ,
+                            config     : &MorphConfig             ,
+                            encoding   : Encoding                 ,
+                            fake_refs  : &FakeRefs                ,
+                            new_orig_n : &mut usize               ,
+                            resolver   : &dyn ResourceResolver    ,
+                            rng        : &mut dyn RngCore           ) -> Result<usize, AlpacaError>
 {
     // We'll have at least as many objects as the original ones
     let initial_obj_no = objects.len();
 
-    // Sample target number of objects (count) and target sizes for morphed
-    // objects. Count is a multiple of "obj_num" and bigger than "min_count".
-    // Target size for each objects is a multiple of "obj_size" and bigger
-    // than the object's  original size.
-    // let target_count = get_multiple(info.obj_num, initial_obj_no);
+    // Round up the number of objects of the kinds counted on their own,
+    // adding fake objects of each kind. Objects are not inlined then.
+    let mut fake_kinds = Vec::new();
+    let mut counted    = Vec::new();
 
-    let target_count;
+    for &kind in COUNTED_KINDS.iter() {
 
-    if info.obj_inlining_enabled != 0 {
-        target_count = info.obj_num;
-    } else {
-        target_count = get_multiple(info.obj_num, initial_obj_no);
-    }
+        if let Some(&obj_num) = params.obj_num_kind.get(kind) {
 
-    for i in 0..objects.len() {
+            let count = count_kind(objects, kind);
 
-        let min_size =   objects[i].content.len()
-                       + match objects[i].kind {
-                            ObjectKind::CSS | ObjectKind::JS => 4,
-                            _ => 0,
-                        };
+            fake_kinds.extend( std::iter::repeat_n( kind.fake(), round_up(params, obj_num, count)? - count ) );
+            counted.push( kind.fake() );
+        }
+    }
 
-        let obj_target_size = get_multiple(info.obj_size, min_size);
+    let rest_obj_no = objects.iter().filter( |obj| !counted.contains( &obj.kind.fake() ) ).count();
+    let inlining    = config.obj_inlining_enabled && counted.is_empty();
 
-        objects[i].target_size = Some(obj_target_size);
-    }
+    // Sample target number of objects (count) and target sizes for morphed
+    // objects. Count is rounded up from "min_count" (to a multiple of
+    // "obj_num"). Target size for each objects is rounded up from the
+    // object's original size (to a multiple of "obj_size").
+    let target_count = if inlining {
+        params.obj_num
+    } else if counted.len() == COUNTED_KINDS.len() {
+        rest_obj_no
+    } else {
+        round_up(params, params.obj_num, rest_obj_no)?
+    };
 
-    let mut count_css_objects: usize = 0;
+    for object in objects.iter_mut() {
 
-    if info.obj_inlining_enabled != 0 && info.css_as_inline_object == 0 && info.force_css_inlining == 0 {
-        for object in objects.iter() {
-            if object.kind == dom::ObjectKind::CSS {
-                count_css_objects += 1;
-            }
-        }
+        let min_size = object.size + pad::min_obj_padding(object);
+
+        object.target_size = Some( round_up(params, params.obj_size(object.kind), min_size)? );
     }
 
-    if target_count < initial_obj_no && info.obj_inlining_enabled != 0 {
+    let count_css_objects = count_css_objects(objects, config);
+
+    if target_count < initial_obj_no && inlining {
 
         // Insert refs and add padding
-        make_objects_inlined( objects, req_mapper, initial_obj_no - target_count - count_css_objects, info.css_as_inline_object ).unwrap();
+        make_objects_inlined( objects, resolver, (initial_obj_no - target_count).saturating_sub(count_css_objects), config.css_as_inline_object )?;
 
         *new_orig_n = objects.len();
 
     } else {
 
-        let fake_objects_sizes: Vec<usize>;
-
-        // The number of fake objects
-        let fake_objects_count = target_count - initial_obj_no;
+        // The rest of the fake objects are images
+        fake_kinds.extend( std::iter::repeat_n( ObjectKind::FakeIMG, target_count - rest_obj_no ) );
 
         // To get the target size of each fake object, sample uniformly a multiple
-        // of "obj_size" which is smaller than "max_obj_size"
-        fake_objects_sizes = get_multiples_in_range(info.obj_size, info.max_obj_size, fake_objects_count)?;
+        // of its kind's "obj_size" (or a Padmé value) which is smaller than
+        // its kind's "max_obj_size"
+        for &kind in COUNTED_KINDS.iter() {
+
+            let kind  = kind.fake();
+            let count = fake_kinds.iter().filter( |&&k| k == kind ).count();
+
+            if count == 0 {
+                continue;
+            }
 
-        // Add the fake objects to the vector
-        for i in 0..fake_objects_count {
-            objects.push( Object::fake_image(fake_objects_sizes[i]) );
+            let obj_size     = params.obj_size    (kind);
+            let max_obj_size = capped_max_obj_size( params, kind, &config.obj_overhead )?;
+
+            let fake_objects_sizes = match params.scheme {
+                DeterministicScheme::Multiple => get_multiples_in_range(obj_size, max_obj_size, count, rng)?,
+                DeterministicScheme::Padme    => get_padme_in_range(max_obj_size, count, rng)?              ,
+            };
+
+            // Add the fake objects to the vector, with room for their
+            // comments if they are stylesheets or scripts
+            for size in fake_objects_sizes {
+
+                let size = if size < pad::min_padding(kind) { round_up(params, obj_size, pad::min_padding(kind))? } else { size };
+
+                let i = objects.len() - *new_orig_n;
+                objects.push( fake_refs.object(kind, size, i) );
+            }
         }
     }
 
-    // Find target size,a multiple of "obj_size".
-    let content = dom::serialize_html(&document);
-    let html_min_size = content.len() + 7; // Plus 7 because of the comment characters.
+    // Find target size, rounded up as the objects' ones from the size of
+    // the html with its references.
+    let html_size = size_model::html_size(document, objects, *new_orig_n);
+
+    round_up( params, params.obj_size, size_model::encoded_html_size(document, html_size, encoding)? )
+}
 
-    Ok( get_multiple(info.obj_size, html_min_size) )
+// Rounds a size or count up, either to a multiple of "num" or to a Padmé
+// value.
+fn round_up(params: &DeterministicConfig, num: usize, min: usize) -> Result<usize, AlpacaError> {
+    match params.scheme {
+        DeterministicScheme::Multiple => get_multiple(num, min),
+        DeterministicScheme::Padme    => Ok( padme(min) )      ,
+    }
+}
+
+// Samples the html size again, at least needed_size, for a page whose html
+// does not fit in the sampled one. Returns None if the html size was sampled
+// together with the objects' ones, in which case the whole page has to be
+// morphed again.
+pub fn resample_html_size(mode: &MorphMode, needed_size: usize, rng: &mut dyn RngCore) -> Result<Option<usize>, AlpacaError> {
+
+    match *mode {
+
+        MorphMode::Deterministic(ref params) => Ok( Some( round_up(params, params.obj_size, needed_size)? ) ),
+
+        MorphMode::Probabilistic(ref params) => {
+
+            let joint = params.page_profiles.is_some()
+                        || !params.dist_total_size.name.is_empty()
+                        || (params.use_total_obj_size && params.dist_obj_size.name == "Joint");
+
+            if joint {
+                Ok(None)
+            } else {
+                Ok( Some( sample_ge(&params.dist_html_size, needed_size, rng)? ) )
+            }
+        },
+    }
 }
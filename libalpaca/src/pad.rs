@@ -1,47 +1,61 @@
 //! Contains padding functions for different resource types.
 use dom::{ ObjectKind, Object };
+use error::AlpacaError;
+use fake::MIN_FAKE_IMAGE_SIZE;
+use image_pad::{ pad_image, MIN_IMAGE_PADDING };
 use rand::distributions::Alphanumeric;
-use rand::{ thread_rng, Rng };
+use rand::{ Rng, RngCore };
 use std::iter::Extend;
 
-static CSS_COMMENT_START       : &'static str = "/*";
+static CSS_COMMENT_START       : &str = "/*";
 const  CSS_COMMENT_START_SIZE  : usize        = 2;
 
-static CSS_COMMENT_END         : &'static str = "*/";
+static CSS_COMMENT_END         : &str = "*/";
 const  CSS_COMMENT_END_SIZE    : usize        = 2;
 
-static HTML_COMMENT_START      : &'static str = "<!--";
+static HTML_COMMENT_START      : &str = "<!--";
 const  HTML_COMMENT_START_SIZE : usize        = 4;
 
-static HTML_COMMENT_END        : &'static str = "-->";
+static HTML_COMMENT_END        : &str = "-->";
 const  HTML_COMMENT_END_SIZE   : usize        = 3;
 
+// The bytes that the padding comment adds to the html, even when empty.
+pub const HTML_COMMENT_SIZE    : usize        = HTML_COMMENT_START_SIZE + HTML_COMMENT_END_SIZE;
+
 // -------------------------------------------------------------------------------------------
 // Private Getter Functions
 
-fn get_css_padding(pad_len: usize) -> Vec<u8> {
+fn get_css_padding(pad_len: usize, rng: &mut dyn RngCore) -> Vec<u8> {
 
     let pad_len = pad_len - CSS_COMMENT_START_SIZE - CSS_COMMENT_END_SIZE;
     let mut pad = Vec::from(CSS_COMMENT_START);
 
-    add_random_chars(&mut pad, pad_len);
+    add_random_chars(&mut pad, pad_len, rng);
     pad.extend( Vec::from(CSS_COMMENT_END) );
 
     pad
 }
 
-fn add_random_chars(pad: &mut Vec<u8>, pad_len: usize) {
+fn get_html_comment_padding(pad_len: usize, rng: &mut dyn RngCore) -> Vec<u8> {
+
+    let mut chars = Vec::new();
+    add_random_chars( &mut chars, pad_len - HTML_COMMENT_SIZE, rng );
+
+    let mut pad = Vec::with_capacity(pad_len);
+    append_html_comment(&mut pad, &chars);
+
+    pad
+}
 
-    let mut rng = thread_rng();
+pub fn add_random_chars(pad: &mut Vec<u8>, pad_len: usize, rng: &mut dyn RngCore) {
 
     for _ in 0..pad_len {
         pad.push( rng.sample(Alphanumeric) as u8 );
     }
 }
 
-fn get_binary_padding(pad_len: usize) -> Vec<u8> {
+fn get_binary_padding(pad_len: usize, rng: &mut dyn RngCore) -> Vec<u8> {
 
-    let mut rng = thread_rng();
     let mut pad: Vec<u8> = Vec::with_capacity(pad_len);
 
     // let p : u8 = 255;
@@ -54,50 +68,91 @@ fn get_binary_padding(pad_len: usize) -> Vec<u8> {
 
 // -------------------------------------------------------------------------------------------
 
-// Pads an html to its target size
-pub fn get_html_padding(content: &mut Vec<u8>, target_size: usize) {
+// Pads an html to exactly its target size.
+pub fn get_html_padding(content: &mut Vec<u8>, target_size: usize, rng: &mut dyn RngCore) -> Result<(), AlpacaError> {
 
-    let current_size = content.len() + HTML_COMMENT_START_SIZE + HTML_COMMENT_END_SIZE;
+    let current_size = content.len() + HTML_COMMENT_SIZE;
 
     if target_size < current_size {
-        eprint!("alpaca: warning: asking for negative html pad\n");
-        return;
+        return Err( AlpacaError::Padding( format!( "cannot pad an html of {} bytes to {} bytes", current_size, target_size ) ) );
     }
 
-    let pad_len = target_size - current_size;
-    let mut pad = Vec::from(HTML_COMMENT_START);
+    let mut chars = Vec::new();
+    add_random_chars( &mut chars, target_size - current_size, rng );
 
-    add_random_chars( &mut pad, pad_len );
+    append_html_comment(content, &chars);
 
-    pad.extend( Vec::from(HTML_COMMENT_END) );
-    content.extend(pad);
+    Ok(())
+}
+
+// Appends the padding comment with the given characters to an html.
+pub fn append_html_comment(content: &mut Vec<u8>, chars: &[u8]) {
+
+    content.extend( HTML_COMMENT_START.as_bytes() );
+    content.extend( chars );
+    content.extend( HTML_COMMENT_END.as_bytes() );
 }
 
 pub fn min_obj_padding(obj: &Object) -> usize {
+    min_padding(obj.kind)
+}
+
+pub fn min_padding(kind: ObjectKind) -> usize {
 
-    // CSS/JS padding needs to be at least 4.
-    match obj.kind {
-        ObjectKind::CSS | ObjectKind::JS => 4,
+    // CSS/JS padding needs to be at least 4, images (with the backgrounds
+    // of stylesheets) are padded with chunks of their format, and fake ones
+    // have to fit a whole image. Framed documents are mostly htmls, padded
+    // with a comment.
+    match kind {
+        ObjectKind::CSS | ObjectKind::JS | ObjectKind::FakeCSS | ObjectKind::FakeJS => 4,
+        ObjectKind::IMG      |
+        ObjectKind::CssImg   => MIN_IMAGE_PADDING  ,
+        ObjectKind::FakeIMG  => MIN_FAKE_IMAGE_SIZE,
+        ObjectKind::HTML     |
+        ObjectKind::Document => HTML_COMMENT_SIZE  ,
         _ => 0
     }
 }
 
 // Pads an object to its target size.
-pub fn get_object_padding(kind: ObjectKind, size: usize, target_size: usize) -> Vec<u8> {
+pub fn get_object_padding(kind: ObjectKind, size: usize, target_size: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    let min_size = size + match kind {
+        ObjectKind::CSS | ObjectKind::JS => CSS_COMMENT_START_SIZE + CSS_COMMENT_END_SIZE, // the additional comment-bytes
+        ObjectKind::HTML                 => HTML_COMMENT_SIZE,
+        _                                => 0,
+    };
+
+    if target_size < min_size {
+        return Err( AlpacaError::Padding( format!( "cannot pad an object of {} bytes to {} bytes", size, target_size ) ) );
+    }
 
     let pad_len = target_size - size;
-    let padding;
 
+    // An html is padded with a comment, as when framed by another page, and
+    // a manifest with the whitespace that json allows after its value
     match kind {
-        ObjectKind::CSS | ObjectKind::JS => {
-            if size + 4 > target_size {
-                // Consider the 4 additional comment-bytes.
-                return Vec::new();
-            }
-            padding  = get_css_padding(pad_len);
+        ObjectKind::CSS | ObjectKind::JS => Ok( get_css_padding(pad_len, rng) ),
+        ObjectKind::HTML                 => Ok( get_html_comment_padding(pad_len, rng) ),
+        ObjectKind::Manifest             => Ok( vec![b' '; pad_len] ),
+        _                                => Ok( get_binary_padding(pad_len, rng) ),
+    }
+}
+
+// Pads an object to its target size, and returns the padded object. Images
+// of a known format are padded inside it, the rest after their end, as are
+// the images whose padding cannot fit a chunk of their format.
+pub fn pad_object(kind: ObjectKind, content: &[u8], target_size: usize, rng: &mut dyn RngCore) -> Result<Vec<u8>, AlpacaError> {
+
+    if kind == ObjectKind::IMG && target_size >= content.len() {
+
+        if let Ok( Some(padded) ) = pad_image( content, target_size - content.len(), rng ) {
+            return Ok(padded);
         }
-        _ => padding = get_binary_padding(pad_len),
-    };
+    }
+
+    let mut padded = content.to_vec();
+    padded.extend( get_object_padding(kind, content.len(), target_size, rng)? );
 
-    padding
+    Ok(padded)
 }
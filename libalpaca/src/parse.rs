@@ -1,9 +1,10 @@
 use dom;
-use utils;
 
-use dom::{ ObjectKind, Object, Map };
+use dom::{ ObjectKind, Object, RefSite };
+use resolver::ResourceResolver;
 use kuchiki::traits::*;
 use kuchiki::{ parse_html_with_options, NodeRef, ParseOpts };
+use std::ops::Range;
 use std::str;
 
 
@@ -17,40 +18,66 @@ pub fn parse_html(input: &str) -> NodeRef {
     parser.finish()
 }
 
-pub fn parse_css_images(css_text: &str) -> Vec<String>{
+// Finds the url()s of a css text, outside its comments, with the range of
+// each uri in the text (without its quotes and the spaces around it).
+pub fn css_urls(css_text: &str) -> Vec<(Range<usize>, &str)> {
 
-	let mut images_paths: Vec<String> = Vec::new();
+	let lower    = css_text.to_ascii_lowercase(); // with the same byte offsets
+	let mut urls = Vec::new();
+	let mut pos  = 0;
 
-	// We basically find every url("style.css"); and
-	// we parse the style.css part of it
-	if css_text.contains("url") {
-		let spl_val: Vec<&str> = css_text.split("\n").collect();
+	loop {
+		let url     = lower[pos..].find("url(").map( |i| pos + i );
+		let comment = lower[pos..].find("/*"  ).map( |i| pos + i );
 
-		for item in spl_val {
-			let mut new_it = utils::remove_whitespace(&item);
+		let start = match (url, comment) {
 
-			if new_it.contains("url") {
-                new_it = new_it.replace("\'", "\"");
+			// Skip the comment, up to its end
+			(Some(url), Some(comment)) if comment < url => {
+				match css_text[comment + 2..].find("*/") {
+					Some(end) => { pos = comment + 2 + end + 2; continue; },
+					None      => break,
+				}
+			},
 
-                let spl       = new_it.split("url");
-				let mut found = false;
+			(Some(url), _) => url + "url(".len(),
+			(None     , _) => break             ,
+		};
 
-                for it in spl {
-					if found == true {
+		let body = start + ( css_text[start..].len() - css_text[start..].trim_start().len() );
 
-						let path = it.replace("\"", "").replace("(", "").replace(")", "").replace(")", "").replace(";", "");
+		// A quoted uri may contain parentheses, an unquoted one ends before them
+		let (range, after) = match css_text[body..].chars().next() {
 
-                        if !path.contains("*/") {
-							images_paths.push(path);
-						}
-						break;
-					}
-					found = true;
-				}
-			}
+			Some(quote) if quote == '"' || quote == '\'' => match css_text[body + 1..].find(quote) {
+				Some(end) => ( body + 1..body + 1 + end, body + 1 + end + 1 ),
+				None      => break,
+			},
+
+			_ => match css_text[body..].find(')') {
+				Some(end) => ( body..body + css_text[body..body + end].trim_end().len(), body + end ),
+				None      => break,
+			},
+		};
+
+		let close = match css_text[after..].find(')') {
+			Some(close) => after + close,
+			None        => break        ,
+		};
+
+		if !range.is_empty() {
+			urls.push( ( range.clone(), &css_text[range] ) );
 		}
+
+		pos = close + 1;
 	}
-	return images_paths;
+
+	urls
+}
+
+// Parses the uris of the url()s of a css text (eg. url("alpaca.png")).
+pub fn parse_css_images(css_text: &str) -> Vec<String> {
+	css_urls(css_text).into_iter().map( |(_, uri)| String::from(uri) ).collect()
 }
 
 pub fn parse_css_names(document: &NodeRef) -> Vec<String> {
@@ -61,7 +88,7 @@ pub fn parse_css_names(document: &NodeRef) -> Vec<String> {
 
 	// Searches for link tags (eg. <link rel="stylesheet" href="style.css">)
 	// and saves path attribute, path and kuckiki node to object vector
-    for node_data in document.select("link").unwrap() {
+    for node_data in document.select("link").into_iter().flatten() {
 
         let node = node_data.as_node();
 		let name = node_data.name.local.to_lowercase();
@@ -69,8 +96,8 @@ pub fn parse_css_names(document: &NodeRef) -> Vec<String> {
 
 		let path_attr = if name == "link" { "href" } else { "src" };
 		let path      = match dom::node_get_attribute(node, path_attr) {
-			Some(p) if p != "" && !p.starts_with("data:") => p       ,
-			_                                             => continue,
+			Some(p) if !p.is_empty() && !p.starts_with("data:") => p       ,
+			_                                                    => continue,
 		};
 
 		let temp = format!( "/{}", path.as_str());
@@ -93,17 +120,19 @@ pub fn parse_css_names(document: &NodeRef) -> Vec<String> {
 	objects
 }
 
-pub fn parse_css_and_inline(document: &NodeRef, req_mapper: Map) -> () {
+pub fn parse_css_and_inline(document: &NodeRef, resolver: &dyn ResourceResolver) {
 
     let mut css_inlined = false;
 
-	for node_data in document.select("link,style").unwrap() {
+	for node_data in document.select("link,style").into_iter().flatten() {
 
         let node      = node_data.as_node();
 		let path_attr = "href";
 
         if css_inlined {
-            node.previous_sibling().unwrap().detach();
+            if let Some(link) = node.previous_sibling() {
+                link.detach();
+            }
             css_inlined = false;
             continue;
         }
@@ -113,15 +142,21 @@ pub fn parse_css_and_inline(document: &NodeRef, req_mapper: Map) -> () {
         }
 
         let path = match dom::node_get_attribute(node, path_attr) {
-			Some(p) if p != "" && !p.starts_with("data:") => p       ,
-			_                                             => continue,
+			Some(p) if !p.is_empty() && !p.starts_with("data:") => p       ,
+			_                                                    => continue,
 		};
 
 		if path.contains("favicon.ico") {
 			continue;
 		}
 
-		let res  = dom::get_map_element(req_mapper, format!("/{}",path) );
+		let res = match resolver.content_of( &format!("/{}",path) ) {
+			Some(res) => res,
+			None      => {
+				eprintln!("libalpaca: warning: /{} not found, it will not be inlined", path);
+				continue;
+			}
+		};
 
 		let temp = res.iter().map(|&c| c as char).collect::<String>();
 
@@ -132,150 +167,254 @@ pub fn parse_css_and_inline(document: &NodeRef, req_mapper: Map) -> () {
 	}
 }
 
-// Parses the target size of an object from its HTTP request query.
-// Returns 0 on error.
-pub fn parse_target_size(query: &str) -> usize {
-
-    let split1: Vec<&str> = query.split("alpaca-padding=").collect();
-	let split2: Vec<&str> = split1[ split1.len() - 1 ].split("&").collect();
-    let size_str          = split2[0];
-
-	// Return the size
-	match size_str.parse::<usize>() {
-	  Ok(size) => return size,
-	  Err(_)   => return 0
-	}
-}
-
 // Parses the object's kind from its raw representation
 pub fn parse_object_kind(mime: &str) -> ObjectKind {
+
+	// Without its parameters, eg "; charset=utf-8"
+	let mime = mime.split(';').next().unwrap_or_default().trim();
+
 	match mime {
-		"text/html"                  => ObjectKind::HTML,
-		"text/css"                   => ObjectKind::CSS ,
-		x if x.starts_with("image/") => ObjectKind::IMG ,
+		"text/html"                  => ObjectKind::HTML    ,
+		"text/css"                   => ObjectKind::CSS     ,
+		"application/javascript"     |
+		"text/javascript"            => ObjectKind::JS      ,
+		"application/manifest+json"  => ObjectKind::Manifest,
+		"text/vtt"                   => ObjectKind::Media   ,
+		x if x.starts_with("image/") => ObjectKind::IMG     ,
+		x if x.starts_with("video/") ||
+		     x.starts_with("audio/") => ObjectKind::Media   ,
+		x if is_font_mime(x)         => ObjectKind::Font    ,
     	_                            => ObjectKind::Unknown
     }
 }
 
-// Parses the objects contained in an HTML page.
-pub fn parse_object_names(document: &NodeRef) -> Vec<String> {
+fn is_font_mime(mime: &str) -> bool {
+	mime.starts_with("font/")               ||
+	mime.starts_with("application/font-")   ||
+	mime.starts_with("application/x-font-") ||
+	mime == "application/vnd.ms-fontobject"
+}
 
-    // Objects vector
-	let mut objects: Vec<String> = Vec::new();
-	let mut found_favicon        = false;
+// The kind of an object referenced with url() in a <style>: a font, by its
+// extension, or else an image.
+fn css_url_kind(path: &str) -> ObjectKind {
 
-	// Searches for link, image and script tags (eg. <link rel="stylesheet" href="style.css">)
-	// and saves path attribute, path and kuckiki node to object vector
-    for node_data in document.select("img,link,script").unwrap() {
+	let path = path.split(['?', '#']).next().unwrap_or_default().to_lowercase();
 
-        let node = node_data.as_node();
-		let name = node_data.name.local.to_lowercase();
+	if [".woff", ".woff2", ".ttf", ".otf", ".eot"].iter().any( |ext| path.ends_with(ext) ) {
+		ObjectKind::Font
+	} else {
+		ObjectKind::CssImg
+	}
+}
 
-		let path_attr = if name == "link" { "href" } else { "src" };
-		let path      = match dom::node_get_attribute(node, path_attr) {
-			Some(p) if p != "" && !p.starts_with("data:") => p       ,
-			_                                             => continue,
-		};
+// The elements that may reference objects through their attributes.
+static REFERENCING_ELEMENTS: &str = "img,source,video,audio,track,iframe,embed,object,input,link,script";
 
-		let temp = format!( "/{}", path.as_str());
+// A reference to an object in the html: the element, where the uri is in
+// it, and the kind of the object.
+struct Reference {
+	node : NodeRef   ,
+	site : RefSite   ,
+	uri  : String    ,
+	kind : ObjectKind,
+}
 
-		objects.push(temp.clone());
+// Parses the candidate urls of a srcset attribute (eg. "a.jpg 1x, b.jpg 2x").
+pub fn parse_srcset(srcset: &str) -> Vec<&str> {
+	srcset.split(',')
+	      .filter_map( |candidate| candidate.split_whitespace().next() )
+	      .collect()
+}
 
-		let rel  = dom::node_get_attribute(node, "rel").unwrap_or_default();
+// Parses the tokens of a space-separated attribute (eg. rel="shortcut icon").
+fn attribute_tokens(node: &NodeRef, name: &str) -> Vec<String> {
+	dom::node_get_attribute(node, name).unwrap_or_default()
+	                                   .to_ascii_lowercase()
+	                                   .split_whitespace()
+	                                   .map(String::from)
+	                                   .collect()
+}
 
-        match ( name.as_str(), rel.as_str() ) {
-			("link", "stylesheet")                       => ObjectKind::CSS                          ,
-			("link", "shortcut icon") | ("link", "icon") => { found_favicon = true; ObjectKind::IMG },
-			("script", _)                                => ObjectKind::JS                           ,
-			("img", _)                                   => ObjectKind::IMG                          ,
-			_                                            => continue                                 ,
-		};
+// The kind of an object fetched with <link rel=preload>, by its "as".
+fn preload_kind(destination: &str) -> Option<ObjectKind> {
+	match destination {
+		"style"                                   => Some(ObjectKind::CSS     ),
+		"script"                                  => Some(ObjectKind::JS      ),
+		"font"                                    => Some(ObjectKind::Font    ),
+		"image"                                   => Some(ObjectKind::IMG     ),
+		"audio" | "video" | "track"               => Some(ObjectKind::Media   ),
+		"document" | "embed" | "object" | "frame" |
+		"iframe"                                  => Some(ObjectKind::Document),
+		_                                         => None                      ,
 	}
+}
 
-	for node_data in document.select("style").unwrap() {
+// The places where an element references objects, with their kinds.
+fn element_sites(node: &NodeRef, name: &str) -> Vec<(RefSite, ObjectKind)> {
+
+	match name {
+		"img"    => vec![ (RefSite::Attr("src"), ObjectKind::IMG), (RefSite::Srcset("srcset"), ObjectKind::IMG) ],
+		"script" => vec![ (RefSite::Attr("src"), ObjectKind::JS) ],
+		"video"  => vec![ (RefSite::Attr("poster"), ObjectKind::IMG), (RefSite::Attr("src"), ObjectKind::Media) ],
+		"audio"  |
+		"track"  => vec![ (RefSite::Attr("src"), ObjectKind::Media) ],
+		"iframe" |
+		"embed"  => vec![ (RefSite::Attr("src"), ObjectKind::Document) ],
+		"object" => vec![ (RefSite::Attr("data"), ObjectKind::Document) ],
+
+		// The images of a <picture>, or the sources of a <video> or an <audio>
+		"source" => match node.parent().and_then( |parent| dom::node_tag(&parent) ).as_deref() {
+			Some("picture") => vec![ (RefSite::Srcset("srcset"), ObjectKind::IMG) ],
+			_               => vec![ (RefSite::Attr("src"), ObjectKind::Media) ],
+		},
+
+		"input" if attribute_tokens(node, "type") == ["image"] => vec![ (RefSite::Attr("src"), ObjectKind::IMG) ],
+
+		"link" => {
+			let rel = attribute_tokens(node, "rel");
+			let has = |token: &str| rel.iter().any( |r| r == token );
+
+			if has("stylesheet") && !has("alternate") {
+				vec![ (RefSite::Attr("href"), ObjectKind::CSS) ]
+			} else if has("icon") || has("apple-touch-icon") || has("apple-touch-icon-precomposed") {
+				vec![ (RefSite::Attr("href"), ObjectKind::IMG) ]
+			} else if has("manifest") {
+				vec![ (RefSite::Attr("href"), ObjectKind::Manifest) ]
+			} else if has("modulepreload") {
+				vec![ (RefSite::Attr("href"), ObjectKind::JS) ]
+			} else if has("preload") {
+				let destination = attribute_tokens(node, "as").concat();
+
+				match preload_kind(&destination) {
+					Some(ObjectKind::IMG) => vec![ (RefSite::Attr("href"), ObjectKind::IMG), (RefSite::Srcset("imagesrcset"), ObjectKind::IMG) ],
+					Some(kind)            => vec![ (RefSite::Attr("href"), kind) ],
+					None                  => vec![]                                 ,
+				}
+			} else {
+				vec![]
+			}
+		},
 
-		let last_child   = node_data .as_node().last_child().unwrap();
-		let refc         = last_child.into_text_ref().unwrap();
+		_ => vec![],
+	}
+}
 
-        let refc_val     = refc.borrow();
-		let images_paths = parse_css_images(&refc_val);
+// Whether an element is the favicon of the page.
+fn is_favicon(node: &NodeRef) -> bool {
+	dom::node_tag(node).as_deref() == Some("link") && attribute_tokens(node, "rel").iter().any( |r| r == "icon" )
+}
 
-		for img in images_paths {
-			let temp = format!("/{}",img);
-			objects.push(temp);
-		}
-	}
+// Adds a reference to an object, unless the uri is inline data or has
+// already been found at the same place.
+fn push(refs: &mut Vec<Reference>, node: &NodeRef, site: RefSite, uri: String, kind: ObjectKind) {
 
-	// If no favicon was found, insert an empty one
-	if !found_favicon {
-		dom::insert_empty_favicon(document);
+	if uri.is_empty() || uri.starts_with("data:") {
+		return;
 	}
 
-    // objects.sort_unstable_by( |a, b| b.content.len().cmp( &a.content.len() ) ); // larger first
-	objects
+	if !refs.iter().any( |r| r.node == *node && r.site == site && r.uri == uri ) {
+		refs.push( Reference { node: node.clone(), site, uri, kind } );
+	}
 }
 
-pub fn parse_objects(document: &NodeRef, req_mapper: Map) -> Vec<Object> {
+// Finds every reference to an object in an HTML page: the attributes of the
+// elements fetching one, the candidates of their srcsets, and the url()s of
+// the style attributes and of the <style>s. A uri referenced more than once
+// at the same place of an element is found once. If no favicon is found, an
+// empty one is inserted, so that the browser does not request it.
+fn find_references(document: &NodeRef) -> Vec<Reference> {
 
-    let mut objects: Vec<Object> = Vec::with_capacity(10);
+	let mut refs: Vec<Reference> = Vec::new();
 	let mut found_favicon        = false;
 
-    for node_data in document.select("img,link,script").unwrap() {
+	for node_data in document.select(REFERENCING_ELEMENTS).into_iter().flatten() {
 
-        let node = node_data.as_node();
+		let node = node_data.as_node();
 		let name = node_data.name.local.to_lowercase();
 
-		let path_attr = if name == "link" { "href" } else { "src" };
-		let path      = match dom::node_get_attribute(node, path_attr) {
-			Some(p) if p != "" && !p.starts_with("data:") => p       ,
-			_                                             => continue,
-		};
-
-		let rel  = dom::node_get_attribute(node, "rel").unwrap_or_default();
-		let kind = match ( name.as_str(), rel.as_str() ) {
-			("link", "stylesheet")                       => ObjectKind::CSS                          ,
-			("link", "shortcut icon") | ("link", "icon") => { found_favicon = true; ObjectKind::IMG },
-			("script", _)                                => ObjectKind::JS                           ,
-			("img", _)                                   => ObjectKind::IMG                          ,
-			_                                            => continue                                 ,
-		};
+		for (site, kind) in element_sites(node, &name) {
 
-		// Consider the posibility that the css file already has some GET parameters
-		let split: Vec<&str> = path.split('?').collect();
-		let relative         = format!("/{}",split[0]);
+			let value = match site {
+				RefSite::Attr(attr) | RefSite::Srcset(attr) => dom::node_get_attribute(node, attr).unwrap_or_default(),
+				_                                           => continue                                             ,
+			};
 
-		let res = dom::get_map_element(req_mapper, relative);
+			if let RefSite::Srcset(_) = site {
+				for uri in parse_srcset(&value) {
+					push( &mut refs, node, site, String::from(uri), kind );
+				}
+			} else {
+				push( &mut refs, node, site, value, kind );
+			}
+		}
 
-		objects.push( Object::existing(&res, kind, path, node) );
+		found_favicon |= is_favicon(node);
 	}
 
-	// Finds css images and adds their paths to objects vector
-	for node_data in document.select("style").unwrap() {
-
-		let node         = node_data .as_node();
-		let last_child   = node_data .as_node().last_child().unwrap();
-		let refc         = last_child.into_text_ref().unwrap();
+	// Finds the images and the fonts of the style attributes
+	for node_data in document.select("[style]").into_iter().flatten() {
 
-        let refc_val     = refc.borrow();
-		let images_paths = parse_css_images(&refc_val);
+		let node  = node_data.as_node();
+		let style = dom::node_get_attribute(node, "style").unwrap_or_default();
 
-		for path in images_paths {
+		for path in parse_css_images(&style) {
+			let kind = css_url_kind(&path);
+			push( &mut refs, node, RefSite::Style, path, kind );
+		}
+	}
 
-			let kind = ObjectKind::CSS;
+	// Finds css images and fonts of the <style>s
+	for node_data in document.select("style").into_iter().flatten() {
 
-			let split: Vec<&str> = path.split('?').collect();
-			let relative         = format!("/{}",split[0]);
+		let node = node_data.as_node();
+		let refc = match dom::style_text(node) {
+			Some(refc) => refc    ,
+			None       => continue,
+		};
 
-			let res = dom::get_map_element(req_mapper,relative);
+		let paths = parse_css_images( &refc.borrow() );
 
-			objects.push( Object::existing(&res, kind, path, node) );
+		for path in paths {
+			let kind = css_url_kind(&path);
+			push( &mut refs, node, RefSite::StyleText, path, kind );
 		}
 	}
 
+	// If no favicon was found, insert an empty one
 	if !found_favicon {
 		dom::insert_empty_favicon(document);
 	}
 
-    objects.sort_unstable_by( |a, b| b.content.len().cmp( &a.content.len() ) ); // larger first
+	refs
+}
+
+// Parses the objects contained in an HTML page.
+pub fn parse_object_names(document: &NodeRef) -> Vec<String> {
+	find_references(document).into_iter().map( |r| format!("/{}", r.uri) ).collect()
+}
+
+pub fn parse_objects(document: &NodeRef, resolver: &dyn ResourceResolver) -> Vec<Object> {
+
+    let mut objects: Vec<Object> = Vec::with_capacity(10);
+
+	for r in find_references(document) {
+
+		// Consider the posibility that the file already has some GET parameters
+		let split: Vec<&str> = r.uri.split('?').collect();
+		let relative         = format!("/{}",split[0]);
+
+		let size = match resolver.size_of(&relative) {
+			Some(size) => size,
+			None       => {
+				eprintln!("libalpaca: warning: {} not found, it will not be padded", relative);
+				continue;
+			}
+		};
+
+		objects.push( Object::existing(size, r.kind, r.uri, &r.node, r.site) );
+	}
+
+    objects.sort_unstable_by_key( |obj| std::cmp::Reverse(obj.size) ); // larger first
 	objects
 }
@@ -0,0 +1,230 @@
+//! Provides page profiles: whole real pages (html size, objects' sizes
+//! and kinds), to morph a page to one of them instead of sampling each
+//! size independently.
+use rand::{ Rng, RngCore };
+use rand_distr;
+use std::fs;
+
+use dom::{ Object, ObjectKind };
+use error::AlpacaError;
+use fake_refs::FakeRefs;
+use pad;
+use size_model;
+
+// A real page of the corpus.
+pub struct PageProfile {
+    pub html_size : usize                     ,
+    pub objects   : Vec<(usize, ObjectKind)> , // size and kind of each object
+}
+
+// Page profiles with their probabilities, read from a ".profile" file with
+// a page per line:
+//     prob html_size n_objects size:kind size:kind ...
+// where kind is one of css, js, img, font.
+#[derive(Default)]
+pub struct PageProfiles {
+    pub probs    : Vec<f64>        ,
+    pub profiles : Vec<PageProfile>,
+}
+
+impl PageProfiles {
+
+    pub fn from(path: &str) -> Result<PageProfiles, AlpacaError> {
+
+        if !path.ends_with(".profile") {
+            return Err( AlpacaError::Distribution( format!("page profiles need to be given in a .profile file (got: {})", path) ) );
+        }
+
+        let data = fs::read_to_string(path).map_err( |e| AlpacaError::Distribution( format!("cannot open {}: {}", path, e) ) )?;
+
+        let mut profiles = PageProfiles::default();
+
+        for (i, line) in data.lines().enumerate() {
+
+            if line.trim().is_empty() {
+                continue;
+            }
+
+            let invalid = |msg: &str| AlpacaError::Distribution( format!("invalid profile file {}, line {}: {}", path, i+1, msg) );
+
+            let v: Vec<&str> = line.split_whitespace().collect();
+
+            if v.len() < 3 {
+                return Err( invalid("expected prob html_size n_objects [size:kind ...]") );
+            }
+
+            let prob: f64        = match v[0].parse::<f64>() {
+                Ok (p) if p.is_finite() && p >= 0.0 => p,
+                _                                   => return Err( invalid( &format!("invalid probability {}", v[0]) ) ),
+            };
+            let html_size: usize = v[1].parse().map_err( |_| invalid("invalid html size")   )?;
+            let n_objects: usize = v[2].parse().map_err( |_| invalid("invalid object count"))?;
+
+            if n_objects != v.len() - 3 {
+                return Err( invalid( &format!("{} objects declared, {} given", n_objects, v.len() - 3) ) );
+            }
+
+            let mut objects = Vec::with_capacity(n_objects);
+
+            for obj in &v[3..] {
+
+                let (size, kind) = match obj.find(':') {
+                    Some(pos) => ( &obj[..pos], &obj[pos+1..] ),
+                    None      => return Err( invalid( &format!("object {} is not size:kind", obj) ) ),
+                };
+
+                let size: usize = match size.parse() {
+                    Ok (size) if size > 0 => size,
+                    _                     => return Err( invalid( &format!("invalid object size {}", size) ) ),
+                };
+
+                let kind = match kind {
+                    "css"  => ObjectKind::CSS ,
+                    "js"   => ObjectKind::JS  ,
+                    "img"  => ObjectKind::IMG ,
+                    "font" => ObjectKind::Font,
+                    _      => return Err( invalid( &format!("unknown object kind {}", kind) ) ),
+                };
+
+                objects.push( (size, kind) );
+            }
+
+            profiles.probs   .push(prob);
+            profiles.profiles.push( PageProfile { html_size, objects } );
+        }
+
+        if profiles.profiles.is_empty() {
+            return Err( AlpacaError::Distribution( format!("empty profile file {}", path) ) );
+        }
+
+        if profiles.probs.iter().sum::<f64>() <= 0.0 {
+            return Err( AlpacaError::Distribution( format!("profile file {}: probabilities sum up to 0", path) ) );
+        }
+
+        Ok(profiles)
+    }
+
+    // Samples a profile which dominates the page, that is its html is at
+    // least min_html_size given the bytes of the references to the padded
+    // objects and to the fake ones, and every object can be padded to a
+    // distinct object of the profile with the same kind. Returns the profile and the index of the profile's
+    // object assigned to each of the page's objects.
+    pub fn sample_dominating( &self,
+                              min_html_size : &dyn Fn(usize) -> usize,
+                              objects       : &[Object]              ,
+                              fake_refs     : &FakeRefs              ,
+                              rng           : &mut dyn RngCore         ) -> Result<(&PageProfile, Vec<usize>), AlpacaError>
+    {
+
+        let mut candidates = Vec::new();
+        let mut total_mass = 0.0;
+
+        for (profile, &prob) in self.profiles.iter().zip(&self.probs) {
+
+            if prob <= 0.0 || profile.objects.len() < objects.len() {
+                continue;
+            }
+
+            let slots = match assign_objects(profile, objects) {
+                Some(slots) => slots   ,
+                None        => continue,
+            };
+
+            // The references to the objects, padded to their slots, and to
+            // the fake ones left
+            let mut used = vec![false; profile.objects.len()];
+
+            for &slot in &slots {
+                used[slot] = true;
+            }
+
+            let refs_size = objects.iter()
+                                   .map(size_model::ref_size)
+                                   .sum::<usize>()
+                          + profile.objects.iter()
+                                           .zip(&used)
+                                           .filter( |&(_, &used)| !used )
+                                           .zip(0..)
+                                           .map( |((&(size, kind), _), i)| size_model::fake_ref_size( &fake_refs.object( fake_kind(kind, size), size, i ) ) )
+                                           .sum::<usize>();
+
+            if profile.html_size < min_html_size(refs_size) {
+                continue;
+            }
+
+            total_mass += prob;
+            candidates.push( (profile, prob, slots) );
+        }
+
+        if candidates.is_empty() {
+            return Err( AlpacaError::Distribution( String::from("no page profile dominates the page") ) );
+        }
+
+        let probability: f64 = rng.sample( rand_distr::OpenClosed01 );
+        let mut sum           = 0.0;
+        let mut chosen        = candidates.len() - 1; // make sure we keep one
+
+        for (i, &(_, prob, _)) in candidates.iter().enumerate() {
+
+            sum += prob / total_mass;
+
+            if sum >= probability {
+                chosen = i;
+                break;
+            }
+        }
+
+        let (profile, _, slots) = candidates.swap_remove(chosen);
+
+        Ok( (profile, slots) )
+    }
+}
+
+// The kind of a profile object that a page object can be padded to.
+fn profile_kind(kind: ObjectKind) -> ObjectKind {
+    match kind {
+        ObjectKind::CSS | ObjectKind::JS | ObjectKind::Font => kind,
+        _                                                   => ObjectKind::IMG,
+    }
+}
+
+// The kind of the fake object standing for a profile object, which is an
+// image if the object is too small for the comments of a stylesheet or script.
+pub fn fake_kind(kind: ObjectKind, size: usize) -> ObjectKind {
+
+    if size < pad::min_padding( kind.fake() ) {
+        ObjectKind::FakeIMG
+    } else {
+        kind.fake()
+    }
+}
+
+// Assigns each object to a distinct profile object of the same kind which
+// is large enough, or returns None if that is not possible. Larger objects
+// are assigned first, to the smallest profile object they fit in.
+fn assign_objects(profile: &PageProfile, objects: &[Object]) -> Option< Vec<usize> > {
+
+    let mut order: Vec<usize> = (0..objects.len()).collect();
+    order.sort_unstable_by_key( |&i| std::cmp::Reverse( objects[i].size + pad::min_obj_padding(&objects[i]) ) );
+
+    let mut used  = vec![false; profile.objects.len()];
+    let mut slots = vec![0; objects.len()];
+
+    for i in order {
+
+        let obj      = &objects[i];
+        let min_size = obj.size + pad::min_obj_padding(obj);
+        let kind     = profile_kind(obj.kind);
+
+        let slot = profile.objects.iter()
+                                  .enumerate()
+                                  .filter( |&(j, &(size, k))| !used[j] && k == kind && size >= min_size )
+                                  .min_by_key( |&(_, &(size, _))| size )
+                                  .map( |(j, _)| j )?;
+
+        used[slot] = true;
+        slots[i]   = slot;
+    }
+
+    Some(slots)
+}
@@ -0,0 +1,154 @@
+//! A process-wide registry of the parsed distributions and page profiles,
+//! and of the secret keys. Each one is parsed once, when the nginx
+//! configuration is loaded, and files are read again only when their
+//! modification time changes, or for secrets when the configuration is
+//! loaded again.
+use std::collections::HashMap;
+use std::fs;
+use std::sync::{ Arc, Mutex, OnceLock, PoisonError };
+use std::time::SystemTime;
+
+use distribution::{ self, Dist };
+use error::{ AlpacaError, ALPACA_OK, ALPACA_PANIC };
+use profile::PageProfiles;
+use token::TokenKey;
+use utils::{ c_string_to_str, ffi_guard };
+
+struct Entry<T> {
+    mtimes : Vec< Option<SystemTime> >, // of the files it is read from, if any
+    value  : Arc<T>                   ,
+}
+
+type Cache<T> = Mutex< HashMap< String, Entry<T> > >;
+
+static DISTRIBUTIONS : OnceLock< Cache<Dist> >         = OnceLock::new();
+static PAGE_PROFILES : OnceLock< Cache<PageProfiles> > = OnceLock::new();
+static SECRETS       : OnceLock< Cache<Vec<u8>> >      = OnceLock::new();
+static TOKEN_KEYS    : OnceLock< Cache<TokenKey> >     = OnceLock::new();
+
+// Returns the cached value for the key, (re)loading it if needed, that is
+// if one of the files it is read from changes. If it then cannot be parsed,
+// this is reported once and the previous version is kept.
+fn get<T>( cache : &Cache<T>                               ,
+           key   : &str                                    ,
+           files : &[&str]                                 ,
+           load  : fn(&str) -> Result<T, AlpacaError>        ) -> Result<Arc<T>, AlpacaError>
+{
+    let mtimes: Vec< Option<SystemTime> > = files.iter().map( |file| fs::metadata(file).and_then( |meta| meta.modified() ).ok() ).collect();
+
+    let mut entries = cache.lock().unwrap_or_else(PoisonError::into_inner);
+
+    if let Some(entry) = entries.get_mut(key) {
+
+        if mtimes.contains(&None) || entry.mtimes == mtimes {
+            return Ok( entry.value.clone() );
+        }
+
+        match load(key) {
+            Ok (value) => entry.value = Arc::new(value),
+            Err(e)     => eprintln!("libalpaca: {} changed but cannot be loaded, keeping the previous version: {}", key, e),
+        }
+
+        entry.mtimes = mtimes;
+
+        return Ok( entry.value.clone() );
+    }
+
+    let value = Arc::new( load(key)? );
+
+    entries.insert( String::from(key), Entry { mtimes, value: value.clone() } );
+
+    Ok(value)
+}
+
+// The distribution given as in the alpaca_dist_* directives.
+pub fn distribution(spec: &str) -> Result<Arc<Dist>, AlpacaError> {
+    get( DISTRIBUTIONS.get_or_init(Cache::default), spec, &distribution::spec_files(spec), Dist::from )
+}
+
+// The page profiles of a ".profile" file.
+pub fn page_profiles(path: &str) -> Result<Arc<PageProfiles>, AlpacaError> {
+    get( PAGE_PROFILES.get_or_init(Cache::default), path, &[path], PageProfiles::from )
+}
+
+fn read_secret(path: &str) -> Result<Vec<u8>, AlpacaError> {
+
+    let secret = fs::read(path).map_err( |e| AlpacaError::Config( format!("cannot read secret file {}: {}", path, e) ) )?;
+
+    if secret.is_empty() {
+        return Err( AlpacaError::Config( format!("the secret file {} is empty", path) ) );
+    }
+
+    Ok(secret)
+}
+
+// The secret key of an alpaca_secret_file, which is not read again on the
+// requests' path.
+pub fn secret(path: &str) -> Result<Arc< Vec<u8> >, AlpacaError> {
+    get( SECRETS.get_or_init(Cache::default), path, &[], read_secret )
+}
+
+// The key of the padding tokens derived from a secret file, as the key of
+// the process is created once, before nginx forks its workers.
+pub fn token_key(path: &str) -> Result<Arc<TokenKey>, AlpacaError> {
+    get( TOKEN_KEYS.get_or_init(Cache::default), path, &[], |path| secret(path).map( |secret| TokenKey::from_secret(&secret) ) )
+}
+
+// Loads a distribution into the registry when the configuration is read,
+// so that invalid ones are reported at startup.
+#[no_mangle]
+pub extern "C" fn load_distribution(spec: *const u8, is_profile: bool) -> u8 {
+
+    ffi_guard( "load_distribution", ALPACA_PANIC, || {
+
+        let res = c_string_to_str(spec).and_then( |spec| {
+            if is_profile {
+                page_profiles(spec).map( |_| () )
+            } else {
+                distribution(spec).map( |_| () )
+            }
+        });
+
+        match res {
+            Ok (()) => ALPACA_OK,
+            Err(e)  => {
+                eprintln!("libalpaca: {}", e);
+                e.code()
+            }
+        }
+    })
+}
+
+// Reads a secret file into the registry when the configuration is read, so
+// that an unreadable one is reported at startup, and a changed one is used
+// once the configuration is reloaded.
+#[no_mangle]
+pub extern "C" fn load_secret(path: *const u8) -> u8 {
+
+    ffi_guard( "load_secret", ALPACA_PANIC, || {
+
+        let res = c_string_to_str(path).and_then( |path| {
+
+            let value = Arc::new( read_secret(path)? );
+            let key   = Arc::new( TokenKey::from_secret(&value) );
+
+            SECRETS.get_or_init(Cache::default)
+                   .lock().unwrap_or_else(PoisonError::into_inner)
+                   .insert( String::from(path), Entry { mtimes: Vec::new(), value } );
+
+            TOKEN_KEYS.get_or_init(Cache::default)
+                      .lock().unwrap_or_else(PoisonError::into_inner)
+                      .insert( String::from(path), Entry { mtimes: Vec::new(), value: key } );
+
+            Ok(())
+        });
+
+        match res {
+            Ok (()) => ALPACA_OK,
+            Err(e)  => {
+                eprintln!("libalpaca: {}", e);
+                e.code()
+            }
+        }
+    })
+}
@@ -0,0 +1,105 @@
+//! Provides access to the content of the objects referenced by an html page.
+use dom;
+use dom::Map;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{ Component, Path, PathBuf };
+
+// Looks up an object by its uri, as requested by the browser (eg
+// "/images/alpaca.png"). Both functions return None if the object is not
+// available, in which case it is left out of the morphing.
+pub trait ResourceResolver {
+
+    fn size_of(&self, uri: &str) -> Option<usize> {
+        self.content_of(uri).map( |content| content.len() )
+    }
+
+    fn content_of(&self, uri: &str) -> Option<Vec<u8>>;
+}
+
+// Resolves objects from the C map that the nginx module fills with the
+// responses of its subrequests. Objects the subrequests missed can
+// optionally be read from the document root instead.
+pub struct MapResolver {
+    map      : Map               ,
+    fallback : Option<FsResolver>,
+}
+
+impl MapResolver {
+
+    pub fn new(map: Map) -> MapResolver {
+        MapResolver { map, fallback: None }
+    }
+
+    pub fn with_root(map: Map, root: &str) -> MapResolver {
+        MapResolver { map, fallback: Some( FsResolver::new(root) ) }
+    }
+}
+
+impl ResourceResolver for MapResolver {
+
+    fn size_of(&self, uri: &str) -> Option<usize> {
+        match dom::get_map_element_size(self.map, uri) {
+            Some(size) => Some(size),
+            None       => self.fallback.as_ref().and_then( |fs| fs.size_of(uri) ),
+        }
+    }
+
+    fn content_of(&self, uri: &str) -> Option<Vec<u8>> {
+        match dom::get_map_element(self.map, uri) {
+            Some(content) => Some(content),
+            None          => self.fallback.as_ref().and_then( |fs| fs.content_of(uri) ),
+        }
+    }
+}
+
+// In-memory objects, keyed by uri.
+impl ResourceResolver for HashMap<String, Vec<u8>> {
+
+    fn size_of(&self, uri: &str) -> Option<usize> {
+        self.get(uri).map( |content| content.len() )
+    }
+
+    fn content_of(&self, uri: &str) -> Option<Vec<u8>> {
+        self.get(uri).cloned()
+    }
+}
+
+// Reads objects from the filesystem, under the given document root.
+pub struct FsResolver {
+    root: PathBuf,
+}
+
+impl FsResolver {
+
+    pub fn new<P: AsRef<Path>>(root: P) -> FsResolver {
+        FsResolver { root: root.as_ref().to_path_buf() }
+    }
+
+    // Maps a uri to a file under the root. Uris that would escape it
+    // (eg "/../etc/passwd") are not resolved.
+    fn path_of(&self, uri: &str) -> Option<PathBuf> {
+
+        let relative = Path::new( uri.trim_start_matches('/') );
+
+        if relative.components().any( |c| !matches!(c, Component::Normal(_) | Component::CurDir) ) {
+            return None;
+        }
+
+        Some( self.root.join(relative) )
+    }
+}
+
+impl ResourceResolver for FsResolver {
+
+    fn size_of(&self, uri: &str) -> Option<usize> {
+        let meta = fs::metadata( self.path_of(uri)? ).ok()?;
+
+        if meta.is_file() { Some( meta.len() as usize ) } else { None }
+    }
+
+    fn content_of(&self, uri: &str) -> Option<Vec<u8>> {
+        fs::read( self.path_of(uri)? ).ok()
+    }
+}
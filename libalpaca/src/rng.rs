@@ -0,0 +1,77 @@
+//! Provides the random number generators used for morphing. By default
+//! every morph is sampled afresh; with a secret key, all random choices
+//! for a uri are derived from the key, so they stay the same within an
+//! epoch and reloading a page reveals nothing new.
+use hmac::{ Hmac, Mac };
+use rand::{ RngCore, SeedableRng };
+use rand_chacha::ChaCha20Rng;
+use sha2::Sha256;
+use std::fs;
+use std::time::{ SystemTime, UNIX_EPOCH };
+
+use error::AlpacaError;
+
+// The secret key and the epoch length (in seconds) for keyed morphing.
+#[derive(Clone)]
+pub struct KeyedConfig {
+    pub secret       : Vec<u8>,
+    pub epoch_length : u64    , // 0 means that the morphs never change
+}
+
+impl KeyedConfig {
+
+    pub fn new(secret: Vec<u8>, epoch_length: u64) -> Result<KeyedConfig, AlpacaError> {
+
+        if secret.is_empty() {
+            return Err( AlpacaError::Config( String::from("the secret key is empty") ) );
+        }
+
+        Ok( KeyedConfig { secret, epoch_length } )
+    }
+
+    // Reads the secret key from a file.
+    pub fn from_file(path: &str, epoch_length: u64) -> Result<KeyedConfig, AlpacaError> {
+
+        let secret = fs::read(path).map_err( |e| AlpacaError::Config( format!("cannot read secret file {}: {}", path, e) ) )?;
+
+        KeyedConfig::new(secret, epoch_length)
+    }
+
+    // The current epoch.
+    pub fn epoch(&self) -> u64 {
+
+        if self.epoch_length == 0 {
+            return 0;
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map( |d| d.as_secs() ).unwrap_or(0);
+
+        now / self.epoch_length
+    }
+
+    // A ChaCha RNG seeded with HMAC-SHA256(secret, uri, epoch).
+    pub fn rng_at(&self, uri: &str, epoch: u64) -> Result<ChaCha20Rng, AlpacaError> {
+
+        let mut mac = Hmac::<Sha256>::new_from_slice(&self.secret)
+                                     .map_err( |e| AlpacaError::Config( format!("invalid secret key: {}", e) ) )?;
+
+        mac.update( uri.as_bytes() );
+        mac.update( &[0] );
+        mac.update( &epoch.to_be_bytes() );
+
+        let mut seed = [0u8; 32];
+        seed.copy_from_slice( &mac.finalize().into_bytes() );
+
+        Ok( ChaCha20Rng::from_seed(seed) )
+    }
+}
+
+// The RNG for morphing the given uri: keyed if a secret is configured,
+// otherwise the thread's one.
+pub fn morph_rng(keyed: Option<&KeyedConfig>, uri: &str) -> Result<Box<dyn RngCore>, AlpacaError> {
+
+    match keyed {
+        Some(keyed) => Ok( Box::new( keyed.rng_at( uri, keyed.epoch() )? ) ),
+        None        => Ok( Box::new( rand::thread_rng() ) ),
+    }
+}
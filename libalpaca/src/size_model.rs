@@ -0,0 +1,91 @@
+//! Accounts exactly for the bytes that morphing adds to the html: the
+//! tokens of the objects' target sizes in their references, the fake
+//! objects and the padding comment.
+use compression;
+use dom;
+use pad;
+use parse;
+use token;
+
+use compression::Encoding;
+use dom::{ Object, RefSite };
+use error::AlpacaError;
+use kuchiki::NodeRef;
+
+
+// The size of the html once its references are rewritten for the objects'
+// target sizes, with an empty padding comment.
+pub fn html_size(document: &NodeRef, objects: &[Object], orig_n: usize) -> usize {
+    dom::serialize_html(document).len() + refs_size(objects, orig_n) + pad::HTML_COMMENT_SIZE
+}
+
+// A lower bound of html_size, for objects whose target sizes are not
+// sampled yet: they are counted with their minimum ones.
+pub fn min_html_size(document: &NodeRef, objects: &[Object], orig_n: usize) -> usize {
+    dom::serialize_html(document).len() + min_refs_size(objects, orig_n) + pad::HTML_COMMENT_SIZE
+}
+
+// The size of the html sent with the given encoding. A compressed html is
+// measured without its references, which compress together with it, so
+// this is only a lower bound that is checked once they are inserted.
+pub fn encoded_html_size(document: &NodeRef, plain_size: usize, encoding: Encoding) -> Result<usize, AlpacaError> {
+
+    match encoding {
+        Encoding::Identity => Ok(plain_size),
+        _                  => compression::min_html_size( &dom::serialize_html(document), encoding ),
+    }
+}
+
+// The bytes that the references add to the html. Original objects without
+// a target size are not referenced.
+pub fn refs_size(objects: &[Object], orig_n: usize) -> usize {
+
+    let (existing, fakes) = objects.split_at(orig_n);
+
+    existing.iter().filter( |obj| obj.target_size.is_some() ).map(ref_size).sum::<usize>()
+    + fakes.iter().map(fake_ref_size).sum::<usize>()
+}
+
+// The bytes that the references add to the html, counting every object as
+// it will have a target size.
+pub fn min_refs_size(objects: &[Object], orig_n: usize) -> usize {
+
+    let (existing, fakes) = objects.split_at(orig_n);
+
+    existing.iter().map(ref_size).sum::<usize>() + fakes.iter().map(fake_ref_size).sum::<usize>()
+}
+
+// The bytes that the token parameter adds to the reference to an existing
+// object, which do not depend on its target size. References in a <style>
+// or a style attribute are rewritten at every url() of the uri, and those
+// in a srcset at every candidate of the uri, while the '&' before a
+// parameter is escaped in attributes.
+pub fn ref_size(obj: &Object) -> usize {
+
+    let param = token::TOKEN_PARAM_SIZE;
+    let attr  = if obj.uri.contains('?') { "&amp;".len() + param } else { 1 + param };
+
+    let node = match obj.node {
+        Some(ref node) => node       ,
+        None           => return attr,
+    };
+
+    let value = |name: &str| dom::node_get_attribute(node, name).unwrap_or_default();
+
+    match obj.site {
+        RefSite::Attr(_)      => attr,
+        RefSite::Srcset(name) => parse::parse_srcset( &value(name) ).iter().filter( |&&uri| uri == obj.uri ).count() * attr,
+        RefSite::Style        => css_url_count( &value("style"), &obj.uri ) * attr,
+        RefSite::StyleText    => dom::style_text(node).map_or( 0, |text| css_url_count( &text.borrow(), &obj.uri ) * (1 + param) ),
+    }
+}
+
+// The number of url()s of a css text which are exactly uri.
+fn css_url_count(css_text: &str, uri: &str) -> usize {
+    parse::css_urls(css_text).iter().filter( |&&(_, url)| url == uri ).count()
+}
+
+// The size of the element referencing a fake object, with its token.
+pub fn fake_ref_size(obj: &Object) -> usize {
+    obj.node.as_ref().map_or( 0, |node| node.to_string().len() ) + ref_size(obj)
+}
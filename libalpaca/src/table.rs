@@ -0,0 +1,321 @@
+//! Precomputed tables for sampling custom distributions, built when they
+//! are loaded: the rows sorted by value with the mass of every suffix, so
+//! that a sample above a lower bound (or in a range) takes O(log n), an
+//! alias table for samples over all rows, which take O(1), and for pairs a
+//! merge sort tree, so that a sample above a lower bound takes O(log² n).
+use rand::{ Rng, RngCore };
+use rand_distr;
+
+// Walker's alias table over weighted items.
+pub struct AliasTable {
+    prob  : Vec<f64>  ,
+    alias : Vec<usize>,
+}
+
+impl AliasTable {
+
+    // None if the weights sum up to 0.
+    pub fn new(weights: &[f64]) -> Option<AliasTable> {
+
+        let n          = weights.len();
+        let total: f64 = weights.iter().sum();
+
+        if n == 0 || total.is_nan() || total <= 0.0 {
+            return None;
+        }
+
+        let mut scaled: Vec<f64> = weights.iter().map( |w| w * n as f64 / total ).collect();
+        let mut prob             = vec![1.0; n];
+        let mut alias: Vec<usize> = (0..n).collect();
+
+        let (mut small, mut large): (Vec<usize>, Vec<usize>) = (0..n).partition( |&i| scaled[i] < 1.0 );
+
+        while let Some(s) = small.pop() {
+
+            // Only rounding errors are left
+            let l = match large.pop() {
+                Some(l) => l,
+                None    => continue,
+            };
+
+            prob[s]  = scaled[s];
+            alias[s] = l;
+
+            scaled[l] -= 1.0 - scaled[s];
+
+            if scaled[l] < 1.0 { small.push(l); } else { large.push(l); }
+        }
+
+        Some( AliasTable { prob, alias } )
+    }
+
+    pub fn sample(&self, rng: &mut dyn RngCore) -> usize {
+
+        let i = rng.gen_range( 0, self.prob.len() );
+
+        if rng.gen::<f64>() < self.prob[i] { i } else { self.alias[i] }
+    }
+}
+
+// The rows of a custom distribution sorted by value, for sampling single
+// values. Each row covers the values [low, high]: a single one, or a bin.
+pub struct SortedRows {
+    rows   : Vec<usize>        , // index of each row in the file, sorted by value
+    lows   : Vec<usize>        ,
+    highs  : Vec<usize>        ,
+    probs  : Vec<f64>          ,
+    suffix : Vec<f64>          , // suffix[k] is the mass of rows k.., summed from the end
+    alias  : Option<AliasTable>,
+}
+
+impl SortedRows {
+
+    // None if some bin is empty or bins overlap, as then more than the
+    // first and last rows in a range may be partly in it.
+    pub fn new(values: &[Vec<usize>], probs: &[f64], bins: bool) -> Option<SortedRows> {
+
+        let mut cells = Vec::with_capacity( values.len() );
+
+        for (i, row) in values.iter().enumerate() {
+
+            if bins && row[0] >= row[1] {
+                return None;
+            }
+
+            let high = if bins { row[1] - 1 } else { row[0] };
+
+            cells.push( (row[0], high, i) );
+        }
+
+        cells.sort_unstable();
+
+        if bins && cells.windows(2).any( |w| w[0].1 >= w[1].0 ) {
+            return None;
+        }
+
+        let rows : Vec<usize> = cells.iter().map( |c| c.2 ).collect();
+        let probs: Vec<f64>   = rows.iter().map( |&i| probs[i] ).collect();
+
+        let mut suffix = vec![0.0; rows.len() + 1];
+
+        for k in (0..rows.len()).rev() {
+            suffix[k] = suffix[k + 1] + probs[k];
+        }
+
+        Some( SortedRows {
+            lows   : cells.iter().map( |c| c.0 ).collect(),
+            highs  : cells.iter().map( |c| c.1 ).collect(),
+            alias  : AliasTable::new(&probs)              ,
+            rows                                          ,
+            probs                                         ,
+            suffix                                        ,
+        })
+    }
+
+    // The sorted positions [i, j) of the rows with values in range.
+    fn span(&self, range: (usize, usize)) -> (usize, usize) {
+
+        let i = self.highs.partition_point( |&h| h <  range.0 );
+        let j = self.lows .partition_point( |&l| l <= range.1 );
+
+        (i, std::cmp::max(i, j))
+    }
+
+    // The mass of the row at sorted position k which is in range.
+    fn partial_mass(&self, k: usize, range: (usize, usize)) -> f64 {
+
+        let lo = std::cmp::max(self.lows [k], range.0);
+        let hi = std::cmp::min(self.highs[k], range.1);
+
+        if lo > hi {
+            return 0.0;
+        }
+
+        self.probs[k] * (hi - lo + 1) as f64 / (self.highs[k] - self.lows[k] + 1) as f64
+    }
+
+    // The masses of the first row, the middle rows and the last row in range.
+    fn masses(&self, (i, j): (usize, usize), range: (usize, usize)) -> (f64, f64, f64) {
+
+        match j - i {
+            0 => (0.0, 0.0, 0.0),
+            1 => (self.partial_mass(i, range), 0.0, 0.0),
+            _ => (self.partial_mass(i, range), self.suffix[i + 1] - self.suffix[j - 1], self.partial_mass(j - 1, range)),
+        }
+    }
+
+    // The mass of the values in range.
+    pub fn mass(&self, range: (usize, usize)) -> f64 {
+
+        let (first, middle, last) = self.masses( self.span(range), range );
+
+        first + middle + last
+    }
+
+    // Samples a row with values in range (by their mass in it), returning
+    // its index in the file. None if the range has no mass.
+    pub fn sample(&self, range: (usize, usize), rng: &mut dyn RngCore) -> Option<usize> {
+
+        let n = self.rows.len();
+
+        if n > 0 && range.0 <= self.lows[0] && range.1 >= self.highs[n - 1] {
+            return self.alias.as_ref().map( |alias| self.rows[ alias.sample(rng) ] );
+        }
+
+        let (i, j)                = self.span(range);
+        let (first, middle, last) = self.masses( (i, j), range );
+        let total                 = first + middle + last;
+
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+
+        let probability: f64 = rng.sample( rand_distr::OpenClosed01 );
+        let mut target       = probability * total;
+
+        if target <= first {
+            return Some( self.rows[i] );
+        }
+
+        target -= first;
+
+        if target <= middle {
+
+            // The first row k in i+1..j-1 with suffix[i+1] - suffix[k+1] >= target
+            let k = i + 1 + self.suffix[i+2..j].partition_point( |&s| self.suffix[i + 1] - s < target );
+
+            return Some( self.rows[ std::cmp::min(k, j - 2) ] );
+        }
+
+        Some( self.rows[ if last > 0.0 { j - 1 } else { i } ] )
+    }
+}
+
+// The rows of a joint custom distribution of pairs, for sampling pairs
+// above a lower bound. Sorted by their first value, the rows are split in
+// blocks of 1, 2, 4... rows (one level per block size), and each block is
+// sorted by the second value with the mass of its suffixes. The rows above
+// the first bound are those of O(log n) blocks, and in each a binary search
+// finds the ones above the second bound: a mass or a sample takes O(log² n).
+pub struct SortedPairs {
+    firsts : Vec<usize>    , // the first values, sorted
+    levels : Vec<PairLevel>, // levels[d] has blocks of 2^d rows
+}
+
+struct PairLevel {
+    rows   : Vec<usize>, // index of each row in the file, sorted by second value in its block
+    suffix : Vec<f64>  , // suffix[k] is the mass of rows k.. up to the end of their block
+}
+
+impl SortedPairs {
+
+    pub fn new(values: &[Vec<usize>], probs: &[f64]) -> SortedPairs {
+
+        let n = values.len();
+
+        let mut sorted: Vec<usize> = (0..n).collect();
+        sorted.sort_by_key( |&i| values[i][0] );
+
+        let mut levels = Vec::new();
+        let mut size   = 1;
+
+        loop {
+
+            let mut rows   = sorted.clone();
+            let mut suffix = vec![0.0; n];
+
+            for start in (0..n).step_by(size) {
+
+                let end = std::cmp::min(start + size, n);
+
+                rows[start..end].sort_by_key( |&i| values[i][1] );
+
+                let mut mass = 0.0;
+
+                for k in (start..end).rev() {
+                    mass      += probs[ rows[k] ];
+                    suffix[k]  = mass;
+                }
+            }
+
+            levels.push( PairLevel { rows, suffix } );
+
+            if size >= n {
+                break;
+            }
+
+            size *= 2;
+        }
+
+        SortedPairs {
+            firsts : sorted.iter().map( |&i| values[i][0] ).collect(),
+            levels                                                   ,
+        }
+    }
+
+    // The blocks (level, start, end) which hold the sorted positions from
+    // i to the end, each with the position of its first row whose second
+    // value is >= lower_bound, and the mass of the rows from there.
+    fn blocks(&self, values: &[Vec<usize>], lower_bound: (usize, usize)) -> Vec<(usize, usize, usize, f64)> {
+
+        let n       = self.firsts.len();
+        let mut pos = self.firsts.partition_point( |&a| a < lower_bound.0 );
+        let mut out = Vec::new();
+
+        while pos < n {
+
+            // The largest block starting at pos, which may end past n
+            let d     = std::cmp::min( pos.trailing_zeros() as usize, self.levels.len() - 1 );
+            let end   = std::cmp::min( pos + (1 << d), n );
+            let level = &self.levels[d];
+
+            let k    = pos + level.rows[pos..end].partition_point( |&row| values[row][1] < lower_bound.1 );
+            let mass = if k < end { level.suffix[k] } else { 0.0 };
+
+            out.push( (d, k, end, mass) );
+            pos = end;
+        }
+
+        out
+    }
+
+    // Samples a row with values >= lower_bound, returning its index in the
+    // file: a block by its mass above the bound, and then a row in it by a
+    // binary search. None if there is no mass above the bound.
+    pub fn sample(&self, values: &[Vec<usize>], lower_bound: (usize, usize), rng: &mut dyn RngCore) -> Option<usize> {
+
+        let blocks     = self.blocks(values, lower_bound);
+        let total: f64 = blocks.iter().map( |b| b.3 ).sum();
+
+        if total.is_nan() || total <= 0.0 {
+            return None;
+        }
+
+        let probability: f64 = rng.sample( rand_distr::OpenClosed01 );
+        let mut target       = probability * total;
+
+        // The last block with mass, if rounding errors leave the target above it
+        let last = blocks.iter().rposition( |b| b.3 > 0.0 )?;
+
+        for (b, &(d, k, end, mass)) in blocks.iter().enumerate() {
+
+            if mass <= 0.0 || (target > mass && b != last) {
+                target -= mass;
+                continue;
+            }
+
+            // The first row j >= k with suffix[k] - suffix[j+1] >= target
+            let suffix = &self.levels[d].suffix;
+            let j      = k + suffix[k+1..end].partition_point( |&s| mass - s < target );
+
+            return Some( self.levels[d].rows[ std::cmp::min(j, end - 1) ] );
+        }
+
+        None
+    }
+
+    // The mass of the pairs >= lower_bound.
+    pub fn mass(&self, values: &[Vec<usize>], lower_bound: (usize, usize)) -> f64 {
+        self.blocks(values, lower_bound).iter().map( |b| b.3 ).sum()
+    }
+}
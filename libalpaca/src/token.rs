@@ -0,0 +1,270 @@
+//! Issues and verifies the padding tokens, the query parameter that tells
+//! the size an object is padded to. A token encrypts the target size and an
+//! expiry with ChaCha20, and authenticates them together with the object's
+//! path with HMAC-SHA256: clients cannot ask for any other size, and
+//! observers learn neither the size nor that the page is morphed.
+use base64;
+
+use error::{ AlpacaError, ALPACA_OK, ALPACA_PANIC };
+use hmac::{ Hmac, Mac };
+use rand::{ RngCore, SeedableRng };
+use rand_chacha::ChaCha20Rng;
+use sha2::Sha256;
+use std::sync::{ Arc, OnceLock };
+use std::time::{ SystemTime, UNIX_EPOCH };
+use utils::ffi_guard;
+
+// The query parameter of the token, which looks like a version parameter.
+pub const TOKEN_PARAM : &str = "v";
+
+// The seconds a token is valid for, by default.
+pub const DEFAULT_TOKEN_TTL : u64 = 86400;
+
+const NONCE_SIZE : usize = 8;
+const DATA_SIZE  : usize = 8;  // target size and expiry, as u32
+const TAG_SIZE   : usize = 16; // truncated HMAC-SHA256
+const TOKEN_SIZE : usize = NONCE_SIZE + DATA_SIZE + TAG_SIZE;
+
+// The length of "v=<token>", the token being in unpadded base64url.
+pub const TOKEN_PARAM_SIZE : usize = TOKEN_PARAM.len() + 1 + (4 * TOKEN_SIZE).div_ceil(3);
+
+static PROCESS_KEY : OnceLock< Arc<TokenKey> > = OnceLock::new();
+
+// The keys that encrypt and authenticate the tokens.
+#[derive(Clone)]
+pub struct TokenKey {
+    enc : [u8; 32],
+    mac : [u8; 32],
+}
+
+type HmacSha256 = Hmac<Sha256>;
+
+fn hmac(key: &[u8]) -> HmacSha256 {
+    HmacSha256::new_from_slice(key).expect("HMAC takes keys of any size")
+}
+
+fn derive(secret: &[u8], label: &[u8]) -> [u8; 32] {
+
+    let mut mac = hmac(secret);
+    mac.update(label);
+
+    let mut key = [0u8; 32];
+    key.copy_from_slice( &mac.finalize().into_bytes() );
+
+    key
+}
+
+// The current time, in seconds since the UNIX epoch.
+pub fn now() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map( |d| d.as_secs() ).unwrap_or(0)
+}
+
+impl TokenKey {
+
+    // The keys derived from a secret, as the one of alpaca_secret_file.
+    pub fn from_secret(secret: &[u8]) -> TokenKey {
+        TokenKey {
+            enc : derive(secret, b"alpaca token encryption"),
+            mac : derive(secret, b"alpaca token authentication"),
+        }
+    }
+
+    // A random key of this process. The nginx module creates it when the
+    // configuration is loaded, so that all workers share it.
+    pub fn process() -> Arc<TokenKey> {
+
+        PROCESS_KEY.get_or_init( || {
+
+            let mut secret = [0u8; 32];
+            rand::thread_rng().fill_bytes(&mut secret);
+
+            Arc::new( TokenKey::from_secret(&secret) )
+        }).clone()
+    }
+
+    fn keystream(&self, nonce: &[u8]) -> [u8; DATA_SIZE] {
+
+        let mut stream_id = [0u8; 8];
+        stream_id.copy_from_slice(nonce);
+
+        let mut cipher = ChaCha20Rng::from_seed(self.enc);
+        cipher.set_stream( u64::from_le_bytes(stream_id) );
+
+        let mut keystream = [0u8; DATA_SIZE];
+        cipher.fill_bytes(&mut keystream);
+
+        keystream
+    }
+
+    fn tag(&self, path: &str, nonce: &[u8], data: &[u8]) -> HmacSha256 {
+
+        let mut mac = hmac(&self.mac);
+
+        mac.update( path.as_bytes() );
+        mac.update( &[0] );
+        mac.update(nonce);
+        mac.update(data);
+
+        mac
+    }
+
+    // The token of the object at path, padded to target_size until expiry.
+    pub fn issue(&self, path: &str, target_size: usize, expiry: u64, rng: &mut dyn RngCore) -> Result<String, AlpacaError> {
+
+        if target_size > u32::MAX as usize {
+            return Err( AlpacaError::Padding( format!("target size {} is too large for a token", target_size) ) );
+        }
+
+        let mut token = vec![0u8; NONCE_SIZE];
+        rng.fill_bytes(&mut token);
+
+        let expiry    = std::cmp::min( expiry, u64::from(u32::MAX) ) as u32;
+        let keystream = self.keystream(&token);
+
+        let data = (target_size as u32).to_be_bytes().iter()
+                                        .chain( expiry.to_be_bytes().iter() )
+                                        .zip( keystream.iter() )
+                                        .map( |(byte, key)| byte ^ key )
+                                        .collect::< Vec<u8> >();
+
+        let tag = self.tag(path, &token, &data).finalize().into_bytes();
+
+        token.extend(data);
+        token.extend_from_slice( &tag[..TAG_SIZE] );
+
+        Ok( base64::encode_config(&token, base64::URL_SAFE_NO_PAD) )
+    }
+
+    // The target size of a token of the object at path, or None if it is
+    // not authentic or has expired.
+    pub fn verify(&self, path: &str, token: &str, now: u64) -> Option<usize> {
+
+        let token = base64::decode_config(token, base64::URL_SAFE_NO_PAD).ok()?;
+
+        if token.len() != TOKEN_SIZE {
+            return None;
+        }
+
+        let (nonce, rest) = token.split_at(NONCE_SIZE);
+        let (data , tag ) = rest .split_at(DATA_SIZE);
+
+        self.tag(path, nonce, data).verify_truncated_left(tag).ok()?;
+
+        let plain: Vec<u8> = data.iter().zip( self.keystream(nonce).iter() ).map( |(byte, key)| byte ^ key ).collect();
+
+        let target_size = u32::from_be_bytes( [ plain[0], plain[1], plain[2], plain[3] ] ) as usize;
+        let expiry      = u32::from_be_bytes( [ plain[4], plain[5], plain[6], plain[7] ] ) as u64;
+
+        if expiry < now {
+            return None;
+        }
+
+        Some(target_size)
+    }
+
+    // The target size of the object at path from its query, that of its
+    // first valid token.
+    pub fn target_size(&self, path: &str, query: &str, now: u64) -> Option<usize> {
+        query.split('&')
+             .filter_map( |param| param.strip_prefix(TOKEN_PARAM).and_then( |rest| rest.strip_prefix('=') ) )
+             .find_map( |token| self.verify(path, token, now) )
+    }
+}
+
+// Issues the tokens of the objects referenced by a page: their paths are
+// resolved against the page's uri, and they expire together.
+pub struct TokenIssuer {
+    key      : Arc<TokenKey>,
+    page_uri : String       ,
+    expiry   : u64          ,
+}
+
+impl TokenIssuer {
+
+    pub fn new(key: Arc<TokenKey>, page_uri: &str, expiry: u64) -> TokenIssuer {
+        TokenIssuer { key, page_uri: String::from(page_uri), expiry }
+    }
+
+    // The "v=<token>" parameter of the object referenced as uri. Its nonce
+    // is always fresh, even when morphing is keyed.
+    pub fn param(&self, uri: &str, target_size: usize) -> Result<String, AlpacaError> {
+
+        let path  = resolve_path(&self.page_uri, uri);
+        let token = self.key.issue( &path, target_size, self.expiry, &mut rand::thread_rng() )?;
+
+        Ok( format!("{}={}", TOKEN_PARAM, token) )
+    }
+}
+
+// The path requested for a reference in the page at page_uri, as nginx sees
+// it: without its query, resolved, with its dot segments removed and
+// percent-decoded.
+pub fn resolve_path(page_uri: &str, uri: &str) -> String {
+
+    let uri = uri.split(['?', '#']).next().unwrap_or_default();
+
+    // The path of an absolute or scheme-relative url
+    let uri = match uri.find("//") {
+        Some(pos) if pos == 0 || uri[..pos].strip_suffix(':').is_some_and( |scheme| scheme.chars().all( |c| c.is_ascii_alphanumeric() || "+.-".contains(c) ) ) => {
+            let rest = &uri[pos + 2..];
+            rest.find('/').map_or( "/", |slash| &rest[slash..] )
+        },
+        _ => uri,
+    };
+
+    let joined = if uri.starts_with('/') {
+        String::from(uri)
+    } else {
+        let page_dir = page_uri.rfind('/').map_or( "/", |pos| &page_uri[..=pos] );
+        format!("{}{}", page_dir, uri)
+    };
+
+    let mut segments: Vec<&str> = Vec::new();
+
+    for segment in joined.split('/').skip(1) {
+        match segment {
+            "."  => {},
+            ".." => { segments.pop(); },
+            _    => segments.push(segment),
+        }
+    }
+
+    // A trailing dot segment still refers to a directory
+    if joined.ends_with("/.") || joined.ends_with("/..") {
+        segments.push("");
+    }
+
+    percent_decode( &format!("/{}", segments.join("/")) )
+}
+
+fn percent_decode(path: &str) -> String {
+
+    let bytes       = path.as_bytes();
+    let mut decoded = Vec::with_capacity( bytes.len() );
+    let mut i       = 0;
+
+    while i < bytes.len() {
+
+        let hex = bytes.get(i + 1..i + 3).filter( |hex| hex.iter().all( u8::is_ascii_hexdigit ) )
+                                         .and_then( |hex| std::str::from_utf8(hex).ok() )
+                                         .and_then( |hex| u8::from_str_radix(hex, 16).ok() );
+
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => { decoded.push(byte); i += 3; },
+            (byte, _)          => { decoded.push(byte); i += 1; },
+        }
+    }
+
+    String::from_utf8_lossy(&decoded).into_owned()
+}
+
+// Creates the key of the process when the configuration is loaded, before
+// the workers are forked.
+#[no_mangle]
+pub extern "C" fn init_padding_tokens() -> u8 {
+
+    ffi_guard( "init_padding_tokens", ALPACA_PANIC, || {
+        TokenKey::process();
+        ALPACA_OK
+    })
+}
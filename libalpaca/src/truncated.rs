@@ -0,0 +1,426 @@
+//! Provides exact sampling from the predefined distributions truncated to
+//! a range, by inverting their survival function in log space. This never
+//! fails when the range has some mass, however far in the tail it is.
+use rand::{ Rng, RngCore };
+
+use distribution::Dist;
+use error::AlpacaError;
+
+const EPS    : f64   = 1e-15;
+const FPMIN  : f64   = 1e-300;
+const MAXIT  : usize = 100_000;
+
+// -------------------------------------------------------------------------------------------
+// Special functions
+
+// ln Γ(x), for x > 0 (Lanczos approximation).
+fn ln_gamma(x: f64) -> f64 {
+
+    const COF: [f64; 6] = [ 76.18009172947146 , -86.50532032941677 , 24.01409824083091,
+                            -1.231739572450155, 0.1208650973866179e-2, -0.5395239384953e-5 ];
+
+    let mut y   = x;
+    let tmp     = x + 5.5;
+    let tmp     = tmp - (x + 0.5) * tmp.ln();
+    let mut ser = 1.000000000190015;
+
+    for c in COF.iter() {
+        y   += 1.0;
+        ser += c / y;
+    }
+
+    -tmp + (2.5066282746310005 * ser / x).ln()
+}
+
+// ln erfc(x). For x >= 0 the approximation has the form t·exp(-x² + poly(t)),
+// so its log is accurate (relative error < 1.2e-7) arbitrarily far in the tail.
+fn ln_erfc(x: f64) -> f64 {
+
+    if x < 0.0 {
+        return ( 2.0 - ln_erfc(-x).exp() ).ln();
+    }
+
+    let t    = 1.0 / (1.0 + 0.5 * x);
+    let poly = -1.26551223 + t * (1.00002368 + t * (0.37409196 + t * (0.09678418 + t * (-0.18628806 +
+                t * (0.27886807 + t * (-1.13520398 + t * (1.48851587 + t * (-0.82215223 + t * 0.17087277))))))));
+
+    t.ln() - x * x + poly
+}
+
+// ln P(Z >= z) for a standard normal Z.
+fn ln_normal_sf(z: f64) -> f64 {
+
+    if z >= 0.0 {
+        ln_erfc( z / std::f64::consts::SQRT_2 ) - std::f64::consts::LN_2
+    } else {
+        ( -ln_erfc( -z / std::f64::consts::SQRT_2 ).exp() / 2.0 ).ln_1p()
+    }
+}
+
+// ln P(X >= x) for X ~ N(mean, std_dev²), where std_dev may be 0.
+fn ln_normal_sf_at(x: f64, mean: f64, std_dev: f64) -> f64 {
+
+    if std_dev > 0.0 {
+        ln_normal_sf( (x - mean) / std_dev )
+    } else if x <= mean {
+        0.0
+    } else {
+        f64::NEG_INFINITY
+    }
+}
+
+// (ln P(a, x), ln Q(a, x)), the logs of the regularized lower and upper
+// incomplete gamma functions. The smaller of the two is computed directly,
+// so that it stays accurate in the tails.
+fn ln_gamma_pq(a: f64, x: f64) -> (f64, f64) {
+
+    if x <= 0.0 {
+        return (f64::NEG_INFINITY, 0.0);
+    }
+
+    let ln_prefix = -x + a * x.ln() - ln_gamma(a);
+
+    if x < a + 1.0 {
+
+        // Series for P(a, x), then Q = 1 - P
+        let mut ap  = a;
+        let mut del = 1.0 / a;
+        let mut sum = del;
+
+        for _ in 0..MAXIT {
+
+            ap  += 1.0;
+            del *= x / ap;
+            sum += del;
+
+            if del.abs() < sum.abs() * EPS {
+                break;
+            }
+        }
+
+        let ln_p = ln_prefix + sum.ln();
+
+        ( ln_p, ( -ln_p.exp() ).ln_1p() )
+
+    } else {
+
+        // Continued fraction for Q(a, x) (modified Lentz)
+        let mut b = x + 1.0 - a;
+        let mut c = 1.0 / FPMIN;
+        let mut d = 1.0 / b;
+        let mut h = d;
+
+        for i in 1..MAXIT {
+
+            let an = -(i as f64) * (i as f64 - a);
+            b += 2.0;
+
+            d = an * d + b;
+            if d.abs() < FPMIN { d = FPMIN; }
+
+            c = b + an / c;
+            if c.abs() < FPMIN { c = FPMIN; }
+
+            d = 1.0 / d;
+
+            let del = d * c;
+            h *= del;
+
+            if (del - 1.0).abs() < EPS {
+                break;
+            }
+        }
+
+        let ln_q = ln_prefix + h.ln();
+
+        ( ( -ln_q.exp() ).ln_1p(), ln_q )
+    }
+}
+
+// Continued fraction for the incomplete beta function (modified Lentz).
+fn beta_cf(a: f64, b: f64, x: f64) -> f64 {
+
+    let qab = a + b;
+    let qap = a + 1.0;
+    let qam = a - 1.0;
+
+    let mut c = 1.0;
+    let mut d = 1.0 - qab * x / qap;
+
+    if d.abs() < FPMIN { d = FPMIN; }
+
+    d = 1.0 / d;
+
+    let mut h = d;
+
+    for m in 1..MAXIT {
+
+        let m  = m as f64;
+        let m2 = 2.0 * m;
+
+        let aa = m * (b - m) * x / ((qam + m2) * (a + m2));
+
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN { d = FPMIN; }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN { c = FPMIN; }
+        d = 1.0 / d;
+        h *= d * c;
+
+        let aa = -(a + m) * (qab + m) * x / ((a + m2) * (qap + m2));
+
+        d = 1.0 + aa * d;
+        if d.abs() < FPMIN { d = FPMIN; }
+        c = 1.0 + aa / c;
+        if c.abs() < FPMIN { c = FPMIN; }
+        d = 1.0 / d;
+
+        let del = d * c;
+        h *= del;
+
+        if (del - 1.0).abs() < EPS {
+            break;
+        }
+    }
+
+    h
+}
+
+// ln I_x(a, b), the log of the regularized incomplete beta function.
+fn ln_beta_i(a: f64, b: f64, x: f64) -> f64 {
+
+    if x <= 0.0 {
+        return f64::NEG_INFINITY;
+    }
+
+    if x >= 1.0 {
+        return 0.0;
+    }
+
+    let ln_front = ln_gamma(a + b) - ln_gamma(a) - ln_gamma(b) + a * x.ln() + b * (-x).ln_1p();
+
+    if x < (a + 1.0) / (a + b + 2.0) {
+        ln_front + beta_cf(a, b, x).ln() - a.ln()
+    } else {
+        ( -(ln_front + beta_cf(b, a, 1.0 - x).ln() - b.ln()).exp() ).ln_1p()
+    }
+}
+
+// -------------------------------------------------------------------------------------------
+
+// Zipf distributions are given up to this many values.
+pub const ZIPF_MAX_N: f64 = 1_000_000.0;
+
+// Checks the parameters of a predefined distribution.
+pub fn check_params(name: &str, params: &[f64]) -> Result<(), AlpacaError> {
+
+    let is_int = |x: f64| x.fract() == 0.0;
+    let is_p   = |x: f64| x > 0.0 && x <= 1.0;
+
+    let ok = params.iter().all( |p| p.is_finite() ) && match name {
+        "Normal" | "LogNormal" => params[1] >= 0.0,
+        "Exp"                  => params[0] >  0.0,
+        "Poisson"              => params[0] >  0.0,
+        "Binomial"             => params[0] >= 0.0 && is_int(params[0]) && (0.0..=1.0).contains(&params[1]),
+        "Gamma"                => params[0] >  0.0 && params[1] > 0.0,
+        "Uniform"              => params[0] <  params[1],
+        "Pareto" | "Weibull"   => params[0] >  0.0 && params[1] > 0.0,
+        "Zipf"                 => params[0] >= 1.0 && params[0] <= ZIPF_MAX_N && is_int(params[0]) && params[1] >= 0.0,
+        "Geometric"            => is_p(params[0]),
+        "NegBinomial"          => params[0] >  0.0 && is_p(params[1]),
+        _                      => return Err( AlpacaError::Distribution( format!( "cannot sample from distribution {}", name ) ) ),
+    };
+
+    if ok { Ok(()) } else { Err( AlpacaError::Distribution( format!( "invalid parameters for {}: {:?}", name, params ) ) ) }
+}
+
+// The tail sums of the Zipf weights: element i is the sum of j^-s for j in
+// i+1..=n. Summed from the end, so that small tails stay accurate.
+pub fn zipf_tails(n: usize, s: f64) -> Vec<f64> {
+
+    let mut tails = vec![0.0; n];
+    let mut sum   = 0.0;
+
+    for j in (1..=n).rev() {
+        sum         += (j as f64).powf(-s);
+        tails[j - 1] = sum;
+    }
+
+    tails
+}
+
+// ln P(Y >= y) for a sample Y of the distribution, before rounding. For the
+// discrete distributions y is an integer.
+fn ln_sf(dist: &Dist, y: f64) -> f64 {
+
+    let p = &dist.params;
+
+    match dist.name.as_str() {
+
+        "Normal"    => ln_normal_sf_at( y, p[0], p[1] ),
+        "LogNormal" => if y <= 0.0 { 0.0 } else { ln_normal_sf_at( y.ln(), p[0], p[1] ) },
+
+        "Exp"     => if y <= 0.0 { 0.0 } else { -p[0] * y },
+        "Gamma"   => ln_gamma_pq( p[0], y / p[1] ).1,
+        "Weibull" => if y <= 0.0 { 0.0 } else { -(y / p[0]).powf(p[1]) },
+        "Pareto"  => if y <= p[0] { 0.0 } else { p[1] * (p[0].ln() - y.ln()) },
+
+        "Uniform" => {
+            if y <= p[0] {
+                0.0
+            } else if y >= p[1] {
+                f64::NEG_INFINITY
+            } else {
+                ( (p[1] - y) / (p[1] - p[0]) ).ln()
+            }
+        },
+
+        // P(X >= k) = P(k, lambda), the regularized lower incomplete gamma
+        "Poisson" => if y <= 0.0 { 0.0 } else { ln_gamma_pq( y, p[0] ).0 },
+
+        // P(X >= k) = I_p(k, n - k + 1)
+        "Binomial" => {
+            let n = p[0];
+
+            if y <= 0.0 {
+                0.0
+            } else if y > n || p[1] == 0.0 {
+                f64::NEG_INFINITY
+            } else if p[1] == 1.0 {
+                0.0
+            } else {
+                ln_beta_i( y, n - y + 1.0, p[1] )
+            }
+        },
+
+        // Failures before the first success: P(X >= k) = (1 - p)^k
+        "Geometric" => if y <= 0.0 { 0.0 } else { y * (-p[0]).ln_1p() },
+
+        // Failures before the r-th success: P(X >= k) = I_{1-p}(k, r)
+        "NegBinomial" => if y <= 0.0 { 0.0 } else { ln_beta_i( y, p[0], 1.0 - p[1] ) },
+
+        // Values 1..=n with probability proportional to k^-s
+        "Zipf" => {
+            let tails = &dist.cumulative;
+
+            if y <= 1.0 {
+                0.0
+            } else if y > tails.len() as f64 {
+                f64::NEG_INFINITY
+            } else {
+                ( tails[y as usize - 1] / tails[0] ).ln()
+            }
+        },
+
+        _ => f64::NEG_INFINITY,
+    }
+}
+
+// ln P(X >= k), where X is a sample of the distribution rounded down to an
+// integer. Samples are conditioned on being non-negative, so distributions
+// that can take negative values (eg Normal) are truncated at 0 instead of
+// piling their negative mass on 0.
+pub fn ln_survival(dist: &Dist, k: usize) -> f64 {
+
+    if k == 0 {
+        return 0.0;
+    }
+
+    ln_sf(dist, k as f64) - ln_sf(dist, 0.0)
+}
+
+// Checks that the distribution takes non-negative values.
+pub fn check_support(dist: &Dist) -> Result<(), AlpacaError> {
+
+    if ln_sf(dist, 0.0) == f64::NEG_INFINITY {
+        return Err( AlpacaError::Distribution( format!( "distribution {}/{:?} takes no non-negative values", dist.name, dist.params ) ) );
+    }
+
+    Ok(())
+}
+
+// (ln P(X >= lower_bound), P(X <= upper_bound | X >= lower_bound)), or None
+// if the range has no mass.
+fn range_mass(dist: &Dist, lower_bound: usize, upper_bound: usize) -> Option<(f64, f64)> {
+
+    if lower_bound > upper_bound {
+        return None;
+    }
+
+    let ln_sa = ln_survival(dist, lower_bound);
+    let ln_sb = if upper_bound == usize::MAX { f64::NEG_INFINITY } else { ln_survival(dist, upper_bound + 1) };
+
+    let ratio = -(ln_sb - ln_sa).exp_m1();
+
+    if ln_sa == f64::NEG_INFINITY || ratio.is_nan() || ratio <= 0.0 {
+        return None;
+    }
+
+    Some( (ln_sa, ratio) )
+}
+
+// ln P(lower_bound <= X <= upper_bound), -inf if the range has no mass.
+pub fn ln_mass(dist: &Dist, lower_bound: usize, upper_bound: usize) -> f64 {
+
+    match range_mass(dist, lower_bound, upper_bound) {
+        Some( (ln_sa, ratio) ) => ln_sa + ratio.ln(),
+        None                   => f64::NEG_INFINITY,
+    }
+}
+
+// Samples a value in [lower_bound, upper_bound] from the distribution
+// conditioned on that range.
+pub fn sample_truncated(dist: &Dist, lower_bound: usize, upper_bound: usize, rng: &mut dyn RngCore) -> Result<usize, AlpacaError> {
+
+    let no_mass = || {
+        let range = if upper_bound == usize::MAX { format!(">= {}", lower_bound) } else { format!("in [{}, {}]", lower_bound, upper_bound) };
+
+        AlpacaError::Distribution( format!("values {} have prob 0 in distribution {}", range, dist.name) )
+    };
+
+    let (ln_sa, ratio) = match range_mass(dist, lower_bound, upper_bound) {
+        Some(mass) => mass,
+        None       => return Err( no_mass() ),
+    };
+
+    // Find the largest k in range with P(X >= k) >= target, where
+    // target = P(X >= lower_bound) - u * P(lower_bound <= X <= upper_bound)
+    let u: f64    = rng.gen();
+    let ln_target = ln_sa + (-u * ratio).ln_1p();
+
+    let mut lo = lower_bound; // P(X >= lo) >= target
+    let mut hi;               // P(X >= hi) <  target, or the end of the range
+
+    if upper_bound == usize::MAX {
+
+        // Exponential search for an upper end
+        let mut step: usize = 1;
+
+        loop {
+            hi = lower_bound.saturating_add(step);
+
+            if hi == usize::MAX || ln_survival(dist, hi) < ln_target {
+                break;
+            }
+
+            lo   = hi;
+            step = step.saturating_mul(2);
+        }
+
+    } else {
+        hi = upper_bound.saturating_add(1);
+    }
+
+    while hi - lo > 1 {
+
+        let mid = lo + (hi - lo) / 2;
+
+        if ln_survival(dist, mid) >= ln_target {
+            lo = mid;
+        } else {
+            hi = mid;
+        }
+    }
+
+    Ok(lo)
+}
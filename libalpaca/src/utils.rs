@@ -1,117 +1,140 @@
 use base64;
 use dom;
+use fake_refs;
 use libc;
 use parse;
 
+use dom::RefSite;
+use error::{ AlpacaError, ALPACA_OK };
 use kuchiki::NodeRef;
 use morphing::MorphInfo;
+use rand::RngCore;
+use resolver::ResourceResolver;
+use token::TokenIssuer;
 
 use std::ffi::CStr;
 use std::ffi::CString;
 use std::os::raw::c_int;
 use std::fs::File;
 use std::io::prelude::*;
+use std::panic::{ self, AssertUnwindSafe };
 use std::path::Path;
 
 // -----------------------------------------------------------------------------------------------------
 // NODE OBJECT REFERENCE MANIPULATION FUNCTIONS
 
-// Appends the ALPaCA GET parameter to an html element
-fn append_ref(object: &dom::Object) {
+// Appends the ALPaCA GET parameter, a token of the target size, to an html
+// element
+fn append_ref(object: &dom::Object, target_size: usize, tokens: &TokenIssuer) -> Result<(), AlpacaError> {
 
     // Construct the link with the appended new parameter
-    let mut new_link = String::from("alpaca-padding=");
-
-    new_link.push_str( &(object.target_size.unwrap().to_string()) ); // Append the target size
-
-    let node = object.node.as_ref().unwrap();
-    let attr = match node.as_element()
-                         .unwrap()
-                         .name
-                         .local
-                         .to_lowercase()
-                         .as_ref()
-    {
-        "img" | "script" => "src",
-        "link"           => "href",
-        "style"          => "style",
-        _                => panic!("shouldn't happen"),
+    let mut new_link = tokens.param(&object.uri, target_size)?;
+
+    let node = match object.node {
+        Some(ref node) => node,
+        None           => return Err( AlpacaError::Parse( format!("object {} is not in the html", object.uri) ) ),
     };
 
     // Check if there is already a GET parameter in the file path
-    let prefix = if object.uri.contains("?") { '&' } else { '?' };
+    let prefix = if object.uri.contains('?') { '&' } else { '?' };
 
     new_link.insert    (0, prefix);
     new_link.insert_str(0, &object.uri);
 
-    if attr != "style" {
-        dom::node_set_attribute(node, attr, new_link);
-    } else {
+    match object.site {
+        RefSite::Attr(attr) => dom::node_set_attribute(node, attr, new_link),
+
+        RefSite::Srcset(attr) => {
+            let srcset = dom::node_get_attribute(node, attr).unwrap_or_default();
+            dom::node_set_attribute( node, attr, replace_srcset_url(&srcset, &object.uri, &new_link) );
+        },
+
+        RefSite::Style => {
+            let style = dom::node_get_attribute(node, "style").unwrap_or_default();
+            dom::node_set_attribute( node, "style", replace_css_url(&style, &object.uri, &new_link) );
+        },
+
+        RefSite::StyleText => {
+            let refc = match dom::style_text(node) {
+                Some(refc) => refc,
+                None       => return Err( AlpacaError::Parse( format!("object {} is not in a <style>", object.uri) ) ),
+            };
+
+            let refc_val = replace_css_url( &refc.borrow(), &object.uri, &new_link );
 
-        let last_child   = node.last_child().unwrap();
-        let refc         = last_child.into_text_ref().unwrap();
+            *refc.borrow_mut() = refc_val;
+        },
+    }
 
-        let mut refc_val = refc.borrow().clone();
+    Ok(())
+}
 
-        refc_val = refc_val.replace(&object.uri, &new_link);
+// Replaces the uri of the url()s which are exactly uri, leaving the other
+// occurrences of it (eg. in other uris or in comments) as they are.
+fn replace_css_url(css_text: &str, uri: &str, new_link: &str) -> String {
 
-        *refc.borrow_mut() = refc_val;
+    let mut replaced = String::with_capacity( css_text.len() );
+    let mut last     = 0;
+
+    for (range, _) in parse::css_urls(css_text).into_iter().filter( |&(_, url)| url == uri ) {
+        replaced.push_str( &css_text[last..range.start] );
+        replaced.push_str( new_link );
+        last = range.end;
     }
+
+    replaced.push_str( &css_text[last..] );
+    replaced
+}
+
+// Replaces the url of the srcset candidates which are exactly uri, leaving
+// their descriptors and the other candidates as they are.
+fn replace_srcset_url(srcset: &str, uri: &str, new_link: &str) -> String {
+
+    srcset.split(',').map( |candidate| {
+
+        let start = candidate.len() - candidate.trim_start().len();
+        let end   = candidate[start..].find( char::is_whitespace ).map_or( candidate.len(), |pos| start + pos );
+
+        if &candidate[start..end] == uri {
+            format!( "{}{}{}", &candidate[..start], new_link, &candidate[end..] )
+        } else {
+            String::from(candidate)
+        }
+
+    }).collect::<Vec<String>>().join(",")
 }
 
-// Inserts the ALPaCA GET parameters to the html objects, and adds the fake objects to the html.
-pub fn insert_objects_refs(document: &NodeRef, objects: &[dom::Object], n: usize) -> Result<(), String> {
+// Inserts the ALPaCA GET parameters to the html objects, and scatters the
+// elements of the fake objects through the html.
+pub fn insert_objects_refs(document: &NodeRef, objects: &[dom::Object], n: usize, tokens: &TokenIssuer, rng: &mut dyn RngCore) -> Result<(), AlpacaError> {
+
+    if n > objects.len() {
+        return Err( AlpacaError::Padding( format!( "{} original objects given, only {} found", n, objects.len() ) ) );
+    }
 
     let init_obj    = &objects[0..n]; // Slice which contains initial objects
     let padding_obj = &objects[n..];  // Slice which contains ALPaCA objects
 
-    println!("TO BE PADDED {}", n);
-
     for object in init_obj {
         // Ignore objects without target size
-        if !object.target_size.is_none() {
-            append_ref(&object);
+        if let Some(target_size) = object.target_size {
+            append_ref(object, target_size, tokens)?;
         }
     }
 
-    add_padding_objects(&document, padding_obj);
-
-    Ok(())
-}
-
-// -----------------------------------------------------------------------------------------------------
-// PADDING OBJECT ADDITION FUNCTION
-
-// Adds the fake ALPaCA objects in the end of the html body
-fn add_padding_objects(document: &NodeRef, objects: &[dom::Object]) {
-
-    // Append the objects either to the <body> tag, if exists, otherwise
-    // to the whole document
-    let node_data; // to outlive the match
-    let node = match document.select("body").unwrap().next() {
-        Some(nd) => {
-            node_data = nd;
-            node_data.as_node()
-        }
-        None => document,
-    };
+    let mut elements = Vec::with_capacity( padding_obj.len() );
 
-    let mut i = 1;
+    for object in padding_obj {
 
-    for object in objects {
-        let elem = dom::create_element("img");
+        let target_size = object.target_size.ok_or_else( || AlpacaError::Padding( String::from("fake object without target size") ) )?;
 
-        dom::node_set_attribute(
-            &elem,
-            "src",
-            format!( "/__alpaca_fake_image.png?alpaca-padding={}&i={}", object.target_size.unwrap(), i ),
-        );
+        append_ref(object, target_size, tokens)?;
+        elements.extend( object.node.clone() );
+    }
 
-        dom::node_set_attribute( &elem, "style", String::from("visibility:hidden") );
+    fake_refs::scatter_fake_elements(document, &elements, rng);
 
-        node.append(elem);
-        i += 1;
-    }
+    Ok(())
 }
 
 // -----------------------------------------------------------------------------------------------------
@@ -119,14 +142,12 @@ fn add_padding_objects(document: &NodeRef, objects: &[dom::Object]) {
 
 #[no_mangle]
 pub extern "C" fn get_html_required_files( pinfo: *mut MorphInfo, length: *mut c_int ) -> *mut *mut libc::c_char {
-    let ptr = get_required_files(pinfo, length, true);
-    ptr
+    get_required_files(pinfo, length, true)
 }
 
 #[no_mangle]
 pub extern "C" fn get_required_css_files( pinfo: *mut MorphInfo, length: *mut c_int ) -> *mut *mut libc::c_char {
-    let ptr = get_required_files(pinfo, length, false);
-    ptr
+    get_required_files(pinfo, length, false)
 }
 
 #[no_mangle]
@@ -134,51 +155,76 @@ pub extern "C" fn get_required_files( pinfo: *mut MorphInfo, length: *mut c_int,
 
     std::env::set_var("RUST_BACKTRACE", "full");
 
-    let info = unsafe { &mut *pinfo };
-    let uri  = c_string_to_str(info.uri).unwrap();
-
-    // Convert arguments into &str
-    let html = match c_string_to_str(info.content) {
+    if length.is_null() {
+        return std::ptr::null_mut();
+    }
 
-        Ok (s) => s,
-        Err(e) => {
-            eprint!("libalpaca: cannot read html content of {}: {}\n", uri, e);
-            return std::ptr::null_mut(); // return NULL pointer if html cannot be converted to a string
+    // Nothing to request if anything goes wrong
+    unsafe { std::ptr::write(length, 0); }
+
+    ffi_guard( "get_required_files", std::ptr::null_mut(), || {
+
+        let info = match MorphInfo::from_ptr(pinfo) {
+            Ok (info) => info,
+            Err(e)    => {
+                eprintln!("libalpaca: {}", e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        // Convert arguments into &str
+        let html = match c_string_to_str(info.content) {
+
+            Ok (s) => s,
+            Err(e) => {
+                eprintln!("libalpaca: cannot read html content of {}: {}", info.uri(), e);
+                return std::ptr::null_mut(); // return NULL pointer if html cannot be converted to a string
+            }
+        };
+
+        let limits = match info.limits() {
+            Ok (limits) => limits,
+            Err(e)      => {
+                eprintln!("libalpaca: {}", e);
+                return std::ptr::null_mut();
+            }
+        };
+
+        // Nothing is requested for a page beyond the limits, which is served as it is
+        if limits.check_html_size( html.len() ).is_err() {
+            return std::ptr::null_mut();
         }
-    };
-
-    let document = parse::parse_html(html);
 
-    let mut objects;
-
-    if is_html {
-        objects = parse::parse_object_names(&document); // Vector of objects found in the html.
-    } else {
-        objects = parse::parse_css_names(&document);    // Vector of objects found in the html.
-    }
+        let document = parse::parse_html(html);
 
+        let objects = if is_html {
+            parse::parse_object_names(&document) // Vector of objects found in the html.
+        } else {
+            parse::parse_css_names(&document)    // Vector of objects found in the html.
+        };
 
-    // Prepare vector to be returned as char** array back to C
-    let mut object_uris = vec![];
-
-    for obj in &mut *objects {
-        object_uris.push( CString::new( format!("{}", obj.to_owned()) ).unwrap() );
-    }
+        if limits.check_objects( objects.len() ).is_err() {
+            return std::ptr::null_mut();
+        }
 
-    let mut out = object_uris.into_iter()
+        // Prepare vector to be returned as char** array back to C. Uris
+        // that contain a NUL byte cannot be passed to C, so they are skipped.
+        let mut out = objects.into_iter()
+                             .filter_map( |obj| CString::new(obj).ok() )
                              .map( |s| s.into_raw() )
                              .collect::< Vec<_> >();
 
-    out.shrink_to_fit();
+        out.shrink_to_fit();
 
-    let len = out.len();
-    let ptr = out.as_mut_ptr();
+        let len = out.len();
+        let ptr = out.as_mut_ptr();
 
-    std::mem::forget(out);
+        std::mem::forget(out);
 
-    unsafe { std::ptr::write(length, len as c_int); }
+        unsafe { std::ptr::write(length, len as c_int); }
 
-    ptr
+        ptr
+    })
 }
 
 // -----------------------------------------------------------------------------------------------------
@@ -188,32 +234,31 @@ pub fn keep_local_objects(objects: &mut Vec< dom::Object >) {
     objects.retain( |obj| !obj.uri.contains("http:") && !obj.uri.contains("https:") )
 }
 
-pub fn get_file_extension(file_name: &String) -> String {
-    let mut split: Vec<&str> = file_name.split(".").collect();
+pub fn get_file_extension(file_name: &str) -> String {
+    let mut split: Vec<&str> = file_name.split('.').collect();
     split.pop().unwrap().to_owned()
 }
 
-pub fn get_img_data_uri(req_mapper: dom::Map, file_name: &String) -> String {
+// Returns the image as a data uri.
+pub fn get_img_data_uri(resolver: &dyn ResourceResolver, file_name: &str) -> Result<String, AlpacaError> {
 
-    // let base_img = fs::read(file_full_path).expect("Unable to read file");
-    let base_img = dom::get_map_element(req_mapper, file_name.clone());
+    let extent = get_file_extension(file_name).to_lowercase();
 
-    let extent = get_file_extension(&file_name);
-
-    let ext: String;
-
-    match extent.as_str() {
-        "jpg" | "jpeg" => { ext = String::from("jpeg"); }
-        "png"          => { ext = String::from("png");  }
-        "gif"          => { ext = String::from("gif");  }
-        _ => panic!("unknown image type"),
+    let ext = match extent.as_str() {
+        "jpg" | "jpeg" => "jpeg"   ,
+        "png"          => "png"    ,
+        "gif"          => "gif"    ,
+        "webp"         => "webp"   ,
+        "svg"          => "svg+xml",
+        _              => return Err( AlpacaError::Inlining( format!("unknown image type of {}", file_name) ) ),
     };
 
-    let res_base64 = base64::encode(&base_img);
+    let base_img = resolver.content_of(file_name)
+                           .ok_or_else( || AlpacaError::Resolver( format!("content of {} is not available", file_name) ) )?;
 
-    let temp = format!("data:image/{};charset=utf-8;base64,{}", ext, res_base64);
+    let res_base64 = base64::encode(&base_img);
 
-    temp
+    Ok( format!("data:image/{};charset=utf-8;base64,{}", ext, res_base64) )
 }
 
 pub fn copy_file_to_string(fname: &str) -> Result<String, std::io::Error> {
@@ -223,7 +268,7 @@ pub fn copy_file_to_string(fname: &str) -> Result<String, std::io::Error> {
     let display = path.display();
 
     // Open the path in read-only mode, returns `io::Result<File>`
-    let mut file = match File::open(&path) {
+    let mut file = match File::open(path) {
         Err(why) => {
             println!("couldn't open {}: {}", display, why);
             return Err(why)
@@ -238,9 +283,9 @@ pub fn copy_file_to_string(fname: &str) -> Result<String, std::io::Error> {
     // Read the file contents into a string, returns `io::Result<usize>`
     let mut s = String::new();
 
-    match file.read_to_string(&mut s) {
-        Err(why) => panic!("couldn't read {}: {}", display, why),
-        Ok(_)    => ()
+    if let Err(why) = file.read_to_string(&mut s) {
+        println!("couldn't read {}: {}", display, why);
+        return Err(why)
     }
 
     // `file` goes out of scope, and the "hello.txt" file gets closed
@@ -254,7 +299,7 @@ pub fn copy_file_to_string(fname: &str) -> Result<String, std::io::Error> {
 // a 'forgotten' unsafe pointer to the html, for returning to C
 pub fn document_to_c(document: &NodeRef, info: &mut MorphInfo) -> u8 {
     let content = dom::serialize_html(document);
-    return content_to_c(content, info);
+    content_to_c(content, info)
 }
 
 pub fn content_to_c(content: Vec<u8>, info: &mut MorphInfo) -> u8 {
@@ -264,19 +309,36 @@ pub fn content_to_c(content: Vec<u8>, info: &mut MorphInfo) -> u8 {
 
     info.content = buf.as_mut_ptr();
     std::mem::forget(buf);
-    1
+    ALPACA_OK
 }
 
 // Converst a Result<T,E> to Result<T,String> by calling .to_string() on the error
 pub fn stringify_error<T, E:ToString>(res: Result<T,E>) -> Result<T, String> {
-    match res {
-        Ok(res) => return Ok(res),
-        Err(e)  => return Err( e.to_string() ),
+    res.map_err( |e| e.to_string() )
+}
+
+pub fn c_string_to_str<'a>(s: *const u8) -> Result<&'a str, AlpacaError> {
+
+    if s.is_null() {
+        return Err( AlpacaError::Parse( String::from("null string") ) );
     }
+
+    unsafe { CStr::from_ptr(s as *const libc::c_char) }.to_str()
+                                                     .map_err( |e| AlpacaError::Parse( e.to_string() ) )
 }
 
-pub fn c_string_to_str<'a>(s: *const u8) -> Result<&'a str, String> {
-    return stringify_error( unsafe { CStr::from_ptr(s as *const i8) }.to_str() );
+// Runs the body of an extern "C" function, so that a panic does not unwind
+// into nginx. If it panics, "on_panic" is returned instead.
+pub fn ffi_guard<T, F: FnOnce() -> T>(name: &str, on_panic: T, body: F) -> T {
+
+    match panic::catch_unwind( AssertUnwindSafe(body) ) {
+
+        Ok (res) => res,
+        Err(_)   => {
+            eprintln!("libalpaca: {} panicked, the content is left unchanged", name);
+            on_panic
+        }
+    }
 }
 
 // -----------------------------------------------------------------------------------------------------
@@ -286,11 +348,14 @@ pub fn c_string_to_str<'a>(s: *const u8) -> Result<&'a str, String> {
 #[no_mangle]
 pub extern "C" fn free_memory(data: *mut u8, size: usize) {
 
-    let s = unsafe { std::slice::from_raw_parts_mut(data, size) };
-    let s = s.as_mut_ptr();
+    if data.is_null() {
+        return;
+    }
+
+    let s = std::ptr::slice_from_raw_parts_mut(data, size);
 
     unsafe {
-        Box::from_raw(s);
+        drop( Box::from_raw(s) );
     }
 }
 
@@ -1 +1,1308 @@
+extern crate alpaca;
+extern crate base64;
+extern crate brotli;
+extern crate flate2;
+extern crate image;
+extern crate image_webp;
+extern crate rand;
+extern crate rand_chacha;
 
+use alpaca::{ AlpacaError, Carrier, Encoding, FsResolver, MorphConfig, Morpher, OverheadCap, ResourceResolver, TotalSizeSplit };
+use alpaca::deterministic;
+use alpaca::dom::ObjectKind;
+use alpaca::distribution::{ self, Dist };
+use alpaca::registry;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::sync::Arc;
+use std::time::{ Duration, UNIX_EPOCH };
+
+const HTML: &str = "<html><head><link rel=\"stylesheet\" href=\"style.css\"></head>\
+                    <body><img src=\"alpaca.png\"></body></html>";
+
+fn resolver() -> HashMap<String, Vec<u8>> {
+    let mut objects = HashMap::new();
+    objects.insert( String::from("/style.css") , vec![b'a'; 1500] );
+    objects.insert( String::from("/alpaca.png"), vec![0u8 ; 2500] );
+    objects
+}
+
+// The content of a morphed page with the values of its tokens removed.
+fn without_tokens(content: &[u8]) -> String {
+
+    let content = String::from_utf8_lossy(content);
+
+    content.split("v=").enumerate()
+           .map( |(i, part)| if i == 0 { part } else { &part[ part.len().min(43).. ] } )
+           .collect::< Vec<_> >()
+           .join("v=")
+}
+
+#[test]
+fn deterministic_morph_html() {
+
+    let morpher = Morpher::new( MorphConfig::deterministic(5, 1000, 4000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert_eq!( page.target_size % 1000, 0 );
+
+    // 2 real objects and 3 fake ones
+    assert_eq!( page.objects.len(), 5 );
+
+    for obj in &page.objects {
+        let size = obj.target_size.unwrap();
+        assert!( size % 1000 == 0 && size > 0 && size <= 4000 );
+    }
+
+    let html = String::from_utf8(page.content).unwrap();
+    assert_eq!( html.matches("?v=").count(), 5 );
+}
+
+#[test]
+fn deterministic_config_is_checked() {
+    assert!( MorphConfig::deterministic(5, 1000, 1500).is_err() );
+    assert!( MorphConfig::deterministic(5, 0   , 1000).is_err() );
+}
+
+#[test]
+fn padme_bounds_the_overhead() {
+
+    assert_eq!( deterministic::padme(1000), 1024 );
+    assert_eq!( deterministic::padme(1025), 1088 );
+    assert_eq!( deterministic::padme_floor(1100), 1088 );
+
+    for n in 1..(1 << 20) {
+        let rounded = deterministic::padme(n);
+        assert!( rounded >= n && ((rounded - n) as f64) < 0.12 * n as f64 );
+        assert_eq!( deterministic::padme(rounded), rounded );
+    }
+
+    assert!( MorphConfig::padme(5, 0).is_err() );
+
+    let morpher = Morpher::new( MorphConfig::padme(5, 4000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert_eq!( deterministic::padme(page.target_size), page.target_size );
+
+    // padme(2) is 2, so no fake objects are needed
+    assert_eq!( page.objects.len(), 2 );
+
+    for obj in &page.objects {
+        let size = obj.target_size.unwrap();
+        assert_eq!( deterministic::padme(size), size );
+    }
+}
+
+#[test]
+fn objects_are_padded_per_kind() {
+
+    let html = "<html><head><link rel=\"stylesheet\" href=\"style.css\">\
+                <style>@font-face {\n src: url(font.woff2);\n}</style></head>\
+                <body><img src=\"alpaca.png\"></body></html>";
+
+    let mut objects = resolver();
+    objects.insert( String::from("/font.woff2"), vec![0u8; 700] );
+
+    let kind_of = |page: &alpaca::MorphedPage, uri: &str| page.objects.iter().find( |obj| obj.uri == uri ).unwrap().kind;
+    let size_of = |page: &alpaca::MorphedPage, uri: &str| page.objects.iter().find( |obj| obj.uri == uri ).unwrap().target_size.unwrap();
+
+    let config = MorphConfig::deterministic(5, 1000, 4000).unwrap()
+                             .with_kind_obj_size( ObjectKind::CSS , 100, 1000 ).unwrap()
+                             .with_kind_obj_size( ObjectKind::Font, 300, 900  ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", html, &objects ).unwrap();
+
+    assert_eq!( kind_of(&page, "font.woff2"), ObjectKind::Font );
+    assert_eq!( size_of(&page, "style.css") , 1600 );
+    assert_eq!( size_of(&page, "font.woff2"), 900  );
+    assert_eq!( size_of(&page, "alpaca.png"), 3000 );
+
+    // Fake objects are images
+    assert_eq!( page.objects.len(), 5 );
+    assert!( page.objects.iter().skip(3).all( |obj| obj.target_size.unwrap() % 1000 == 0 ) );
+
+    assert!( MorphConfig::deterministic(5, 1000, 4000).unwrap().with_kind_obj_size( ObjectKind::JS, 300, 1000 ).is_err() );
+    assert!( MorphConfig::probabilistic("", "", "").unwrap().with_kind_obj_size( ObjectKind::JS, 100, 1000 ).is_err() );
+
+    let config = MorphConfig::probabilistic( "", "Normal/5.0,0.01", "" ).unwrap()
+                             .with_kind_dist_obj_size( ObjectKind::CSS, "Uniform/1600,1700" ).unwrap()
+                             .with_kind_dist_obj_size( ObjectKind::IMG, "Uniform/9000,9100" ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", html, &objects ).unwrap();
+
+    assert!( (1600..1700).contains( &size_of(&page, "style.css") ) );
+    assert!( page.objects.iter().filter( |obj| obj.kind == ObjectKind::FakeIMG || obj.kind == ObjectKind::IMG )
+                                .all( |obj| (9000..9100).contains( &obj.target_size.unwrap() ) ) );
+
+    // Fonts have no distribution of their own, and dist_obj_size is empty
+    assert_eq!( size_of(&page, "font.woff2"), 700 );
+}
+
+#[test]
+fn object_counts_are_morphed_per_kind() {
+
+    let count = |page: &alpaca::MorphedPage, kind: ObjectKind| page.objects.iter().filter( |obj| obj.kind == kind ).count();
+
+    // Stylesheets are rounded up to 3 on their own, and the image to 5 with the rest
+    let config = MorphConfig::deterministic(5, 1000, 4000).unwrap()
+                             .with_kind_obj_num( ObjectKind::CSS, 3 ).unwrap();
+
+    let page    = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+    let content = String::from_utf8( page.content.clone() ).unwrap();
+
+    assert_eq!( count(&page, ObjectKind::FakeCSS), 2 );
+    assert_eq!( count(&page, ObjectKind::FakeIMG), 4 );
+    assert_eq!( content.len(), page.target_size );
+    assert!( page.objects.iter().filter( |obj| obj.kind == ObjectKind::FakeCSS )
+                                .all( |obj| obj.uri.ends_with(".css") && content.contains( &format!( "{}?v=", obj.uri ) ) ) );
+
+    assert!( MorphConfig::deterministic(5, 1000, 4000).unwrap().with_kind_obj_num( ObjectKind::JS, 0 ).is_err() );
+    assert!( MorphConfig::probabilistic("", "", "").unwrap().with_kind_obj_num( ObjectKind::JS, 2 ).is_err() );
+
+    // Joint counts of css, js, img and font objects
+    let path = std::env::temp_dir().join("alpaca-joint-counts.dist");
+    fs::write( &path, "0.5 0 1 1 0\n\
+                       0.5 2 1 3 1\n" ).unwrap();
+
+    let config = MorphConfig::probabilistic( "", path.to_str().unwrap(), "Uniform/100,200" ).unwrap();
+    let page   = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    // Only the second row dominates the page
+    assert_eq!( count(&page, ObjectKind::FakeCSS) , 1 );
+    assert_eq!( count(&page, ObjectKind::FakeJS)  , 1 );
+    assert_eq!( count(&page, ObjectKind::FakeIMG) , 2 );
+    assert_eq!( count(&page, ObjectKind::FakeFont), 1 );
+    assert_eq!( page.content.len(), page.target_size );
+
+    // The fake script is padded with a comment
+    let morpher = Morpher::new( MorphConfig::default() );
+    let query   = morpher.padding_query("/__alpaca_fake_script.js", 150).unwrap();
+    let padding = morpher.morph_object("/__alpaca_fake_script.js", "application/javascript", 0, &query).unwrap();
+    assert!( padding.starts_with(b"/*") && padding.ends_with(b"*/") );
+}
+
+#[test]
+fn overhead_is_capped_by_resampling() {
+
+    let cap = |max_ratio, max_bytes| OverheadCap { max_ratio, max_bytes };
+
+    let config = MorphConfig::probabilistic( "", "Uniform/2,6", "Uniform/1000,100000" ).unwrap()
+                             .with_overhead_cap( cap( Some(2.0), None ), cap( Some(0.5), Some(3000) ) ).unwrap();
+
+    let morpher = Morpher::new(config);
+
+    for _ in 0..20 {
+
+        let page = morpher.morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+        for obj in &page.objects {
+
+            let limit = match obj.uri.as_str() {
+                "style.css"  => 1500 + 750,
+                "alpaca.png" => 2500 + 1250,
+                _            => 3000,
+            };
+
+            assert!( obj.target_size.unwrap() <= limit );
+        }
+
+        let objects_size: usize = page.objects.iter().map( |obj| obj.target_size.unwrap() ).sum();
+
+        assert_eq!( page.overhead.original, HTML.len() + 4000 );
+        assert_eq!( page.overhead.morphed , page.content.len() + objects_size );
+        assert!( page.overhead.morphed <= 3 * page.overhead.original );
+        assert!( page.overhead.ratio() <= 2.0 );
+    }
+
+    // Fake objects are sampled up to the cap, rounded down to a multiple of obj_size
+    let config = MorphConfig::deterministic(5, 1000, 4000).unwrap()
+                             .with_overhead_cap( OverheadCap::default(), cap( None, Some(2000) ) ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    assert!( page.objects.iter().skip(2).all( |obj| obj.target_size.unwrap() <= 2000 ) );
+
+    // The rounded sizes cannot be drawn again
+    let config = MorphConfig::deterministic(5, 1000, 4000).unwrap()
+                             .with_overhead_cap( OverheadCap::default(), cap( None, Some(100) ) ).unwrap();
+
+    assert!( Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).is_err() );
+    assert!( MorphConfig::default().with_overhead_cap( cap( Some(-1.0), None ), OverheadCap::default() ).is_err() );
+
+    // Unless no fake object is needed
+    let config = MorphConfig::deterministic(2, 1000, 4000).unwrap()
+                             .with_overhead_cap( OverheadCap::default(), cap( None, Some(600) ) ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+    assert_eq!( page.objects.len(), 2 );
+}
+
+#[test]
+fn html_size_is_hit_exactly() {
+
+    // Small html sizes have to be drawn again once the references are rewritten
+    let config = MorphConfig::probabilistic( "Uniform/100,1000", "Uniform/2,6", "Uniform/1000,10000" ).unwrap();
+    let morpher = Morpher::new(config);
+
+    for _ in 0..20 {
+
+        let page = morpher.morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+        let content = String::from_utf8( page.content.clone() ).unwrap();
+
+        assert_eq!( content.len(), page.target_size );
+        assert!( page.objects.iter().skip(2).all( |obj| content.contains( &format!( "{}?v=", obj.uri ) ) ) );
+    }
+
+    let page = Morpher::new( MorphConfig::deterministic(5, 100, 1000).unwrap() ).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert_eq!( page.target_size % 100, 0 );
+
+    // No size of the distribution fits the html
+    let config = MorphConfig::probabilistic( "Uniform/10,50", "Uniform/2,6", "Uniform/1000,10000" ).unwrap();
+
+    assert!( Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).is_err() );
+
+    let mut content = vec![b'a'; 100];
+    assert!( alpaca::pad::get_html_padding( &mut content, 100, &mut rand::thread_rng() ).is_err() );
+}
+
+#[test]
+fn compressed_html_size_is_hit_exactly() {
+
+    assert_eq!( Encoding::negotiate("gzip, deflate, br")    , Encoding::Brotli   );
+    assert_eq!( Encoding::negotiate("gzip;q=1.0, br;q=0.5") , Encoding::Gzip     );
+    assert_eq!( Encoding::negotiate("*, br;q=0")            , Encoding::Gzip     );
+    assert_eq!( Encoding::negotiate("deflate")              , Encoding::Identity );
+
+    let config = MorphConfig::probabilistic( "Uniform/100,5000", "Uniform/2,6", "Uniform/1000,10000" ).unwrap();
+    let morpher = Morpher::new(config);
+
+    for &encoding in &[Encoding::Gzip, Encoding::Brotli] {
+
+        for _ in 0..5 {
+
+            let page = morpher.morph_html_encoded( "/index.html", HTML, encoding, &resolver() ).unwrap();
+
+            assert_eq!( page.encoding, encoding );
+            assert_eq!( page.content.len(), page.target_size );
+
+            let mut html = String::new();
+
+            match encoding {
+                Encoding::Gzip => flate2::read::GzDecoder::new( page.content.as_slice() ).read_to_string(&mut html).unwrap(),
+                _              => brotli::Decompressor::new( page.content.as_slice(), 4096 ).read_to_string(&mut html).unwrap(),
+            };
+
+            assert!( html.contains("style.css?v=") && html.ends_with("-->") );
+        }
+    }
+
+    let page = Morpher::new( MorphConfig::deterministic(5, 100, 1000).unwrap() ).morph_html_encoded( "/index.html", HTML, Encoding::Gzip, &resolver() ).unwrap();
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert_eq!( page.target_size % 100, 0 );
+}
+
+#[test]
+fn morph_object_padding() {
+
+    let morpher = Morpher::new( MorphConfig::default() );
+
+    let query   = morpher.padding_query("/alpaca.png", 300).unwrap();
+    let padding = morpher.morph_object("/alpaca.png", "image/png", 100, &query).unwrap();
+    assert_eq!( padding.len(), 200 );
+
+    let query   = format!( "v=2&{}", morpher.padding_query("/style.css", 300).unwrap() );
+    let padding = morpher.morph_object("/style.css", "text/css", 100, &query).unwrap();
+    assert!( padding.starts_with(b"/*") && padding.ends_with(b"*/") );
+
+    let query = morpher.padding_query("/alpaca.png", 50).unwrap();
+    assert!( morpher.morph_object("/alpaca.png", "image/png", 100, &query).is_err() );
+}
+
+#[test]
+fn images_are_padded_inside_their_format() {
+
+    let morpher = Morpher::new( MorphConfig::default() );
+    let picture = image::DynamicImage::ImageRgb8( image::RgbImage::from_pixel( 8, 8, image::Rgb([200, 120, 40]) ) );
+
+    let mut images = Vec::new();
+
+    for format in [ image::ImageOutputFormat::Png, image::ImageOutputFormat::Jpeg(80), image::ImageOutputFormat::Gif ] {
+
+        let mut content = Vec::new();
+        picture.write_to(&mut content, format).unwrap();
+        images.push(content);
+    }
+
+    // A 1x1 lossy WebP
+    images.push( base64::decode("UklGRiIAAABXRUJQVlA4IBYAAAAwAQCdASoBAAEADsD+JaQAA3AAAAAA").unwrap() );
+
+    for content in &images {
+
+        for &pad_len in &[12, 13, 259, 260, 70000] {
+
+            let query  = morpher.padding_query( "/alpaca.img", content.len() + pad_len ).unwrap();
+            let padded = morpher.pad_object("/alpaca.img", "image/png", content, &query).unwrap();
+
+            assert_eq!( padded.len(), content.len() + pad_len );
+            assert!( image::load_from_memory(&padded).is_ok() );
+        }
+    }
+
+    // Padding too small for a chunk is added after the image's end
+    for &pad_len in &[1, 5, 11] {
+
+        let query  = morpher.padding_query( "/alpaca.img", images[0].len() + pad_len ).unwrap();
+        let padded = morpher.pad_object("/alpaca.img", "image/png", &images[0], &query).unwrap();
+
+        assert_eq!( padded.len(), images[0].len() + pad_len );
+        assert!( padded.starts_with(&images[0]) );
+    }
+
+    // Background images are planned with room for a chunk
+    assert_eq!( alpaca::pad::min_padding(ObjectKind::CssImg), alpaca::pad::min_padding(ObjectKind::IMG) );
+
+    // Other objects are padded after their end
+    let query  = morpher.padding_query("/alpaca.png", 300).unwrap();
+    let padded = morpher.pad_object("/alpaca.png", "image/png", &[0u8; 100], &query).unwrap();
+    assert_eq!( padded.len(), 300 );
+    assert!( padded.starts_with(&[0u8; 100]) );
+
+    // and streamed objects only need their size for it
+    let query = morpher.padding_query("/alpaca.mp4", 100_000_300).unwrap();
+    assert_eq!( morpher.morph_object("/alpaca.mp4", "video/mp4", 100_000_000, &query).unwrap().len(), 300 );
+}
+
+#[test]
+fn fake_objects_are_generated_valid() {
+
+    let morpher = Morpher::new( MorphConfig::default() );
+
+    for &target_size in &[128, 200, 5000, 100000] {
+
+        for mime in &["image/png", "image/jpeg", "image/gif"] {
+
+            let query = format!( "{}&i=1", morpher.padding_query("/__alpaca_fake_image.png", target_size).unwrap() );
+
+            match morpher.fake_object("/__alpaca_fake_image.png", mime, &query) {
+
+                Ok (image) => {
+                    assert_eq!( image.len(), target_size );
+                    assert!( image::load_from_memory(&image).is_ok() );
+                },
+                // JPEG tables alone take more than the smallest sizes
+                Err(_) => assert!( *mime == "image/jpeg" && target_size < 1000 ),
+            }
+        }
+
+        let query   = morpher.padding_query("/__alpaca_fake_image.webp", target_size).unwrap();
+        let image   = morpher.fake_object("/__alpaca_fake_image.webp", "image/webp", &query).unwrap();
+        let decoder = image_webp::WebPDecoder::new( std::io::Cursor::new(&image) ).unwrap();
+
+        assert_eq!( image.len(), target_size );
+        assert!( decoder.dimensions().0 >= 1 );
+
+        let query = morpher.padding_query("/__alpaca_fake_style.css", target_size).unwrap();
+        let style = morpher.fake_object("/__alpaca_fake_style.css", "text/css", &query).unwrap();
+        assert!( style.len() == target_size && style.starts_with(b"/*") && style.ends_with(b"*/") );
+    }
+
+    // The same query gives the same object with a secret
+    let secret = MorphConfig::default().with_secret( b"key".to_vec(), 0 ).unwrap();
+    let keyed  = Morpher::new(secret);
+    let query  = keyed.padding_query("/__alpaca_fake_image.png", 3000).unwrap();
+
+    assert_eq!( keyed.fake_object("/__alpaca_fake_image.png", "image/png", &query).unwrap(),
+                keyed.fake_object("/__alpaca_fake_image.png", "image/png", &query).unwrap() );
+
+    assert!( alpaca::fake::generate_fake_object( ObjectKind::FakeIMG, 100000, &mut rand::thread_rng() ).is_ok() );
+}
+
+#[test]
+fn fake_objects_follow_the_page_uris() {
+
+    let html = "<html><head><link rel=\"stylesheet\" href=\"static/css/main.css\"></head>\
+                <body><div><p>alpaca</p></div><div><img src=\"static/img/photo-of-alpacas.jpg\"></div></body></html>";
+
+    let mut objects = HashMap::new();
+    objects.insert( String::from("/static/css/main.css")            , vec![b'a'; 1500] );
+    objects.insert( String::from("/static/img/photo-of-alpacas.jpg"), vec![0u8 ; 2500] );
+
+    let fakes = |page: &alpaca::MorphedPage| page.objects.iter().filter( |obj| obj.kind.is_fake() ).map( |obj| obj.uri.clone() ).collect::< Vec<_> >();
+
+    let mut scattered = false;
+
+    for _ in 0..5 {
+
+        let page    = Morpher::new( MorphConfig::deterministic(10, 1000, 4000).unwrap() ).morph_html( "/index.html", html, &objects ).unwrap();
+        let content = String::from_utf8( page.content.clone() ).unwrap();
+
+        assert_eq!( content.len(), page.target_size );
+        assert!( !content.contains("__alpaca_fake") && !content.contains("i=") && !content.contains("visibility") );
+
+        for uri in fakes(&page) {
+            assert!( uri.starts_with("static/img/") && uri.ends_with(".jpg") && uri.len() == "static/img/photo-of-alpacas.jpg".len(), "fake uri {}", uri );
+            assert!( content.contains( &format!( "{}?v=", uri ) ) );
+
+            scattered |= content.find(&uri) < content.rfind("</div>");
+        }
+    }
+
+    assert!(scattered);
+
+    // Only background images
+    let config  = MorphConfig::deterministic(10, 1000, 4000).unwrap().with_fake_carriers( &[Carrier::Background] ).unwrap();
+    let page    = Morpher::new(config).morph_html( "/index.html", html, &objects ).unwrap();
+    let content = String::from_utf8( page.content.clone() ).unwrap();
+
+    assert_eq!( content.len(), page.target_size );
+    assert_eq!( content.matches("<div style=\"background-image:url(static/img/").count(), 8 );
+
+    assert!( MorphConfig::default().with_fake_carriers(&[]).is_err() );
+    assert_eq!( Carrier::from_mask(0).unwrap().len(), 4 );
+    assert_eq!( Carrier::from_mask(1 | 4 | 8).unwrap(), vec![Carrier::Prefetch, Carrier::Preload] );
+    assert!( Carrier::from_mask(32).is_err() );
+}
+
+#[test]
+fn small_fake_images_are_not_jpegs() {
+
+    let html = "<html><body><img src=\"static/img/photo-of-alpacas.jpg\"></body></html>";
+
+    let mut objects = HashMap::new();
+    objects.insert( String::from("/static/img/photo-of-alpacas.jpg"), vec![0u8 ; 300] );
+
+    let morpher = Morpher::new( MorphConfig::deterministic(5, 200, 400).unwrap() );
+    let page    = morpher.morph_html( "/index.html", html, &objects ).unwrap();
+    let content = String::from_utf8( page.content.clone() ).unwrap();
+
+    let fakes: Vec<_> = page.objects.iter().filter( |obj| obj.kind.is_fake() ).collect();
+    assert!( !fakes.is_empty() );
+
+    // Each is served as the PNG its uri names, whose size it has
+    for obj in fakes {
+
+        assert!( obj.uri.starts_with("static/img/") && obj.uri.ends_with(".png"), "fake uri {}", obj.uri );
+
+        let start = content.find( &format!( "{}?v=", obj.uri ) ).unwrap() + obj.uri.len() + 1;
+        let query = &content[ start..start + 45 ];
+        let image = morpher.fake_object( &format!( "/{}", obj.uri ), "image/png", query ).unwrap();
+
+        assert_eq!( Some( image.len() ), obj.target_size );
+        assert!( image::load_from_memory(&image).is_ok() );
+    }
+}
+
+#[test]
+fn missing_objects_are_skipped() {
+
+    let mut objects = resolver();
+    objects.remove("/alpaca.png");
+
+    let morpher = Morpher::new( MorphConfig::deterministic(5, 1000, 4000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", HTML, &objects ).unwrap();
+
+    // The image is left as is, and replaced by a fake object
+    assert_eq!( page.objects.len(), 5 );
+    assert!( page.objects.iter().all( |obj| obj.uri != "alpaca.png" ) );
+    assert!( String::from_utf8(page.content).unwrap().contains("src=\"alpaca.png\"") );
+}
+
+#[test]
+fn fs_resolver_stays_under_root() {
+
+    let root = std::env::temp_dir().join("alpaca-fs-resolver");
+    fs::create_dir_all( root.join("css") ).unwrap();
+    fs::write( root.join("css/style.css"), b"body {}" ).unwrap();
+
+    let resolver = FsResolver::new(&root);
+
+    assert_eq!( resolver.size_of("/css/style.css"), Some(7) );
+    assert_eq!( resolver.content_of("/css/style.css"), Some( b"body {}".to_vec() ) );
+    assert_eq!( resolver.size_of("/css/missing.css"), None );
+    assert_eq!( resolver.size_of("/css/../../etc/passwd"), None );
+}
+
+#[test]
+fn invalid_distributions_are_reported() {
+
+    let dist_file = std::env::temp_dir().join("alpaca-invalid.dist");
+    fs::write( &dist_file, "0.5 100\n0.5 abc\n" ).unwrap();
+
+    match MorphConfig::probabilistic( dist_file.to_str().unwrap(), "", "" ) {
+        Err( AlpacaError::Distribution(msg) ) => assert!( msg.contains("line 2") ),
+        _                                     => panic!("expected a distribution error"),
+    }
+
+    assert!( matches!( MorphConfig::probabilistic("Normal/1.0,x", "", ""), Err( AlpacaError::Distribution(_) ) ) );
+    assert!( matches!( MorphConfig::probabilistic("Normal/1.0,-1.0", "", ""), Err( AlpacaError::Distribution(_) ) ) );
+}
+
+#[test]
+fn morph_errors_have_codes() {
+
+    let morpher = Morpher::new( MorphConfig::default() );
+
+    let err = morpher.morph_object("/alpaca.png", "image/png", 100, "v=2").unwrap_err();
+    assert_eq!( err.code(), AlpacaError::Parse( String::new() ).code() );
+
+    let err = morpher.morph_object("/style.css", "text/css", 100, &morpher.padding_query("/style.css", 100).unwrap()).unwrap_err();
+    assert!( matches!( err, AlpacaError::Padding(_) ) );
+}
+
+#[test]
+fn empty_style_does_not_panic() {
+
+    let html    = "<html><head><style></style></head><body><img src=\"alpaca.png\"></body></html>";
+    let morpher = Morpher::new( MorphConfig::deterministic(2, 1000, 2000).unwrap() );
+
+    assert!( morpher.morph_html( "/index.html", html, &resolver() ).is_ok() );
+}
+
+fn total_size_of(split: TotalSizeSplit) {
+
+    let dir = std::env::temp_dir();
+    fs::write( dir.join("alpaca-total.dist"), "1.0 50000\n" ).unwrap();
+    fs::write( dir.join("alpaca-html.dist") , "0.5 2000\n0.5 3000\n" ).unwrap();
+
+    let config = MorphConfig::probabilistic( dir.join("alpaca-html.dist").to_str().unwrap(), "Normal/4.5,0.01", "Normal/5000.0,100.0" ).unwrap()
+                             .with_total_size( dir.join("alpaca-total.dist").to_str().unwrap(), split ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    // The objects and the html share the sampled total
+    let obj_total: usize = page.objects.iter().map( |obj| obj.target_size.unwrap() ).sum();
+
+    assert_eq!( page.objects.len(), 4 );
+    assert_eq!( page.target_size + obj_total, 50000 );
+    assert_eq!( page.content.len(), page.target_size );
+
+    for obj in &page.objects {
+        match obj.uri.as_str() {
+            "style.css"  => assert!( obj.target_size.unwrap() >= 1504 ),
+            "alpaca.png" => assert!( obj.target_size.unwrap() >= 2500 ),
+            _            => assert!( obj.target_size.unwrap() >= 1 ),
+        }
+    }
+}
+
+#[test]
+fn total_size_is_split_proportionally() {
+    total_size_of(TotalSizeSplit::Proportional);
+}
+
+#[test]
+fn total_size_is_split_conditionally() {
+    total_size_of(TotalSizeSplit::Conditional);
+}
+
+#[test]
+fn page_is_morphed_to_a_dominating_profile() {
+
+    let profiles = std::env::temp_dir().join("alpaca-pages.profile");
+
+    // Only the last profile has a large enough html, and room for both a css and an image
+    fs::write( &profiles, "0.2 100 2 2000:css 3000:img\n\
+                           0.3 9000 2 5000:img 5000:img\n\
+                           0.5 9000 4 1600:css 2600:img 4000:img 700:js\n" ).unwrap();
+
+    let config = MorphConfig::probabilistic("", "", "").unwrap()
+                             .with_page_profiles( profiles.to_str().unwrap() ).unwrap();
+
+    let page = Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+
+    assert_eq!( page.target_size, 9000 );
+    assert_eq!( page.content.len(), 9000 );
+
+    let mut sizes: Vec<usize> = page.objects.iter().map( |obj| obj.target_size.unwrap() ).collect();
+    sizes.sort_unstable();
+
+    assert_eq!( sizes, vec![700, 1600, 2600, 4000] );
+    assert_eq!( page.objects.iter().find( |obj| obj.uri == "style.css" ).unwrap().target_size, Some(1600) );
+
+    // A single dominating page of a large crawl is still chosen
+    fs::write( &profiles, "0.999999 100 2 2000:css 3000:img\n\
+                           0.000001 9000 4 1600:css 2600:img 4000:img 700:js\n" ).unwrap();
+
+    let config = MorphConfig::probabilistic("", "", "").unwrap()
+                             .with_page_profiles( profiles.to_str().unwrap() ).unwrap();
+
+    assert_eq!( Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap().target_size, 9000 );
+
+    // Probabilities are checked when the profiles are loaded
+    let error = |data: &str| match alpaca::profile::PageProfiles::from( { fs::write(&profiles, data).unwrap(); profiles.to_str().unwrap() } ) {
+        Err( AlpacaError::Distribution(msg) ) => msg,
+        _                                     => panic!("{:?} accepted", data),
+    };
+
+    assert!( error("0.5 100 0\nNaN 200 0\n").contains("line 2") );
+    assert!( error("inf 100 0\n").contains("line 1") );
+    assert!( error("0.5 100 0\n-0.5 200 0\n").contains("line 2") );
+    assert!( error("0 100 0\n0 200 0\n").contains("sum up to 0") );
+
+    // A compressed html is judged by its compressed size
+    fs::write( &profiles, "1.0 5000 2 1600:css 2600:img\n" ).unwrap();
+
+    let morpher = Morpher::new( MorphConfig::probabilistic("", "", "").unwrap()
+                                              .with_page_profiles( profiles.to_str().unwrap() ).unwrap() );
+    let html    = HTML.replace( "<body>", &format!( "<body><p>{}</p>", "alpaca ".repeat(2000) ) );
+
+    assert!( morpher.morph_html( "/index.html", &html, &resolver() ).is_err() );
+
+    let page = morpher.morph_html_encoded( "/index.html", &html, Encoding::Gzip, &resolver() ).unwrap();
+    assert_eq!( page.content.len(), 5000 );
+}
+
+#[test]
+fn keyed_morphs_are_reproducible() {
+
+    let keyed = || MorphConfig::probabilistic("Normal/9000.0,2000.0", "Normal/6.5,2.0", "Normal/3000.0,800.0").unwrap()
+                               .with_secret( b"secret".to_vec(), 0 ).unwrap();
+
+    let first  = Morpher::new( keyed() ).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+    let second = Morpher::new( keyed() ).morph_html( "/index.html", HTML, &resolver() ).unwrap();
+    let other  = Morpher::new( keyed() ).morph_html( "/other.html", HTML, &resolver() ).unwrap();
+
+    // Only the tokens are issued afresh
+    assert_eq!( without_tokens(&first.content), without_tokens(&second.content) );
+    assert_ne!( without_tokens(&first.content), without_tokens(&other.content) );
+
+    let morpher = Morpher::new( MorphConfig::default().with_secret( b"secret".to_vec(), 0 ).unwrap() );
+    let query   = morpher.padding_query("/alpaca.png", 300).unwrap();
+
+    assert_eq!( morpher.morph_object("/alpaca.png", "image/png", 100, &query).unwrap(),
+                morpher.morph_object("/alpaca.png", "image/png", 100, &query).unwrap() );
+}
+
+#[test]
+fn distributions_are_cached_until_modified() {
+
+    let path = std::env::temp_dir().join("alpaca-cached.dist");
+    let spec = path.to_str().unwrap();
+
+    fs::write( &path, "0.5 100\n0.5 200\n" ).unwrap();
+
+    let first  = registry::distribution(spec).unwrap();
+    let second = registry::distribution(spec).unwrap();
+
+    assert!( Arc::ptr_eq(&first, &second) );
+    assert_eq!( first.cumulative, vec![0.5, 1.0] );
+
+    // A modified file is reloaded, but a malformed one keeps the previous version
+    let touch = |secs| fs::File::options().write(true).open(&path).unwrap()
+                                        .set_modified( UNIX_EPOCH + Duration::from_secs(secs) ).unwrap();
+
+    fs::write( &path, "1.0 300\n" ).unwrap();
+    touch(1_000_000);
+
+    let reloaded = registry::distribution(spec).unwrap();
+    assert_eq!( reloaded.values, Some( vec![ vec![300] ] ) );
+
+    fs::write( &path, "1.0 abc\n" ).unwrap();
+    touch(2_000_000);
+
+    assert!( Arc::ptr_eq( &reloaded, &registry::distribution(spec).unwrap() ) );
+}
+
+#[test]
+fn truncated_sampling_reaches_the_tail() {
+
+    let mut rng = rand::thread_rng();
+
+    // Far beyond what rejection sampling could reach
+    for spec in &[ "Normal/100,1", "LogNormal/3,0.1", "Exp/0.5", "Gamma/2,10", "Binomial/1000,0.01" ] {
+
+        let dist = Dist::from(spec).unwrap();
+
+        for _ in 0..100 {
+            assert!( distribution::sample_ge(&dist, 200, &mut rng).unwrap() >= 200 );
+
+            let v = distribution::sample_between(&dist, 150, 160, &mut rng).unwrap();
+            assert!( (150..=160).contains(&v) );
+        }
+    }
+
+    // Exp is memoryless: the excess over the bound keeps the same mean
+    let exp  = Dist::from("Exp/0.01").unwrap();
+    let mean = (0..4000).map( |_| distribution::sample_ge(&exp, 10_000, &mut rng).unwrap() - 10_000 ).sum::<usize>() as f64 / 4000.0;
+
+    assert!( (mean - 99.5).abs() < 10.0, "mean {}", mean );
+
+    // No mass in range
+    assert!( distribution::sample_ge( &Dist::from("Binomial/10,0.5").unwrap(), 11, &mut rng ).is_err() );
+}
+
+#[test]
+fn distribution_catalog_is_validated() {
+
+    let mut rng = rand::thread_rng();
+
+    for spec in &[ "Poisson/50", "Uniform/100,200", "Pareto/100,3", "Weibull/100,2", "Zipf/1000,1.2", "Geometric/0.1", "NegBinomial/5,0.5" ] {
+
+        let dist = Dist::from(spec).unwrap();
+        let v    = distribution::sample_between(&dist, 120, 180, &mut rng).unwrap();
+
+        assert!( (120..=180).contains(&v), "{} sampled {}", spec, v );
+    }
+
+    // Uniform values are rounded down, so they stay below max
+    let uniform = Dist::from("Uniform/10,12").unwrap();
+    assert!( (0..100).all( |_| (10..12).contains( &distribution::sample_ge(&uniform, 0, &mut rng).unwrap() ) ) );
+
+    // Negative values are not clamped to 0 but excluded
+    let normal = Dist::from("Normal/-100,10").unwrap();
+    assert!( (0..100).all( |_| distribution::sample_ge(&normal, 0, &mut rng).unwrap() < 100 ) );
+
+    for spec in &[ "Poisson/-1", "Uniform/2,1", "Zipf/0,1", "Zipf/1.5,1", "Geometric/0", "NegBinomial/5,1.5", "Weibull/1", "Normal/-10,0" ] {
+        assert!( matches!( Dist::from(spec), Err( AlpacaError::Distribution(_) ) ), "{} accepted", spec );
+    }
+}
+
+#[test]
+fn bins_and_mixtures_are_sampled_in_range() {
+
+    let mut rng = rand::thread_rng();
+    let dir     = std::env::temp_dir();
+
+    let bins  = dir.join("alpaca-bins.dist");
+    let joint = dir.join("alpaca-joint-bins.dist");
+
+    fs::write( &bins , "@bins\n0.5 0 1000\n0.5 1000 5000\n" ).unwrap();
+    fs::write( &joint, "@bins\n0.5 0 1000 10 20\n0.5 1000 2000 30 40\n" ).unwrap();
+
+    let bins = Dist::from( bins.to_str().unwrap() ).unwrap();
+
+    for _ in 0..100 {
+        assert!( distribution::sample_ge(&bins, 0, &mut rng).unwrap() < 5000 );
+        assert!( (2000..5000).contains( &distribution::sample_ge(&bins, 2000, &mut rng).unwrap() ) );
+    }
+
+    let (a, b) = distribution::sample_pair_ge( &Dist::from( joint.to_str().unwrap() ).unwrap(), (900, 25), &mut rng ).unwrap();
+    assert!( (1000..2000).contains(&a) && (30..40).contains(&b) );
+
+    // Without @bins, two values per row are a pair, and not a bin
+    let pairs = dir.join("alpaca-pairs.dist");
+    fs::write( &pairs, "0.5 0 1000\n0.5 1000 5000\n" ).unwrap();
+
+    let pairs = Dist::from( pairs.to_str().unwrap() ).unwrap();
+
+    assert!( distribution::sample_ge(&pairs, 0, &mut rng).is_err() );
+    assert!( distribution::sample_pair_ge(&bins, (0, 0), &mut rng).is_err() );
+    assert_eq!( distribution::sample_pair_ge(&pairs, (500, 2000), &mut rng).unwrap(), (1000, 5000) );
+
+    // The lower bound only leaves mass to the LogNormal component
+    let mix = Dist::from( &format!( "Mix(0.3*LogNormal/8,1; 0.7*{})", dir.join("alpaca-bins.dist").to_str().unwrap() ) ).unwrap();
+
+    for _ in 0..100 {
+        assert!( distribution::sample_ge(&mix, 10_000, &mut rng).unwrap() >= 10_000 );
+    }
+
+    let nested = Dist::from("Mix(1*Mix(1*Exp/0.1; 1*Poisson/20); 2*Uniform/100,200)").unwrap();
+    assert!( distribution::sample_between(&nested, 150, 160, &mut rng).is_ok() );
+
+    for spec in &[ "Mix()", "Mix(0.5 Exp/1)", "Mix(-1*Exp/1)", "Mix(1*Exp/1; 1*Joint)" ] {
+        assert!( matches!( Dist::from(spec), Err( AlpacaError::Distribution(_) ) ), "{} accepted", spec );
+    }
+}
+
+#[test]
+fn versioned_dist_files_are_validated() {
+
+    let path = std::env::temp_dir().join("alpaca-versioned.dist");
+    let spec = path.to_str().unwrap();
+
+    fs::write( &path, "# html sizes\n\
+                       @version 1\n\
+                       @columns prob size\n\
+                       @units KB\n\
+                       @description sizes of a crawl # of the top sites\n\
+                       @normalize\n\
+                       \n\
+                       2 1.5e1   # 15 KB\n\
+                       6 2.5\n" ).unwrap();
+
+    let dist = Dist::from(spec).unwrap();
+
+    assert_eq!( dist.values, Some( vec![ vec![15_000], vec![2_500] ] ) );
+    assert_eq!( dist.params, vec![0.25, 0.75] );
+
+    let error = |data: &str| match Dist::from( { fs::write(&path, data).unwrap(); spec } ) {
+        Err( AlpacaError::Distribution(msg) ) => msg,
+        _                                     => panic!("{:?} accepted", data),
+    };
+
+    assert!( error("0.5 100\n0.4 200\n").contains("sum up to 0.9") );
+    assert!( error("0.5 100\n0.5 200 300\n").contains("line 2, column 9") );
+    assert!( error("0.5 100\n0.5 -2\n").contains("line 2, column 5") );
+    assert!( error("@version 2\n1 100\n").contains("line 1, column 10") );
+    assert!( error("@columns prob a b\n1 100\n").contains("line 2") );
+    assert!( error("1 100\n@normalize\n").contains("line 2, column 1") );
+    assert!( error("# nothing\n\n").contains("empty") );
+    assert!( error("@bins\n1 100\n").contains("line 2, column 3") );
+    assert!( error("@bins\n1 100 200 300 300\n").contains("empty bin") );
+}
+
+#[test]
+fn large_custom_distributions_are_sampled_quickly() {
+
+    let mut rng = rand::thread_rng();
+    let dir     = std::env::temp_dir();
+    let n       = 100_000;
+
+    let single = dir.join("alpaca-large.dist");
+    let joint  = dir.join("alpaca-large-joint.dist");
+
+    fs::write( &single, (1..=n).map( |v| format!("1e-5 {}\n", v) ).collect::<String>() ).unwrap();
+    fs::write( &joint , (1..=n).map( |v| format!("1e-5 {} {}\n", v, n - v) ).collect::<String>() ).unwrap();
+
+    let single = Dist::from( single.to_str().unwrap() ).unwrap();
+    let joint  = Dist::from( joint .to_str().unwrap() ).unwrap();
+
+    // 10000 linear scans of 100k rows would take far too long
+    for _ in 0..10_000 {
+
+        assert!( distribution::sample_ge(&single, n - 10, &mut rng).unwrap() >= n - 10 );
+        assert!( (50..=59).contains( &distribution::sample_between(&single, 50, 59, &mut rng).unwrap() ) );
+
+        let (a, b) = distribution::sample_pair_ge( &joint, (50_000, 40_000), &mut rng ).unwrap();
+        assert!( (50_000..=60_000).contains(&a) && b == n - a );
+
+        // Only 10 of the rows are above both bounds
+        let (a, b) = distribution::sample_pair_ge( &joint, (0, n - 10), &mut rng ).unwrap();
+        assert!( a <= 10 && b == n - a );
+    }
+
+    // Without a bound, values are drawn from all rows
+    let mean = (0..10_000).map( |_| distribution::sample_ge(&single, 0, &mut rng).unwrap() ).sum::<usize>() / 10_000;
+    assert!( (45_000..55_000).contains(&mean) );
+
+    // Overlapping bins cannot be sorted, but are still sampled
+    let overlapping = dir.join("alpaca-overlapping.dist");
+    fs::write( &overlapping, "@bins\n0.5 0 100\n0.5 50 150\n" ).unwrap();
+
+    let overlapping = Dist::from( overlapping.to_str().unwrap() ).unwrap();
+    assert!( (120..150).contains( &distribution::sample_ge(&overlapping, 120, &mut rng).unwrap() ) );
+}
+
+#[test]
+fn pair_tables_match_a_scan_of_the_rows() {
+
+    use alpaca::table::SortedPairs;
+    use rand::{ Rng, SeedableRng };
+
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(12);
+
+    for &n in &[1, 2, 7, 64, 100] {
+
+        let values: Vec<Vec<usize>> = (0..n).map( |_| vec![ rng.gen_range(0, 20), rng.gen_range(0, 20) ] ).collect();
+        let probs : Vec<f64>        = (0..n).map( |i| if i % 5 == 0 { 0.0 } else { rng.gen::<f64>() } ).collect();
+        let table                   = SortedPairs::new(&values, &probs);
+
+        for a in 0..21 {
+            for b in 0..21 {
+
+                let above    = |row: &Vec<usize>| row[0] >= a && row[1] >= b;
+                let expected = values.iter().zip(&probs).filter( |(row, _)| above(row) ).map( |(_, p)| p ).sum::<f64>();
+
+                assert!( (table.mass( &values, (a, b) ) - expected).abs() < 1e-9 );
+
+                match table.sample( &values, (a, b), &mut rng ) {
+                    Some(row) => assert!( above(&values[row]) && probs[row] > 0.0 ),
+                    None      => assert!( expected <= 0.0 ),
+                }
+            }
+        }
+    }
+}
+
+#[test]
+fn corpus_fits_round_trip_to_dist_files() {
+
+    use alpaca::fit;
+    use rand::SeedableRng;
+
+    let mut rng = rand_chacha::ChaCha20Rng::seed_from_u64(7);
+    let html    = Dist::from("LogNormal/9,0.5").unwrap();
+
+    let csv = (0..500).fold( String::from("html_size,obj_sizes\n"), |csv, i| {
+        csv + &format!( "{},{};{}\n", distribution::sample_ge(&html, 0, &mut rng).unwrap(), 1000 + i, 2000 + i )
+    });
+
+    let pages = fit::from_csv(&csv).unwrap();
+    assert_eq!( pages.len(), 500 );
+    assert_eq!( pages[3].obj_sizes, vec![1003, 2003] );
+
+    let samples = fit::samples( &pages, &|p| p.html_size );
+    let fits    = fit::fit_parametric(&samples);
+
+    assert!( fits[0].spec.starts_with("LogNormal/"), "best fit {}", fits[0].spec );
+    assert!( fits.windows(2).all( |w| w[0].ks <= w[1].ks ) );
+
+    // The generated files load, and sample from the corpus
+    let dir = std::env::temp_dir();
+
+    let files = vec![
+        ( "alpaca-fit-empirical.dist", fit::empirical(&samples, "html_size", "HTML sizes", &fits) ),
+        ( "alpaca-fit-histogram.dist", fit::histogram(&samples, 20, "html_size", "HTML sizes", &fits) ),
+    ];
+
+    for (name, data) in files {
+        let path = dir.join(name);
+        fs::write(&path, data).unwrap();
+
+        let dist = Dist::from( path.to_str().unwrap() ).unwrap();
+        let max  = samples.iter().map( |s| s.0 ).max().unwrap();
+
+        assert!( distribution::sample_ge(&dist, 0, &mut rng).unwrap() <= max );
+    }
+
+    let joint = dir.join("alpaca-fit-joint.dist");
+    fs::write( &joint, fit::joint(&pages, Some(10), "joint") ).unwrap();
+
+    let (_, obj_size) = distribution::sample_pair_ge( &Dist::from( joint.to_str().unwrap() ).unwrap(), (0, 3500), &mut rng ).unwrap();
+    assert!( (3500..=4000).contains(&obj_size) );
+
+    assert!( matches!( fit::from_jsonl("{\"html_size\": 10}\n{\"obj_sizes\": []}\n"), Err( AlpacaError::Parse(_) ) ) );
+}
+
+#[test]
+fn padding_tokens_are_authenticated() {
+
+    use alpaca::token::{ self, TokenKey };
+
+    let morpher = Morpher::new( MorphConfig::deterministic(5, 1000, 4000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", HTML, &resolver() ).unwrap();
+    let html    = String::from_utf8( page.content.clone() ).unwrap();
+
+    // The token of the page verifies for the path requested, and the size is not in the clear
+    let start = html.find("style.css?v=").unwrap() + "style.css?".len();
+    let query = &html[ start..start + token::TOKEN_PARAM_SIZE ];
+    let size  = page.objects.iter().find( |obj| obj.uri == "style.css" ).unwrap().target_size.unwrap();
+
+    assert!( !query.contains( &size.to_string() ) );
+    assert_eq!( morpher.morph_object("/style.css", "text/css", 1500, query).unwrap().len(), size - 1500 );
+
+    // Nor for another path, a forged token or a size in the clear
+    let mut forged = query.to_string().into_bytes();
+    forged[10] = if forged[10] == b'A' { b'B' } else { b'A' };
+
+    assert!( morpher.morph_object("/blog/style.css", "text/css", 1500, query).is_err() );
+    assert!( morpher.morph_object("/style.css", "text/css", 1500, std::str::from_utf8(&forged).unwrap()).is_err() );
+    assert!( morpher.morph_object("/style.css", "text/css", 1500, "alpaca-padding=4000").is_err() );
+
+    // Expired tokens, and the tokens of another key
+    let key   = TokenKey::from_secret(b"secret");
+    let valid = key.issue( "/alpaca.png", 3000, token::now() + 60, &mut rand::thread_rng() ).unwrap();
+    let old   = key.issue( "/alpaca.png", 3000, token::now() - 1 , &mut rand::thread_rng() ).unwrap();
+
+    assert_eq!( key.verify( "/alpaca.png", &valid, token::now() ), Some(3000) );
+    assert_eq!( key.verify( "/alpaca.png", &old  , token::now() ), None );
+    assert_eq!( TokenKey::from_secret(b"other").verify( "/alpaca.png", &valid, token::now() ), None );
+    assert!( MorphConfig::default().with_token_ttl(0).is_err() );
+
+    // References are resolved as nginx resolves the requests
+    assert_eq!( token::resolve_path( "/blog/index.html", "../img/a%20b.png?x=1" ), "/img/a b.png" );
+    assert_eq!( token::resolve_path( "/blog/"          , "https://example.com/c.css" ), "/c.css" );
+    assert_eq!( token::resolve_path( "/blog/"          , "img//./d.png" ), "/blog/img//d.png" );
+}
+
+#[test]
+fn pages_beyond_the_limits_fall_back() {
+
+    use alpaca::{ Fallback, FallbackReport, Limit, Limits };
+    use alpaca::morpher::{ DeterministicConfig, MorphMode };
+
+    let padme = || match MorphConfig::padme(0, 4000).unwrap().mode {
+        MorphMode::Deterministic(params) => params,
+        _                                => unreachable!(),
+    };
+
+    let morph = |limits: Limits| {
+        let config = MorphConfig::probabilistic( "", "Uniform/2,6", "Uniform/10000,20000" ).unwrap().with_limits(limits).unwrap();
+        Morpher::new(config).morph_html( "/index.html", HTML, &resolver() ).unwrap()
+    };
+
+    let report = |limit, fallback| Some( FallbackReport { limit, fallback } );
+
+    // Too large an html or too many objects are always served as they are
+    let page = morph( Limits { max_html_size: Some(50), deterministic_fallback: Some( padme() ), ..Limits::default() } );
+
+    assert_eq!( page.content, HTML.as_bytes() );
+    assert_eq!( page.fallback, report(Limit::HtmlSize, Fallback::Passthrough) );
+
+    let page = morph( Limits { max_objects: Some(1), deterministic_fallback: Some( padme() ), ..Limits::default() } );
+    assert_eq!( page.fallback, report(Limit::ObjectCount, Fallback::Passthrough) );
+    assert!( page.objects.is_empty() );
+
+    // Too much padding falls back to Padmé, which stays within it
+    let page = morph( Limits { max_page_padding: Some(2000), deterministic_fallback: Some( padme() ), ..Limits::default() } );
+
+    assert_eq!( page.fallback, report(Limit::PagePadding, Fallback::Deterministic) );
+    assert_eq!( page.content.len(), page.target_size );
+    assert!( page.overhead.bytes() <= 2000 );
+
+    let page = morph( Limits { max_obj_padding: Some(5000), ..Limits::default() } );
+    assert_eq!( page.fallback, report(Limit::ObjectPadding, Fallback::Passthrough) );
+
+    let page = morph( Limits { time_budget: Some( Duration::from_nanos(1) ), ..Limits::default() } );
+    assert_eq!( page.fallback, report(Limit::TimeBudget, Fallback::Passthrough) );
+
+    // Pages within the limits are morphed
+    assert_eq!( morph( Limits { max_html_size: Some(1000), max_objects: Some(2), ..Limits::default() } ).fallback, None );
+
+    // Objects are not padded beyond the limit
+    let config  = MorphConfig::default().with_limits( Limits { max_obj_padding: Some(100), ..Limits::default() } ).unwrap();
+    let morpher = Morpher::new(config);
+    let query   = morpher.padding_query("/alpaca.png", 300).unwrap();
+
+    assert!( matches!( morpher.morph_object("/alpaca.png", "image/png", 100, &query), Err( AlpacaError::Limit(Limit::ObjectPadding) ) ) );
+
+    let bad_fallback = DeterministicConfig { obj_size: 300, ..*padme() };
+    let bad_fallback = DeterministicConfig { scheme: alpaca::DeterministicScheme::Multiple, ..bad_fallback };
+
+    assert!( MorphConfig::default().with_limits( Limits { max_objects: Some(0), ..Limits::default() } ).is_err() );
+    assert!( MorphConfig::default().with_limits( Limits { deterministic_fallback: Some( Box::new(bad_fallback) ), ..Limits::default() } ).is_err() );
+}
+
+#[test]
+fn all_subresources_are_discovered() {
+
+    use alpaca::parse;
+    use alpaca::token;
+
+    let html = "<html><head><link rel=\"preload\" as=\"font\" href=\"f.woff2\" crossorigin><link rel=\"manifest\" href=\"app.webmanifest\">\
+                <link rel=\"apple-touch-icon\" href=\"touch.png\"><link rel=\"modulepreload\" href=\"m.js\"></head>\
+                <body style=\"background:url(bg.jpg)\"><img src=\"a.jpg\" srcset=\"a.jpg 1x, a2.jpg 2x\">\
+                <picture><source srcset=\"p.webp 1x,p2.webp 2x\"><img src=\"p.jpg\"></picture>\
+                <video poster=\"poster.jpg\"><source src=\"v.mp4\"><track src=\"t.vtt\"></video><audio src=\"s.ogg\"></audio>\
+                <iframe src=\"frame.html\"></iframe><embed src=\"doc.pdf\"><object data=\"o.svg\"></object>\
+                <input type=\"image\" src=\"button.png\"></body></html>";
+
+    let expected = [
+        ("f.woff2"   , ObjectKind::Font    ), ("app.webmanifest", ObjectKind::Manifest), ("touch.png", ObjectKind::IMG     ),
+        ("m.js"      , ObjectKind::JS      ), ("bg.jpg"         , ObjectKind::CssImg  ), ("a.jpg"    , ObjectKind::IMG     ),
+        ("a2.jpg"    , ObjectKind::IMG     ), ("p.webp"         , ObjectKind::IMG     ), ("p2.webp"  , ObjectKind::IMG     ),
+        ("p.jpg"     , ObjectKind::IMG     ), ("poster.jpg"     , ObjectKind::IMG     ), ("v.mp4"    , ObjectKind::Media   ),
+        ("t.vtt"     , ObjectKind::Media   ), ("s.ogg"          , ObjectKind::Media   ), ("frame.html", ObjectKind::Document),
+        ("doc.pdf"   , ObjectKind::Document), ("o.svg"          , ObjectKind::Document), ("button.png", ObjectKind::IMG    ),
+    ];
+
+    let mut objects = HashMap::new();
+
+    for (i, &(uri, _)) in expected.iter().enumerate() {
+        objects.insert( format!("/{}", uri), vec![b' '; 1000 + 10 * i] );
+    }
+
+    objects.insert( String::from("/app.webmanifest"), format!( "{{\"name\":\"{}\"}}", "a".repeat(1000) ).into_bytes() );
+
+    // a.jpg is referenced both by the src and by the srcset of the <img>
+    assert_eq!( parse::parse_object_names( &parse::parse_html(html) ).len(), expected.len() + 1 );
+
+    let morpher = Morpher::new( MorphConfig::deterministic(5, 1000, 100000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", html, &objects ).unwrap();
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert_eq!( page.objects.len(), 20 );
+
+    for &(uri, kind) in expected.iter() {
+        assert!( page.objects.iter().any( |obj| obj.uri == uri && obj.kind == kind ), "{} is not a {:?}", uri, kind );
+    }
+
+    // Every reference is rewritten, the srcset candidates one by one
+    let content = String::from_utf8( page.content.clone() ).unwrap();
+    let plain   = without_tokens(&page.content);
+
+    assert_eq!( content.matches("?v=").count(), 20 );
+    assert!( plain.contains("<img src=\"a.jpg?v=\" srcset=\"a.jpg?v= 1x, a2.jpg?v= 2x\">") );
+    assert!( plain.contains("<source srcset=\"p.webp?v= 1x,p2.webp?v= 2x\">") );
+    assert!( plain.contains("style=\"background:url(bg.jpg?v=)\"") );
+    assert!( plain.contains("<object data=\"o.svg?v=\">") );
+
+    // A framed html is padded with a comment, and a manifest with whitespace
+    let padded = |uri: &str, content_type: &str| {
+
+        let start   = content.find( &format!("{}?v=", uri) ).unwrap() + uri.len() + 1;
+        let query   = &content[ start..start + token::TOKEN_PARAM_SIZE ];
+        let target  = page.objects.iter().find( |obj| obj.uri == uri ).unwrap().target_size.unwrap();
+        let padded  = morpher.pad_object( &format!("/{}", uri), content_type, &objects[ &format!("/{}", uri) ], query ).unwrap();
+
+        assert_eq!( padded.len(), target );
+        padded
+    };
+
+    assert!( padded("frame.html", "text/html; charset=utf-8").ends_with(b"-->") );
+    let manifest = &objects["/app.webmanifest"];
+    let padded   = padded("app.webmanifest", "application/manifest+json");
+
+    assert!( padded.starts_with(manifest) && padded[ manifest.len().. ].iter().all( |&b| b == b' ' ) );
+}
+
+#[test]
+fn style_urls_are_found_with_their_values() {
+
+    use alpaca::parse;
+
+    assert_eq!( parse::parse_css_images("background:url(bg.png) no-repeat center"), ["bg.png"] );
+    assert_eq!( parse::parse_css_images("background:url(a.png),URL( 'b (1).png' ) , url(\"c.png\")"), ["a.png", "b (1).png", "c.png"] );
+    assert_eq!( parse::parse_css_images("/* url(old.png) */ src: url(f.woff2) format(\"woff2\")"), ["f.woff2"] );
+
+    let html = "<html><body><div style=\"background:url(bg.png) no-repeat center\"></div>\
+                <div style=\"background:url(a.png),url( 'b.png' )\"></div></body></html>";
+
+    let mut objects = HashMap::new();
+    objects.insert( String::from("/bg.png"), vec![0u8; 1000] );
+    objects.insert( String::from("/a.png") , vec![0u8; 1100] );
+    objects.insert( String::from("/b.png") , vec![0u8; 1200] );
+
+    let morpher = Morpher::new( MorphConfig::deterministic(3, 1000, 10000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", html, &objects ).unwrap();
+
+    for uri in ["bg.png", "a.png", "b.png"].iter() {
+        assert!( page.objects.iter().any( |obj| obj.uri == *uri && obj.kind == ObjectKind::CssImg ), "{} is not found", uri );
+    }
+
+    let plain = without_tokens(&page.content);
+
+    assert_eq!( page.content.len(), page.target_size );
+    assert!( plain.contains("style=\"background:url(bg.png?v=) no-repeat center\"") );
+    assert!( plain.contains("style=\"background:url(a.png?v=),url( 'b.png?v=' )\"") );
+}
+
+#[test]
+fn style_urls_are_rewritten_exactly() {
+
+    let html = "<html><head><style>.a{background:url(a.png)} .b{background:url(ba.png)} .c{background:url(\"a.png\")}</style></head>\
+                <body><div style=\"background:url(a.png),url(ba.png)\"></div></body></html>";
+
+    let mut objects = HashMap::new();
+    objects.insert( String::from("/a.png") , vec![0u8; 1000] );
+    objects.insert( String::from("/ba.png"), vec![0u8; 1100] );
+
+    let morpher = Morpher::new( MorphConfig::deterministic(4, 1000, 10000).unwrap() );
+    let page    = morpher.morph_html( "/index.html", html, &objects ).unwrap();
+    let content = String::from_utf8( page.content.clone() ).unwrap();
+    let plain   = without_tokens(&page.content);
+
+    // Each url() gets a single token, and a.png is not rewritten inside ba.png
+    assert_eq!( page.content.len(), page.target_size );
+    assert!( !content.contains("?v=?v=") );
+    assert!( plain.contains(".a{background:url(a.png?v=)} .b{background:url(ba.png?v=)} .c{background:url(\"a.png?v=\")}") );
+    assert!( plain.contains("style=\"background:url(a.png?v=),url(ba.png?v=)\"") );
+}
+
+#[test]
+fn secrets_are_read_with_the_configuration() {
+
+    let path = std::env::temp_dir().join("alpaca-cached.secret");
+    let file = path.to_str().unwrap();
+
+    fs::write( &path, "first" ).unwrap();
+    assert_eq!( *registry::secret(file).unwrap(), b"first" );
+
+    // Not read again on the requests' path, but when the configuration is loaded
+    fs::write( &path, "second" ).unwrap();
+    assert_eq!( *registry::secret(file).unwrap(), b"first" );
+
+    let c_file = format!("{}\0", file);
+    assert_eq!( registry::load_secret( c_file.as_ptr() ), alpaca::error::ALPACA_OK );
+    assert_eq!( *registry::secret(file).unwrap(), b"second" );
+
+    // Unreadable and empty files are reported
+    fs::write( &path, "" ).unwrap();
+    assert_ne!( registry::load_secret( c_file.as_ptr() ), alpaca::error::ALPACA_OK );
+    assert!( registry::secret("/nonexistent/alpaca.secret").is_err() );
+}
+
+#[test]
+fn token_keys_are_derived_once() {
+
+    use alpaca::token::{ self, TokenKey };
+
+    let path = std::env::temp_dir().join("alpaca-token.secret");
+    let file = path.to_str().unwrap();
+
+    fs::write( &path, "tokens" ).unwrap();
+
+    let key = registry::token_key(file).unwrap();
+    assert!( Arc::ptr_eq( &key, &registry::token_key(file).unwrap() ) );
+
+    let issued = TokenKey::from_secret(b"tokens").issue( "/alpaca.png", 3000, token::now() + 60, &mut rand::thread_rng() ).unwrap();
+    assert_eq!( key.verify( "/alpaca.png", &issued, token::now() ), Some(3000) );
+
+    // Derived again from a secret loaded with the configuration
+    fs::write( &path, "reloaded" ).unwrap();
+    assert_eq!( registry::load_secret( format!("{}\0", file).as_ptr() ), alpaca::error::ALPACA_OK );
+
+    assert_eq!( registry::token_key(file).unwrap().verify( "/alpaca.png", &issued, token::now() ), None );
+
+    // A configuration keeps the key it was built with
+    assert!( Arc::ptr_eq( &MorphConfig::default().token_key, &TokenKey::process() ) );
+
+    let config = MorphConfig::default().with_secret( b"tokens".to_vec(), 0 ).unwrap();
+    assert_eq!( config.token_key.verify( "/alpaca.png", &issued, token::now() ), Some(3000) );
+}
+
+#[test]
+fn webp_chunks_stay_even_when_padded() {
+
+    let morpher = Morpher::new( MorphConfig::default() );
+
+    // An extended (VP8X) WebP, as with an ICC profile
+    let mut content = Vec::new();
+    let mut encoder = image_webp::WebPEncoder::new(&mut content);
+
+    encoder.set_icc_profile( vec![7u8; 5] );
+    encoder.encode( &[120u8; 4 * 4 * 3], 4, 4, image_webp::ColorType::Rgb8 ).unwrap();
+
+    assert_eq!( &content[12..16], b"VP8X" );
+
+    for &pad_len in &[12, 13, 14, 259, 70001] {
+
+        let query  = morpher.padding_query( "/alpaca.webp", content.len() + pad_len ).unwrap();
+        let padded = morpher.pad_object("/alpaca.webp", "image/webp", &content, &query).unwrap();
+
+        assert_eq!( padded.len(), content.len() + pad_len );
+
+        // The chunks, each padded to an even size, end at the RIFF size
+        let riff_end = 8 + u32::from_le_bytes( [padded[4], padded[5], padded[6], padded[7]] ) as usize;
+        let mut pos  = 12;
+
+        while pos < riff_end {
+            let size = u32::from_le_bytes( [padded[pos + 4], padded[pos + 5], padded[pos + 6], padded[pos + 7]] ) as usize;
+            pos += 8 + size + size % 2;
+        }
+
+        assert_eq!( pos, riff_end );
+        assert!( riff_end.is_multiple_of(2) && padded.len() - riff_end == pad_len % 2 );
+
+        let mut decoder = image_webp::WebPDecoder::new( std::io::Cursor::new(&padded) ).unwrap();
+        let mut pixels  = vec![0u8; decoder.output_buffer_size().unwrap()];
+
+        decoder.read_image(&mut pixels).unwrap();
+    }
+}
+
+#[test]
+fn mixtures_are_reloaded_with_their_files() {
+
+    let path = std::env::temp_dir().join("alpaca-component.dist");
+    let spec = format!( "Mix(0.5*{}; 0.5*Uniform/1,10)", path.to_str().unwrap() );
+
+    fs::write( &path, "1.0 100\n" ).unwrap();
+
+    let first = registry::distribution(&spec).unwrap();
+    assert_eq!( first.components[0].values, Some( vec![ vec![100] ] ) );
+    assert!( Arc::ptr_eq( &first, &registry::distribution(&spec).unwrap() ) );
+
+    // A modified component is reloaded with the mixture
+    fs::write( &path, "1.0 300\n" ).unwrap();
+    fs::File::options().write(true).open(&path).unwrap().set_modified( UNIX_EPOCH + Duration::from_secs(1_000_000) ).unwrap();
+
+    assert_eq!( registry::distribution(&spec).unwrap().components[0].values, Some( vec![ vec![300] ] ) );
+}
@@ -8,6 +8,10 @@
 
 #include "./utils/map/map.h"
 
+// Kinds of objects with settings of their own, in the order of the
+// *_kind arrays: css, js, img, font
+#define ALPACA_KINDS 4
+
 // This struct fills up from requests
 // It's passed to rust
 struct MorphInfo {
@@ -27,18 +31,62 @@ struct MorphInfo {
     u_char*    dist_html_size;
     u_char*    dist_obj_num;
     u_char*    dist_obj_size;
+    u_char*    dist_total_size;
+    u_char*    dist_page_profile;
     ngx_uint_t probabilistic;
     ngx_uint_t use_total_obj_size;
+    ngx_uint_t total_size_split;
+    u_char*    dist_obj_size_kind[ALPACA_KINDS];
+    u_char*    dist_obj_num_kind [ALPACA_KINDS];
 
     // for deterministic
     ngx_uint_t max_obj_size;
     ngx_uint_t obj_num;
     ngx_uint_t obj_size;
+    ngx_uint_t deter_scheme;
+    ngx_uint_t obj_size_kind    [ALPACA_KINDS];
+    ngx_uint_t max_obj_size_kind[ALPACA_KINDS];
+    ngx_uint_t obj_num_kind     [ALPACA_KINDS];
 
     // for object inlining
     ngx_uint_t obj_inlining_enabled;
     ngx_uint_t force_css_inlining;
     ngx_uint_t css_as_inline_object;
+
+    // for keyed morphing
+    u_char*    secret_file;
+    ngx_uint_t epoch_length;
+
+    // for the overhead caps, 0 for no cap
+    ngx_uint_t max_page_overhead_pct;
+    ngx_uint_t max_page_overhead_bytes;
+    ngx_uint_t max_obj_overhead_pct;
+    ngx_uint_t max_obj_overhead_bytes;
+
+    // the elements referencing the fake objects, a mask of the
+    // alpaca_fake_carriers values; none for all
+    ngx_uint_t fake_carriers;
+
+    // the seconds the tokens of a morphed html are valid for
+    ngx_uint_t token_ttl;
+
+    // the limits on a page, 0 for no limit, and the fallback of the pages
+    // beyond them, 0: passthrough, 1: deterministic
+    ngx_uint_t max_html_size;
+    ngx_uint_t max_objects;
+    ngx_uint_t max_obj_padding;
+    ngx_uint_t max_page_padding;
+    ngx_uint_t time_budget_ms;
+    ngx_uint_t limit_fallback;
+
+    // reported after morphing an html
+    ngx_uint_t orig_page_size;
+    ngx_uint_t morphed_page_size;
+    ngx_uint_t fallback;       // 0 if morphed, 1: passed through, 2: deterministic
+    ngx_uint_t fallback_limit; // 1: html size, 2: objects, 3: object padding, 4: page padding, 5: time
+
+    // the encoding negotiated for the html, 0: identity, 1: gzip, 2: br
+    ngx_uint_t content_encoding;
 };
 
 // This struct fills up from config
@@ -49,23 +97,61 @@ typedef struct {
     ngx_uint_t obj_num;
     ngx_uint_t obj_size;
     ngx_uint_t max_obj_size;
+    ngx_uint_t deter_scheme;
+    ngx_uint_t obj_size_kind    [ALPACA_KINDS];
+    ngx_uint_t max_obj_size_kind[ALPACA_KINDS];
+    ngx_uint_t obj_num_kind     [ALPACA_KINDS];
 
     ngx_str_t  dist_html_size;
     ngx_str_t  dist_obj_num;
     ngx_str_t  dist_obj_size;
+    ngx_str_t  dist_total_size;
+    ngx_str_t  dist_page_profile;
+    ngx_str_t  dist_obj_size_kind[ALPACA_KINDS];
+    ngx_str_t  dist_obj_num_kind [ALPACA_KINDS];
 
     ngx_flag_t use_total_obj_size;
+    ngx_uint_t total_size_split;
     ngx_flag_t obj_inlining_enabled;
     ngx_flag_t force_css_inlining;
     ngx_flag_t css_as_inline_object;
+
+    ngx_str_t  secret_file;
+    time_t     epoch_length;
+
+    ngx_uint_t max_page_overhead_pct;
+    size_t     max_page_overhead_bytes;
+    ngx_uint_t max_obj_overhead_pct;
+    size_t     max_obj_overhead_bytes;
+
+    ngx_flag_t compression;
+    ngx_uint_t fake_carriers;
+    time_t     token_ttl;
+
+    size_t     max_html_size;
+    ngx_uint_t max_objects;
+    size_t     max_obj_padding;
+    size_t     max_page_padding;
+    ngx_msec_t time_budget;
+    ngx_uint_t limit_fallback;
 } ngx_http_alpaca_loc_conf_t;
 
+// The fake objects added to the pages take the uris of missing files next
+// to the pages' objects, and a content type by their extension
+typedef struct {
+    ngx_str_t extension;
+    ngx_str_t content_type;
+} ngx_http_alpaca_fake_object_t;
+
 // Keep a state for each request
 typedef struct {
     u_char*    response;
     u_char*    end;
     ngx_uint_t size;
     ngx_uint_t capacity;
+    ngx_uint_t encoding; // negotiated by the header filter, 0 if not compressed
+    ngx_http_alpaca_fake_object_t* fake; // requested, found by the header filter
+    ngx_flag_t framed; // an html requested with a padding token, padded as an object
 } ngx_http_alpaca_ctx_t;
 
 typedef struct {
@@ -75,14 +161,27 @@ typedef struct {
 
 // -----------------------------------------------------------------------------------------------------
 
+// Status returned by libalpaca when the content was morphed. Any other
+// value is an error code, and the content is left unchanged.
+#define ALPACA_OK 1
+
 u_char** get_html_required_files(struct MorphInfo *info , int *length);
 u_char** get_required_css_files (struct MorphInfo *info , int *length);
 u_char   inline_all_css         (struct MorphInfo *info , map req_mapper);
 u_char   morph_html             (struct MorphInfo *info , map req_mapper);
 u_char   morph_object           (struct MorphInfo *info);
+u_char   object_padding         (struct MorphInfo *info);
+u_char   generate_fake_object   (struct MorphInfo *info);
+u_char   compress_content       (struct MorphInfo *info);
+u_char   negotiate_encoding     (u_char *accept_encoding);
+u_char   check_padding_token    (struct MorphInfo *info);
+u_char   init_padding_tokens    (void);
 
 void free_memory(u_char* data, ngx_uint_t size);
 
+u_char load_distribution(u_char *spec, bool is_profile);
+u_char load_secret      (u_char *path);
+
 // -----------------------------------------------------------------------------------------------------
 
 static ngx_int_t ngx_http_alpaca_header_filter  (ngx_http_request_t *r);
@@ -94,6 +193,36 @@ static char*     ngx_http_alpaca_merge_loc_conf (ngx_conf_t *cf, void *parent, v
 
 // -----------------------------------------------------------------------------------------------------
 
+// Values of the alpaca_total_size_split directive
+static ngx_conf_enum_t ngx_http_alpaca_total_size_split[] = {
+    { ngx_string("proportional"), 0 },
+    { ngx_string("conditional") , 1 },
+    { ngx_null_string           , 0 }
+};
+
+// Values of the alpaca_fake_carriers directive, as the masks of libalpaca
+static ngx_conf_bitmask_t ngx_http_alpaca_fake_carriers[] = {
+    { ngx_string("img")       , 0x0002 },
+    { ngx_string("prefetch")  , 0x0004 },
+    { ngx_string("preload")   , 0x0008 },
+    { ngx_string("background"), 0x0010 },
+    { ngx_null_string         , 0      }
+};
+
+// Values of the alpaca_limit_fallback directive
+static ngx_conf_enum_t ngx_http_alpaca_limit_fallback[] = {
+    { ngx_string("passthrough")  , 0 },
+    { ngx_string("deterministic"), 1 },
+    { ngx_null_string            , 0 }
+};
+
+// Values of the alpaca_deter_scheme directive
+static ngx_conf_enum_t ngx_http_alpaca_deter_scheme[] = {
+    { ngx_string("multiple"), 0 },
+    { ngx_string("padme")   , 1 },
+    { ngx_null_string       , 0 }
+};
+
 // These are the ALPaCA commands/directives given in the nginx.conf
 static ngx_command_t ngx_http_alpaca_commands[] = {
     {
@@ -126,6 +255,84 @@ static ngx_command_t ngx_http_alpaca_commands[] = {
         ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
         offsetof(ngx_http_alpaca_loc_conf_t, max_obj_size), NULL
     },
+    {
+        ngx_string("alpaca_deter_scheme"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_enum_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, deter_scheme), &ngx_http_alpaca_deter_scheme
+    },
+    {
+        ngx_string("alpaca_obj_size_css"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_size_kind[0]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_size_js"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_size_kind[1]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_size_img"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_size_kind[2]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_size_font"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_size_kind[3]), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_size_css"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_size_kind[0]), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_size_js"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_size_kind[1]), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_size_img"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_size_kind[2]), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_size_font"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_size_kind[3]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_num_css"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_num_kind[0]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_num_js"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_num_kind[1]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_num_img"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_num_kind[2]), NULL
+    },
+    {
+        ngx_string("alpaca_obj_num_font"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, obj_num_kind[3]), NULL
+    },
     {
         ngx_string("alpaca_dist_html_size"),
         NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
@@ -144,6 +351,72 @@ static ngx_command_t ngx_http_alpaca_commands[] = {
         ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
         offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_size), NULL
     },
+    {
+        ngx_string("alpaca_dist_obj_size_css"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_size_kind[0]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_size_js"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_size_kind[1]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_size_img"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_size_kind[2]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_size_font"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_size_kind[3]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_num_css"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_num_kind[0]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_num_js"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_num_kind[1]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_num_img"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_num_kind[2]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_obj_num_font"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_obj_num_kind[3]), NULL
+    },
+    {
+        ngx_string("alpaca_dist_total_size"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_total_size), NULL
+    },
+    {
+        ngx_string("alpaca_total_size_split"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_enum_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, total_size_split), &ngx_http_alpaca_total_size_split
+    },
+    {
+        ngx_string("alpaca_dist_page_profile"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, dist_page_profile), NULL
+    },
     {
         ngx_string("alpaca_use_total_obj_size"),
         NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
@@ -168,6 +441,96 @@ static ngx_command_t ngx_http_alpaca_commands[] = {
         ngx_conf_set_flag_slot, NGX_HTTP_LOC_CONF_OFFSET,
         offsetof(ngx_http_alpaca_loc_conf_t, css_as_inline_object), NULL
     },
+    {
+        ngx_string("alpaca_secret_file"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_str_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, secret_file), NULL
+    },
+    {
+        ngx_string("alpaca_epoch_length"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_sec_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, epoch_length), NULL
+    },
+    {
+        ngx_string("alpaca_max_page_overhead_pct"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_page_overhead_pct), NULL
+    },
+    {
+        ngx_string("alpaca_max_page_overhead_bytes"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_size_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_page_overhead_bytes), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_overhead_pct"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_overhead_pct), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_overhead_bytes"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_size_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_overhead_bytes), NULL
+    },
+    {
+        ngx_string("alpaca_compression"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_FLAG,
+        ngx_conf_set_flag_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, compression), NULL
+    },
+    {
+        ngx_string("alpaca_fake_carriers"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_1MORE,
+        ngx_conf_set_bitmask_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, fake_carriers), &ngx_http_alpaca_fake_carriers
+    },
+    {
+        ngx_string("alpaca_token_ttl"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_sec_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, token_ttl), NULL
+    },
+    {
+        ngx_string("alpaca_max_html_size"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_size_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_html_size), NULL
+    },
+    {
+        ngx_string("alpaca_max_objects"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_num_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_objects), NULL
+    },
+    {
+        ngx_string("alpaca_max_obj_padding"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_size_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_obj_padding), NULL
+    },
+    {
+        ngx_string("alpaca_max_page_padding"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_size_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, max_page_padding), NULL
+    },
+    {
+        ngx_string("alpaca_time_budget"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_msec_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, time_budget), NULL
+    },
+    {
+        ngx_string("alpaca_limit_fallback"),
+        NGX_HTTP_MAIN_CONF | NGX_HTTP_SRV_CONF | NGX_HTTP_LOC_CONF | NGX_CONF_TAKE1,
+        ngx_conf_set_enum_slot, NGX_HTTP_LOC_CONF_OFFSET,
+        offsetof(ngx_http_alpaca_loc_conf_t, limit_fallback), &ngx_http_alpaca_limit_fallback
+    },
     ngx_null_command
 };
 
@@ -208,8 +571,73 @@ static ngx_http_output_body_filter_pt   ngx_http_next_body_filter;
 
 // -----------------------------------------------------------------------------------------------------
 
-static ngx_int_t is_fake_image(ngx_http_request_t* r) {
-    return ngx_strncmp(r->uri.data, "/__alpaca_fake_image.png", 24) == 0;
+static u_char* copy_ngx_str(ngx_str_t str, ngx_pool_t* pool) {
+
+    u_char* res = ngx_pcalloc(pool, str.len + 1);
+
+    ngx_memcpy(res, str.data, str.len);
+    res[str.len] = '\0';
+
+    return res;
+}
+
+// The content types of the fake objects, by the extension of their uri.
+static ngx_http_alpaca_fake_object_t fake_objects[] = {
+    { ngx_string(".png")  , ngx_string("image/png")              },
+    { ngx_string(".jpg")  , ngx_string("image/jpeg")             },
+    { ngx_string(".jpeg") , ngx_string("image/jpeg")             },
+    { ngx_string(".gif")  , ngx_string("image/gif")              },
+    { ngx_string(".webp") , ngx_string("image/webp")             },
+    { ngx_string(".css")  , ngx_string("text/css")               },
+    { ngx_string(".js")   , ngx_string("application/javascript") },
+    { ngx_string(".woff2"), ngx_string("font/woff2")             },
+    { ngx_string(".woff") , ngx_string("font/woff")              },
+    { ngx_string(".ttf")  , ngx_string("font/ttf")               },
+    { ngx_string(".otf")  , ngx_string("font/otf")               },
+};
+
+// Whether the query of the request carries a valid padding token for its uri.
+static ngx_int_t has_padding_token(ngx_http_request_t* r) {
+
+    ngx_http_alpaca_loc_conf_t *plcf;
+
+    if ( r != r->main || r->args.len == 0 )
+        return 0;
+
+    plcf = ngx_http_get_module_loc_conf(r, ngx_http_alpaca_module);
+
+    struct MorphInfo info = {
+        .query        = copy_ngx_str(r->args, r->pool)          ,
+        .uri          = copy_ngx_str(r->uri, r->pool)           ,
+        .secret_file  = copy_ngx_str(plcf->secret_file, r->pool),
+        .epoch_length = plcf->epoch_length                      ,
+    };
+
+    return check_padding_token(&info) == ALPACA_OK;
+}
+
+// Returns the fake object requested, or NULL for other requests: a fake
+// object is not found, has a known extension and a valid padding token.
+static ngx_http_alpaca_fake_object_t* fake_object(ngx_http_request_t* r) {
+
+    ngx_http_alpaca_fake_object_t *fake = NULL;
+
+    if ( r->headers_out.status != NGX_HTTP_NOT_FOUND || r != r->main || r->args.len == 0 )
+        return NULL;
+
+    for (size_t i = 0; i < sizeof(fake_objects) / sizeof(fake_objects[0]) && fake == NULL; i++) {
+
+        ngx_str_t *ext = &fake_objects[i].extension;
+
+        if ( r->uri.len > ext->len && ngx_strncasecmp(r->uri.data + r->uri.len - ext->len, ext->data, ext->len) == 0 )
+            fake = &fake_objects[i];
+    }
+
+    if (fake == NULL)
+        return NULL;
+
+    // Other missing files are left as they are
+    return has_padding_token(r) ? fake : NULL;
 }
 
 static ngx_int_t is_html(ngx_http_request_t* r) {
@@ -226,22 +654,108 @@ static ngx_int_t is_paddable(ngx_http_request_t* r) {
 
 	return ( r->headers_out.content_type.len >= 6                                                                          &&
 		     ngx_strncmp(r->headers_out.content_type.data, "image/", 6) == 0)                                              ||
+		   ( r->headers_out.content_type.len >= 5                                                                          &&
+		     ngx_strncmp(r->headers_out.content_type.data, "font/", 5) == 0)                                               ||
+		   ( r->headers_out.content_type.len >= 6                                                                          &&
+		     ngx_strncmp(r->headers_out.content_type.data, "video/", 6) == 0)                                              ||
+		   ( r->headers_out.content_type.len >= 6                                                                          &&
+		     ngx_strncmp(r->headers_out.content_type.data, "audio/", 6) == 0)                                              ||
+		   ( r->headers_out.content_type.len >= 8                                                                          &&
+		     ngx_strncmp(r->headers_out.content_type.data, "text/vtt", 8) == 0)                                            ||
+		   ( r->headers_out.content_type.len >= 25                                                                         &&
+		     ngx_strncmp(r->headers_out.content_type.data, "application/manifest+json", 25) == 0)                          ||
 		     ngx_strncmp(r->headers_out.content_type.data, "application/javascript", r->headers_out.content_type.len) == 0 ||
 		     ngx_strncmp(r->headers_out.content_type.data, "text/css"              , r->headers_out.content_type.len) == 0;
 
 		//    || ngx_strncmp(r->headers_out.content_type.data, "text/plain", r->headers_out.content_type.len) == 0;
 }
 
+// Whether the object is an image, which is padded inside its format and so
+// has to be captured whole. The other objects are streamed, and their
+// padding is sent after them.
+static ngx_int_t is_image(ngx_http_request_t* r) {
+    return r->headers_out.content_type.len >= 6 && ngx_strncmp(r->headers_out.content_type.data, "image/", 6) == 0;
+}
+
+// Whether the response is an html that the body filter morphs, and so the
+// only one that may be sent compressed by libalpaca.
+static ngx_int_t is_morphed_html(ngx_http_request_t* r, ngx_http_alpaca_ctx_t* ctx) {
+    return is_html(r) && r->headers_out.status != NGX_HTTP_NOT_FOUND && r == r->main && ctx->fake == NULL && !ctx->framed;
+}
+
 // -----------------------------------------------------------------------------------------------------
 
-static u_char* copy_ngx_str(ngx_str_t str, ngx_pool_t* pool) {
+// The encoding negotiated by libalpaca from the Accept-Encoding header of
+// the request, 0 if the html should not be compressed.
+static ngx_uint_t accepted_encoding(ngx_http_request_t *r) {
 
-    u_char* res = ngx_pcalloc(pool, str.len + 1);
+#if (NGX_HTTP_GZIP || NGX_HTTP_HEADERS)
+    if (r->headers_in.accept_encoding == NULL)
+        return 0;
 
-    ngx_memcpy(res, str.data, str.len);
-    res[str.len] = '\0';
+    return negotiate_encoding( copy_ngx_str(r->headers_in.accept_encoding->value, r->pool) );
+#else
+    return 0;
+#endif
+}
 
-    return res;
+// Sends the html with the given encoding. libalpaca compresses it itself,
+// and the gzip filter skips responses that already have a Content-Encoding.
+static ngx_int_t set_content_encoding(ngx_http_request_t *r, ngx_uint_t encoding) {
+
+    static ngx_str_t names[] = { ngx_null_string, ngx_string("gzip"), ngx_string("br") };
+
+    ngx_table_elt_t *h, *vary;
+
+    h    = ngx_list_push(&r->headers_out.headers);
+    vary = ngx_list_push(&r->headers_out.headers);
+
+    if (h == NULL || vary == NULL)
+        return NGX_ERROR;
+
+    h->hash  = 1;
+    h->value = names[encoding];
+    ngx_str_set(&h->key, "Content-Encoding");
+
+    vary->hash = 1;
+    ngx_str_set(&vary->key  , "Vary");
+    ngx_str_set(&vary->value, "Accept-Encoding");
+
+    r->headers_out.content_encoding = h;
+
+    return NGX_OK;
+}
+
+// Compresses the original html of a page that could not be morphed, since
+// its Content-Encoding header has already been sent.
+static void compress_original(struct MorphInfo *main_info, u_char **response, ngx_http_request_t *r) {
+
+    main_info->content = *response;
+
+    if ( compress_content(main_info) != ALPACA_OK ) {
+        ngx_log_error( NGX_LOG_ERR, r->connection->log, 0, "[Alpaca filter]: could not compress %V", &r->uri );
+        return;
+    }
+
+    *response = ngx_pcalloc( r->pool, main_info->size * sizeof(u_char) );
+
+    ngx_memcpy(*response, main_info->content, main_info->size);
+    free_memory(main_info->content, main_info->size);
+}
+
+// Logs the sizes of a morphed page, or the fallback it was served with.
+static void log_morph(struct MorphInfo *main_info, ngx_http_request_t *r) {
+
+    static const char* limits[] = { "", "html size", "object count", "object padding", "page padding", "time budget" };
+
+    if ( main_info->fallback != 0 && main_info->fallback_limit < sizeof(limits) / sizeof(limits[0]) ) {
+        ngx_log_error( NGX_LOG_WARN, r->connection->log, 0, "[Alpaca filter]: %V exceeds the %s limit, %s",
+                       &r->uri, limits[main_info->fallback_limit],
+                       main_info->fallback == 2 ? "morphed deterministically" : "served as it is" );
+    }
+
+    ngx_log_error( NGX_LOG_INFO, r->connection->log, 0, "[Alpaca filter]: morphed %V from %ui to %ui bytes",
+                   &r->uri, main_info->orig_page_size, main_info->morphed_page_size );
 }
 
 static u_char* get_response(ngx_http_alpaca_ctx_t *ctx ,
@@ -320,9 +834,20 @@ struct MorphInfo* initialize_morph_html_struct(ngx_http_request_t         *r
     main_info->content   = ctx->response;
     main_info->size      = ctx->size;
 
-    main_info->dist_obj_size  = copy_ngx_str(plcf->dist_obj_size , r->pool);
-    main_info->dist_obj_num   = copy_ngx_str(plcf->dist_obj_num  , r->pool);
-    main_info->dist_html_size = copy_ngx_str(plcf->dist_html_size, r->pool);
+    main_info->dist_obj_size   = copy_ngx_str(plcf->dist_obj_size  , r->pool);
+    main_info->dist_obj_num    = copy_ngx_str(plcf->dist_obj_num   , r->pool);
+    main_info->dist_html_size  = copy_ngx_str(plcf->dist_html_size , r->pool);
+    main_info->dist_total_size = copy_ngx_str(plcf->dist_total_size, r->pool);
+
+    main_info->dist_page_profile = copy_ngx_str(plcf->dist_page_profile, r->pool);
+
+    for (int k = 0; k < ALPACA_KINDS; k++) {
+        main_info->dist_obj_size_kind[k] = copy_ngx_str(plcf->dist_obj_size_kind[k], r->pool);
+        main_info->dist_obj_num_kind[k]  = copy_ngx_str(plcf->dist_obj_num_kind[k] , r->pool);
+        main_info->obj_size_kind[k]      = plcf->obj_size_kind[k];
+        main_info->max_obj_size_kind[k]  = plcf->max_obj_size_kind[k];
+        main_info->obj_num_kind[k]       = plcf->obj_num_kind[k];
+    }
 
     main_info->max_obj_size         = plcf->max_obj_size;
     main_info->obj_inlining_enabled = plcf->obj_inlining_enabled;
@@ -330,8 +855,32 @@ struct MorphInfo* initialize_morph_html_struct(ngx_http_request_t         *r
     main_info->css_as_inline_object = plcf->css_as_inline_object;
     main_info->obj_num              = plcf->obj_num;
     main_info->obj_size             = plcf->obj_size;
+    main_info->deter_scheme         = plcf->deter_scheme;
     main_info->probabilistic        = plcf->prob_enabled;
     main_info->use_total_obj_size   = plcf->use_total_obj_size;
+    main_info->total_size_split     = plcf->total_size_split;
+
+    main_info->secret_file  = copy_ngx_str(plcf->secret_file, r->pool);
+    main_info->epoch_length = plcf->epoch_length;
+
+    main_info->max_page_overhead_pct   = plcf->max_page_overhead_pct;
+    main_info->max_page_overhead_bytes = plcf->max_page_overhead_bytes;
+    main_info->max_obj_overhead_pct    = plcf->max_obj_overhead_pct;
+    main_info->max_obj_overhead_bytes  = plcf->max_obj_overhead_bytes;
+
+    main_info->fake_carriers = plcf->fake_carriers;
+    main_info->token_ttl     = plcf->token_ttl;
+
+    main_info->max_html_size    = plcf->max_html_size;
+    main_info->max_objects      = plcf->max_objects;
+    main_info->max_obj_padding  = plcf->max_obj_padding;
+    main_info->max_page_padding = plcf->max_page_padding;
+    main_info->time_budget_ms   = plcf->time_budget;
+    main_info->limit_fallback   = plcf->limit_fallback;
+    main_info->fallback         = 0;
+    main_info->fallback_limit   = 0;
+
+    main_info->content_encoding = ctx->encoding;
 
     return main_info;
 }
@@ -384,14 +933,86 @@ static void* ngx_http_alpaca_create_loc_conf(ngx_conf_t* cf) {
     conf->obj_num              = NGX_CONF_UNSET_UINT;
     conf->obj_size             = NGX_CONF_UNSET_UINT;
     conf->max_obj_size         = NGX_CONF_UNSET_UINT;
+    conf->deter_scheme         = NGX_CONF_UNSET_UINT;
+
+    for (int k = 0; k < ALPACA_KINDS; k++) {
+        conf->obj_size_kind[k]     = NGX_CONF_UNSET_UINT;
+        conf->max_obj_size_kind[k] = NGX_CONF_UNSET_UINT;
+        conf->obj_num_kind[k]      = NGX_CONF_UNSET_UINT;
+    }
     conf->use_total_obj_size   = NGX_CONF_UNSET;
+    conf->total_size_split     = NGX_CONF_UNSET_UINT;
     conf->obj_inlining_enabled = NGX_CONF_UNSET;
     conf->force_css_inlining   = NGX_CONF_UNSET;
     conf->css_as_inline_object = NGX_CONF_UNSET;
+    conf->epoch_length         = NGX_CONF_UNSET;
+    conf->token_ttl            = NGX_CONF_UNSET;
+
+    conf->max_page_overhead_pct   = NGX_CONF_UNSET_UINT;
+    conf->max_page_overhead_bytes = NGX_CONF_UNSET_SIZE;
+    conf->max_obj_overhead_pct    = NGX_CONF_UNSET_UINT;
+    conf->max_obj_overhead_bytes  = NGX_CONF_UNSET_SIZE;
+
+    conf->compression             = NGX_CONF_UNSET;
+
+    conf->max_html_size    = NGX_CONF_UNSET_SIZE;
+    conf->max_objects      = NGX_CONF_UNSET_UINT;
+    conf->max_obj_padding  = NGX_CONF_UNSET_SIZE;
+    conf->max_page_padding = NGX_CONF_UNSET_SIZE;
+    conf->time_budget      = NGX_CONF_UNSET_MSEC;
+    conf->limit_fallback   = NGX_CONF_UNSET_UINT;
 
     return conf;
 }
 
+// Parses a distribution given in the configuration, so that invalid ones
+// are reported at startup and the workers find it already parsed.
+static bool check_distribution(ngx_conf_t *cf, ngx_str_t *spec, bool is_profile) {
+
+    if (spec->len == 0)
+        return true;
+
+    u_char rc = load_distribution( copy_ngx_str(*spec, cf->pool), is_profile );
+
+    if (rc != ALPACA_OK) {
+        ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "invalid distribution \"%V\" (error %d)", spec, rc );
+        return false;
+    }
+
+    return true;
+}
+
+static bool has_kind_distribution(ngx_http_alpaca_loc_conf_t *conf) {
+
+    for (int k = 0; k < ALPACA_KINDS; k++) {
+        if (conf->dist_obj_size_kind[k].len != 0)
+            return true;
+    }
+
+    return false;
+}
+
+// Checks the object size and max object size of each kind, which default
+// to alpaca_obj_size and alpaca_max_obj_size.
+static bool check_kind_sizes(ngx_conf_t *cf, ngx_http_alpaca_loc_conf_t *conf) {
+
+    static const char* names[ALPACA_KINDS] = { "css", "js", "img", "font" };
+
+    for (int k = 0; k < ALPACA_KINDS; k++) {
+
+        ngx_uint_t obj_size     = conf->obj_size_kind[k]     ? conf->obj_size_kind[k]     : conf->obj_size;
+        ngx_uint_t max_obj_size = conf->max_obj_size_kind[k] ? conf->max_obj_size_kind[k] : conf->max_obj_size;
+
+        if ( conf->deter_scheme == 0 && (max_obj_size < obj_size || max_obj_size % obj_size != 0) ) {
+            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "Max object size of %s objects has to be a multiple of their "
+                                                      "object size for deterministic ALPaCA.", names[k] );
+            return false;
+        }
+    }
+
+    return true;
+}
+
 static char* ngx_http_alpaca_merge_loc_conf(ngx_conf_t* cf, void* parent, void* child) {
 
     ngx_http_alpaca_loc_conf_t* prev = parent;
@@ -402,20 +1023,63 @@ static char* ngx_http_alpaca_merge_loc_conf(ngx_conf_t* cf, void* parent, void*
     ngx_conf_merge_uint_value(conf->obj_num             , prev->obj_num             , 0 );
     ngx_conf_merge_uint_value(conf->obj_size            , prev->obj_size            , 0 );
     ngx_conf_merge_uint_value(conf->max_obj_size        , prev->max_obj_size        , 0 );
+    ngx_conf_merge_uint_value(conf->deter_scheme        , prev->deter_scheme        , 0 );
     ngx_conf_merge_str_value (conf->dist_html_size      , prev->dist_html_size      , "");
     ngx_conf_merge_str_value (conf->dist_obj_num        , prev->dist_obj_num        , "");
     ngx_conf_merge_str_value (conf->dist_obj_size       , prev->dist_obj_size       , "");
+    ngx_conf_merge_str_value (conf->dist_total_size     , prev->dist_total_size     , "");
+    ngx_conf_merge_str_value (conf->dist_page_profile   , prev->dist_page_profile   , "");
     ngx_conf_merge_value     (conf->use_total_obj_size  , prev->use_total_obj_size  , 0 );
+    ngx_conf_merge_uint_value(conf->total_size_split    , prev->total_size_split    , 0 );
     ngx_conf_merge_value     (conf->obj_inlining_enabled, prev->obj_inlining_enabled, 0 );
     ngx_conf_merge_value     (conf->force_css_inlining  , prev->force_css_inlining  , 0 );
     ngx_conf_merge_value     (conf->css_as_inline_object, prev->css_as_inline_object, 0 );
+    ngx_conf_merge_str_value (conf->secret_file         , prev->secret_file         , "");
+    ngx_conf_merge_sec_value (conf->epoch_length        , prev->epoch_length        , 86400);
+
+    ngx_conf_merge_uint_value(conf->max_page_overhead_pct  , prev->max_page_overhead_pct  , 0);
+    ngx_conf_merge_size_value(conf->max_page_overhead_bytes, prev->max_page_overhead_bytes, 0);
+    ngx_conf_merge_uint_value(conf->max_obj_overhead_pct   , prev->max_obj_overhead_pct   , 0);
+    ngx_conf_merge_size_value(conf->max_obj_overhead_bytes , prev->max_obj_overhead_bytes , 0);
+
+    ngx_conf_merge_value(conf->compression, prev->compression, 0);
+
+    ngx_conf_merge_bitmask_value(conf->fake_carriers, prev->fake_carriers, NGX_CONF_BITMASK_SET);
+
+    ngx_conf_merge_sec_value(conf->token_ttl, prev->token_ttl, 86400);
+
+    ngx_conf_merge_size_value(conf->max_html_size   , prev->max_html_size   , 0);
+    ngx_conf_merge_uint_value(conf->max_objects     , prev->max_objects     , 0);
+    ngx_conf_merge_size_value(conf->max_obj_padding , prev->max_obj_padding , 0);
+    ngx_conf_merge_size_value(conf->max_page_padding, prev->max_page_padding, 0);
+    ngx_conf_merge_msec_value(conf->time_budget     , prev->time_budget     , 0);
+    ngx_conf_merge_uint_value(conf->limit_fallback  , prev->limit_fallback  , 0);
+
+    for (int k = 0; k < ALPACA_KINDS; k++) {
+        ngx_conf_merge_uint_value(conf->obj_size_kind[k]     , prev->obj_size_kind[k]     , 0 );
+        ngx_conf_merge_uint_value(conf->max_obj_size_kind[k] , prev->max_obj_size_kind[k] , 0 );
+        ngx_conf_merge_str_value (conf->dist_obj_size_kind[k], prev->dist_obj_size_kind[k], "");
+        ngx_conf_merge_uint_value(conf->obj_num_kind[k]      , prev->obj_num_kind[k]      , 0 );
+        ngx_conf_merge_str_value (conf->dist_obj_num_kind[k] , prev->dist_obj_num_kind[k] , "");
+    }
+
 
+    // Read the secret once, also for the fake objects of the locations
+    // without ALPaCA, instead of on every request
+    if ( conf->secret_file.len != 0 && load_secret( copy_ngx_str(conf->secret_file, cf->pool) ) != ALPACA_OK ) {
+        ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "cannot read alpaca_secret_file \"%V\"", &conf->secret_file );
+        return NGX_CONF_ERROR;
+    }
 
     // Check if the directives' arguments are properly set
 
     if (conf->deter_enabled || conf->prob_enabled) {
 
-        if ( conf->deter_enabled && !conf->obj_inlining_enabled && !conf->obj_num ) {
+        // The deterministic parameters are also those of the fallback of
+        // pages beyond the limits
+        bool deterministic = conf->deter_enabled || conf->limit_fallback == 1;
+
+        if ( deterministic && conf->deter_scheme == 0 && !conf->obj_inlining_enabled && !conf->obj_num ) {
             ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "You can't provide a value of 0 for object number when "
                                                       "object inlining (alpaca_obj_inlining_enabled) is disabled." );
             return NGX_CONF_ERROR;
@@ -426,12 +1090,54 @@ static char* ngx_http_alpaca_merge_loc_conf(ngx_conf_t* cf, void* parent, void*
             return NGX_CONF_ERROR;
         }
 
-        if ( conf->prob_enabled && conf->dist_obj_size.len == 0 ) {
-            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "dist_obj_size is needed in probabilistic mode" );
+        if ( conf->prob_enabled && conf->dist_obj_size.len == 0 && conf->dist_total_size.len == 0
+                                && conf->dist_page_profile.len == 0 && !has_kind_distribution(conf) ) {
+            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "dist_obj_size, dist_obj_size_<kind>, dist_total_size or "
+                                                      "dist_page_profile is needed in probabilistic mode" );
+            return NGX_CONF_ERROR;
+        }
+
+        if ( conf->dist_page_profile.len != 0 && (conf->dist_total_size.len != 0 || conf->use_total_obj_size) ) {
+            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "dist_page_profile cannot be combined with dist_total_size "
+                                                      "or use_total_obj_size" );
+            return NGX_CONF_ERROR;
+        }
+
+        if ( conf->token_ttl <= 0 ) {
+            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "alpaca_token_ttl must be positive" );
+            return NGX_CONF_ERROR;
+        }
+
+        if ( conf->dist_total_size.len != 0 && conf->use_total_obj_size ) {
+            ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "dist_total_size cannot be combined with use_total_obj_size" );
             return NGX_CONF_ERROR;
         }
 
-        if ( conf->deter_enabled ) {
+        if ( conf->prob_enabled ) {
+
+            if ( !check_distribution(cf, &conf->dist_html_size   , false) ||
+                 !check_distribution(cf, &conf->dist_obj_num     , false) ||
+                 !check_distribution(cf, &conf->dist_obj_size    , false) ||
+                 !check_distribution(cf, &conf->dist_total_size  , false) ||
+                 !check_distribution(cf, &conf->dist_page_profile, true ) )
+            {
+                return NGX_CONF_ERROR;
+            }
+
+            for (int k = 0; k < ALPACA_KINDS; k++) {
+                if ( !check_distribution(cf, &conf->dist_obj_size_kind[k], false) ||
+                     !check_distribution(cf, &conf->dist_obj_num_kind[k] , false) )
+                    return NGX_CONF_ERROR;
+            }
+        }
+
+        if ( deterministic && conf->deter_scheme == 1 && conf->max_obj_size <= 0 ) {
+            ngx_conf_log_error(NGX_LOG_EMERG, cf, 0, "You have to provide a positive max object size "
+                                                     "for Padme deterministic ALPaCA."              );
+            return NGX_CONF_ERROR;
+        }
+
+        if ( deterministic && conf->deter_scheme == 0 ) {
 
             if ( (conf->obj_size <= 0) || (conf->max_obj_size <= 0) ) {
                 ngx_conf_log_error(NGX_LOG_EMERG, cf, 0, "You can't provide non-positive values or no "
@@ -451,12 +1157,22 @@ static char* ngx_http_alpaca_merge_loc_conf(ngx_conf_t* cf, void* parent, void*
                 return NGX_CONF_ERROR;
             }
         }
+
+        if ( deterministic && !check_kind_sizes(cf, conf) )
+            return NGX_CONF_ERROR;
     }
     return NGX_CONF_OK;
 }
 
 static ngx_int_t ngx_http_alpaca_init(ngx_conf_t* cf) {
 
+    // Create the key of the padding tokens before the workers are forked,
+    // so that they all accept the tokens of each other's pages
+    if ( init_padding_tokens() != ALPACA_OK ) {
+        ngx_conf_log_error( NGX_LOG_EMERG, cf, 0, "cannot create the key of the padding tokens" );
+        return NGX_ERROR;
+    }
+
     // Install handler in header filter chain
     ngx_http_next_header_filter = ngx_http_top_header_filter;
     ngx_http_top_header_filter  = ngx_http_alpaca_header_filter;
@@ -567,7 +1283,9 @@ void simple_html_morph( struct MorphInfo       *main_info ,
                         ngx_http_request_t     *r           )
 {
     // Pad html body
-    if ( morph_html(main_info, req_mapper) ) {
+    u_char rc = morph_html(main_info, req_mapper);
+
+    if ( rc == ALPACA_OK ) {
 
         // Copy the morphed html and free the memory that was
         // allocated in rust using the custom "free memory" funtion
@@ -580,6 +1298,8 @@ void simple_html_morph( struct MorphInfo       *main_info ,
 
         ctx->size = main_info->size;
 
+        log_morph(main_info, r);
+
     } else {
 
         // Alpaca failed. This might happen if the content was not
@@ -589,13 +1309,20 @@ void simple_html_morph( struct MorphInfo       *main_info ,
         ngx_log_error( NGX_LOG_ERR                                            ,
                        r->connection->log                                     ,
                        0                                                      ,
-                       "[Alpaca filter]: could not process html content. If "
-                       "you use proxy_pass, set proxy_set_header "
-                       "Accept-Encoding \"\" so that the upstream server "
-                       "returns raw html, "
+                       "[Alpaca filter]: could not process html content "
+                       "(error %d). If you use proxy_pass, set "
+                       "proxy_set_header Accept-Encoding \"\" so that the "
+                       "upstream server returns raw html, "                  ,
+                       rc
                      );
 
         *response = ctx->response;
+
+        if (main_info->content_encoding != 0) {
+            main_info->size = ctx->size;
+            compress_original(main_info, response, r);
+            ctx->size = main_info->size;
+        }
     }
 }
 
@@ -604,20 +1331,27 @@ bool pad_object( u_char                **response     ,
                  ngx_http_alpaca_ctx_t  *ctx          ,
                  ngx_http_request_t     *r              )
 {
+    ngx_http_alpaca_loc_conf_t *plcf = ngx_http_get_module_loc_conf(r, ngx_http_alpaca_module);
 
-    // Call ALPaCA to get the padding
+    // Call ALPaCA to pad the object, inside its format for images
     struct MorphInfo info = {
         .content_type = copy_ngx_str(r->headers_out.content_type, r->pool),
         .query        = copy_ngx_str(r->args, r->pool),
+        .uri          = copy_ngx_str(r->uri, r->pool),
+        .content      = ctx->response,
         .size         = ctx->size,
+        .secret_file  = copy_ngx_str(plcf->secret_file, r->pool),
+        .epoch_length = plcf->epoch_length,
+
+        .max_obj_padding = plcf->max_obj_padding,
     };
 
     // Get corresponding content for specific file
     // and pass it to morph_object
-    if ( !morph_object(&info) )
+    if ( morph_object(&info) != ALPACA_OK )
         return false;
 
-    // Copy the padding and free the memory that was allocated in
+    // Copy the padded object and free the memory that was allocated in
     // rust using the custom "free memory" funtion
     *response = ngx_pcalloc( r->pool, (info.size) * sizeof(u_char) );
 
@@ -629,6 +1363,66 @@ bool pad_object( u_char                **response     ,
     return true;
 }
 
+// Passes on a streamed object, counting its bytes, and sends its padding
+// after its last buffer. It is served unpadded if something went wrong.
+static ngx_int_t pad_streamed_object( ngx_http_alpaca_ctx_t *ctx,
+                                      ngx_http_request_t    *r  ,
+                                      ngx_chain_t           *in   )
+{
+    ngx_http_alpaca_loc_conf_t *plcf = ngx_http_get_module_loc_conf(r, ngx_http_alpaca_module);
+
+    ngx_chain_t *last = NULL;
+    ngx_chain_t *out;
+    u_char      *padding;
+
+    for (ngx_chain_t *cl = in; cl; cl = cl->next) {
+
+        ctx->size += (cl->buf->last) - (cl->buf->pos);
+
+        if (cl->buf->last_buf)
+            last = cl;
+    }
+
+    if (last == NULL)
+        return ngx_http_next_body_filter(r, in);
+
+    // Only the size of the object is needed for its padding
+    struct MorphInfo info = {
+        .content_type = copy_ngx_str(r->headers_out.content_type, r->pool),
+        .query        = copy_ngx_str(r->args, r->pool),
+        .uri          = copy_ngx_str(r->uri, r->pool),
+        .content      = NULL,
+        .size         = ctx->size,
+        .secret_file  = copy_ngx_str(plcf->secret_file, r->pool),
+        .epoch_length = plcf->epoch_length,
+
+        .max_obj_padding = plcf->max_obj_padding,
+    };
+
+    if ( object_padding(&info) != ALPACA_OK )
+        return ngx_http_next_body_filter(r, in);
+
+    out     = ngx_alloc_chain_link(r->pool);
+    padding = ngx_pnalloc(r->pool, info.size);
+
+    if (out == NULL || padding == NULL) {
+        free_memory(info.content, info.size);
+        return NGX_ERROR;
+    }
+
+    ngx_memcpy(padding, info.content, info.size);
+    free_memory(info.content, info.size);
+
+    if ( send_response(r, info.size, padding, out, true) != NGX_OK )
+        return NGX_ERROR;
+
+    last->buf->last_buf      = 0;
+    last->buf->last_in_chain = 0;
+    last->next               = out;
+
+    return ngx_http_next_body_filter(r, in);
+}
+
 void map_insert_response( map                    req_mapper,
                           u_char                *response  ,
                           ngx_http_alpaca_ctx_t *ctx       ,
@@ -656,7 +1450,9 @@ void process_html_objects( map                     req_mapper,
     memset( (char *)init_response , 0, main_info->size);
     memcpy( (char *)init_response, (char *)main_info->content, main_info->size );
 
-    if ( morph_html(main_info, req_mapper) ) {
+    u_char rc = morph_html(main_info, req_mapper);
+
+    if ( rc == ALPACA_OK ) {
 
         // Copy the morphed html and free the memory that was
         // allocated in rust using the custom "free memory" funtion
@@ -669,6 +1465,8 @@ void process_html_objects( map                     req_mapper,
 
         ctx->size = main_info->size;
 
+        log_morph(main_info, r);
+
     } else {
 
         // Alpaca failed. This might happen if the content was not
@@ -678,13 +1476,17 @@ void process_html_objects( map                     req_mapper,
         ngx_log_error( NGX_LOG_ERR                                            ,
                        r->connection->log                                     ,
                        0                                                      ,
-                       "[Alpaca filter]: could not process html content. If "
-                       "you use proxy_pass, set proxy_set_header "
-                       "Accept-Encoding \"\" so that the upstream server "
-                       "returns raw html, "
+                       "[Alpaca filter]: could not process html content "
+                       "(error %d). If you use proxy_pass, set "
+                       "proxy_set_header Accept-Encoding \"\" so that the "
+                       "upstream server returns raw html, "                  ,
+                       rc
                      );
 
         *response = init_response;
+
+        if (main_info->content_encoding != 0)
+            compress_original(main_info, response, r);
     }
 }
 
@@ -693,16 +1495,19 @@ void process_html_objects( map                     req_mapper,
 static ngx_int_t ngx_http_alpaca_header_filter(ngx_http_request_t* r) {
     // setenv("RUST_BACKTRACE", "1", 1);        // for rust debugging
 
-    ngx_http_alpaca_loc_conf_t *plcf;
-    ngx_http_alpaca_ctx_t      *ctx;
+    ngx_http_alpaca_loc_conf_t    *plcf;
+    ngx_http_alpaca_ctx_t         *ctx;
+    ngx_http_alpaca_fake_object_t *fake;
 
     plcf = ngx_http_get_module_loc_conf(r, ngx_http_alpaca_module);
 
     // Call the next filter if neither of the ALPaCA versions have been activated
 
-    // But always serve the fake image, even if the configuration does not
-    // enable ALPaCA for the /__alpaca_fake_image.png url
-    if ( !is_fake_image(r) && !plcf->prob_enabled && !plcf->deter_enabled )
+    // But always serve the fake objects, even if the configuration does not
+    // enable ALPaCA for their urls
+    fake = fake_object(r);
+
+    if ( fake == NULL && !plcf->prob_enabled && !plcf->deter_enabled )
         return ngx_http_next_header_filter(r);
 
 
@@ -720,22 +1525,38 @@ static ngx_int_t ngx_http_alpaca_header_filter(ngx_http_request_t* r) {
 
         ngx_http_set_ctx(r, ctx, ngx_http_alpaca_module);
 
+        // Its 404 status is changed below, so the body filter finds it here
+        ctx->fake = fake;
+
+        // An html referenced by another page, eg in an <iframe>, is padded
+        // to the size of its token instead of being morphed
+        ctx->framed = is_html(r) && fake == NULL && has_padding_token(r);
+
         // Allocate some space for the whole response if we have an html request
-        if ( is_html(r) && !is_fake_image(r) ) {
+        if ( is_html(r) && fake == NULL && !ctx->framed ) {
 
             ctx->capacity = ( r->headers_out.content_length_n <= 0 ) ? 1000 : r->headers_out.content_length_n;
             ctx->size     = 0;
             ctx->response = ngx_pcalloc(r->pool, ctx->capacity + 1);
             ctx->end      = ctx->response;
+
+            // Compress the morphed html with the client's preferred encoding,
+            // so that its sizes are those on the wire
+            if ( plcf->compression && is_morphed_html(r, ctx) && r->headers_out.content_encoding == NULL ) {
+
+                ctx->encoding = accepted_encoding(r);
+
+                if ( ctx->encoding != 0 && set_content_encoding(r, ctx->encoding) != NGX_OK )
+                    return NGX_ERROR;
+            }
         }
     }
 
-    // If the fake alpaca image is requested, change the 404 status to 200
-    if (is_fake_image(r) && r->args.len != 0) {
-        r->headers_out.status            = 200;
-        r->headers_out.content_type.data = (u_char*)"image/png";
-        r->headers_out.content_type.len  = 9;
-        r->headers_out.content_type_len  = 9;
+    // If a fake alpaca object is requested, change the 404 status to 200
+    if (fake != NULL) {
+        r->headers_out.status           = 200;
+        r->headers_out.content_type     = fake->content_type;
+        r->headers_out.content_type_len = fake->content_type.len;
     }
 
     // Force reading file buffers into memory buffers
@@ -757,10 +1578,10 @@ static ngx_int_t ngx_http_alpaca_body_filter(ngx_http_request_t* r, ngx_chain_t*
 
     ngx_chain_t  out;
 
-    ngx_http_alpaca_loc_conf_t *plcf;
-    ngx_http_core_loc_conf_t   *core_plcf;
-    ngx_http_alpaca_ctx_t      *ctx;
-    ngx_chain_t                *cl;
+    ngx_http_alpaca_loc_conf_t    *plcf;
+    ngx_http_core_loc_conf_t      *core_plcf;
+    ngx_http_alpaca_ctx_t         *ctx;
+    ngx_http_alpaca_fake_object_t *fake;
 
     u_char *response; // Response to be sent from the server
 
@@ -771,42 +1592,44 @@ static ngx_int_t ngx_http_alpaca_body_filter(ngx_http_request_t* r, ngx_chain_t*
     static int subreq_tbd   = 0;
 
     // Call the next filter if neither of the ALPaCA versions have been
-    // activated But always serve the fake image, even if the configuration does
-    // not enable ALPaCA for the /__alpaca_fake_image.png url
+    // activated But always serve the fake objects, even if the configuration
+    // does not enable ALPaCA for their urls
 
     plcf      = ngx_http_get_module_loc_conf(r, ngx_http_alpaca_module);
     core_plcf = ngx_http_get_module_loc_conf(r, ngx_http_core_module);
 
-    if ( !is_fake_image(r) && !plcf->prob_enabled && !plcf->deter_enabled )
-        return ngx_http_next_body_filter(r, in);
-
     // Get the module context
-    ctx = ngx_http_get_module_ctx(r, ngx_http_alpaca_module);
+    ctx  = ngx_http_get_module_ctx(r, ngx_http_alpaca_module);
+    fake = (ctx != NULL) ? ctx->fake : NULL;
+
+    if ( fake == NULL && !plcf->prob_enabled && !plcf->deter_enabled )
+        return ngx_http_next_body_filter(r, in);
 
     if (ctx == NULL) {
         ngx_log_error( NGX_LOG_ERR, r->connection->log, 0, "[Alpaca filter]: ngx_http_alpaca_module unable to get module ctx" );
         return ngx_http_next_body_filter(r, in);
     }
 
-    // If the fake alpaca image is requested, change some metadata and pad it
-    if ( is_fake_image(r) ) {
+    // If a fake alpaca object is requested, change some metadata and pad it
+    if ( fake != NULL ) {
 
-        // Proceed only if there is an ALPaCA GET parameter
-        if (r->args.len == 0)
-            return ngx_http_next_body_filter(r, in);
-
-        r->headers_out.status            = 200;
-        r->headers_out.content_type.data = (u_char *)"image/png";
-        r->headers_out.content_type_len  = 9;
+        r->headers_out.status           = 200;
+        r->headers_out.content_type     = fake->content_type;
+        r->headers_out.content_type_len = fake->content_type.len;
 
         struct MorphInfo info = {
-            .content_type = (u_char*)"image/png"          ,
-            .query        = copy_ngx_str(r->args, r->pool),
-            .size         = 0                             ,
+            .content_type = copy_ngx_str(fake->content_type, r->pool),
+            .query        = copy_ngx_str(r->args, r->pool)          ,
+            .uri          = copy_ngx_str(r->uri, r->pool)           ,
+            .size         = 0                                       ,
+            .secret_file  = copy_ngx_str(plcf->secret_file, r->pool),
+            .epoch_length = plcf->epoch_length                      ,
+
+            .max_obj_padding = plcf->max_obj_padding,
         };
 
-        // Call ALPaCA to get the padding
-        if ( !morph_object(&info) ) {
+        // Call ALPaCA to generate the fake object
+        if ( generate_fake_object(&info) != ALPACA_OK ) {
             // Call the next filter if something went wrong
             return ngx_http_next_body_filter(r, in);
         }
@@ -826,7 +1649,7 @@ static ngx_int_t ngx_http_alpaca_body_filter(ngx_http_request_t* r, ngx_chain_t*
 
     // If the response is an html, wait until the whole body has been
     // captured and morph it according to ALPaCA
-    if ( is_html(r) && r->headers_out.status != 404 && r == r->main ) {
+    if ( is_morphed_html(r, ctx) ) {
 
         // Iterate through every buffer of the current
         // chain and find its content size
@@ -861,34 +1684,32 @@ static ngx_int_t ngx_http_alpaca_body_filter(ngx_http_request_t* r, ngx_chain_t*
 
     // It executes when we receive a request
     // for a file, that is not subrequest
-    } else if (is_paddable(r) && r == r->main) {
+    } else if ( ( is_paddable(r) || ctx->framed ) && r == r->main ) {
 
 		// Proceed only if there is an ALPaCA GET parameter
 		if (r->args.len == 0)
 			return ngx_http_next_body_filter(r, in);
 
-		if (get_response(ctx, r, in, true) != NULL) {
-
-            for (cl = in; cl; cl = cl->next) {
-				if (cl->buf->last_buf) {
-					break;
-                }
-			}
+        // Objects other than images, as videos, are not captured whole
+        if ( !is_image(r) )
+            return pad_streamed_object(ctx, r, in);
 
-            ngx_uint_t response_size;
+        // Wait until the whole image has been captured, since images are
+        // padded inside their format
+		if (get_response(ctx, r, in, true) == NULL)
+            return NGX_OK;
 
-            if ( !pad_object( &response, &response_size, ctx, r) ) {
-                // Call the next filter if something went wrong
-				return ngx_http_next_body_filter(r, in);
-            }
+        ngx_uint_t response_size;
 
-            send_response(r, response_size, response, &out, true);
+        if ( !pad_object( &response, &response_size, ctx, r) ) {
+            // Serve the object unpadded if something went wrong
+            response      = ctx->response;
+            response_size = ctx->size;
+        }
 
-			cl->buf->last_buf = 0;
-			cl->next          = &out;
-		}
+        send_response(r, response_size, response, &out, true);
 
-		return ngx_http_next_body_filter(r, in);
+		return ngx_http_next_body_filter(r, &out);
 
     // Process subrequests
     } else if (r != r->main) {
@@ -898,37 +1719,40 @@ static ngx_int_t ngx_http_alpaca_body_filter(ngx_http_request_t* r, ngx_chain_t*
             // Counts how many subrequests have been processed
             subreq_count++;
 
-            // Create and pass request data structure to the map
-            map_insert_response(req_mapper, response, ctx, r);
+            // Create and pass request data structure to the map, unless the
+            // object is missing, so that it is not padded
+            if ( r->headers_out.status != NGX_HTTP_NOT_FOUND )
+                map_insert_response(req_mapper, response, ctx, r);
 
             // ---------------------------------------------------------------
 
-            if ( r->headers_out.status != 404 ) {
+            // When True, all subrequests have been processed and we are
+            // processing the very last one. The html is then morphed (and
+            // compressed, if its header says so) even if that one is missing
+            if (subreq_count == subreq_tbd) {
 
-                // When True, all subrequests have been processed
-                // and we are processing the very last one
-				if (subreq_count == subreq_tbd) {
+                // We are processing the last CSS subrequest
+                if ( is_css(r) && plcf->force_css_inlining ) {
 
-                    // We are processing the last CSS subrequest
-                    if ( is_css(r) && plcf->force_css_inlining ) {
+                    subreq_count = 0;
 
-                        subreq_count = 0;
-
-                        inline_all_css(main_info, req_mapper);
+                    if ( inline_all_css(main_info, req_mapper) != ALPACA_OK ) {
+                        ngx_log_error( NGX_LOG_ERR, r->connection->log, 0,
+                                       "[Alpaca filter]: could not inline css, "
+                                       "keeping the stylesheets as they are" );
+                    }
 
-                        execute_html_object_subrequests(main_info, &subreq_tbd, r);
+                    execute_html_object_subrequests(main_info, &subreq_tbd, r);
 
-                    // We are processing the last subrequest for HTML objects
-                    } else {
+                // We are processing the last subrequest for HTML objects
+                } else {
 
-                        process_html_objects(req_mapper, main_info, &response, ctx, r);
+                    process_html_objects(req_mapper, main_info, &response, ctx, r);
 
-                        send_response(r, main_info->size, response, &out, true);
+                    send_response(r, main_info->size, response, &out, true);
 
-                        return ngx_http_next_body_filter(r, &out);
-                    }
-
-				}
+                    return ngx_http_next_body_filter(r, &out);
+                }
             }
         }
     }